@@ -0,0 +1,238 @@
+use crate::runtime::js::JsRuntimeBuilder;
+use crate::runtime::midi::{self, MidiEvent};
+use crate::runtime::runtime::{self, ScriptRuntime};
+use serde::Serialize;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// ベンチマークの条件。各項目の全ての組み合わせで実行する。
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchOptions {
+    pub block_sizes: Vec<usize>,
+    pub sample_rates: Vec<u32>,
+
+    // 同時に押し続ける鍵盤の数
+    pub voices: Vec<usize>,
+    pub channels: usize,
+
+    // 計測するオーディオの長さ (秒) と、計測前に JIT を温めるための長さ
+    pub duration: f64,
+    pub warmup: f64,
+}
+
+impl Default for BenchOptions {
+    fn default() -> Self {
+        BenchOptions {
+            block_sizes: vec![64, 512],
+            sample_rates: vec![48000],
+            voices: vec![1, 8, 32],
+            channels: 2,
+            duration: 5.0,
+            warmup: 0.5,
+        }
+    }
+}
+
+/// 1 つの条件での計測結果。時間の単位はマイクロ秒。
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BenchResult {
+    pub block_size: usize,
+    pub sample_rate: u32,
+    pub voices: usize,
+    pub blocks: usize,
+
+    // 1 ブロックの処理にかけられる時間 (ブロックの長さ)
+    pub budget_us: f64,
+    pub p50_us: f64,
+    pub p90_us: f64,
+    pub p99_us: f64,
+    pub max_us: f64,
+
+    // 処理時間の合計 / オーディオの長さ (1 未満なら実時間より速い)
+    pub realtime_factor: f64,
+
+    // ブロックの処理時間が budget_us を超えた回数
+    pub overruns: usize,
+
+    pub gc_count: u64,
+    pub gc_pause_us: f64,
+    // 最大値だけはコンパイルとウォームアップ中の GC も含む
+    pub gc_max_pause_us: f64,
+
+    // 計測中に JavaScript のヒープに確保されたバイト数
+    pub allocated_bytes: u64,
+    pub allocated_bytes_per_block: f64,
+}
+
+impl BenchResult {
+    // p99 の処理時間がブロックの長さに収まっているか
+    pub fn sustainable(&self) -> bool {
+        self.p99_us <= self.budget_us
+    }
+}
+
+/// 全ての条件でスクリプトを実行して計測する。条件ごとにコンパイルし直す。
+///
+/// 最初のブロックで voices 個の Note On を送り、それ以降は無音の入力でそのまま押し続ける。
+/// voices が 128 を超える場合は MIDI チャンネルを変えて送る。
+pub fn bench(
+    code: &str,
+    path: Option<&Path>,
+    options: &BenchOptions,
+) -> runtime::Result<Vec<BenchResult>> {
+    let mut results = Vec::new();
+    for &sample_rate in &options.sample_rates {
+        for &block_size in &options.block_sizes {
+            for &voices in &options.voices {
+                results.push(bench_one(
+                    code,
+                    path,
+                    sample_rate,
+                    block_size,
+                    voices,
+                    options,
+                )?);
+            }
+        }
+    }
+    Ok(results)
+}
+
+fn bench_one(
+    code: &str,
+    path: Option<&Path>,
+    sample_rate: u32,
+    block_size: usize,
+    voices: usize,
+    options: &BenchOptions,
+) -> runtime::Result<BenchResult> {
+    if block_size == 0 || sample_rate == 0 || options.channels == 0 {
+        return Err("block size, sample rate and channels must be greater than 0".into());
+    }
+    // console.log の出力は計測の邪魔になり、JSON の出力にも混ざるため捨てる
+    let mut runtime = JsRuntimeBuilder::new()
+        .on_log(Rc::new(|_| {}))
+        .random_seed(0)
+        .build();
+    runtime.compile(code, path)?;
+
+    let note_on: Vec<u8> = (0..voices)
+        .flat_map(|i| {
+            let channel = ((i / 128) % 16) as u8;
+            MidiEvent::new(0, midi::NOTE_ON, channel, (i % 128) as u8, 100).encode()
+        })
+        .collect();
+    let block_seconds = block_size as f64 / sample_rate as f64;
+    let warmup_blocks = (options.warmup / block_seconds).ceil() as usize;
+    let blocks = ((options.duration / block_seconds).ceil() as usize).max(1);
+    let mut audio = vec![0.0; block_size * options.channels];
+    let mut process = |runtime: &mut dyn ScriptRuntime, midi: &[u8]| {
+        audio.fill(0.0);
+        let start = Instant::now();
+        runtime.audio(&mut audio, options.channels, sample_rate as f32, midi)?;
+        runtime::Result::Ok(start.elapsed())
+    };
+
+    process(&mut runtime, &note_on)?;
+    for _ in 0..warmup_blocks {
+        process(&mut runtime, &[])?;
+    }
+
+    let gc_before = runtime.gc_stats();
+    let heap_before = runtime.heap_used();
+    let mut times = Vec::with_capacity(blocks);
+    for _ in 0..blocks {
+        times.push(process(&mut runtime, &[])?);
+    }
+    let gc_after = runtime.gc_stats();
+    let heap_after = runtime.heap_used();
+
+    // 増えたヒープの使用量と GC で解放された量の和が確保された量になる
+    let allocated_bytes = (heap_after as i64 - heap_before as i64
+        + (gc_after.reclaimed_bytes - gc_before.reclaimed_bytes) as i64)
+        .max(0) as u64;
+    let total: Duration = times.iter().sum();
+    let budget = Duration::from_secs_f64(block_seconds);
+    let overruns = times.iter().filter(|t| **t > budget).count();
+    times.sort();
+    let percentile = |p: f64| {
+        let index = ((times.len() as f64 * p).ceil() as usize).clamp(1, times.len()) - 1;
+        micros(times[index])
+    };
+    Ok(BenchResult {
+        block_size,
+        sample_rate,
+        voices,
+        blocks,
+        budget_us: micros(budget),
+        p50_us: percentile(0.5),
+        p90_us: percentile(0.9),
+        p99_us: percentile(0.99),
+        max_us: micros(*times.last().unwrap()),
+        realtime_factor: total.as_secs_f64() / (blocks as f64 * block_seconds),
+        overruns,
+        gc_count: gc_after.count - gc_before.count,
+        gc_pause_us: micros(gc_after.pause - gc_before.pause),
+        gc_max_pause_us: micros(gc_after.max_pause),
+        allocated_bytes,
+        allocated_bytes_per_block: allocated_bytes as f64 / blocks as f64,
+    })
+}
+
+fn micros(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1e6
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bench_voices() {
+        let options = BenchOptions {
+            block_sizes: vec![64, 128],
+            sample_rates: vec![48000],
+            voices: vec![0, 200],
+            channels: 2,
+            duration: 0.05,
+            warmup: 0.01,
+        };
+        // 押されている鍵盤の数だけ配列を確保する
+        let code = r#"
+            const held = new Set();
+            const audio = (ctx) => {
+                for (const e of ps88.parseMidi(ctx.midi)) held.add(`${e.channel}:${e.data1}`);
+                const garbage = [];
+                for (let i = 0; i < held.size; i++) garbage.push(new Float32Array(64));
+                ctx.audio.fill(held.size / 1000);
+            };
+            const gui = () => {};
+        "#;
+        let results = bench(code, None, &options).unwrap();
+        assert_eq!(results.len(), 4);
+        assert_eq!(
+            results
+                .iter()
+                .map(|r| (r.block_size, r.voices))
+                .collect::<Vec<_>>(),
+            vec![(64, 0), (64, 200), (128, 0), (128, 200)]
+        );
+        for result in &results {
+            assert_eq!(
+                result.blocks,
+                (0.05 * 48000.0 / result.block_size as f64).ceil() as usize
+            );
+            assert!(result.p50_us <= result.p90_us && result.p90_us <= result.p99_us);
+            assert!(result.p99_us <= result.max_us);
+            assert!((result.budget_us - result.block_size as f64 / 0.048).abs() < 1e-6);
+        }
+        // 200 個の鍵盤 (2 チャンネルに分けて送られる) の分だけ確保される
+        assert!(results[1].allocated_bytes_per_block >= 200.0 * 64.0 * 4.0);
+        assert!(results[0].allocated_bytes_per_block < results[1].allocated_bytes_per_block);
+
+        // JSON に変換できる
+        let json = serde_json::to_value(&results[0]).unwrap();
+        assert_eq!(json["voices"], 0);
+    }
+}
@@ -0,0 +1,232 @@
+use crate::render::{self, smf::TimedEvent};
+use crate::runtime::js::JsRuntimeBuilder;
+use crate::runtime::midi::{self, MidiEvent};
+use crate::runtime::runtime::ScriptRuntime;
+use std::path::Path;
+
+// この値を超える直流成分 (出力の平均値) は警告する
+const DC_OFFSET_THRESHOLD: f32 = 1e-2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// スクリプトの問題
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{}: {}", severity, self.message)
+    }
+}
+
+/// スクリプトを実行する条件
+#[derive(Debug, Clone, PartialEq)]
+pub struct CheckOptions {
+    pub sample_rates: Vec<u32>,
+    pub channels: Vec<usize>,
+    pub block_size: usize,
+
+    // 条件ごとに実行するブロック数
+    pub blocks: usize,
+}
+
+impl Default for CheckOptions {
+    fn default() -> Self {
+        CheckOptions {
+            sample_rates: vec![44100, 48000, 96000],
+            channels: vec![1, 2],
+            block_size: 512,
+            blocks: 8,
+        }
+    }
+}
+
+/// スクリプトをコンパイルし、サンプリングレートとチャンネル数の組み合わせごとに数ブロック実行して問題を探す。
+///
+/// 入力は無音で、ボイスの処理も確認できるように 2 ブロック目から途中まで 1 音だけ鳴らす。
+/// コンパイルエラー (構文エラーや audio / gui が定義されていないなど)、実行時の例外、
+/// NaN / Inf の出力はエラー、非正規化数や直流成分の出力は警告になる。
+pub fn check(code: &str, path: Option<&Path>, options: &CheckOptions) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    for &sample_rate in &options.sample_rates {
+        for &channels in &options.channels {
+            let condition = format!("{} Hz, {} ch", sample_rate, channels);
+
+            // 前の条件での実行結果が影響しないように、条件ごとにコンパイルし直す
+            let mut runtime = JsRuntimeBuilder::new().random_seed(0).build();
+            if let Err(err) = runtime.compile(code, path) {
+                // コンパイルエラーは条件によらないため 1 度だけ報告する
+                return vec![Diagnostic {
+                    severity: Severity::Error,
+                    message: err.to_string(),
+                }];
+            }
+            let frames = options.block_size * options.blocks;
+            let time = |frame: usize| frame as f64 / sample_rate as f64;
+            let events = [
+                TimedEvent {
+                    time: time(options.block_size),
+                    event: MidiEvent::new(0, midi::NOTE_ON, 0, 60, 100),
+                },
+                TimedEvent {
+                    time: time(frames / 2),
+                    event: MidiEvent::new(0, midi::NOTE_OFF, 0, 60, 0),
+                },
+            ];
+            let render_options = render::RenderOptions {
+                sample_rate,
+                block_size: options.block_size,
+                channels,
+                frames,
+            };
+            match render::render(&mut runtime, &[], &events, &render_options) {
+                Ok(output) => analyze(&output, options.block_size, &condition, &mut diagnostics),
+                Err(err) => diagnostics.push(Diagnostic {
+                    severity: Severity::Error,
+                    message: format!("{} ({})", err, condition),
+                }),
+            }
+        }
+    }
+    diagnostics
+}
+
+// 出力の各チャンネルの値を確認する
+fn analyze(
+    output: &[Vec<f32>],
+    block_size: usize,
+    condition: &str,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    for (c, channel) in output.iter().enumerate() {
+        let position = |frame: usize| {
+            format!(
+                "channel {}, block {}, sample {} ({})",
+                c,
+                frame / block_size,
+                frame % block_size,
+                condition
+            )
+        };
+        if let Some(frame) = channel.iter().position(|v| v.is_nan()) {
+            diagnostics.push(Diagnostic {
+                severity: Severity::Error,
+                message: format!("output is NaN at {}", position(frame)),
+            });
+        }
+        if let Some(frame) = channel.iter().position(|v| v.is_infinite()) {
+            diagnostics.push(Diagnostic {
+                severity: Severity::Error,
+                message: format!("output is infinite at {}", position(frame)),
+            });
+        }
+        if let Some(frame) = channel.iter().position(|v| v.is_subnormal()) {
+            diagnostics.push(Diagnostic {
+                severity: Severity::Warning,
+                message: format!(
+                    "output contains denormal numbers, which are slow to process, at {}",
+                    position(frame)
+                ),
+            });
+        }
+        let finite: Vec<f32> = channel.iter().copied().filter(|v| v.is_finite()).collect();
+        if !finite.is_empty() {
+            let mean = finite.iter().map(|v| *v as f64).sum::<f64>() / finite.len() as f64;
+            if mean.abs() as f32 > DC_OFFSET_THRESHOLD {
+                diagnostics.push(Diagnostic {
+                    severity: Severity::Warning,
+                    message: format!(
+                        "output has a DC offset of {:.4} on channel {} ({})",
+                        mean, c, condition
+                    ),
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(code: &str) -> Vec<Diagnostic> {
+        let options = CheckOptions {
+            sample_rates: vec![48000],
+            channels: vec![1, 2],
+            block_size: 64,
+            blocks: 4,
+        };
+        check(code, None, &options)
+    }
+
+    #[test]
+    fn valid_script() {
+        let diagnostics = run(include_str!("default_script.js"));
+        assert!(
+            diagnostics.iter().all(|d| d.severity == Severity::Warning),
+            "{:?}",
+            diagnostics
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let diagnostics = run("const audio = (ctx) => {};");
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0]
+            .message
+            .contains("'gui' function is not defined"));
+
+        let diagnostics = run("const audio = 1; const gui = () => {};");
+        assert!(diagnostics[0].message.contains("'audio' is not a function"));
+
+        let diagnostics = run("const audio = (ctx) => { ; const gui");
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+
+        // 実行時の例外は条件ごとに報告される
+        let diagnostics = run(r#"
+            const audio = (ctx) => { if (ps88.parseMidi(ctx.midi).length) throw new Error("boom"); };
+            const gui = () => {};
+        "#);
+        assert_eq!(diagnostics.len(), 2);
+        assert!(diagnostics[1].message.contains("boom"));
+        assert!(diagnostics[1].message.contains("48000 Hz, 2 ch"));
+
+        // 2 ブロック目の先頭で NaN になる
+        let diagnostics = run(r#"
+            const audio = (ctx) => {
+                for (const e of ps88.parseMidi(ctx.midi)) ctx.audio[e.time] = e.type === 0x9 ? NaN : 0;
+            };
+            const gui = () => {};
+        "#);
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+        assert!(diagnostics[0]
+            .message
+            .contains("NaN at channel 0, block 1, sample 0"));
+    }
+
+    #[test]
+    fn warnings() {
+        let diagnostics = run(r#"
+            const audio = (ctx) => {
+                ctx.audio.fill(0.5);
+                ctx.audio[0] = 1e-40;
+            };
+            const gui = () => {};
+        "#);
+        assert!(diagnostics.iter().all(|d| d.severity == Severity::Warning));
+        assert!(diagnostics[0].message.contains("denormal"));
+        assert!(diagnostics[1].message.contains("DC offset of 0.5"));
+    }
+}
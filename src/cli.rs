@@ -0,0 +1,77 @@
+mod bench;
+mod check;
+mod process;
+mod render;
+mod repl;
+mod test;
+
+use crate::runtime::js::{JsRuntime, JsRuntimeBuilder};
+use crate::runtime::runtime::{self, ScriptRuntime};
+use clap::{CommandFactory, Parser, Subcommand};
+use std::path::Path;
+use std::process::ExitCode;
+use std::rc::Rc;
+
+#[derive(Parser)]
+#[command(name = "ps88", version, about = "Programmable Synthesizer 88")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Render a script and a Standard MIDI File to a WAV file
+    Render(render::RenderArgs),
+    /// Process audio files through an effect script
+    Process(process::ProcessArgs),
+    /// Run golden-audio tests and ps88.test() tests in scripts
+    Test(test::TestArgs),
+    /// Check scripts for compile errors, runtime exceptions and invalid output
+    Check(check::CheckArgs),
+    /// Run a script in real time and evaluate code in its context
+    Repl(repl::ReplArgs),
+    /// Measure the processing time of a script under synthetic MIDI load
+    Bench(bench::BenchArgs),
+}
+
+/// コマンドライン引数でサブコマンドが指定されている場合は、それを実行して終了コードを返す。
+/// サブコマンドが指定されていない場合は None を返す (スタンドアロンのプラグインとして起動する)。
+pub fn run() -> Option<ExitCode> {
+    let name = std::env::args().nth(1)?;
+    if !Cli::command()
+        .get_subcommands()
+        .any(|command| command.get_name() == name)
+    {
+        return None;
+    }
+    let result = match Cli::parse().command {
+        Command::Render(args) => render::run(args),
+        Command::Process(args) => process::run(args),
+        Command::Test(args) => test::run(args),
+        Command::Check(args) => check::run(args),
+        Command::Repl(args) => repl::run(args),
+        Command::Bench(args) => bench::run(args),
+    };
+    Some(match result {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            ExitCode::FAILURE
+        }
+    })
+}
+
+// スクリプトのファイルを読み込んでコンパイルする
+// console.log の出力は標準エラー出力に書き出す
+fn compile_script(path: &Path, random_seed: u32) -> runtime::Result<JsRuntime> {
+    let code = std::fs::read_to_string(path)
+        .map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+    let path = std::fs::canonicalize(path)?;
+    let mut runtime = JsRuntimeBuilder::new()
+        .on_log(Rc::new(|log| eprintln!("{}", log)))
+        .random_seed(random_seed)
+        .build();
+    runtime.compile(&code, Some(&path))?;
+    Ok(runtime)
+}
@@ -0,0 +1,128 @@
+use crate::bench::{self, BenchOptions, BenchResult};
+use crate::runtime::runtime;
+use clap::Args;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+#[derive(Args)]
+pub struct BenchArgs {
+    /// Script to benchmark
+    script: PathBuf,
+
+    #[arg(long, value_delimiter = ',', default_value = "64,512")]
+    block_sizes: Vec<usize>,
+
+    #[arg(long, value_delimiter = ',', default_value = "48000")]
+    sample_rates: Vec<u32>,
+
+    /// Numbers of notes held at the same time
+    #[arg(long, value_delimiter = ',', default_value = "1,8,32")]
+    voices: Vec<usize>,
+
+    #[arg(long, default_value_t = 2)]
+    channels: usize,
+
+    /// Seconds of audio to measure for each case
+    #[arg(long, default_value_t = 5.0)]
+    duration: f64,
+
+    /// Seconds of audio to process before measuring
+    #[arg(long, default_value_t = 0.5)]
+    warmup: f64,
+
+    /// Print the results as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+pub fn run(args: BenchArgs) -> runtime::Result<ExitCode> {
+    let code = std::fs::read_to_string(&args.script)
+        .map_err(|err| format!("failed to read {}: {}", args.script.display(), err))?;
+    let path = std::fs::canonicalize(&args.script)?;
+    let options = BenchOptions {
+        block_sizes: args.block_sizes,
+        sample_rates: args.sample_rates,
+        voices: args.voices,
+        channels: args.channels,
+        duration: args.duration,
+        warmup: args.warmup,
+    };
+    let results = bench::bench(&code, Some(&path), &options)?;
+
+    if args.json {
+        // 実行環境による差を比較できるよう、バージョンと条件も一緒に出力する
+        let output = serde_json::json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "script": args.script,
+            "channels": options.channels,
+            "duration": options.duration,
+            "results": results,
+        });
+        println!("{}", serde_json::to_string_pretty(&output)?);
+    } else {
+        print_table(&results);
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn print_table(results: &[BenchResult]) {
+    println!(
+        "{:>6} {:>6} {:>6} {:>10} {:>10} {:>10} {:>10} {:>10} {:>6} {:>5} {:>10} {:>12}",
+        "rate",
+        "block",
+        "voices",
+        "budget us",
+        "p50 us",
+        "p90 us",
+        "p99 us",
+        "max us",
+        "rtf",
+        "gc",
+        "gc ms",
+        "alloc/block"
+    );
+    for r in results {
+        println!(
+            "{:>6} {:>6} {:>6} {:>10.1} {:>10.1} {:>10.1} {:>10.1} {:>10.1} {:>6.3} {:>5} {:>10.2} {:>12.0}{}",
+            r.sample_rate,
+            r.block_size,
+            r.voices,
+            r.budget_us,
+            r.p50_us,
+            r.p90_us,
+            r.p99_us,
+            r.max_us,
+            r.realtime_factor,
+            r.gc_count,
+            r.gc_pause_us / 1000.0,
+            r.allocated_bytes_per_block,
+            if r.sustainable() { "" } else { "  overrun" }
+        );
+    }
+
+    // 条件ごとに p99 がブロックの長さに収まる最大のボイス数
+    println!();
+    let mut cases: Vec<(u32, usize)> = results
+        .iter()
+        .map(|r| (r.sample_rate, r.block_size))
+        .collect();
+    cases.dedup();
+    for (sample_rate, block_size) in cases {
+        let max_voices = results
+            .iter()
+            .filter(|r| r.sample_rate == sample_rate && r.block_size == block_size)
+            .filter(|r| r.sustainable())
+            .map(|r| r.voices)
+            .max();
+        match max_voices {
+            Some(voices) => println!(
+                "{} Hz / {} samples: up to {} voices within budget",
+                sample_rate, block_size, voices
+            ),
+            None => println!(
+                "{} Hz / {} samples: no case within budget",
+                sample_rate, block_size
+            ),
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use crate::check::{self, CheckOptions, Severity};
+use crate::runtime::runtime;
+use clap::Args;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+// 警告のみの場合に --deny-warnings で返す終了コード (1 はエラー、2 は clap の引数のエラーで使われる)
+const WARNINGS_EXIT_CODE: u8 = 3;
+
+#[derive(Args)]
+pub struct CheckArgs {
+    /// Scripts to check
+    #[arg(required = true)]
+    scripts: Vec<PathBuf>,
+
+    /// Sample rates to run the scripts at
+    #[arg(long, value_delimiter = ',', default_value = "44100,48000,96000")]
+    sample_rates: Vec<u32>,
+
+    /// Channel counts to run the scripts with
+    #[arg(long, value_delimiter = ',', default_value = "1,2")]
+    channels: Vec<usize>,
+
+    #[arg(long, default_value_t = 512)]
+    block_size: usize,
+
+    /// Number of blocks to run for each sample rate and channel count
+    #[arg(long, default_value_t = 8)]
+    blocks: usize,
+
+    /// Exit with code 3 when there are warnings but no errors
+    #[arg(long)]
+    deny_warnings: bool,
+}
+
+/// 終了コード: 0 = 問題なし (警告のみを含む), 1 = エラー, 3 = 警告のみ (--deny-warnings)
+pub fn run(args: CheckArgs) -> runtime::Result<ExitCode> {
+    if args.block_size == 0 || args.sample_rates.contains(&0) || args.channels.contains(&0) {
+        return Err("--sample-rates, --channels and --block-size must be greater than 0".into());
+    }
+    let options = CheckOptions {
+        sample_rates: args.sample_rates,
+        channels: args.channels,
+        block_size: args.block_size,
+        blocks: args.blocks,
+    };
+
+    let mut worst = None;
+    for path in &args.scripts {
+        let code = std::fs::read_to_string(path)
+            .map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+        let absolute = std::fs::canonicalize(path)?;
+        // エディタなどから読み取りやすいように "ファイル: 重要度: 内容" の形式で出力する
+        for diagnostic in check::check(&code, Some(&absolute), &options) {
+            println!("{}: {}", path.display(), diagnostic);
+            worst = worst.max(Some(diagnostic.severity));
+        }
+    }
+    Ok(match worst {
+        Some(Severity::Error) => ExitCode::FAILURE,
+        Some(Severity::Warning) if args.deny_warnings => ExitCode::from(WARNINGS_EXIT_CODE),
+        _ => ExitCode::SUCCESS,
+    })
+}
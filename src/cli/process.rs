@@ -0,0 +1,125 @@
+use crate::render::{self, wav};
+use crate::runtime::{runtime, sample};
+use clap::Args;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+// フォルダを処理する時に読み込むファイルの拡張子 (sample::decode が対応している形式)
+const AUDIO_EXTENSIONS: [&str; 4] = ["wav", "aif", "aiff", "flac"];
+
+#[derive(Args)]
+pub struct ProcessArgs {
+    /// Effect script
+    script: PathBuf,
+
+    /// Input audio file, or a folder processed recursively
+    #[arg(short, long)]
+    input: PathBuf,
+
+    /// Output WAV file, or a folder when the input is a folder
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// Number of samples per channel processed in a single audio() call
+    #[arg(long, default_value_t = 512)]
+    block_size: usize,
+
+    /// Number of channels passed to the script (defaults to the channel count of each input)
+    #[arg(long)]
+    channels: Option<usize>,
+
+    /// Seconds to keep processing after the end of the input (e.g. for reverb tails)
+    #[arg(long, default_value_t = 0.0)]
+    tail: f64,
+
+    /// Seed for Math.random
+    #[arg(long, default_value_t = 0)]
+    seed: u32,
+
+    /// Write 16-bit PCM instead of 32-bit float
+    #[arg(long)]
+    pcm16: bool,
+}
+
+pub fn run(args: ProcessArgs) -> runtime::Result<ExitCode> {
+    if args.block_size == 0 || args.channels == Some(0) {
+        return Err("--block-size and --channels must be greater than 0".into());
+    }
+    if !args.input.is_dir() {
+        process_file(&args, &args.input, &args.output)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    // フォルダの場合は同じ構成で出力し、失敗したファイルがあっても残りのファイルの処理を続ける
+    let mut files = Vec::new();
+    collect_files(&args.input, &mut files)?;
+    files.sort();
+    let mut failed = 0;
+    for input in &files {
+        let relative = input.strip_prefix(&args.input).unwrap_or(input);
+        let output = args.output.join(relative).with_extension("wav");
+        let result = (|| -> runtime::Result<()> {
+            if let Some(parent) = output.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            process_file(&args, input, &output)
+        })();
+        if let Err(err) = result {
+            eprintln!("error: {}: {}", input.display(), err);
+            failed += 1;
+        }
+    }
+    eprintln!("processed {} files, {} failed", files.len(), failed);
+    Ok(if failed == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else if path
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| AUDIO_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()))
+        {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+// ファイルごとにスクリプトをコンパイルし直し、前のファイルの処理の状態を引き継がないようにする
+fn process_file(args: &ProcessArgs, input: &Path, output: &Path) -> runtime::Result<()> {
+    let sample = sample::decode(input)
+        .map_err(|err| format!("failed to read {}: {}", input.display(), err))?;
+    if sample.sample_rate == 0 || sample.channels.is_empty() {
+        return Err(format!("{} has no audio", input.display()).into());
+    }
+    let channels = args.channels.unwrap_or(sample.channels.len());
+    let audio = render::map_channels(&sample.channels, channels);
+    let len = audio.iter().map(|c| c.len()).max().unwrap_or(0);
+    let tail = (args.tail.max(0.0) * sample.sample_rate as f64).ceil() as usize;
+
+    let mut runtime = super::compile_script(&args.script, args.seed)?;
+    let options = render::RenderOptions {
+        sample_rate: sample.sample_rate,
+        block_size: args.block_size,
+        channels,
+        frames: len + tail,
+    };
+    let result = render::render(&mut runtime, &audio, &[], &options)?;
+
+    let format = if args.pcm16 {
+        wav::WavFormat::Pcm16
+    } else {
+        wav::WavFormat::Float32
+    };
+    wav::write(output, sample.sample_rate, &result, format)
+        .map_err(|err| format!("failed to write {}: {}", output.display(), err))?;
+    Ok(())
+}
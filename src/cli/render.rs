@@ -0,0 +1,93 @@
+use crate::render::{self, smf, wav};
+use crate::runtime::runtime;
+use clap::Args;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Instant;
+
+#[derive(Args)]
+pub struct RenderArgs {
+    /// Script to render
+    script: PathBuf,
+
+    /// Standard MIDI File to play (renders without MIDI input if omitted)
+    #[arg(short, long)]
+    midi: Option<PathBuf>,
+
+    /// Output WAV file
+    #[arg(short, long)]
+    output: PathBuf,
+
+    #[arg(long, default_value_t = 48000)]
+    sample_rate: u32,
+
+    /// Number of samples per channel processed in a single audio() call
+    #[arg(long, default_value_t = 512)]
+    block_size: usize,
+
+    #[arg(long, default_value_t = 2)]
+    channels: usize,
+
+    /// Length in seconds (defaults to the last MIDI event plus --tail)
+    #[arg(long)]
+    duration: Option<f64>,
+
+    /// Seconds to keep rendering after the last MIDI event
+    #[arg(long, default_value_t = 2.0)]
+    tail: f64,
+
+    /// Seed for Math.random
+    #[arg(long, default_value_t = 0)]
+    seed: u32,
+
+    /// Write 16-bit PCM instead of 32-bit float
+    #[arg(long)]
+    pcm16: bool,
+}
+
+pub fn run(args: RenderArgs) -> runtime::Result<ExitCode> {
+    if args.sample_rate == 0 || args.block_size == 0 || args.channels == 0 {
+        return Err("--sample-rate, --block-size and --channels must be greater than 0".into());
+    }
+    let events = match &args.midi {
+        Some(path) => {
+            let bytes = std::fs::read(path)
+                .map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+            smf::parse(&bytes).map_err(|err| format!("{}: {}", path.display(), err))?
+        }
+        None => Vec::new(),
+    };
+    let frames = match args.duration {
+        Some(duration) => (duration.max(0.0) * args.sample_rate as f64).ceil() as usize,
+        None => render::length(&events, args.sample_rate, args.tail),
+    };
+
+    let mut runtime = super::compile_script(&args.script, args.seed)?;
+    let options = render::RenderOptions {
+        sample_rate: args.sample_rate,
+        block_size: args.block_size,
+        channels: args.channels,
+        frames,
+    };
+    let start = Instant::now();
+    let output = render::render(&mut runtime, &[], &events, &options)?;
+    let elapsed = start.elapsed().as_secs_f64();
+
+    let format = if args.pcm16 {
+        wav::WavFormat::Pcm16
+    } else {
+        wav::WavFormat::Float32
+    };
+    wav::write(&args.output, args.sample_rate, &output, format)
+        .map_err(|err| format!("failed to write {}: {}", args.output.display(), err))?;
+
+    let seconds = frames as f64 / args.sample_rate as f64;
+    eprintln!(
+        "rendered {:.2}s to {} in {:.2}s ({:.1}x realtime)",
+        seconds,
+        args.output.display(),
+        elapsed,
+        seconds / elapsed.max(f64::EPSILON)
+    );
+    Ok(ExitCode::SUCCESS)
+}
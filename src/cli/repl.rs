@@ -0,0 +1,127 @@
+use crate::runtime::js_inspector;
+use crate::runtime::js_sync::JsRuntimeBuilder;
+use crate::runtime::runtime::{self, ScriptRuntime};
+use clap::Args;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+type SharedRuntime = Arc<Mutex<dyn ScriptRuntime + Send + Sync>>;
+
+#[derive(Args)]
+pub struct ReplArgs {
+    /// Script to run
+    script: PathBuf,
+
+    #[arg(long, default_value_t = 48000)]
+    sample_rate: u32,
+
+    /// Number of samples per channel processed in a single audio() call
+    #[arg(long, default_value_t = 512)]
+    block_size: usize,
+
+    #[arg(long, default_value_t = 2)]
+    channels: usize,
+
+    /// Accept Chrome DevTools connections (default address: 127.0.0.1:9229)
+    #[arg(long, value_name = "ADDRESS", num_args = 0..=1, default_missing_value = js_inspector::DEFAULT_ADDRESS)]
+    inspect: Option<String>,
+}
+
+/// スクリプトを実時間で動かしながら、そのコンテキストで入力されたコードを評価する。
+/// オーディオデバイスは使わず、無音の入力で audio をブロックの長さの間隔で呼び出し続ける。
+/// コードは js_sync のスレッドで audio の呼び出しの合間に評価される。
+pub fn run(args: ReplArgs) -> runtime::Result<ExitCode> {
+    if args.sample_rate == 0 || args.block_size == 0 || args.channels == 0 {
+        return Err("--sample-rate, --block-size and --channels must be greater than 0".into());
+    }
+    let builder = JsRuntimeBuilder::new().on_log(Arc::new(|log| println!("{}", log)));
+    // ブレークポイントで停止している間、audio は無音を返し、REPL の評価はエラーになる
+    let builder = match &args.inspect {
+        Some(address) => match js_inspector::start(address) {
+            Some(server) => builder.inspector(server),
+            None => return Ok(ExitCode::FAILURE),
+        },
+        None => builder,
+    };
+    let runtime: SharedRuntime = Arc::new(Mutex::new(builder.build()));
+    compile(&runtime, &args.script)?;
+
+    let running = Arc::new(AtomicBool::new(true));
+    let clock = {
+        let (runtime, running) = (runtime.clone(), running.clone());
+        let block = Duration::from_secs_f64(args.block_size as f64 / args.sample_rate as f64);
+        let (channels, block_size, sample_rate) =
+            (args.channels, args.block_size, args.sample_rate);
+        std::thread::spawn(move || {
+            let mut audio = vec![0.0; block_size * channels];
+            let mut next = Instant::now();
+            while running.load(Ordering::Relaxed) {
+                audio.fill(0.0);
+                if let Err(err) =
+                    runtime
+                        .lock()
+                        .unwrap()
+                        .audio(&mut audio, channels, sample_rate as f32, &[])
+                {
+                    eprintln!("process error: {}", err);
+                }
+                next += block;
+                std::thread::sleep(next.saturating_duration_since(Instant::now()));
+            }
+        })
+    };
+
+    println!(
+        "ps88 repl: .reload to recompile {}, .exit to quit",
+        args.script.display()
+    );
+    let stdin = std::io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("> ");
+        std::io::stdout().flush()?;
+        let Some(line) = lines.next() else {
+            break;
+        };
+        let mut code = line?;
+        // 行末の \ で複数行のコードを入力できる
+        while code.ends_with('\\') {
+            code.pop();
+            code.push('\n');
+            print!(". ");
+            std::io::stdout().flush()?;
+            match lines.next() {
+                Some(line) => code.push_str(&line?),
+                None => break,
+            }
+        }
+        match code.trim() {
+            "" => continue,
+            ".exit" => break,
+            ".reload" => {
+                if let Err(err) = compile(&runtime, &args.script) {
+                    eprintln!("{}", err);
+                }
+            }
+            code => match runtime.lock().unwrap().evaluate(code) {
+                Ok(result) => println!("{}", result),
+                Err(err) => eprintln!("{}", err),
+            },
+        }
+    }
+
+    running.store(false, Ordering::Relaxed);
+    let _ = clock.join();
+    Ok(ExitCode::SUCCESS)
+}
+
+fn compile(runtime: &SharedRuntime, path: &Path) -> runtime::Result<()> {
+    let code = std::fs::read_to_string(path)
+        .map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+    let path = std::fs::canonicalize(path)?;
+    runtime.lock().unwrap().compile(&code, Some(&path))
+}
@@ -0,0 +1,165 @@
+use crate::render::golden::{self, GoldenTest, Outcome};
+use crate::render::wav;
+use crate::runtime::runtime;
+use clap::Args;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+#[derive(Args)]
+pub struct TestArgs {
+    /// Test definitions (*.test.json), scripts using ps88.test(), or folders to search for test definitions
+    #[arg(default_value = ".")]
+    paths: Vec<PathBuf>,
+
+    /// Overwrite the reference files with the current output instead of comparing
+    #[arg(long)]
+    update: bool,
+}
+
+// 実行したテストの数
+#[derive(Default)]
+struct Summary {
+    passed: usize,
+    failed: usize,
+}
+
+impl Summary {
+    fn pass(&mut self, name: &str, detail: &str) {
+        self.passed += 1;
+        println!("ok     {}{}", name, detail);
+    }
+
+    fn fail(&mut self, name: &str, reasons: &[String]) {
+        self.failed += 1;
+        println!("FAILED {}", name);
+        for line in reasons.iter().flat_map(|reason| reason.lines()) {
+            println!("       {}", line);
+        }
+    }
+}
+
+pub fn run(args: TestArgs) -> runtime::Result<ExitCode> {
+    let mut definitions = Vec::new();
+    let mut scripts = Vec::new();
+    for path in &args.paths {
+        if path.is_dir() {
+            collect_definitions(path, &mut definitions)?;
+        } else if is_definition(path) {
+            definitions.push(path.clone());
+        } else if path.exists() {
+            scripts.push(path.clone());
+        } else {
+            return Err(format!("{} does not exist", path.display()).into());
+        }
+    }
+    definitions.sort();
+
+    let mut summary = Summary::default();
+    for path in &definitions {
+        let name = path.display().to_string();
+        match GoldenTest::load(path) {
+            Ok(test) => {
+                // 参照されているスクリプトの ps88.test も実行する
+                if !scripts.contains(&test.script) {
+                    scripts.push(test.script.clone());
+                }
+                run_golden(&test, &name, args.update, &mut summary);
+            }
+            Err(err) => summary.fail(&name, &[err.to_string()]),
+        }
+    }
+    for script in &scripts {
+        run_script_tests(script, &mut summary);
+    }
+
+    println!();
+    println!("{} passed, {} failed", summary.passed, summary.failed);
+    Ok(if summary.failed == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+fn is_definition(path: &Path) -> bool {
+    path.to_string_lossy().ends_with(golden::EXTENSION)
+}
+
+fn collect_definitions(dir: &Path, definitions: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_definitions(&path, definitions)?;
+        } else if is_definition(&path) {
+            definitions.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn run_golden(test: &GoldenTest, name: &str, update: bool, summary: &mut Summary) {
+    let output = super::compile_script(&test.script, test.seed)
+        .and_then(|mut runtime| test.render(&mut runtime));
+    let output = match output {
+        Ok(output) => output,
+        Err(err) => return summary.fail(name, &[err.to_string()]),
+    };
+    let write = |path: &Path| {
+        wav::write(
+            path,
+            output.sample_rate,
+            &output.channels,
+            wav::WavFormat::Float32,
+        )
+        .map_err(|err| format!("failed to write {}: {}", path.display(), err))
+    };
+
+    if update {
+        return match write(&test.reference) {
+            Ok(()) => summary.pass(name, &format!(" (updated {})", test.reference.display())),
+            Err(err) => summary.fail(name, &[err]),
+        };
+    }
+    match test.check(&output) {
+        Outcome::Passed(difference) => summary.pass(name, &format!(" ({})", difference)),
+        Outcome::Failed {
+            difference,
+            mut failures,
+        } => {
+            if let Some(difference) = difference {
+                failures.push(difference.to_string());
+            }
+            // 比較用に出力を参照用のファイルの隣に書き出す
+            let actual = test.reference.with_extension("actual.wav");
+            match write(&actual) {
+                Ok(()) => failures.push(format!("output written to {}", actual.display())),
+                Err(err) => failures.push(err),
+            }
+            summary.fail(name, &failures);
+        }
+        Outcome::MissingReference => summary.fail(
+            name,
+            &[format!(
+                "{} does not exist (run with --update to create it)",
+                test.reference.display()
+            )],
+        ),
+    }
+}
+
+fn run_script_tests(script: &Path, summary: &mut Summary) {
+    let name = script.display().to_string();
+    let results = super::compile_script(script, 0).and_then(|mut runtime| runtime.run_tests());
+    match results {
+        Ok(results) => {
+            for result in results {
+                let name = format!("{}: {}", name, result.name);
+                match result.error {
+                    Some(error) => summary.fail(&name, &[error]),
+                    None => summary.pass(&name, ""),
+                }
+            }
+        }
+        Err(err) => summary.fail(&name, &[err.to_string()]),
+    }
+}
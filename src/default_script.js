@@ -1,16 +1,19 @@
 "use strict";
 console.log("hello world");
 let count = 0;
-const sawtooth = (rad) => {
-  let x = rad / (2 * Math.PI);
-  return 2 * (x - Math.floor(x)) - 1;
-};
-const triangle = (rad) => {
-  return sawtooth(2 * rad) * (Math.floor(rad / (2 * Math.PI)) % 2 === 0 ? 1 : -1);
-};
-const square = (rad) => {
-  return rad % (2 * Math.PI) < Math.PI ? 1 : -1;
-};
+
+// 標準ライブラリはグローバル変数 ps88 から利用できる
+// e.g. 帯域制限されたノコギリ波
+//   const osc = new ps88.Oscillator("sawtooth", 440);
+//   osc.next(ctx.sampling_rate);
+// 重い信号処理は Rust で実装された ps88.dsp の関数を使うと速い
+// e.g. ps88.dsp.mix(ctx.audio, buffer, 0.5);
+// オーディオファイル (WAV / AIFF / FLAC) はスクリプトのディレクトリからの相対パスで読み込める
+// e.g. const kick = await ps88.loadSample("kick.wav"); // kick.channels[0] は Float32Array
+// ps88.state に保存した値は DAW のプロジェクトと一緒に保存される
+// e.g. ps88.state.set("pattern", [1, 0, 1, 0]); const pattern = ps88.state.get("pattern");
+// ps88.test で登録したテストは `ps88 test main.js` で実行される (プラグインとしての実行中は実行されない)
+// e.g. ps88.test("silent", () => { const ctx = ps88.testContext(); audio(ctx); ps88.assertClose(ctx.audio[0], 0); });
 
 /**
  * オーディオ処理
@@ -32,50 +35,92 @@ const square = (rad) => {
  *        5 byte: ノート番号 (0-127)
  *        6 byte: ベロシティ (1-127)
  */
-const keys = new Map();
-const audio = (ctx) => {
-  const half = ctx.audio.length / ctx.ch;
-  if (ctx.midi.length > 0) {
-    for (let i = 0; i < ctx.midi.length; i += 7) {
-      const time = (ctx.midi[i] << 24) | (ctx.midi[i + 1] << 16) | (ctx.midi[i + 2] << 8) | ctx.midi[i + 3];
-      const type = ctx.midi[i + 4] >> 4;
-      //const channel = ctx.midi[i + 4] & 0x0f;
-      const note = ctx.midi[i + 5];
-      const velocity = ctx.midi[i + 6];
-      if (type === 0x9) {
-        keys.set(note, [time, velocity, 0]);
-        keys.set(note + 4, [time, velocity, 0]);
-        keys.set(note + 5, [time, velocity, 0]);
-        keys.set(note + 9, [time, velocity, 0]);
-      } else if (type === 0x8) {
-        //keys.delete(note);
-      }
-    }
+
+/**
+ * ボイスマネージャーの設定 (省略可)
+ *
+ * @property {number} maxVoices - 同時に発音できるボイス数
+ * @property {string} steal - ボイス数が足りない時に止めるボイス ("oldest" | "quietest")
+ * @property {number} releaseTimeout - Note Off からこの秒数が経ったボイスは強制的に終了する
+ */
+const voiceOptions = { maxVoices: 16, steal: "oldest", releaseTimeout: 10 };
+
+/**
+ * GC の設定 (省略可)
+ * audio の実行中に GC が行われると音が途切れることがあるため、schedule を指定するとブロックの合間に GC を行わせられる。
+ * GC の統計は ps88.gcStats() で取得できる。
+ *
+ * @property {string} schedule - ブロックの合間の GC ("auto": V8 に任せる | "memoryPressure": メモリの逼迫を伝えてインクリメンタルに回収させる | "lowMemory": その場で全て回収)
+ * @property {number} threshold - 前回の GC からヒープの使用量がこのバイト数増えたら GC を行う
+ * @property {boolean} strict - audio の呼び出しで JavaScript のヒープに確保したら警告する
+ */
+const gcOptions = { schedule: "auto", threshold: 1 << 20, strict: false };
+
+/**
+ * ボイスごとの処理
+ * 関数 voice を定義すると、Note On ごとにボイスが割り当てられて呼び出される。
+ * 全てのボイスの出力は足し合わされて ctx.audio に書き込まれた後、audio(ctx) が呼び出される。
+ *
+ * @param {ps88.Voice} v - ボイスの状態
+ *    v.note, v.velocity, v.frequency, v.time (発音開始からのサンプル数), v.released (Note Off 済み) など。
+ *    ボイスが終了するまで同じオブジェクトが渡されるため、任意のプロパティを追加して状態を保持できる。
+ *    v.level に現在の音量を入れると steal: "quietest" の判定に使われる。
+ *    音が鳴り終わったら v.free() を呼んでボイスを解放する。
+ * @param {Float32Array} out - このボイスの出力
+ *    ctx.audio と同じ並びで、MIDI イベントの時刻で区切られた区間の長さになっている。
+ * @param {Object} ctx - audio(ctx) と同じ
+ */
+const voice = (v, out, ctx) => {
+  const len = out.length / ctx.ch;
+  v.osc ??= {
+    freqs: new Float32Array([v.frequency]),
+    phases: new Float32Array(1),
+    amps: new Float32Array([v.velocity * 0.2]),
+  };
+  const mono = out.subarray(0, len);
+  ps88.dsp.oscBank(mono, v.osc.freqs, v.osc.phases, v.osc.amps, ctx.sampling_rate);
+  for (let c = 1; c < ctx.ch; c++) {
+    out.set(mono, c * len);
   }
-  for (let index = 0; index < half; index++) {
-    let val = 0.0;
-    for (const [note, value] of keys) {
-      const [time, velocity, count] = value;
-      if (time > index) {
-        continue;
-      }
-      const v = (velocity / 127.0) * Math.exp(-5 * count / ctx.sampling_rate);
-      if (v < 0.001) {
-        //keys.delete(note);
-        continue;
-      }
-      const freq = 440 * Math.pow(2, (note - 69) / 12);
-      val += Math.sin(count / ctx.sampling_rate * 2 * Math.PI * freq) * v;
-      //val += sawtooth(count / ctx.sampling_rate * 2 * Math.PI * freq) * v;
-      //val += triangle(count / ctx.sampling_rate * 2 * Math.PI * freq) * v;
-      //val += square(count / ctx.sampling_rate * 2 * Math.PI * freq) * v;
-      value[2]++;
-    }
-    val *= 0.8;
-    ctx.audio[index] = val;
-    ctx.audio[index+half] = val;
+  v.osc.amps[0] *= Math.exp(-(v.released ? 20 : 5) * len / ctx.sampling_rate);
+  v.level = v.osc.amps[0];
+  if (v.level < 0.0002) {
+    v.free();
   }
-  return 100;
 };
 
-const gui = () => {};
+/**
+ * GUI の描画
+ * エディタのパラメータの下の領域に描く図形の一覧を返す。約 30 fps で呼び出される。
+ * 座標は領域の左上を原点とするピクセル単位で、色は "#rrggbb" / "#rrggbbaa" か [r, g, b, a] (0-255) で指定する。
+ *
+ * @param {Object} ctx
+ * @param {number} ctx.width - 領域の幅
+ * @param {number} ctx.height - 領域の高さ
+ * @param {Object} ctx.input - 前のフレームからの入力
+ *    pointer: ポインタの位置 [x, y] (領域の外では null), delta: 移動量 [dx, dy], wheel: ホイールの回転量 [x, y]
+ *    buttons / pressed / released: 押されている / 押された / 離されたボタン ({ primary, secondary, middle })
+ *    hover: ポインタの下にあるヒット領域の id, active: ボタンを押した時にポインタの下にあったヒット領域の id (離すまで変わらない)
+ *    keys: キーイベントの配列 ([{ key: "A", pressed: true }, ...]), text: 入力された文字列
+ *    modifiers: { shift, ctrl, alt, command }
+ * @param {Object} ctx.params - ホストのパラメータの値 ({ param1, param2, param3, param4 })
+ * @param {Object} ctx.changes - 前のフレームで操作されたウィジェットの新しい値 (id → 値)。ps88.Widgets で保持できる
+ * @returns {Object[]} 図形の一覧 (何も描かない場合は省略できる)
+ *    { type: "line", points: [[x, y], ...], color, lineWidth, closed }
+ *    { type: "polygon", points: [[x, y], ...], fill, stroke, lineWidth } (凸でない多角形も描ける)
+ *    { type: "rect", x, y, width, height, fill, stroke, lineWidth, radius }
+ *    { type: "circle", x, y, radius, fill, stroke, lineWidth }
+ *    { type: "text", x, y, text, size, color, align ("left" | "center" | "right") }
+ *    { type: "region", id, x, y, width, height } (描画されないヒット領域)
+ *    ウィジェット (param にホストのパラメータの id を指定すると、そのパラメータを操作する)
+ *    { type: "knob" | "slider", id, x, y, width, height, value, min, max, param, label }
+ *    { type: "toggle", id, x, y, width, height, value, param, label }
+ *    { type: "dropdown", id, x, y, width, height, options, value, param }
+ *    { type: "xyPad", id, x, y, width, height, value: [x, y], paramX, paramY }
+ *    { type: "waveform" | "spectrum", x, y, width, height, data, min, max, color }
+ *    { type: "keyboard", id, x, y, width, height, low, high, notes }
+ */
+const gui = (ctx) => [
+  { type: "rect", x: 0, y: 0, width: ctx.width, height: 24, fill: "#303030", radius: 4 },
+  { type: "text", x: 8, y: 4, text: "ps88", color: "#c0c0c0" },
+];
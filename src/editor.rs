@@ -1,40 +1,247 @@
 use super::file_watcher::Watcher;
+use crate::preset::{self, Preset};
+use crate::runtime::gui::{
+    self, Align, Buttons, Color, GuiContext, Input, KeyEvent, Shape, WidgetValue,
+};
+use crate::runtime::js_sync::GuiHandle;
+use crate::runtime::profile::{self, FunctionTime, Profile};
+use crate::runtime::stats::{ProcessStats, XRUN_RISK_LOAD};
 use nih_plug::prelude::*;
+use nih_plug::wrapper::state::PluginState;
 use nih_plug_egui::{create_egui_editor, egui, widgets};
+use std::collections::BTreeMap;
 use std::io::Read;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
+mod widget;
+
+type SharedRuntime = Arc<Mutex<dyn crate::runtime::runtime::ScriptRuntime + Sync + Send>>;
+
+struct EditorState {
+    watcher: Option<SharedWatcher>,
+
+    // 別スレッドで読み込まれ、次のフレームでプラグインに反映するプリセットとそのスクリプトのパス
+    pending_preset: Option<(PluginState, Option<PathBuf>)>,
+
+    // プリセットブラウザ
+    show_presets: bool,
+    presets: Vec<preset::PresetInfo>,
+
+    // REPL の入力欄と、これまでの入力と結果
+    show_repl: bool,
+    repl_input: String,
+    repl_history: Vec<String>,
+
+    // CPU プロファイラ。記録した結果は関数ごとの実行時間の表にして表示する
+    show_profiler: bool,
+    profile_seconds: f32,
+    profiling: bool,
+    profile: Option<Profile>,
+    profile_functions: Vec<FunctionTime>,
+
+    // スクリプトの gui(ctx) が最後に返した図形と、最後に表示したエラー
+    gui_shapes: Vec<Shape>,
+    gui_error: Option<String>,
+    // ドラッグ中のヒット領域の id
+    gui_active: Option<String>,
+    // まだ gui(ctx) に渡していない、ウィジェットの操作で変わった値
+    gui_changes: BTreeMap<String, WidgetValue>,
+    // ウィジェットで操作中のパラメータ
+    gestures: widget::Gestures,
+}
+
+// プロファイラの表に表示する関数の数
+const PROFILE_TABLE_ROWS: usize = 30;
+
+// スクリプトの gui(ctx) を呼び出して描き直す間隔
+const GUI_FRAME_INTERVAL: std::time::Duration = std::time::Duration::from_millis(33);
+
 pub fn editor(
     params: Arc<crate::params::PS88Params>,
-    runtime: Arc<Mutex<dyn crate::runtime::runtime::ScriptRuntime + Sync + Send>>,
+    runtime: SharedRuntime,
+    gui_handle: GuiHandle,
+    stats: Arc<ProcessStats>,
 ) -> Option<Box<dyn Editor>> {
-    create_egui_editor(
+    let editor_params = params.clone();
+    let state = Arc::new(Mutex::new(EditorState {
+        watcher: None,
+        pending_preset: None,
+        show_presets: false,
+        presets: Vec::new(),
+        show_repl: false,
+        repl_input: String::new(),
+        repl_history: Vec::new(),
+        show_profiler: false,
+        profile_seconds: 5.0,
+        profiling: false,
+        profile: None,
+        profile_functions: Vec::new(),
+        gui_shapes: Vec::new(),
+        gui_error: None,
+        gui_active: None,
+        gui_changes: BTreeMap::new(),
+        gestures: widget::Gestures::default(),
+    }));
+    let editor = create_egui_editor(
         params.editor_state.clone(),
-        Arc::<Mutex<Option<Box<dyn super::file_watcher::Watcher + Sync + Send>>>>::new(Mutex::new(
-            None,
-        )),
+        state.clone(),
         |_, _| {},
         move |egui_ctx, setter, state| {
+            // プリセットの読み込み
+            let pending_preset = state.lock().unwrap().pending_preset.take();
+            if let Some((plugin_state, script_path)) = pending_preset {
+                setter.raw_context.set_state(plugin_state);
+                match script_path {
+                    Some(path) => {
+                        let (runtime, params, state) =
+                            (runtime.clone(), params.clone(), state.clone());
+                        std::thread::spawn(move || open_script(path, runtime, params, state));
+                    }
+                    None => state.lock().unwrap().watcher = None,
+                }
+            }
+
+            // 処理時間の統計。オーディオのスレッドを止めないよう、ロックせずに読み取る
+            egui::TopBottomPanel::top("stats").show(egui_ctx, |ui| {
+                let snapshot = stats.snapshot();
+                let text = egui::RichText::new(snapshot.to_string()).monospace();
+                // ピークがブロックの長さを超えそうな場合は目立たせる
+                if snapshot.peak_load > XRUN_RISK_LOAD {
+                    ui.label(text.color(ui.visuals().warn_fg_color));
+                } else {
+                    ui.label(text);
+                }
+            });
+            egui_ctx.request_repaint_after(std::time::Duration::from_millis(250));
+
+            let show_presets = state.lock().unwrap().show_presets;
+            egui::SidePanel::left("presets").show_animated(egui_ctx, show_presets, |ui| {
+                ui.horizontal(|ui| {
+                    ui.heading("Presets");
+                    if ui.button("Refresh").clicked() {
+                        refresh_presets(state);
+                    }
+                });
+                ui.separator();
+                let presets = state.lock().unwrap().presets.clone();
+                if presets.is_empty() {
+                    ui.label("No presets");
+                }
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    for info in presets {
+                        let response = ui
+                            .selectable_label(false, &info.name)
+                            .on_hover_text(info.path.display().to_string());
+                        if response.clicked() {
+                            let (egui_ctx, state) = (egui_ctx.clone(), state.clone());
+                            std::thread::spawn(move || {
+                                import_preset(&info.path, &egui_ctx, &state)
+                            });
+                        }
+                    }
+                });
+            });
+
+            let show_repl = state.lock().unwrap().show_repl;
+            egui::TopBottomPanel::bottom("repl").show_animated(egui_ctx, show_repl, |ui| {
+                egui::ScrollArea::vertical()
+                    .max_height(160.0)
+                    .stick_to_bottom(true)
+                    .show(ui, |ui| {
+                        for line in state.lock().unwrap().repl_history.iter() {
+                            ui.monospace(line.as_str());
+                        }
+                    });
+                let response = ui.add(
+                    egui::TextEdit::singleline(&mut state.lock().unwrap().repl_input)
+                        .code_editor()
+                        .desired_width(f32::INFINITY)
+                        .hint_text("expression"),
+                );
+                if response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+                    let code = std::mem::take(&mut state.lock().unwrap().repl_input);
+                    if !code.trim().is_empty() {
+                        let (runtime, egui_ctx, state) =
+                            (runtime.clone(), egui_ctx.clone(), state.clone());
+                        std::thread::spawn(move || evaluate(&code, &runtime, &egui_ctx, &state));
+                    }
+                    response.request_focus();
+                }
+            });
+
+            let mut show_profiler = state.lock().unwrap().show_profiler;
+            egui::Window::new("Profiler")
+                .open(&mut show_profiler)
+                .show(egui_ctx, |ui| {
+                    let profiling = state.lock().unwrap().profiling;
+                    ui.horizontal(|ui| {
+                        ui.add_enabled(
+                            !profiling,
+                            egui::DragValue::new(&mut state.lock().unwrap().profile_seconds)
+                                .clamp_range(1.0..=60.0)
+                                .suffix(" s"),
+                        );
+                        if ui
+                            .add_enabled(!profiling, egui::Button::new("Record"))
+                            .clicked()
+                        {
+                            let seconds = state.lock().unwrap().profile_seconds;
+                            let (runtime, egui_ctx, state) =
+                                (runtime.clone(), egui_ctx.clone(), state.clone());
+                            std::thread::spawn(move || {
+                                record_profile(seconds, &runtime, &egui_ctx, &state)
+                            });
+                        }
+                        let profile = state.lock().unwrap().profile.clone();
+                        if ui
+                            .add_enabled(profile.is_some(), egui::Button::new("Save"))
+                            .clicked()
+                        {
+                            if let Some(profile) = profile {
+                                std::thread::spawn(move || save_profile(&profile));
+                            }
+                        }
+                        if profiling {
+                            ui.spinner();
+                        }
+                    });
+                    ui.separator();
+                    egui::ScrollArea::vertical().show(ui, |ui| {
+                        egui::Grid::new("profile").striped(true).show(ui, |ui| {
+                            ui.strong("Self");
+                            ui.strong("Self %");
+                            ui.strong("Total %");
+                            ui.strong("Function");
+                            ui.strong("Location");
+                            ui.end_row();
+                            let state = state.lock().unwrap();
+                            for function in state.profile_functions.iter().take(PROFILE_TABLE_ROWS)
+                            {
+                                ui.monospace(format!(
+                                    "{:.1} ms",
+                                    function.self_time.as_secs_f64() * 1000.0
+                                ));
+                                ui.monospace(format!("{:.1}", function.self_ratio * 100.0));
+                                ui.monospace(format!("{:.1}", function.total_ratio * 100.0));
+                                ui.monospace(&function.name);
+                                ui.monospace(function.location());
+                                ui.end_row();
+                            }
+                        });
+                    });
+                });
+            state.lock().unwrap().show_profiler = show_profiler;
+
             egui::CentralPanel::default().show(egui_ctx, |ui| {
                 ui.menu_button("File", |ui| {
                     if ui.button("Open").clicked() {
-                        let runtime = runtime.clone();
-                        let state = state.clone();
-                        let param_code = params.code.clone();
+                        let (runtime, params, state) =
+                            (runtime.clone(), params.clone(), state.clone());
                         std::thread::spawn(move || {
                             let result = rfd::FileDialog::new().pick_file();
                             if let Some(path) = result {
-                                if let Ok(watcher) = load_script(&path, move |code| {
-                                    if let Err(err) = runtime.lock().unwrap().compile(&*code) {
-                                        println!("{}", err);
-                                    }
-                                    if let Ok(mut param_code) = param_code.lock() {
-                                        *param_code = code;
-                                    }
-                                }) {
-                                    let mut state = state.lock().unwrap();
-                                    *state = Some(watcher);
-                                }
+                                open_script(path, runtime, params, state);
                             }
                         });
                         ui.close_menu();
@@ -42,35 +249,613 @@ pub fn editor(
                     if ui.button("Save Script").clicked() {
                         ui.close_menu();
                     }
+                    ui.separator();
+                    if ui.button("Import Preset").clicked() {
+                        let (egui_ctx, state) = (egui_ctx.clone(), state.clone());
+                        std::thread::spawn(move || {
+                            let result = preset_dialog().pick_file();
+                            if let Some(path) = result {
+                                import_preset(&path, &egui_ctx, &state);
+                            }
+                        });
+                        ui.close_menu();
+                    }
+                    if ui.button("Export Preset").clicked() {
+                        // プラグインの状態は GUI のスレッドで取得する
+                        let plugin_state = setter.raw_context.get_state();
+                        let script_path = params.script_path.lock().unwrap().clone();
+                        let (gui_handle, state) = (gui_handle.clone(), state.clone());
+                        std::thread::spawn(move || {
+                            let result = preset_dialog()
+                                .set_file_name(format!("preset.{}", preset::EXTENSION))
+                                .save_file();
+                            let Some(path) = result else {
+                                return;
+                            };
+                            // 依存するファイルが分からないまま書き出すと、読み込めないプリセットになるため中止する
+                            match gui_handle.dependencies() {
+                                Ok(dependencies) => {
+                                    export_preset(
+                                        &path,
+                                        &plugin_state,
+                                        script_path.as_deref(),
+                                        &dependencies,
+                                    );
+                                    refresh_presets(&state);
+                                }
+                                Err(err) => println!("failed to export preset: {}", err),
+                            }
+                        });
+                        ui.close_menu();
+                    }
+                    if ui.button("Preset Browser").clicked() {
+                        let show_presets = !state.lock().unwrap().show_presets;
+                        if show_presets {
+                            refresh_presets(state);
+                        }
+                        state.lock().unwrap().show_presets = show_presets;
+                        ui.close_menu();
+                    }
+                    if ui.button("REPL").clicked() {
+                        let mut state = state.lock().unwrap();
+                        state.show_repl = !state.show_repl;
+                        ui.close_menu();
+                    }
+                    if ui.button("Profiler").clicked() {
+                        let mut state = state.lock().unwrap();
+                        state.show_profiler = !state.show_profiler;
+                        ui.close_menu();
+                    }
                 });
                 ui.label("Gain");
                 ui.add(widgets::ParamSlider::for_param(&params.param1, setter));
                 ui.add(widgets::ParamSlider::for_param(&params.param2, setter));
                 ui.add(widgets::ParamSlider::for_param(&params.param3, setter));
                 ui.add(widgets::ParamSlider::for_param(&params.param4, setter));
+                ui.separator();
+
+                // 残りの領域にスクリプトの gui(ctx) が返した図形を描く
+                // (領域自体がクリックやドラッグを受け取ると上に置くウィジェットに届かなくなるため、hover だけにする。
+                // スクリプトに渡すポインタの入力は gui_input で直接読む)
+                let (response, painter) =
+                    ui.allocate_painter(ui.available_size(), egui::Sense::hover());
+                let rect = response.rect;
+                let mut state = state.lock().unwrap();
+                let state = &mut *state;
+                let mut input = gui_input(ui, rect, state.gui_active.is_some());
+                input.hit_test(&state.gui_shapes, &mut state.gui_active);
+                // オーディオのスレッドを待たせないよう、runtime のロックを取らずにハンドルから呼び出す
+                let ctx = GuiContext {
+                    width: rect.width(),
+                    height: rect.height(),
+                    input,
+                    params: widget::param_values(&params),
+                    changes: std::mem::take(&mut state.gui_changes),
+                };
+                match gui_handle.gui(&ctx) {
+                    Ok(shapes) => {
+                        state.gui_shapes = shapes;
+                        state.gui_error = None;
+                    }
+                    Err(err) => {
+                        // 毎フレーム同じエラーを表示しないようにする
+                        let err = err.to_string();
+                        if state.gui_error.as_ref() != Some(&err) {
+                            println!("{}", err);
+                            state.gui_error = Some(err);
+                        }
+                    }
+                }
+                paint(&painter.with_clip_rect(rect), rect.min, &state.gui_shapes);
+                // ウィジェットは図形の上に表示する
+                let widget_error = widget::show(
+                    ui,
+                    rect,
+                    &state.gui_shapes,
+                    &params,
+                    setter,
+                    &mut state.gui_changes,
+                    &mut state.gestures,
+                )
+                .err();
+                if let Some(err) = widget_error.as_ref().or(state.gui_error.as_ref()) {
+                    painter.text(
+                        rect.left_bottom(),
+                        egui::Align2::LEFT_BOTTOM,
+                        err,
+                        egui::FontId::monospace(12.0),
+                        ui.visuals().warn_fg_color,
+                    );
+                }
+                egui_ctx.request_repaint_after(GUI_FRAME_INTERVAL);
             });
         },
-    )
+    )?;
+    Some(Box::new(ClosingEditor {
+        editor,
+        params: editor_params,
+        state,
+    }))
+}
+
+/// エディタを閉じた時に、ウィジェットで操作中だったパラメータの操作を終えるためのラッパー。
+/// egui のエディタには閉じた時の処理を登録できないため、spawn が返すハンドルの drop で行う。
+struct ClosingEditor {
+    editor: Box<dyn Editor>,
+    params: Arc<crate::params::PS88Params>,
+    state: Arc<Mutex<EditorState>>,
+}
+
+struct ClosingEditorHandle {
+    // egui のウィンドウのハンドル。drop するとウィンドウが閉じる
+    _handle: Box<dyn std::any::Any + Send>,
+    context: Arc<dyn nih_plug::prelude::GuiContext>,
+    params: Arc<crate::params::PS88Params>,
+    state: Arc<Mutex<EditorState>>,
+}
+
+impl Editor for ClosingEditor {
+    fn spawn(
+        &self,
+        parent: ParentWindowHandle,
+        context: Arc<dyn nih_plug::prelude::GuiContext>,
+    ) -> Box<dyn std::any::Any + Send> {
+        Box::new(ClosingEditorHandle {
+            _handle: self.editor.spawn(parent, context.clone()),
+            context,
+            params: self.params.clone(),
+            state: self.state.clone(),
+        })
+    }
+
+    fn size(&self) -> (u32, u32) {
+        self.editor.size()
+    }
+
+    fn set_scale_factor(&self, factor: f32) -> bool {
+        self.editor.set_scale_factor(factor)
+    }
+
+    fn param_value_changed(&self, id: &str, normalized_value: f32) {
+        self.editor.param_value_changed(id, normalized_value)
+    }
+
+    fn param_modulation_changed(&self, id: &str, modulation_offset: f32) {
+        self.editor.param_modulation_changed(id, modulation_offset)
+    }
+
+    fn param_values_changed(&self) {
+        self.editor.param_values_changed()
+    }
+}
+
+impl Drop for ClosingEditorHandle {
+    fn drop(&mut self) {
+        let setter = ParamSetter::new(&*self.context);
+        if let Ok(mut state) = self.state.lock() {
+            state.gestures.end_all(&self.params, &setter);
+        }
+    }
+}
+
+// egui の入力を、gui(ctx) に渡す領域 rect を基準にした ctx.input にする。
+// dragging の場合は領域の外にポインタがあっても位置を渡す
+fn gui_input(ui: &egui::Ui, rect: egui::Rect, dragging: bool) -> Input {
+    // REPL の入力欄などにフォーカスがある時は、キー入力をスクリプトに渡さない
+    let focused = ui.memory(|memory| memory.focus().is_some());
+    ui.input(|i| {
+        let pointer = i
+            .pointer
+            .hover_pos()
+            .filter(|pos| dragging || rect.contains(*pos))
+            .map(|pos| [pos.x - rect.min.x, pos.y - rect.min.y]);
+        let buttons = |down: &dyn Fn(egui::PointerButton) -> bool| Buttons {
+            primary: down(egui::PointerButton::Primary),
+            secondary: down(egui::PointerButton::Secondary),
+            middle: down(egui::PointerButton::Middle),
+        };
+        // 領域の外でのクリックやホイールはスクリプトに渡さない
+        let inside = pointer.is_some();
+        let mut input = Input {
+            pointer,
+            buttons: buttons(&|button| i.pointer.button_down(button)),
+            delta: [i.pointer.delta().x, i.pointer.delta().y],
+            modifiers: gui::Modifiers {
+                shift: i.modifiers.shift,
+                ctrl: i.modifiers.ctrl,
+                alt: i.modifiers.alt,
+                command: i.modifiers.command,
+            },
+            ..Default::default()
+        };
+        if inside {
+            input.pressed = buttons(&|button| i.pointer.button_pressed(button));
+            input.released = buttons(&|button| i.pointer.button_released(button));
+            input.wheel = [i.scroll_delta.x, i.scroll_delta.y];
+        }
+        if !focused {
+            for event in &i.events {
+                match event {
+                    egui::Event::Key { key, pressed, .. } => {
+                        if let Some(name) = key_name(*key) {
+                            input.keys.push(KeyEvent {
+                                key: name.into(),
+                                pressed: *pressed,
+                            });
+                        }
+                    }
+                    egui::Event::Text(text) => input.text.push_str(text),
+                    _ => {}
+                }
+            }
+        }
+        input
+    })
+}
+
+// スクリプトに渡すキーの名前。egui のバージョンが変わっても名前が変わらないよう、対応を明示的に書く。
+// ここに無いキーはスクリプトに渡さない
+fn key_name(key: egui::Key) -> Option<&'static str> {
+    use egui::Key;
+    Some(match key {
+        Key::ArrowDown => "ArrowDown",
+        Key::ArrowLeft => "ArrowLeft",
+        Key::ArrowRight => "ArrowRight",
+        Key::ArrowUp => "ArrowUp",
+        Key::Escape => "Escape",
+        Key::Tab => "Tab",
+        Key::Backspace => "Backspace",
+        Key::Enter => "Enter",
+        Key::Space => "Space",
+        Key::Insert => "Insert",
+        Key::Delete => "Delete",
+        Key::Home => "Home",
+        Key::End => "End",
+        Key::PageUp => "PageUp",
+        Key::PageDown => "PageDown",
+        Key::Minus => "Minus",
+        Key::Num0 => "0",
+        Key::Num1 => "1",
+        Key::Num2 => "2",
+        Key::Num3 => "3",
+        Key::Num4 => "4",
+        Key::Num5 => "5",
+        Key::Num6 => "6",
+        Key::Num7 => "7",
+        Key::Num8 => "8",
+        Key::Num9 => "9",
+        Key::A => "A",
+        Key::B => "B",
+        Key::C => "C",
+        Key::D => "D",
+        Key::E => "E",
+        Key::F => "F",
+        Key::G => "G",
+        Key::H => "H",
+        Key::I => "I",
+        Key::J => "J",
+        Key::K => "K",
+        Key::L => "L",
+        Key::M => "M",
+        Key::N => "N",
+        Key::O => "O",
+        Key::P => "P",
+        Key::Q => "Q",
+        Key::R => "R",
+        Key::S => "S",
+        Key::T => "T",
+        Key::U => "U",
+        Key::V => "V",
+        Key::W => "W",
+        Key::X => "X",
+        Key::Y => "Y",
+        Key::Z => "Z",
+        Key::F1 => "F1",
+        Key::F2 => "F2",
+        Key::F3 => "F3",
+        Key::F4 => "F4",
+        Key::F5 => "F5",
+        Key::F6 => "F6",
+        Key::F7 => "F7",
+        Key::F8 => "F8",
+        Key::F9 => "F9",
+        Key::F10 => "F10",
+        Key::F11 => "F11",
+        Key::F12 => "F12",
+        Key::F13 => "F13",
+        Key::F14 => "F14",
+        Key::F15 => "F15",
+        Key::F16 => "F16",
+        Key::F17 => "F17",
+        Key::F18 => "F18",
+        Key::F19 => "F19",
+        Key::F20 => "F20",
+        _ => return None,
+    })
+}
+
+// スクリプトの gui(ctx) が返した図形を描く。図形の座標は origin からの相対位置
+fn paint(painter: &egui::Painter, origin: egui::Pos2, shapes: &[Shape]) {
+    let pos = |x: f32, y: f32| origin + egui::vec2(x, y);
+    let color =
+        |color: &Color| egui::Color32::from_rgba_unmultiplied(color.r, color.g, color.b, color.a);
+    let stroke = |stroke: &Option<Color>, width: f32| match stroke {
+        Some(stroke) => egui::Stroke::new(width, color(stroke)),
+        None => egui::Stroke::NONE,
+    };
+    let fill = |fill: &Option<Color>| fill.as_ref().map_or(egui::Color32::TRANSPARENT, color);
+    for shape in shapes {
+        match shape {
+            Shape::Line {
+                points,
+                color: line_color,
+                line_width,
+                closed,
+            } => {
+                let points: Vec<egui::Pos2> = points.iter().map(|p| pos(p[0], p[1])).collect();
+                let line_stroke = egui::Stroke::new(*line_width, color(line_color));
+                painter.add(if *closed {
+                    egui::Shape::closed_line(points, line_stroke)
+                } else {
+                    egui::Shape::line(points, line_stroke)
+                });
+            }
+            Shape::Polygon {
+                points,
+                fill: polygon_fill,
+                stroke: polygon_stroke,
+                line_width,
+            } => {
+                // egui は凸多角形しか塗りつぶせないため、lyon で三角形に分割してメッシュとして描く
+                if let Some(polygon_fill) = polygon_fill {
+                    let (vertices, indices) = gui::tessellate(points);
+                    let mut mesh = egui::Mesh::default();
+                    for vertex in vertices {
+                        mesh.colored_vertex(pos(vertex[0], vertex[1]), color(polygon_fill));
+                    }
+                    mesh.indices = indices;
+                    painter.add(mesh);
+                }
+                if polygon_stroke.is_some() {
+                    let points = points.iter().map(|p| pos(p[0], p[1])).collect();
+                    painter.add(egui::Shape::closed_line(
+                        points,
+                        stroke(polygon_stroke, *line_width),
+                    ));
+                }
+            }
+            Shape::Rect {
+                x,
+                y,
+                width,
+                height,
+                fill: rect_fill,
+                stroke: rect_stroke,
+                line_width,
+                radius,
+            } => {
+                let rect = egui::Rect::from_min_size(pos(*x, *y), egui::vec2(*width, *height));
+                painter.rect(
+                    rect,
+                    *radius,
+                    fill(rect_fill),
+                    stroke(rect_stroke, *line_width),
+                );
+            }
+            Shape::Circle {
+                x,
+                y,
+                radius,
+                fill: circle_fill,
+                stroke: circle_stroke,
+                line_width,
+            } => {
+                painter.circle(
+                    pos(*x, *y),
+                    *radius,
+                    fill(circle_fill),
+                    stroke(circle_stroke, *line_width),
+                );
+            }
+            Shape::Text {
+                x,
+                y,
+                text,
+                size,
+                color: text_color,
+                align,
+            } => {
+                let anchor = match align {
+                    Align::Left => egui::Align2::LEFT_TOP,
+                    Align::Center => egui::Align2::CENTER_TOP,
+                    Align::Right => egui::Align2::RIGHT_TOP,
+                };
+                painter.text(
+                    pos(*x, *y),
+                    anchor,
+                    text,
+                    egui::FontId::proportional(*size),
+                    color(text_color),
+                );
+            }
+            // ヒット領域は描画せず、ウィジェットは widget::show で表示する
+            Shape::Region { .. }
+            | Shape::Knob { .. }
+            | Shape::Slider { .. }
+            | Shape::Toggle { .. }
+            | Shape::Dropdown { .. }
+            | Shape::XyPad { .. }
+            | Shape::Waveform { .. }
+            | Shape::Spectrum { .. }
+            | Shape::Keyboard { .. } => {}
+        }
+    }
+}
+
+// スクリプトを開き、スクリプトや依存ファイルが変更される度に再度コンパイルする
+fn open_script(
+    path: PathBuf,
+    runtime: SharedRuntime,
+    params: Arc<crate::params::PS88Params>,
+    state: Arc<Mutex<EditorState>>,
+) {
+    if let Ok(mut param_script_path) = params.script_path.lock() {
+        *param_script_path = Some(path.clone());
+    }
+    let script_path = path.clone();
+    let param_code = params.code.clone();
+    if let Ok(watcher) = load_script(&path, move |code| {
+        let mut runtime = runtime.lock().unwrap();
+        if let Err(err) = runtime.compile(&*code, Some(&script_path)) {
+            println!("{}", err);
+        }
+        if let Ok(mut param_code) = param_code.lock() {
+            *param_code = code;
+        }
+        runtime.dependencies()
+    }) {
+        state.lock().unwrap().watcher = Some(watcher);
+    }
+}
+
+// REPL に入力されたコードを実行中のスクリプトのコンテキストで評価する
+// 評価は js_sync のスレッドでオーディオのブロックの処理の合間に行われるため、GUI のスレッドでは待たない
+fn evaluate(
+    code: &str,
+    runtime: &SharedRuntime,
+    egui_ctx: &egui::Context,
+    state: &Arc<Mutex<EditorState>>,
+) {
+    let result = runtime.lock().unwrap().evaluate(code);
+    let mut state = state.lock().unwrap();
+    state.repl_history.push(format!("> {}", code));
+    match result {
+        Ok(result) => state.repl_history.push(result),
+        Err(err) => state.repl_history.push(err.to_string()),
+    }
+    egui_ctx.request_repaint();
+}
+
+// スクリプトの実行時間を seconds 秒間記録する。記録中もオーディオの処理は続ける
+fn record_profile(
+    seconds: f32,
+    runtime: &SharedRuntime,
+    egui_ctx: &egui::Context,
+    state: &Arc<Mutex<EditorState>>,
+) {
+    state.lock().unwrap().profiling = true;
+    egui_ctx.request_repaint();
+    let started = runtime.lock().unwrap().start_profiling();
+    let result = started.and_then(|_| {
+        // 待っている間はランタイムのロックを解放しておく
+        std::thread::sleep(std::time::Duration::from_secs_f32(seconds));
+        runtime.lock().unwrap().stop_profiling()
+    });
+    let mut state = state.lock().unwrap();
+    state.profiling = false;
+    match result {
+        Ok(profile) => {
+            state.profile_functions = profile.top_functions();
+            state.profile = Some(profile);
+        }
+        Err(err) => println!("failed to record a profile: {}", err),
+    }
+    egui_ctx.request_repaint();
+}
+
+// Chrome DevTools の Performance パネルや speedscope で開けるファイルに書き出す
+fn save_profile(profile: &Profile) {
+    let result = rfd::FileDialog::new()
+        .add_filter("CPU profile", &[profile::EXTENSION])
+        .set_file_name(format!("ps88.{}", profile::EXTENSION))
+        .save_file();
+    if let Some(path) = result {
+        if let Err(err) = profile.write(&path) {
+            println!("failed to save the profile: {}", err);
+        }
+    }
+}
+
+fn preset_dialog() -> rfd::FileDialog {
+    let dialog = rfd::FileDialog::new().add_filter("ps88 preset", &[preset::EXTENSION]);
+    match preset::preset_dir() {
+        Some(dir) if std::fs::create_dir_all(&dir).is_ok() => dialog.set_directory(dir),
+        _ => dialog,
+    }
+}
+
+fn refresh_presets(state: &Arc<Mutex<EditorState>>) {
+    let presets = preset::preset_dir()
+        .map(|dir| preset::discover(&dir))
+        .unwrap_or_default();
+    state.lock().unwrap().presets = presets;
+}
+
+// プリセットのファイルを書き出し、次のフレームでプラグインに反映する
+fn import_preset(path: &Path, egui_ctx: &egui::Context, state: &Arc<Mutex<EditorState>>) {
+    match preset::load(path) {
+        Ok(pending_preset) => {
+            state.lock().unwrap().pending_preset = Some(pending_preset);
+            egui_ctx.request_repaint();
+        }
+        Err(err) => println!("failed to import preset: {}", err),
+    }
+}
+
+fn export_preset(
+    path: &Path,
+    plugin_state: &PluginState,
+    script_path: Option<&Path>,
+    dependencies: &[PathBuf],
+) {
+    let name = path
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+    let result = Preset::from_state(&name, plugin_state, script_path, dependencies).and_then(
+        |(preset, skipped)| {
+            for path in skipped {
+                println!(
+                    "{} is outside the script directory and was not included in the preset",
+                    path.display()
+                );
+            }
+            preset.write(path)
+        },
+    );
+    if let Err(err) = result {
+        println!("failed to export preset: {}", err);
+    }
 }
 
-fn load_script<F: Fn(String) + Sync + Send + 'static>(
+type SharedWatcher = Arc<Mutex<Box<dyn Watcher + Send + Sync>>>;
+
+/// スクリプトを読み込み、スクリプトや import されたファイルが変更される度に再度読み込む。
+/// callback はスクリプトのコードを受け取り、スクリプトが依存しているファイルの一覧を返す。
+fn load_script<F: Fn(String) -> Vec<std::path::PathBuf> + Sync + Send + 'static>(
     path: &std::path::Path,
     callback: F,
-) -> Result<Box<dyn super::file_watcher::Watcher + Send + Sync>, ()> {
+) -> Result<SharedWatcher, ()> {
     let Ok(mut file) = std::fs::File::open(&path) else {
         return Err(());
     };
     let mut code = String::new();
     file.read_to_string(&mut code).unwrap();
-    callback(code);
+    let dependencies = callback(code);
 
     let mut watcher: Box<dyn Watcher + Send + Sync> =
         Box::new(super::file_watcher::WatcherImpl::new());
     let Ok(rx) = watcher.watch(path) else {
         return Err(());
     };
+    // 依存ファイルの一部が見つからなくても、見つかったファイルの監視は続ける
+    let _ = watcher.set_paths(&watch_paths(path, dependencies));
+    let watcher = Arc::new(Mutex::new(watcher));
+
     let rx = super::file_watcher::relay_latest(rx, std::time::Duration::from_millis(100));
     let path = path.to_path_buf();
+    let weak_watcher = Arc::downgrade(&watcher);
     std::thread::spawn(move || {
         let path = path;
         for _ in rx {
@@ -79,9 +864,27 @@ fn load_script<F: Fn(String) + Sync + Send + 'static>(
             };
             let mut code = String::new();
             file.read_to_string(&mut code).unwrap();
-            callback(code);
+            let dependencies = callback(code);
+
+            // import 文が変更されていれば監視対象も変わる
+            let Some(watcher) = weak_watcher.upgrade() else {
+                break;
+            };
+            let _ = watcher
+                .lock()
+                .unwrap()
+                .set_paths(&watch_paths(&path, dependencies));
         }
     });
     // 呼び出し元が watcher を drop することでファイル監視が終了するようにする
     return Ok(watcher);
 }
+
+fn watch_paths(
+    path: &std::path::Path,
+    dependencies: Vec<std::path::PathBuf>,
+) -> Vec<std::path::PathBuf> {
+    std::iter::once(path.to_path_buf())
+        .chain(dependencies)
+        .collect()
+}
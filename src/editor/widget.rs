@@ -0,0 +1,807 @@
+use crate::params::PS88Params;
+use crate::runtime::gui::{self, Color, Shape, WidgetValue};
+use nih_plug::prelude::*;
+use nih_plug_egui::egui;
+use std::collections::BTreeMap;
+
+// つまみを上下にこのピクセル数ドラッグすると、値が最小値から最大値まで変わる
+const KNOB_DRAG_PIXELS: f32 = 200.0;
+
+// Shift を押しながらドラッグした時の変化量の倍率
+const FINE_DRAG_SCALE: f32 = 0.1;
+
+// つまみの回転範囲。真上から左右にこの角度 (ラジアン) まで回る
+const KNOB_ANGLE: f32 = 0.75 * std::f32::consts::PI;
+
+// ウィジェットの文字の大きさ
+const TEXT_SIZE: f32 = 12.0;
+
+/// スクリプトが指定したパラメータの id からホストのパラメータを探す
+pub fn param<'a>(params: &'a PS88Params, id: &str) -> Option<&'a FloatParam> {
+    match id {
+        "param1" => Some(&params.param1),
+        "param2" => Some(&params.param2),
+        "param3" => Some(&params.param3),
+        "param4" => Some(&params.param4),
+        _ => None,
+    }
+}
+
+// スクリプトから指定できるパラメータの id
+const PARAM_IDS: [&str; 4] = ["param1", "param2", "param3", "param4"];
+
+// ホストのパラメータから、スクリプトが指定する id を探す
+fn param_id(params: &PS88Params, target: &FloatParam) -> Option<&'static str> {
+    PARAM_IDS
+        .into_iter()
+        .find(|id| param(params, id).is_some_and(|p| std::ptr::eq(p, target)))
+}
+
+/// gui(ctx) の ctx.params に渡すホストのパラメータの値
+pub fn param_values(params: &PS88Params) -> BTreeMap<String, f32> {
+    PARAM_IDS
+        .into_iter()
+        .filter_map(|id| Some((id.to_string(), param(params, id)?.value())))
+        .collect()
+}
+
+/// ウィジェットで操作中 (begin_set_parameter を呼び、まだ end_set_parameter を呼んでいない) のパラメータ。
+/// エディタの状態に保存してフレームをまたいで使う。
+/// 操作していたウィジェットが表示されなくなった場合や、エディタを閉じた場合にも操作を終えられるようにする。
+#[derive(Default)]
+pub struct Gestures {
+    // パラメータの id と、そのパラメータを操作しているウィジェットの egui の id
+    open: BTreeMap<&'static str, egui::Id>,
+    // このフレームで、操作しているウィジェットが表示されたパラメータ
+    shown: Vec<&'static str>,
+}
+
+impl Gestures {
+    /// 全ての操作を終える
+    pub fn end_all(&mut self, params: &PS88Params, setter: &ParamSetter) {
+        for (id, _) in std::mem::take(&mut self.open) {
+            if let Some(param) = param(params, id) {
+                setter.end_set_parameter(param);
+            }
+        }
+        self.shown.clear();
+    }
+
+    fn is_open(&self, id: &str, widget: egui::Id) -> bool {
+        self.open.get(id) == Some(&widget)
+    }
+
+    fn begin(
+        &mut self,
+        setter: &ParamSetter,
+        id: &'static str,
+        param: &FloatParam,
+        widget: egui::Id,
+    ) {
+        if !self.open.contains_key(id) {
+            setter.begin_set_parameter(param);
+        }
+        self.open.insert(id, widget);
+        self.shown.push(id);
+    }
+
+    fn end(&mut self, setter: &ParamSetter, id: &str, param: &FloatParam, widget: egui::Id) {
+        if self.is_open(id, widget) {
+            self.open.remove(id);
+            setter.end_set_parameter(param);
+        }
+    }
+
+    // このフレームで操作しているウィジェットが表示されなかったパラメータの操作を終える
+    fn end_hidden(&mut self, params: &PS88Params, setter: &ParamSetter) {
+        let shown = std::mem::take(&mut self.shown);
+        self.open.retain(|id, _| {
+            if shown.contains(id) {
+                return true;
+            }
+            if let Some(param) = param(params, id) {
+                setter.end_set_parameter(param);
+            }
+            false
+        });
+    }
+}
+
+/// gui(ctx) が返したウィジェットを area の中に表示し、操作を反映する。
+/// パラメータに結び付けたウィジェットは ParamSetter でパラメータを変え、そうでないものは changes に新しい値を入れる。
+/// changes にまだスクリプトに渡していない値がある場合は、gui(ctx) が返した値の代わりにそれを表示する。
+/// gestures は操作中のパラメータで、表示されなかったウィジェットの操作はここで終える。
+/// 存在しないパラメータを指定したウィジェットがあればエラーを返す (そのウィジェットはパラメータに結び付けずに表示する)。
+pub fn show(
+    ui: &mut egui::Ui,
+    area: egui::Rect,
+    shapes: &[Shape],
+    params: &PS88Params,
+    setter: &ParamSetter,
+    changes: &mut BTreeMap<String, WidgetValue>,
+    gestures: &mut Gestures,
+) -> Result<(), String> {
+    let mut widgets = Widgets {
+        painter: ui.painter_at(area),
+        origin: area.min,
+        params,
+        setter,
+        changes,
+        gestures,
+        error: None,
+    };
+    for shape in shapes {
+        widgets.show(ui, shape);
+    }
+    widgets.gestures.end_hidden(params, setter);
+    match widgets.error {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+struct Widgets<'a> {
+    painter: egui::Painter,
+    origin: egui::Pos2,
+    params: &'a PS88Params,
+    setter: &'a ParamSetter<'a>,
+    changes: &'a mut BTreeMap<String, WidgetValue>,
+    gestures: &'a mut Gestures,
+    error: Option<String>,
+}
+
+impl<'a> Widgets<'a> {
+    fn show(&mut self, ui: &mut egui::Ui, shape: &Shape) {
+        match shape {
+            Shape::Knob {
+                id,
+                x,
+                y,
+                width,
+                height,
+                value,
+                min,
+                max,
+                param,
+                label,
+            } => {
+                let rect = self.rect(*x, *y, *width, *height);
+                let param = self.param(param);
+                let value = match self.changes.get(id) {
+                    Some(WidgetValue::Number(value)) => *value,
+                    _ => *value,
+                };
+                self.knob(ui, id, rect, param, (value, (*min, *max)), label);
+            }
+            Shape::Slider {
+                id,
+                x,
+                y,
+                width,
+                height,
+                value,
+                min,
+                max,
+                param,
+                label,
+            } => {
+                let rect = self.rect(*x, *y, *width, *height);
+                let param = self.param(param);
+                let value = match self.changes.get(id) {
+                    Some(WidgetValue::Number(value)) => *value,
+                    _ => *value,
+                };
+                self.slider(ui, id, rect, param, (value, (*min, *max)), label);
+            }
+            Shape::Toggle {
+                id,
+                x,
+                y,
+                width,
+                height,
+                value,
+                param,
+                label,
+            } => {
+                let rect = self.rect(*x, *y, *width, *height);
+                let param = self.param(param);
+                let value = match self.changes.get(id) {
+                    Some(WidgetValue::Bool(value)) => *value,
+                    _ => *value,
+                };
+                self.toggle(ui, id, rect, param, value, label);
+            }
+            Shape::Dropdown {
+                id,
+                x,
+                y,
+                width,
+                height,
+                options,
+                value,
+                param,
+            } => {
+                let rect = self.rect(*x, *y, *width, *height);
+                let param = self.param(param);
+                let value = match self.changes.get(id) {
+                    Some(WidgetValue::Index(value)) => *value,
+                    _ => *value,
+                };
+                self.dropdown(ui, id, rect, param, value, options);
+            }
+            Shape::XyPad {
+                id,
+                x,
+                y,
+                width,
+                height,
+                value,
+                param_x,
+                param_y,
+            } => {
+                let rect = self.rect(*x, *y, *width, *height);
+                let params = [self.param(param_x), self.param(param_y)];
+                let value = match self.changes.get(id) {
+                    Some(WidgetValue::Point(value)) => *value,
+                    _ => *value,
+                };
+                self.xy_pad(ui, id, rect, params, value);
+            }
+            Shape::Waveform {
+                x,
+                y,
+                width,
+                height,
+                data,
+                min,
+                max,
+                color,
+            } => {
+                let rect = self.rect(*x, *y, *width, *height);
+                self.waveform(ui, rect, data, (*min, *max), color);
+            }
+            Shape::Spectrum {
+                x,
+                y,
+                width,
+                height,
+                data,
+                min,
+                max,
+                color,
+            } => {
+                let rect = self.rect(*x, *y, *width, *height);
+                self.spectrum(ui, rect, data, (*min, *max), color);
+            }
+            Shape::Keyboard {
+                id,
+                x,
+                y,
+                width,
+                height,
+                low,
+                high,
+                notes,
+            } => {
+                let rect = self.rect(*x, *y, *width, *height);
+                self.keyboard(ui, id, rect, (*low, *high), notes);
+            }
+            // 図形は editor の paint で描く
+            Shape::Line { .. }
+            | Shape::Polygon { .. }
+            | Shape::Rect { .. }
+            | Shape::Circle { .. }
+            | Shape::Text { .. }
+            | Shape::Region { .. } => {}
+        }
+    }
+
+    fn rect(&self, x: f32, y: f32, width: f32, height: f32) -> egui::Rect {
+        egui::Rect::from_min_size(self.origin + egui::vec2(x, y), egui::vec2(width, height))
+    }
+
+    fn param(&mut self, id: &Option<String>) -> Option<&'a FloatParam> {
+        let id = id.as_ref()?;
+        let param = param(self.params, id);
+        if param.is_none() && self.error.is_none() {
+            self.error = Some(format!("unknown parameter: {}", id));
+        }
+        param
+    }
+
+    fn knob(
+        &mut self,
+        ui: &mut egui::Ui,
+        id: &str,
+        rect: egui::Rect,
+        param: Option<&FloatParam>,
+        (value, range): (f32, (f32, f32)),
+        label: &Option<String>,
+    ) {
+        let response = ui.interact(rect, egui_id(id), egui::Sense::click_and_drag());
+        let normalized = match param {
+            Some(param) => param.unmodulated_normalized_value(),
+            None => normalize(value, range),
+        };
+        let scale = if ui.input(|i| i.modifiers.shift) {
+            FINE_DRAG_SCALE
+        } else {
+            1.0
+        };
+        let delta = -response.drag_delta().y / KNOB_DRAG_PIXELS * scale;
+        self.drag(
+            id,
+            &response,
+            param,
+            (normalized + delta).clamp(0.0, 1.0),
+            range,
+        );
+
+        // 下の 1 行にラベルか、操作中は値を表示し、残りにつまみを描く
+        let visuals = ui.visuals();
+        let text_rect = egui::Rect::from_min_max(
+            egui::pos2(rect.left(), rect.bottom() - TEXT_SIZE - 2.0),
+            rect.max,
+        );
+        let knob_rect =
+            egui::Rect::from_min_max(rect.min, egui::pos2(rect.right(), text_rect.top()));
+        let center = knob_rect.center();
+        let radius = knob_rect.width().min(knob_rect.height()) / 2.0 - 2.0;
+        let angle = |normalized: f32| -KNOB_ANGLE + normalized * 2.0 * KNOB_ANGLE;
+        let point =
+            |angle: f32, radius: f32| center + radius * egui::vec2(angle.sin(), -angle.cos());
+        let normalized = self.normalized(id, param, normalized, range);
+        self.painter
+            .circle_filled(center, radius, visuals.extreme_bg_color);
+        let arc: Vec<egui::Pos2> = (0..=32)
+            .map(|i| point(angle(normalized * i as f32 / 32.0), radius - 2.0))
+            .collect();
+        self.painter.add(egui::Shape::line(
+            arc,
+            egui::Stroke::new(3.0, visuals.selection.bg_fill),
+        ));
+        self.painter.line_segment(
+            [center, point(angle(normalized), radius - 2.0)],
+            egui::Stroke::new(2.0, visuals.text_color()),
+        );
+        let text = if response.hovered() || response.dragged() {
+            Some(display_value(param, normalized, range))
+        } else {
+            label.clone()
+        };
+        if let Some(text) = text {
+            self.painter.text(
+                text_rect.center(),
+                egui::Align2::CENTER_CENTER,
+                text,
+                egui::FontId::proportional(TEXT_SIZE),
+                visuals.text_color(),
+            );
+        }
+    }
+
+    fn slider(
+        &mut self,
+        ui: &mut egui::Ui,
+        id: &str,
+        rect: egui::Rect,
+        param: Option<&FloatParam>,
+        (value, range): (f32, (f32, f32)),
+        label: &Option<String>,
+    ) {
+        let response = ui.interact(rect, egui_id(id), egui::Sense::click_and_drag());
+        let vertical = rect.height() > rect.width();
+        // nih_plug の ParamSlider と同じく、押している位置の値にする
+        let normalized = match response.interact_pointer_pos() {
+            Some(pos) if vertical => (rect.bottom() - pos.y) / rect.height(),
+            Some(pos) => (pos.x - rect.left()) / rect.width(),
+            None => match param {
+                Some(param) => param.unmodulated_normalized_value(),
+                None => normalize(value, range),
+            },
+        };
+        self.drag(id, &response, param, normalized.clamp(0.0, 1.0), range);
+
+        let visuals = ui.visuals();
+        let normalized = self.normalized(id, param, normalized, range);
+        let filled = if vertical {
+            egui::Rect::from_min_max(
+                egui::pos2(rect.left(), rect.bottom() - rect.height() * normalized),
+                rect.max,
+            )
+        } else {
+            egui::Rect::from_min_max(
+                rect.min,
+                egui::pos2(rect.left() + rect.width() * normalized, rect.bottom()),
+            )
+        };
+        self.painter
+            .rect_filled(rect, 2.0, visuals.extreme_bg_color);
+        self.painter
+            .rect_filled(filled, 2.0, visuals.selection.bg_fill);
+        let value = display_value(param, normalized, range);
+        let text = match label {
+            Some(label) if !vertical => format!("{}: {}", label, value),
+            _ => value,
+        };
+        self.painter.text(
+            rect.center(),
+            egui::Align2::CENTER_CENTER,
+            text,
+            egui::FontId::proportional(TEXT_SIZE),
+            visuals.text_color(),
+        );
+    }
+
+    fn toggle(
+        &mut self,
+        ui: &mut egui::Ui,
+        id: &str,
+        rect: egui::Rect,
+        param: Option<&FloatParam>,
+        value: bool,
+        label: &Option<String>,
+    ) {
+        let response = ui.interact(rect, egui_id(id), egui::Sense::click());
+        let mut on = match param {
+            Some(param) => param.unmodulated_normalized_value() >= 0.5,
+            None => value,
+        };
+        if response.clicked() {
+            on = !on;
+            match param {
+                // 1 回のクリックで 1 つのジェスチャーにする
+                Some(param) => {
+                    self.setter.begin_set_parameter(param);
+                    self.setter
+                        .set_parameter_normalized(param, if on { 1.0 } else { 0.0 });
+                    self.setter.end_set_parameter(param);
+                }
+                None => {
+                    self.changes.insert(id.to_string(), WidgetValue::Bool(on));
+                }
+            }
+        }
+
+        let visuals = ui.visuals();
+        let fill = if on {
+            visuals.selection.bg_fill
+        } else {
+            visuals.extreme_bg_color
+        };
+        self.painter
+            .rect(rect, 4.0, fill, visuals.widgets.noninteractive.bg_stroke);
+        if let Some(label) = label {
+            self.painter.text(
+                rect.center(),
+                egui::Align2::CENTER_CENTER,
+                label,
+                egui::FontId::proportional(TEXT_SIZE),
+                visuals.text_color(),
+            );
+        }
+    }
+
+    fn dropdown(
+        &mut self,
+        ui: &mut egui::Ui,
+        id: &str,
+        rect: egui::Rect,
+        param: Option<&FloatParam>,
+        value: usize,
+        options: &[String],
+    ) {
+        // パラメータの範囲を選択肢の数で等分し、最も近い選択肢を選ぶ
+        let steps = options.len().saturating_sub(1).max(1) as f32;
+        let current = match param {
+            Some(param) => (param.unmodulated_normalized_value() * steps).round() as usize,
+            None => value,
+        };
+        let mut selected = current;
+        ui.allocate_ui_at_rect(rect, |ui| {
+            egui::ComboBox::from_id_source(egui_id(id))
+                .width(rect.width())
+                .selected_text(options.get(current).map_or("", String::as_str))
+                .show_ui(ui, |ui| {
+                    for (i, option) in options.iter().enumerate() {
+                        ui.selectable_value(&mut selected, i, option);
+                    }
+                });
+        });
+        if selected == current {
+            return;
+        }
+        match param {
+            Some(param) => {
+                self.setter.begin_set_parameter(param);
+                self.setter
+                    .set_parameter_normalized(param, selected as f32 / steps);
+                self.setter.end_set_parameter(param);
+            }
+            None => {
+                self.changes
+                    .insert(id.to_string(), WidgetValue::Index(selected));
+            }
+        }
+    }
+
+    fn xy_pad(
+        &mut self,
+        ui: &mut egui::Ui,
+        id: &str,
+        rect: egui::Rect,
+        params: [Option<&FloatParam>; 2],
+        value: [f32; 2],
+    ) {
+        let response = ui.interact(rect, egui_id(id), egui::Sense::click_and_drag());
+        let mut point = [0, 1].map(|i| match params[i] {
+            Some(param) => param.unmodulated_normalized_value(),
+            None => value[i].clamp(0.0, 1.0),
+        });
+        if let Some(pos) = response.interact_pointer_pos() {
+            let held = [
+                ((pos.x - rect.left()) / rect.width()).clamp(0.0, 1.0),
+                ((rect.bottom() - pos.y) / rect.height()).clamp(0.0, 1.0),
+            ];
+            if params.iter().any(Option::is_none) && held != point {
+                self.changes
+                    .insert(id.to_string(), WidgetValue::Point(held));
+            }
+            point = held;
+        }
+        // X と Y を 1 つのジェスチャーとして、両方のパラメータを同時に変える
+        let values: Vec<_> = params
+            .into_iter()
+            .zip(point)
+            .filter_map(|(param, value)| Some((param?, value)))
+            .collect();
+        self.gesture(&response, &values);
+
+        let visuals = ui.visuals();
+        self.painter.rect(
+            rect,
+            2.0,
+            visuals.extreme_bg_color,
+            visuals.widgets.noninteractive.bg_stroke,
+        );
+        let pos = egui::pos2(
+            rect.left() + point[0] * rect.width(),
+            rect.bottom() - point[1] * rect.height(),
+        );
+        let stroke = egui::Stroke::new(1.0, visuals.weak_text_color());
+        self.painter.hline(rect.x_range(), pos.y, stroke);
+        self.painter.vline(pos.x, rect.y_range(), stroke);
+        self.painter
+            .circle_filled(pos, 5.0, visuals.selection.bg_fill);
+    }
+
+    fn waveform(
+        &mut self,
+        ui: &egui::Ui,
+        rect: egui::Rect,
+        data: &[f32],
+        range: (f32, f32),
+        color: &Option<Color>,
+    ) {
+        let visuals = ui.visuals();
+        self.painter
+            .rect_filled(rect, 2.0, visuals.extreme_bg_color);
+        if data.len() < 2 {
+            return;
+        }
+        let points = data
+            .iter()
+            .enumerate()
+            .map(|(i, value)| {
+                egui::pos2(
+                    rect.left() + rect.width() * i as f32 / (data.len() - 1) as f32,
+                    rect.bottom() - rect.height() * normalize(*value, range),
+                )
+            })
+            .collect();
+        self.painter.add(egui::Shape::line(
+            points,
+            egui::Stroke::new(1.0, graph_color(ui, color)),
+        ));
+    }
+
+    fn spectrum(
+        &mut self,
+        ui: &egui::Ui,
+        rect: egui::Rect,
+        data: &[f32],
+        range: (f32, f32),
+        color: &Option<Color>,
+    ) {
+        let visuals = ui.visuals();
+        self.painter
+            .rect_filled(rect, 2.0, visuals.extreme_bg_color);
+        let width = rect.width() / data.len().max(1) as f32;
+        for (i, value) in data.iter().enumerate() {
+            let left = rect.left() + width * i as f32;
+            let bar = egui::Rect::from_min_max(
+                egui::pos2(
+                    left,
+                    rect.bottom() - rect.height() * normalize(*value, range),
+                ),
+                egui::pos2(left + (width - 1.0).max(1.0), rect.bottom()),
+            );
+            self.painter.rect_filled(bar, 0.0, graph_color(ui, color));
+        }
+    }
+
+    fn keyboard(
+        &mut self,
+        ui: &mut egui::Ui,
+        id: &str,
+        rect: egui::Rect,
+        range: (u8, u8),
+        notes: &[u8],
+    ) {
+        let egui_id = egui_id(id);
+        let response = ui.interact(rect, egui_id, egui::Sense::click_and_drag());
+        let keys = keys(rect, range);
+
+        // 押している間はポインタの下の鍵盤を押したことにする (ドラッグで別の鍵盤に移れる)
+        let held = if response.is_pointer_button_down_on() {
+            response.interact_pointer_pos().and_then(|pos| {
+                // 黒鍵は白鍵の上に重なっているので先に調べる
+                keys.iter()
+                    .filter(|(_, _, black)| *black)
+                    .chain(keys.iter().filter(|(_, _, black)| !*black))
+                    .find(|(_, key, _)| key.contains(pos))
+                    .map(|(note, _, _)| *note)
+            })
+        } else {
+            None
+        };
+        let previous = ui.data(|data| data.get_temp::<Option<u8>>(egui_id).flatten());
+        if held != previous {
+            ui.data_mut(|data| data.insert_temp(egui_id, held));
+            self.changes.insert(id.to_string(), WidgetValue::Note(held));
+        }
+
+        let visuals = ui.visuals();
+        for black in [false, true] {
+            for (note, key, _) in keys.iter().filter(|(_, _, b)| *b == black) {
+                let fill = if held == Some(*note) || notes.contains(note) {
+                    visuals.selection.bg_fill
+                } else if black {
+                    egui::Color32::BLACK
+                } else {
+                    egui::Color32::WHITE
+                };
+                self.painter
+                    .rect(*key, 1.0, fill, egui::Stroke::new(1.0, egui::Color32::GRAY));
+            }
+        }
+    }
+
+    // 押している間の操作で normalized (0-1) の値を変える。ダブルクリックするとパラメータの初期値に戻す
+    fn drag(
+        &mut self,
+        id: &str,
+        response: &egui::Response,
+        param: Option<&FloatParam>,
+        normalized: f32,
+        range: (f32, f32),
+    ) {
+        let Some(param) = param else {
+            if response.is_pointer_button_down_on() {
+                let value = range.0 + normalized * (range.1 - range.0);
+                self.changes
+                    .insert(id.to_string(), WidgetValue::Number(value));
+            }
+            return;
+        };
+        if response.double_clicked() {
+            // 2 回目の押下で gesture が始めた操作がまだ終わっていなければ、その中で初期値に戻して終える
+            let id = param_id(self.params, param);
+            let started = id.is_some_and(|id| self.gestures.is_open(id, response.id));
+            if !started {
+                self.setter.begin_set_parameter(param);
+            }
+            self.setter
+                .set_parameter_normalized(param, param.default_normalized_value());
+            match id.filter(|_| started) {
+                Some(id) => self.gestures.end(self.setter, id, param, response.id),
+                None => self.setter.end_set_parameter(param),
+            }
+            return;
+        }
+        self.gesture(response, &[(param, normalized)]);
+    }
+
+    // nih_plug の ParamSlider と同じく、押した時と離した時に begin_set_parameter / end_set_parameter を呼び、
+    // その間の変更をホストが 1 つの操作としてオートメーションに記録できるようにする。
+    // 操作中かどうかはフレームをまたいで gestures に保存する
+    fn gesture(&mut self, response: &egui::Response, values: &[(&FloatParam, f32)]) {
+        let held = response.is_pointer_button_down_on();
+        for (param, value) in values {
+            let Some(id) = param_id(self.params, param) else {
+                continue;
+            };
+            if held {
+                self.gestures.begin(self.setter, id, param, response.id);
+                if param.unmodulated_normalized_value() != *value {
+                    self.setter.set_parameter_normalized(*param, *value);
+                }
+            } else {
+                self.gestures.end(self.setter, id, param, response.id);
+            }
+        }
+    }
+
+    // 表示する normalized の値。パラメータは set_parameter_normalized の結果を、スクリプトの値は changes の値を使う
+    fn normalized(
+        &self,
+        id: &str,
+        param: Option<&FloatParam>,
+        normalized: f32,
+        range: (f32, f32),
+    ) -> f32 {
+        match (param, self.changes.get(id)) {
+            (Some(param), _) => param.unmodulated_normalized_value(),
+            (None, Some(WidgetValue::Number(value))) => normalize(*value, range),
+            (None, _) => normalized,
+        }
+    }
+}
+
+fn egui_id(id: &str) -> egui::Id {
+    egui::Id::new(("ps88-gui", id))
+}
+
+// min から max の値を 0-1 にする
+fn normalize(value: f32, (min, max): (f32, f32)) -> f32 {
+    if max == min {
+        return 0.0;
+    }
+    ((value - min) / (max - min)).clamp(0.0, 1.0)
+}
+
+// パラメータの場合は単位付きの文字列にする
+fn display_value(param: Option<&FloatParam>, normalized: f32, (min, max): (f32, f32)) -> String {
+    match param {
+        Some(param) => param.normalized_value_to_string(normalized, true),
+        None => format!("{:.2}", min + normalized * (max - min)),
+    }
+}
+
+fn graph_color(ui: &egui::Ui, color: &Option<Color>) -> egui::Color32 {
+    match color {
+        Some(color) => egui::Color32::from_rgba_unmultiplied(color.r, color.g, color.b, color.a),
+        None => ui.visuals().selection.bg_fill,
+    }
+}
+
+// low から high までの鍵盤の (ノート番号, 範囲, 黒鍵か) の一覧。
+// 白鍵を等幅に並べ、黒鍵は隣り合う白鍵の境目に幅 0.6、高さ 0.6 で重ねる
+fn keys(rect: egui::Rect, (low, high): (u8, u8)) -> Vec<(u8, egui::Rect, bool)> {
+    let notes = low.min(high)..=high.max(low);
+    let white_keys = notes
+        .clone()
+        .filter(|note| !gui::is_black_key(*note))
+        .count();
+    let white_width = rect.width() / white_keys.max(1) as f32;
+    let mut white_index = 0;
+    let mut keys = Vec::new();
+    for note in notes {
+        let left = rect.left() + white_width * white_index as f32;
+        if gui::is_black_key(note) {
+            let key = egui::Rect::from_min_size(
+                egui::pos2(left - white_width * 0.3, rect.top()),
+                egui::vec2(white_width * 0.6, rect.height() * 0.6),
+            );
+            keys.push((note, key, true));
+        } else {
+            let key = egui::Rect::from_min_size(
+                egui::pos2(left, rect.top()),
+                egui::vec2(white_width, rect.height()),
+            );
+            keys.push((note, key, false));
+            white_index += 1;
+        }
+    }
+    keys
+}
@@ -1,6 +1,12 @@
 #[cfg_attr(test, mockall::automock)]
 pub trait Watcher {
     fn watch(&mut self, path: &std::path::Path) -> Result<std::sync::mpsc::Receiver<()>, Error>;
+
+    /// 監視対象を paths に置き換える。
+    /// watch で得た Receiver はそのまま使い続けられる。
+    /// まだ存在しないパスは、作成された時に通知されるよう親ディレクトリを監視する。
+    /// 一部のパスの監視に失敗した場合も、残りのパスの監視は行う。
+    fn set_paths(&mut self, paths: &[std::path::PathBuf]) -> Result<(), Error>;
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -13,44 +19,143 @@ pub enum Error {
 
 pub struct WatcherImpl {
     watcher: Option<notify::RecommendedWatcher>,
+    // 実際に notify で監視しているパス
+    watching: Vec<(std::path::PathBuf, notify::RecursiveMode)>,
+    // 変更を通知するパス。監視のコールバックからも参照する
+    paths: std::sync::Arc<std::sync::Mutex<Vec<std::path::PathBuf>>>,
 }
 
 impl WatcherImpl {
     pub fn new() -> WatcherImpl {
-        WatcherImpl { watcher: None }
+        WatcherImpl {
+            watcher: None,
+            watching: Vec::new(),
+            paths: Default::default(),
+        }
+    }
+}
+
+fn convert_error(err: notify::Error) -> Error {
+    match err {
+        notify::Error {
+            kind: notify::ErrorKind::PathNotFound,
+            ..
+        } => Error::NotFound(err.to_string()),
+        _ => Error::Internal(err.to_string()),
     }
 }
 
+// path の変更を知るために監視するパスと、イベントを絞り込むためのパスを返す。
+// まだ存在しないパス (import に失敗したファイルなど) は、作成されたことが分かるように
+// 最も近い存在する親ディレクトリを、サブディレクトリを含めずに監視する。
+// イベントのパスは正規化されていることがあるため、親ディレクトリを正規化したパスも返す。
+fn watch_target(
+    path: &std::path::Path,
+) -> Option<(
+    std::path::PathBuf,
+    notify::RecursiveMode,
+    Vec<std::path::PathBuf>,
+)> {
+    if path.exists() {
+        return Some((
+            path.to_path_buf(),
+            notify::RecursiveMode::Recursive,
+            vec![path.to_path_buf()],
+        ));
+    }
+    let parent = path.ancestors().skip(1).find(|p| p.is_dir())?;
+    let mut filters = vec![path.to_path_buf()];
+    if let Ok(canonical) = parent.canonicalize() {
+        if let Ok(rest) = path.strip_prefix(parent) {
+            filters.push(canonical.join(rest));
+        }
+    }
+    Some((
+        parent.to_path_buf(),
+        notify::RecursiveMode::NonRecursive,
+        filters,
+    ))
+}
+
+// イベントが paths のどれかに関係するか。
+// 監視しているパス以下の変更と、まだ存在しないパスの途中のディレクトリの作成を通知する
+fn is_relevant(event: &notify::Event, paths: &[std::path::PathBuf]) -> bool {
+    event.paths.is_empty()
+        || event
+            .paths
+            .iter()
+            .any(|e| paths.iter().any(|p| e.starts_with(p) || p.starts_with(e)))
+}
+
 impl Watcher for WatcherImpl {
     fn watch(&mut self, path: &std::path::Path) -> Result<std::sync::mpsc::Receiver<()>, Error> {
-        use notify::Watcher;
-
+        if !path.exists() {
+            return Err(Error::NotFound(path.display().to_string()));
+        }
         let (tx, rx) = std::sync::mpsc::channel();
-        let watcher = notify::recommended_watcher(move |res| match res {
-            Ok(_) => {
-                let _ = tx.send(());
-            }
-            Err(err) => {
-                log::error!("Error: {err:?}");
-            }
-        });
-        let mut watcher = match watcher {
+        let paths = self.paths.clone();
+        let watcher =
+            notify::recommended_watcher(move |res: notify::Result<notify::Event>| match res {
+                Ok(event) => {
+                    if is_relevant(&event, &paths.lock().unwrap()) {
+                        let _ = tx.send(());
+                    }
+                }
+                Err(err) => {
+                    log::error!("Error: {err:?}");
+                }
+            });
+        let watcher = match watcher {
             Ok(watcher) => Ok(watcher),
             Err(err) => return Err(Error::Internal(err.to_string())),
         }?;
-        match watcher.watch(path, notify::RecursiveMode::Recursive) {
-            Ok(_) => Ok(()),
-            Err(err) => match err {
-                notify::Error {
-                    kind: notify::ErrorKind::PathNotFound,
-                    ..
-                } => Err(Error::NotFound(err.to_string())),
-                _ => Err(Error::Internal(err.to_string())),
-            },
-        }?;
         self.watcher = Some(watcher);
+        self.watching = Vec::new();
+        self.set_paths(&[path.to_path_buf()])?;
         return Ok(rx);
     }
+
+    fn set_paths(&mut self, paths: &[std::path::PathBuf]) -> Result<(), Error> {
+        use notify::Watcher;
+
+        let Some(watcher) = self.watcher.as_mut() else {
+            return Err(Error::Internal("not watching".into()));
+        };
+        let mut result = Ok(());
+        let mut targets = Vec::new();
+        let mut filters = Vec::new();
+        for path in paths {
+            match watch_target(path) {
+                Some((target, mode, filter)) => {
+                    if !targets.contains(&(target.clone(), mode)) {
+                        targets.push((target, mode));
+                    }
+                    filters.extend(filter);
+                }
+                None => {
+                    result = result.and(Err(Error::NotFound(path.display().to_string())));
+                }
+            }
+        }
+        for target in self.watching.iter().filter(|t| !targets.contains(t)) {
+            if let Err(err) = watcher.unwatch(&target.0) {
+                result = result.and(Err(convert_error(err)));
+            }
+        }
+        let mut watching = Vec::new();
+        for target in targets {
+            if !self.watching.contains(&target) {
+                if let Err(err) = watcher.watch(&target.0, target.1) {
+                    result = result.and(Err(convert_error(err)));
+                    continue;
+                }
+            }
+            watching.push(target);
+        }
+        self.watching = watching;
+        *self.paths.lock().unwrap() = filters;
+        result
+    }
 }
 
 /// mpsc::Receiver のメッセージを連続で受信した場合に、最後のメッセージのみを受信するようにリレーする。
@@ -118,4 +223,69 @@ mod tests {
         drop(tx);
         assert_eq!(rx.recv(), Err(std::sync::mpsc::RecvError));
     }
+
+    #[test]
+    fn test_set_paths() {
+        let dir = std::env::temp_dir().join("ps88_file_watcher_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let main = dir.join("main.js");
+        let lib = dir.join("lib.js");
+        std::fs::write(&main, "").unwrap();
+        std::fs::write(&lib, "").unwrap();
+        let timeout = std::time::Duration::from_secs(5);
+
+        let mut watcher = WatcherImpl::new();
+        let rx = watcher.watch(&main).unwrap();
+
+        // 追加したパスの変更も同じ Receiver で受信できる
+        watcher.set_paths(&[main.clone(), lib.clone()]).unwrap();
+        std::fs::write(&lib, "a").unwrap();
+        assert!(rx.recv_timeout(timeout).is_ok());
+        while rx
+            .recv_timeout(std::time::Duration::from_millis(100))
+            .is_ok()
+        {}
+
+        // まだ存在しないパスも監視でき、作成されると通知される。同じディレクトリの関係ないファイルの変更は通知されない
+        let missing = dir.join("missing.js");
+        watcher.set_paths(&[main.clone(), missing.clone()]).unwrap();
+        std::fs::write(dir.join("other.js"), "").unwrap();
+        assert!(rx
+            .recv_timeout(std::time::Duration::from_millis(500))
+            .is_err());
+        std::fs::write(&missing, "").unwrap();
+        assert!(rx.recv_timeout(timeout).is_ok());
+        while rx
+            .recv_timeout(std::time::Duration::from_millis(100))
+            .is_ok()
+        {}
+
+        // 存在しないディレクトリの中のファイルは、ディレクトリの作成が通知され、
+        // (エディタが再読み込みした時のように) 監視し直すとファイルの作成も通知される
+        let nested = dir.join("lib/gain.js");
+        watcher.set_paths(&[main.clone(), nested.clone()]).unwrap();
+        std::fs::create_dir(dir.join("lib")).unwrap();
+        assert!(rx.recv_timeout(timeout).is_ok());
+        while rx
+            .recv_timeout(std::time::Duration::from_millis(100))
+            .is_ok()
+        {}
+        watcher.set_paths(&[main.clone(), nested.clone()]).unwrap();
+        std::fs::write(&nested, "").unwrap();
+        assert!(rx.recv_timeout(timeout).is_ok());
+        while rx
+            .recv_timeout(std::time::Duration::from_millis(100))
+            .is_ok()
+        {}
+
+        // 監視から外したパスの変更は通知されず、残りのパスの監視は続く
+        watcher.set_paths(&[main.clone()]).unwrap();
+        std::fs::write(&lib, "b").unwrap();
+        assert!(rx
+            .recv_timeout(std::time::Duration::from_millis(500))
+            .is_err());
+        std::fs::write(&main, "a").unwrap();
+        assert!(rx.recv_timeout(timeout).is_ok());
+    }
 }
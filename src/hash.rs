@@ -0,0 +1,26 @@
+// 64 bit の FNV-1a ハッシュ
+// ファイル名などに使い、別のプロセスや Rust のバージョンをまたいでも同じ値になる必要がある場合に使う
+pub(crate) struct Fnv1a(u64);
+
+impl Fnv1a {
+    pub(crate) fn new() -> Self {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+
+    pub(crate) fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    // 区切りが変わっても同じ値にならないよう、長さを含めて書き込む
+    pub(crate) fn write_part(&mut self, bytes: &[u8]) {
+        self.write(&(bytes.len() as u64).to_le_bytes());
+        self.write(bytes);
+    }
+
+    pub(crate) fn finish(&self) -> u64 {
+        self.0
+    }
+}
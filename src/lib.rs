@@ -1,10 +1,33 @@
+mod bench;
+mod check;
+pub mod cli;
 mod editor;
 mod file_watcher;
+mod hash;
+mod migration;
 mod params;
+mod preset;
+mod render;
 mod runtime;
 
 use nih_plug::prelude::*;
+use runtime::midi::MidiEvent;
+use runtime::runtime::OutputEvent;
+use runtime::stats::ProcessStats;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// スタンドアロンで --stats が指定された場合に、処理時間の統計を標準エラー出力に表示する
+static PRINT_STATS: AtomicBool = AtomicBool::new(false);
+
+// 統計を表示する間隔
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 処理時間の統計を定期的に標準エラー出力に表示する (スタンドアロンの --stats)
+pub fn print_stats() {
+    PRINT_STATS.store(true, Ordering::Relaxed);
+}
 
 pub struct PS88 {
     // プラグイン内で保持するデータ
@@ -13,29 +36,77 @@ pub struct PS88 {
     // JavaScript のランタイム
     runtime: Arc<Mutex<dyn runtime::runtime::ScriptRuntime + Sync + Send>>,
 
+    // エディタから runtime のロックを取らずに gui(ctx) を呼び出すハンドル
+    gui: runtime::js_sync::GuiHandle,
+
+    // ブロックごとの処理時間の統計
+    stats: Arc<ProcessStats>,
+
     sample_rate: f32,
     time: u64,
 }
 
 impl Default for PS88 {
     fn default() -> Self {
+        let params = Arc::new(params::PS88Params::default());
+        let stats = Arc::new(ProcessStats::new());
+        if PRINT_STATS.load(Ordering::Relaxed) {
+            let stats = Arc::downgrade(&stats);
+            std::thread::spawn(move || loop {
+                std::thread::sleep(STATS_INTERVAL);
+                let Some(stats) = stats.upgrade() else {
+                    break;
+                };
+                eprintln!("{}", stats.snapshot());
+            });
+        }
+        young_generation_size();
+        let js_runtime = inspector(
+            runtime::js_sync::JsRuntimeBuilder::new()
+                .on_log(std::sync::Arc::new(|log| {
+                    println!("{}", log);
+                }))
+                .state(params.script_state.clone())
+                .stats(stats.clone())
+                .code_cache(runtime::code_cache::CodeCache::shared()),
+        )
+        .build();
+        let gui = js_runtime.gui_handle();
         let runtime: Arc<Mutex<dyn runtime::runtime::ScriptRuntime + Sync + Send>> =
-            Arc::new(Mutex::new(
-                runtime::js_sync::JsRuntimeBuilder::new()
-                    .on_log(std::sync::Arc::new(|log| {
-                        println!("{}", log);
-                    }))
-                    .build(),
-            ));
+            Arc::new(Mutex::new(js_runtime));
         Self {
-            params: Arc::new(params::PS88Params::default()),
+            params,
             runtime,
+            gui,
+            stats,
             sample_rate: 1.0,
             time: 0,
         }
     }
 }
 
+// 環境変数 PS88_INSPECT が設定されていれば Chrome DevTools から接続できるようにする
+fn inspector(builder: runtime::js_sync::JsRuntimeBuilder) -> runtime::js_sync::JsRuntimeBuilder {
+    match runtime::js_inspector::start_from_env() {
+        Some(server) => builder.inspector(server),
+        None => builder,
+    }
+}
+
+// 環境変数 PS88_YOUNG_GENERATION_MB が設定されていれば V8 の若い世代の大きさ (MB) を変える。
+// V8 の初期化前にしか反映できないため、プロセスで最初のインスタンスを作る時に 1 度だけ設定する
+fn young_generation_size() {
+    static CONFIGURED: std::sync::Once = std::sync::Once::new();
+    CONFIGURED.call_once(|| {
+        if let Some(size) = std::env::var("PS88_YOUNG_GENERATION_MB")
+            .ok()
+            .and_then(|size| size.parse().ok())
+        {
+            runtime::js::set_young_generation_size(size);
+        }
+    });
+}
+
 impl Plugin for PS88 {
     const NAME: &'static str = "ps88";
     const VENDOR: &'static str = "ps88";
@@ -64,8 +135,25 @@ impl Plugin for PS88 {
         self.params.clone()
     }
 
+    fn filter_state(state: &mut PluginState) {
+        // 古いバージョンで保存された状態を現在の形式に変換する
+        match migration::migrate(&mut state.fields) {
+            Ok(version) if version > migration::STATE_VERSION => println!(
+                "the state was saved by a newer version (state version {}), some data may be lost",
+                version
+            ),
+            Ok(_) => {}
+            Err(err) => println!("{}", err),
+        }
+    }
+
     fn editor(&mut self, _async_executor: AsyncExecutor<Self>) -> Option<Box<dyn Editor>> {
-        editor::editor(self.params.clone(), self.runtime.clone())
+        editor::editor(
+            self.params.clone(),
+            self.runtime.clone(),
+            self.gui.clone(),
+            self.stats.clone(),
+        )
     }
 
     fn initialize(
@@ -77,7 +165,9 @@ impl Plugin for PS88 {
         // デフォルトのスクリプトをコンパイル
         {
             let mut runtime = self.runtime.lock().unwrap();
-            if let Err(err) = (&mut runtime).compile(&*self.params.code.lock().unwrap().clone()) {
+            let code = self.params.code.lock().unwrap().clone();
+            let path = self.params.script_path.lock().unwrap().clone();
+            if let Err(err) = (&mut runtime).compile(&code, path.as_deref()) {
                 println!("{}", err);
             }
         }
@@ -111,46 +201,73 @@ impl Plugin for PS88 {
         // イベントを取得
         let mut midi = Vec::<u8>::new();
         while let Some(event) = context.next_event() {
-            match event {
+            let event = match event {
                 NoteEvent::NoteOn {
                     timing,
                     channel,
                     note,
                     velocity,
                     ..
-                } => {
-                    let mut e = [0u8; 7];
-                    e[0..4].copy_from_slice(&timing.to_be_bytes());
-                    e[4] = 0x90 | channel;
-                    e[5] = note;
-                    e[6] = (velocity * 127.0).round().clamp(1.0, 127.0) as u8;
-                    midi.extend_from_slice(&e);
-                }
+                } => MidiEvent::new(
+                    timing,
+                    runtime::midi::NOTE_ON,
+                    channel,
+                    note,
+                    (velocity * 127.0).round().clamp(1.0, 127.0) as u8,
+                ),
                 NoteEvent::NoteOff {
                     timing,
                     channel,
                     note,
                     velocity,
                     ..
-                } => {
-                    let mut e = [0u8; 7];
-                    e[0..4].copy_from_slice(&timing.to_be_bytes());
-                    e[4] = 0x80 | channel;
-                    e[5] = note;
-                    e[6] = (velocity * 127.0).round().clamp(1.0, 127.0) as u8;
-                    midi.extend_from_slice(&e);
-                }
+                } => MidiEvent::new(
+                    timing,
+                    runtime::midi::NOTE_OFF,
+                    channel,
+                    note,
+                    (velocity * 127.0).round().clamp(1.0, 127.0) as u8,
+                ),
+                NoteEvent::MidiCC {
+                    timing,
+                    channel,
+                    cc,
+                    value,
+                } => MidiEvent::new(
+                    timing,
+                    runtime::midi::CONTROL_CHANGE,
+                    channel,
+                    cc,
+                    (value * 127.0).round().clamp(0.0, 127.0) as u8,
+                ),
                 // TODO: 他のイベントも処理する
-                _ => {}
+                _ => continue,
             };
+            midi.extend_from_slice(&event.encode());
         }
 
         // スクリプトを実行
         {
             let mut runtime = self.runtime.lock().unwrap();
             let sampling_rate = self.sample_rate;
-            if let Err(e) = (&mut runtime).audio(&mut audio, slice.len(), sampling_rate, &midi) {
-                println!("process error: {}", e);
+            match (&mut runtime).audio(&mut audio, slice.len(), sampling_rate, &midi) {
+                Ok(events) => {
+                    for event in events {
+                        match event {
+                            OutputEvent::VoiceTerminated {
+                                timing,
+                                channel,
+                                note,
+                            } => context.send_event(NoteEvent::VoiceTerminated {
+                                timing,
+                                voice_id: None,
+                                channel,
+                                note,
+                            }),
+                        }
+                    }
+                }
+                Err(e) => println!("process error: {}", e),
             }
         }
 
@@ -1,7 +1,19 @@
 use nih_plug::prelude::*;
+use std::process::ExitCode;
 
 use ps88::PS88;
 
-fn main() {
-    nih_export_standalone::<PS88>();
+fn main() -> ExitCode {
+    // サブコマンド (ps88 render など) が指定されていればそれを実行する
+    if let Some(code) = ps88::cli::run() {
+        return code;
+    }
+    // --stats は nih-plug のスタンドアロンの引数ではないため、取り除いてから渡す
+    let mut args: Vec<String> = std::env::args().collect();
+    if let Some(index) = args.iter().position(|arg| arg == "--stats") {
+        args.remove(index);
+        ps88::print_stats();
+    }
+    nih_export_standalone_with_args::<PS88, _>(args);
+    ExitCode::SUCCESS
 }
@@ -0,0 +1,196 @@
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+// 保存されるプラグインの状態の形式のバージョン
+// 状態に保存するデータの形式を変更した場合はこの値を増やし、MIGRATIONS に変換処理を追加する
+// (フィールドを追加しただけの場合、古い状態に無いフィールドは nih-plug が既定値のままにするので変換は不要)
+//
+// 0: 最初のリリース。state-version が無い
+// 1: state-version を追加
+pub const STATE_VERSION: u32 = 1;
+
+// 状態のバージョンを保存するフィールドの名前
+pub const VERSION_FIELD: &str = "state-version";
+
+// あるバージョンの状態を、次のバージョンの形式に変換する処理
+type Migration = fn(&mut BTreeMap<String, String>) -> Result<(), MigrationError>;
+
+// MIGRATIONS[v] はバージョン v の状態を v + 1 の形式に変換する。
+// 読み込んだ状態のバージョンから現在のバージョンまで、順に適用する
+const MIGRATIONS: [Migration; STATE_VERSION as usize] = [v0_to_v1];
+
+#[derive(Debug, Error)]
+pub enum MigrationError {
+    #[error("invalid state version: `{0}`")]
+    InvalidVersion(String),
+    #[error("failed to migrate `{0}`: {1}")]
+    InvalidField(String, serde_json::Error),
+}
+
+/// 保存された状態を現在のバージョンの形式に変換し、元のバージョンを返す。
+/// fields は #[persist] のフィールド名と、その値を JSON にした文字列の組。
+///
+/// 新しいバージョンの ps88 で保存された状態は変換せず、読み込める値だけが使われる。
+/// この場合も再度保存する時のために、バージョンは現在のものに書き換える。
+pub fn migrate(fields: &mut BTreeMap<String, String>) -> Result<u32, MigrationError> {
+    let version = version(fields)?;
+    for migration in MIGRATIONS.iter().skip(version as usize) {
+        migration(fields)?;
+    }
+    fields.insert(
+        VERSION_FIELD.into(),
+        to_json(VERSION_FIELD, &STATE_VERSION)?,
+    );
+    Ok(version)
+}
+
+// バージョン 0 から 1 では、バージョンのフィールドが増えただけで他の形式は変わっていない
+// (バージョンは migrate が最後に書き込む)
+fn v0_to_v1(_fields: &mut BTreeMap<String, String>) -> Result<(), MigrationError> {
+    Ok(())
+}
+
+// 状態のバージョンを取得する。バージョンが無い状態は最初のリリースで保存されたもの
+fn version(fields: &BTreeMap<String, String>) -> Result<u32, MigrationError> {
+    match fields.get(VERSION_FIELD) {
+        Some(version) => serde_json::from_str(version)
+            .map_err(|_| MigrationError::InvalidVersion(version.clone())),
+        None => Ok(0),
+    }
+}
+
+fn to_json<T: serde::Serialize>(key: &str, value: &T) -> Result<String, MigrationError> {
+    serde_json::to_string(value).map_err(|e| MigrationError::InvalidField(key.into(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::PS88Params;
+    use crate::runtime::state::{ScriptState, StateValue};
+    use crate::PS88;
+    use nih_plug::prelude::{Params, Plugin};
+    use nih_plug::wrapper::state::{ParamValue, PluginState};
+    use serde::Deserialize;
+    use std::path::PathBuf;
+
+    // 各バージョンで保存された状態
+    const FIXTURES: [&str; STATE_VERSION as usize + 1] = [
+        include_str!("migration/fixtures/v0.json"),
+        include_str!("migration/fixtures/v1.json"),
+    ];
+
+    #[derive(Deserialize)]
+    struct Fixture {
+        params: BTreeMap<String, f32>,
+        fields: BTreeMap<String, String>,
+    }
+
+    fn field<T: serde::de::DeserializeOwned>(fields: &BTreeMap<String, String>, key: &str) -> T {
+        serde_json::from_str(&fields[key]).unwrap()
+    }
+
+    #[test]
+    fn migrate_fixtures() {
+        for (version, fixture) in FIXTURES.iter().enumerate() {
+            let Fixture { mut fields, .. } = serde_json::from_str(fixture).unwrap();
+            let original = fields.clone();
+            assert_eq!(migrate(&mut fields).unwrap(), version as u32);
+
+            // バージョンが現在のものになり、他のフィールドはそのまま残る
+            assert_eq!(field::<u32>(&fields, VERSION_FIELD), STATE_VERSION);
+            for (key, value) in &original {
+                if key != VERSION_FIELD {
+                    assert_eq!(&fields[key], value, "{}", key);
+                }
+            }
+
+            // 変換後の状態を保存して読み込んでも変化しない
+            let saved = serde_json::to_string(&fields).unwrap();
+            let mut loaded: BTreeMap<String, String> = serde_json::from_str(&saved).unwrap();
+            assert_eq!(migrate(&mut loaded).unwrap(), STATE_VERSION);
+            assert_eq!(loaded, fields);
+        }
+    }
+
+    #[test]
+    fn restore_fixtures() {
+        // ホストの wrapper と同じく、filter_state で変換した後に現在の PS88Params に読み込む
+        for (version, fixture) in FIXTURES.iter().enumerate() {
+            let Fixture { params, fields } = serde_json::from_str(fixture).unwrap();
+            let mut state = PluginState {
+                version: String::new(),
+                params: params
+                    .into_iter()
+                    .map(|(id, value)| (id, ParamValue::F32(value)))
+                    .collect(),
+                fields,
+            };
+            PS88::filter_state(&mut state);
+            let restored = PS88Params::default();
+            restored.deserialize_fields(&state.fields);
+
+            assert_eq!(*restored.state_version.lock().unwrap(), STATE_VERSION);
+            assert!(restored.code.lock().unwrap().contains("const audio"));
+            let mut script_state = ScriptState::new();
+            let mut script_path = None;
+            if version >= 1 {
+                script_state.set("pattern", StateValue::Json("[1,0,1,0]".into()));
+                script_state.set("table", StateValue::Binary(vec![0, 0, 128, 63]));
+                script_path = Some(PathBuf::from("/home/user/ps88/main.js"));
+            }
+            assert_eq!(
+                *restored.script_state.lock().unwrap(),
+                script_state,
+                "v{}",
+                version
+            );
+            assert_eq!(
+                *restored.script_path.lock().unwrap(),
+                script_path,
+                "v{}",
+                version
+            );
+
+            // パラメータの値は変換されず、同じ id のパラメータに読み込まれる
+            let ids: Vec<String> = restored
+                .param_map()
+                .into_iter()
+                .map(|(id, ..)| id)
+                .collect();
+            for (id, value) in [
+                ("param1", 0.25),
+                ("param2", 0.5),
+                ("param3", 0.0),
+                ("param4", 1.0),
+            ] {
+                assert!(ids.iter().any(|i| i == id), "{}", id);
+                assert!(
+                    matches!(state.params[id], ParamValue::F32(v) if v == value),
+                    "{}",
+                    id
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn newer_version() {
+        // 新しいバージョンの状態は、知らないフィールドを残したまま読み込む
+        let mut fields = BTreeMap::from([
+            (VERSION_FIELD.to_string(), "100".to_string()),
+            ("code".to_string(), "\"\"".to_string()),
+            ("unknown".to_string(), "1".to_string()),
+        ]);
+        assert_eq!(migrate(&mut fields).unwrap(), 100);
+        assert_eq!(field::<u32>(&fields, VERSION_FIELD), STATE_VERSION);
+        assert_eq!(fields["unknown"], "1");
+
+        // バージョンが読めない場合はエラー
+        let mut fields = BTreeMap::from([(VERSION_FIELD.to_string(), "\"x\"".to_string())]);
+        assert!(matches!(
+            migrate(&mut fields),
+            Err(MigrationError::InvalidVersion(_))
+        ));
+    }
+}
@@ -1,5 +1,8 @@
+use crate::migration;
+use crate::runtime::state::ScriptState;
 use nih_plug::prelude::*;
 use nih_plug_egui::EguiState;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 
 const DEFAULT_SCRIPT: &'static str = std::include_str!("default_script.js");
@@ -7,10 +10,25 @@ const DEFAULT_SCRIPT: &'static str = std::include_str!("default_script.js");
 // プラグイン内で保持するデータ
 #[derive(Params)]
 pub struct PS88Params {
+    // 保存された状態の形式のバージョン
+    // 古いバージョンの状態は読み込む前に migration::migrate で変換される
+    #[persist = "state-version"]
+    pub state_version: Arc<Mutex<u32>>,
+
     // ユーザーが入力したコード
     #[persist = "code"]
     pub code: Arc<Mutex<String>>,
 
+    // ユーザーが開いたスクリプトのファイルパス
+    // import を解決する時の基準になる
+    #[persist = "script-path"]
+    pub script_path: Arc<Mutex<Option<PathBuf>>>,
+
+    // スクリプトが ps88.state で保存したデータ
+    // スクリプトのランタイムと共有しているため、読み込まれた値は次の audio の呼び出しから見える
+    #[persist = "script-state"]
+    pub script_state: Arc<Mutex<ScriptState>>,
+
     // パラメータの数は固定で 4 つだけ
     #[id = "param1"]
     pub param1: FloatParam,
@@ -29,7 +47,10 @@ pub struct PS88Params {
 impl Default for PS88Params {
     fn default() -> Self {
         Self {
+            state_version: Arc::new(Mutex::new(migration::STATE_VERSION)),
             code: Arc::new(Mutex::new(String::from(DEFAULT_SCRIPT))),
+            script_path: Arc::new(Mutex::new(None)),
+            script_state: Arc::new(Mutex::new(ScriptState::new())),
             param1: FloatParam::new("Param1", 0.0, FloatRange::Linear { min: 0.0, max: 1.0 }),
             param2: FloatParam::new("Param2", 0.0, FloatRange::Linear { min: 0.0, max: 1.0 }),
             param3: FloatParam::new("Param3", 0.0, FloatRange::Linear { min: 0.0, max: 1.0 }),
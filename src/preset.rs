@@ -0,0 +1,506 @@
+use crate::hash::Fnv1a;
+use nih_plug::wrapper::state::{self, PluginState};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+use thiserror::Error;
+
+// プリセットファイルの拡張子
+pub const EXTENSION: &str = "ps88";
+
+// プリセットファイルの形式のバージョン
+pub const FORMAT_VERSION: u32 = 1;
+
+// プリセットファイル (zip) 内の設定ファイルと、スクリプトなどのファイルを置くディレクトリ
+const MANIFEST_NAME: &str = "preset.json";
+const FILES_DIR: &str = "files/";
+
+// プリセットに含めない #[persist] のフィールド (環境ごとに異なる値)
+const EXCLUDED_FIELDS: [&str; 2] = ["script-path", "editor-state"];
+
+#[derive(Debug, Error)]
+pub enum PresetError {
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid preset file: {0}")]
+    Zip(#[from] zip::result::ZipError),
+    #[error("invalid preset file: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("unsupported preset version: {0}")]
+    UnsupportedVersion(u32),
+    #[error("invalid file path in preset: `{0}`")]
+    InvalidPath(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ParamValue {
+    F32(f32),
+    I32(i32),
+    Bool(bool),
+    String(String),
+}
+
+/// スクリプト、import されたモジュール、サンプル、パラメータの値、スクリプトが保存したデータをまとめたもの。
+/// ファイルとしては、設定を書いた preset.json と files/ 以下のファイルを含む zip として保存する。
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Preset {
+    pub name: String,
+
+    // メインスクリプトの files 内のパス
+    // スクリプトをファイルから開いていない場合は None で、コードは fields の code に入っている
+    pub script: Option<String>,
+
+    pub params: BTreeMap<String, ParamValue>,
+
+    // #[persist] のフィールド名と、その値を JSON にした文字列
+    pub fields: BTreeMap<String, String>,
+
+    // スクリプトのディレクトリからの相対パス ('/' 区切り) とファイルの内容
+    pub files: BTreeMap<String, Vec<u8>>,
+}
+
+/// 作曲ソフトなどのプリセットブラウザに表示するための、プリセットファイルの情報
+#[derive(Debug, Clone, PartialEq)]
+pub struct PresetInfo {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    format_version: u32,
+    name: String,
+    script: Option<String>,
+    params: BTreeMap<String, ParamValue>,
+    fields: BTreeMap<String, String>,
+}
+
+impl Preset {
+    /// プラグインの状態からプリセットを作成する。
+    /// スクリプトとその依存ファイルのうち、スクリプトのディレクトリ以下にあるものを含める。
+    /// 含められなかったファイルの一覧も返す。
+    pub fn from_state(
+        name: &str,
+        state: &PluginState,
+        script_path: Option<&Path>,
+        dependencies: &[PathBuf],
+    ) -> Result<(Self, Vec<PathBuf>), PresetError> {
+        let mut preset = Preset {
+            name: name.to_string(),
+            params: state
+                .params
+                .iter()
+                .map(|(id, value)| (id.clone(), value.into()))
+                .collect(),
+            fields: state
+                .fields
+                .iter()
+                .filter(|(key, _)| !EXCLUDED_FIELDS.contains(&key.as_str()))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect(),
+            ..Default::default()
+        };
+
+        let mut skipped = Vec::new();
+        let Some(script_path) = script_path else {
+            return Ok((preset, skipped));
+        };
+        let base_dir = script_path.parent().unwrap_or(Path::new(""));
+        preset.script = relative_name(base_dir, script_path);
+        for path in std::iter::once(script_path).chain(dependencies.iter().map(|p| p.as_path())) {
+            let name = relative_name(base_dir, path)
+                .or_else(|| relative_name(&std::fs::canonicalize(base_dir).ok()?, path));
+            match name {
+                // 存在しないファイルは import や読み込みに失敗していたものなので無視する
+                Some(name) if path.is_file() => {
+                    preset.files.insert(name, std::fs::read(path)?);
+                }
+                Some(_) => {}
+                None => skipped.push(path.to_path_buf()),
+            }
+        }
+        Ok((preset, skipped))
+    }
+
+    /// プリセットをプラグインの状態に変換する。
+    /// script_path は extract で書き出したメインスクリプトのパス。
+    pub fn to_state(&self, script_path: Option<&Path>) -> PluginState {
+        let mut fields = self.fields.clone();
+        fields.insert(
+            "script-path".into(),
+            serde_json::to_string(&script_path).unwrap_or("null".into()),
+        );
+        PluginState {
+            version: env!("CARGO_PKG_VERSION").into(),
+            params: self
+                .params
+                .iter()
+                .map(|(id, value)| (id.clone(), value.into()))
+                .collect(),
+            fields,
+        }
+    }
+
+    /// files を dir 以下に書き出し、メインスクリプトのパスを返す
+    pub fn extract(&self, dir: &Path) -> Result<Option<PathBuf>, PresetError> {
+        for (name, data) in &self.files {
+            let path = dir.join(safe_path(name)?);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(path, data)?;
+        }
+        match &self.script {
+            Some(script) => Ok(Some(dir.join(safe_path(script)?))),
+            None => Ok(None),
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), PresetError> {
+        let file = std::fs::File::create(path)?;
+        let mut zip = zip::ZipWriter::new(file);
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated);
+        let manifest = Manifest {
+            format_version: FORMAT_VERSION,
+            name: self.name.clone(),
+            script: self.script.clone(),
+            params: self.params.clone(),
+            fields: self.fields.clone(),
+        };
+        zip.start_file(MANIFEST_NAME, options)?;
+        zip.write_all(serde_json::to_string_pretty(&manifest)?.as_bytes())?;
+        for (name, data) in &self.files {
+            zip.start_file(format!("{}{}", FILES_DIR, name), options)?;
+            zip.write_all(data)?;
+        }
+        zip.finish()?;
+        Ok(())
+    }
+
+    pub fn read(path: &Path) -> Result<Self, PresetError> {
+        let file = std::fs::File::open(path)?;
+        let mut zip = zip::ZipArchive::new(file)?;
+        let manifest = read_manifest(&mut zip)?;
+
+        let mut files = BTreeMap::new();
+        for index in 0..zip.len() {
+            let mut file = zip.by_index(index)?;
+            let Some(name) = file.name().strip_prefix(FILES_DIR).map(|n| n.to_string()) else {
+                continue;
+            };
+            if file.is_dir() {
+                continue;
+            }
+            safe_path(&name)?;
+            let mut data = Vec::new();
+            file.read_to_end(&mut data)?;
+            files.insert(name, data);
+        }
+        Ok(Preset {
+            name: manifest.name,
+            script: manifest.script,
+            params: manifest.params,
+            fields: manifest.fields,
+            files,
+        })
+    }
+}
+
+/// プリセットファイルの情報を、files を展開せずに preset.json だけ読んで取得する
+pub fn read_info(path: &Path) -> Result<PresetInfo, PresetError> {
+    let file = std::fs::File::open(path)?;
+    let manifest = read_manifest(&mut zip::ZipArchive::new(file)?)?;
+    Ok(PresetInfo {
+        name: manifest.name,
+        path: path.to_path_buf(),
+    })
+}
+
+/// プリセットファイルを extract_dir に書き出し、プラグインに渡す状態とメインスクリプトのパスを返す。
+/// エディタからの読み込みと、作曲ソフトからの読み込みで共通の処理。
+pub fn load(path: &Path) -> Result<(PluginState, Option<PathBuf>), PresetError> {
+    let preset = Preset::read(path)?;
+    let dir = extract_dir(path, &preset);
+    // 書き出し先はプリセットのパスと内容ごとに別のディレクトリなので、同じ名前の別のプリセットを上書きすることはない。
+    // 同じプリセットを読み込んだ別のインスタンスが使っている可能性があるため、ディレクトリは消さずに同じ内容で上書きする
+    let script_path = preset.extract(&dir)?;
+    Ok((preset.to_state(script_path.as_deref()), script_path))
+}
+
+fn read_manifest(zip: &mut zip::ZipArchive<std::fs::File>) -> Result<Manifest, PresetError> {
+    let mut json = String::new();
+    zip.by_name(MANIFEST_NAME)?.read_to_string(&mut json)?;
+    let manifest: Manifest = serde_json::from_str(&json)?;
+    if manifest.format_version > FORMAT_VERSION {
+        return Err(PresetError::UnsupportedVersion(manifest.format_version));
+    }
+    Ok(manifest)
+}
+
+/// ユーザーのプリセットを保存するディレクトリ
+/// 環境変数 PS88_PRESET_DIR で変更できる
+pub fn preset_dir() -> Option<PathBuf> {
+    if let Some(dir) = std::env::var_os("PS88_PRESET_DIR") {
+        return Some(PathBuf::from(dir));
+    }
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(|home| PathBuf::from(home).join(".ps88").join("presets"))
+}
+
+/// プリセットに含まれるファイルを書き出すディレクトリ
+/// 読み込んだスクリプトの import などはこのディレクトリからの相対パスで解決される。
+/// 名前が同じでも場所か内容が異なるプリセットは別のディレクトリになるよう、
+/// プリセットファイルの絶対パスと、含まれるファイルの名前と内容のハッシュを付ける。
+pub fn extract_dir(preset_path: &Path, preset: &Preset) -> PathBuf {
+    let canonical =
+        std::fs::canonicalize(preset_path).unwrap_or_else(|_| preset_path.to_path_buf());
+    let mut hash = Fnv1a::new();
+    hash.write_part(canonical.to_string_lossy().as_bytes());
+    for (name, data) in &preset.files {
+        hash.write_part(name.as_bytes());
+        hash.write_part(data);
+    }
+    let name = format!(
+        "{}-{:016x}",
+        preset_path
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy(),
+        hash.finish()
+    );
+    match preset_dir() {
+        Some(dir) => dir.join(".files").join(name),
+        None => std::env::temp_dir().join("ps88").join(name),
+    }
+}
+
+/// dir 以下のプリセットファイルを名前順に列挙する
+pub fn list(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut presets: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.is_file() && path.extension().is_some_and(|e| e == EXTENSION))
+        .collect();
+    presets.sort();
+    presets
+}
+
+/// dir 以下のプリセットファイルの情報を名前順に列挙する
+/// 読み込めないファイルは含めない
+pub fn discover(dir: &Path) -> Vec<PresetInfo> {
+    list(dir)
+        .iter()
+        .filter_map(|path| match read_info(path) {
+            Ok(info) => Some(info),
+            Err(err) => {
+                println!("failed to read preset {}: {}", path.display(), err);
+                None
+            }
+        })
+        .collect()
+}
+
+// base_dir からの相対パスを '/' 区切りの文字列にする。base_dir の外にある場合は None を返す。
+fn relative_name(base_dir: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(base_dir).ok()?;
+    let names: Option<Vec<&str>> = relative
+        .components()
+        .map(|c| match c {
+            Component::Normal(name) => name.to_str(),
+            _ => None,
+        })
+        .collect();
+    names.filter(|n| !n.is_empty()).map(|n| n.join("/"))
+}
+
+// プリセット内のパスを、書き出し先のディレクトリの外を指さない相対パスに変換する
+fn safe_path(name: &str) -> Result<PathBuf, PresetError> {
+    let mut path = PathBuf::new();
+    for part in name.split('/') {
+        match Path::new(part).components().next() {
+            Some(Component::Normal(part)) if Path::new(part).components().count() == 1 => {
+                path.push(part)
+            }
+            _ => return Err(PresetError::InvalidPath(name.into())),
+        }
+    }
+    Ok(path)
+}
+
+impl From<&state::ParamValue> for ParamValue {
+    fn from(value: &state::ParamValue) -> Self {
+        match value {
+            state::ParamValue::F32(v) => ParamValue::F32(*v),
+            state::ParamValue::I32(v) => ParamValue::I32(*v),
+            state::ParamValue::Bool(v) => ParamValue::Bool(*v),
+            state::ParamValue::String(v) => ParamValue::String(v.clone()),
+        }
+    }
+}
+
+impl From<&ParamValue> for state::ParamValue {
+    fn from(value: &ParamValue) -> Self {
+        match value {
+            ParamValue::F32(v) => state::ParamValue::F32(*v),
+            ParamValue::I32(v) => state::ParamValue::I32(*v),
+            ParamValue::Bool(v) => state::ParamValue::Bool(*v),
+            ParamValue::String(v) => state::ParamValue::String(v.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::PS88Params;
+    use crate::runtime::sample::tests::write_wav;
+    use crate::runtime::state::{ScriptState, StateValue};
+    use crate::PS88;
+    use nih_plug::prelude::{ParamPtr, Params, Plugin};
+
+    #[test]
+    fn save_and_load() {
+        let dir = std::env::temp_dir().join("ps88_preset_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("patch/lib")).unwrap();
+        std::fs::create_dir_all(dir.join("patch/samples")).unwrap();
+        let code = r#"
+            import { gain } from "./lib/gain.js";
+            const kick = await ps88.loadSample("./samples/kick.wav");
+            export const audio = (ctx) => {
+                ctx.audio[0] = gain(kick.channels[0][0]);
+                ctx.audio[1] = ps88.state.get("pattern")[1];
+            };
+            export const gui = () => {};
+        "#;
+        let main = dir.join("patch/main.js");
+        std::fs::write(&main, code).unwrap();
+        std::fs::write(
+            dir.join("patch/lib/gain.js"),
+            "export const gain = (x) => x * 2;",
+        )
+        .unwrap();
+        write_wav(&dir.join("patch/samples/kick.wav"), 48000, &[vec![0.25]]);
+        std::fs::write(dir.join("outside.js"), "").unwrap();
+
+        // 保存する時点のプラグインの状態
+        let params = PS88Params::default();
+        *params.code.lock().unwrap() = code.into();
+        *params.script_path.lock().unwrap() = Some(main.clone());
+        params
+            .script_state
+            .lock()
+            .unwrap()
+            .set("pattern", StateValue::Json("[0,3]".into()));
+        let state = PluginState {
+            version: String::new(),
+            params: BTreeMap::from([("param1".into(), state::ParamValue::F32(0.75))]),
+            fields: params.serialize_fields(),
+        };
+        let dependencies = vec![
+            dir.join("patch/lib/gain.js"),
+            dir.join("patch/samples/kick.wav"),
+            dir.join("outside.js"),
+        ];
+
+        // スクリプトのディレクトリの外にあるファイルは含まれない
+        let (preset, skipped) =
+            Preset::from_state("patch", &state, Some(&main), &dependencies).unwrap();
+        assert_eq!(skipped, vec![dir.join("outside.js")]);
+        assert_eq!(
+            preset.files.keys().collect::<Vec<_>>(),
+            vec!["lib/gain.js", "main.js", "samples/kick.wav"]
+        );
+        assert!(!preset.fields.contains_key("script-path"));
+
+        // ファイルに保存して読み込むと同じ内容になる
+        let path = dir.join("patch.ps88");
+        preset.write(&path).unwrap();
+        let loaded = Preset::read(&path).unwrap();
+        assert_eq!(loaded, preset);
+        assert_eq!(list(&dir), vec![path.clone()]);
+        std::fs::write(dir.join("broken.ps88"), "").unwrap();
+        assert_eq!(
+            discover(&dir),
+            vec![PresetInfo {
+                name: "patch".into(),
+                path: path.clone(),
+            }]
+        );
+
+        // 別の場所に書き出したファイルを、新しく作ったプラグインに読み込む
+        // (ホストの wrapper が状態を復元する時と同じく、filter_state の後にフィールドとパラメータを反映する)
+        let extracted = loaded.extract(&dir.join("extracted")).unwrap();
+        assert_eq!(extracted, Some(dir.join("extracted/main.js")));
+        let mut state = Preset::read(&path).unwrap().to_state(extracted.as_deref());
+        PS88::filter_state(&mut state);
+        let plugin = PS88::default();
+        plugin.params().deserialize_fields(&state.fields);
+        assert_eq!(*plugin.params.code.lock().unwrap(), code);
+        assert_eq!(*plugin.params.script_path.lock().unwrap(), extracted);
+        let mut script_state = ScriptState::new();
+        script_state.set("pattern", StateValue::Json("[0,3]".into()));
+        assert_eq!(*plugin.params.script_state.lock().unwrap(), script_state);
+
+        // パラメータの値を設定する ParamMut は nih-plug の crate 内でしか使えず、wrapper は
+        // 同じ id のパラメータの ParamPtr に正規化した値を set_normalized_value で設定する。
+        // そのため、ファイルから読み込んだ値が新しいプラグインのどのパラメータに、どの正規化値で
+        // 設定されるかを確かめる (param1 は 0..1 の線形なので 0.75 のまま)
+        assert_eq!(state.params.len(), 1);
+        let state::ParamValue::F32(value) = state.params["param1"] else {
+            panic!("param1: {:?}", state.params["param1"]);
+        };
+        assert_eq!(value, 0.75);
+        let param_map = plugin.params().param_map();
+        let (_, param, _) = param_map.iter().find(|(id, _, _)| id == "param1").unwrap();
+        assert!(
+            matches!(param, ParamPtr::FloatParam(p) if std::ptr::eq(*p, &plugin.params.param1))
+        );
+        // SAFETY: param_map のポインタは plugin が生きている間だけ使う
+        assert_eq!(unsafe { param.preview_normalized(value) }, 0.75);
+
+        // 新しいプラグインのランタイムで、書き出したスクリプトから import やサンプルの読み込みができる
+        let mut runtime = plugin.runtime.lock().unwrap();
+        let code = plugin.params.code.lock().unwrap().clone();
+        runtime.compile(&code, extracted.as_deref()).unwrap();
+        let mut audio = vec![0.0; 2];
+        runtime.audio(&mut audio, 1, 48000.0, &[]).unwrap();
+        assert_eq!(audio, vec![0.5, 3.0]);
+    }
+
+    #[test]
+    fn separate_extract_dirs() {
+        // 名前が同じでも、場所か内容が異なるプリセットは別のディレクトリに書き出す
+        let dir = std::env::temp_dir().join("ps88_preset_extract_dir_test");
+        let preset = |code: &str| Preset {
+            name: "patch".into(),
+            script: Some("main.js".into()),
+            params: BTreeMap::new(),
+            fields: BTreeMap::new(),
+            files: BTreeMap::from([("main.js".into(), code.as_bytes().to_vec())]),
+        };
+        let a = dir.join("a/patch.ps88");
+        let b = dir.join("b/patch.ps88");
+        assert_eq!(extract_dir(&a, &preset("1")), extract_dir(&a, &preset("1")));
+        assert_ne!(extract_dir(&a, &preset("1")), extract_dir(&a, &preset("2")));
+        assert_ne!(extract_dir(&a, &preset("1")), extract_dir(&b, &preset("1")));
+    }
+
+    #[test]
+    fn invalid_path() {
+        // 書き出し先のディレクトリの外を指すパスは拒否する
+        for name in ["../evil.js", "/etc/passwd", "a/../../b", ""] {
+            assert!(safe_path(name).is_err(), "{}", name);
+        }
+        assert_eq!(
+            safe_path("lib/gain.js").unwrap(),
+            PathBuf::from("lib").join("gain.js")
+        );
+    }
+}
@@ -0,0 +1,228 @@
+pub mod compare;
+pub mod golden;
+pub mod smf;
+pub mod wav;
+
+use crate::runtime::runtime::{self, ScriptRuntime};
+use smf::TimedEvent;
+
+/// オフラインでのレンダリングの設定
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderOptions {
+    pub sample_rate: u32,
+
+    // 1 回の audio の呼び出しで処理するサンプル数 (1 チャンネルあたり)
+    pub block_size: usize,
+    pub channels: usize,
+
+    // 書き出すサンプル数 (1 チャンネルあたり)
+    pub frames: usize,
+}
+
+/// 入力の信号と MIDI イベントをスクリプトに入力し、出力をチャンネルごとの信号として返す。
+/// ホストやオーディオデバイスを使わずに、処理できる限りの速さで実行する。
+///
+/// input は options.channels と同じ数のチャンネルごとの信号で、
+/// 入力が無いチャンネルや input の長さを超えた部分は無音として扱う。
+///
+/// 同じスクリプトとイベント、設定からは常に同じ結果が得られるように、
+/// ps88.loadSample で読み込み中のファイルは各ブロックの処理前に読み込み終わるまで待つ。
+/// Math.random も JsRuntimeBuilder::random_seed で固定しておく必要がある。
+pub fn render(
+    runtime: &mut dyn ScriptRuntime,
+    input: &[Vec<f32>],
+    events: &[TimedEvent],
+    options: &RenderOptions,
+) -> runtime::Result<Vec<Vec<f32>>> {
+    let RenderOptions {
+        sample_rate,
+        block_size,
+        channels,
+        frames,
+    } = *options;
+    if block_size == 0 || channels == 0 {
+        return Err("block size and channels must be greater than 0".into());
+    }
+
+    let mut output = vec![Vec::with_capacity(frames); channels];
+    let mut events = events
+        .iter()
+        .map(|e| {
+            (
+                (e.time * sample_rate as f64).round().max(0.0) as usize,
+                e.event,
+            )
+        })
+        .peekable();
+    let mut audio = vec![0.0; block_size * channels];
+    let mut midi = Vec::new();
+    let mut position = 0;
+    while position < frames {
+        let len = block_size.min(frames - position);
+        let audio = &mut audio[..len * channels];
+        audio.fill(0.0);
+
+        // [[L, L, L, L], [R, R, R, R]] -> [L, L, L, L, R, R, R, R]
+        for (c, channel) in input.iter().take(channels).enumerate() {
+            let source = channel.get(position..).unwrap_or_default();
+            let count = source.len().min(len);
+            audio[c * len..c * len + count].copy_from_slice(&source[..count]);
+        }
+
+        // このブロックの範囲内のイベントを、ブロックの先頭からの時刻に変換して渡す
+        midi.clear();
+        while let Some(&(frame, mut event)) = events.peek() {
+            if frame >= position + len {
+                break;
+            }
+            event.timing = (frame.saturating_sub(position)) as u32;
+            midi.extend_from_slice(&event.encode());
+            events.next();
+        }
+
+        runtime.wait_samples();
+        runtime.audio(audio, channels, sample_rate as f32, &midi)?;
+
+        // [L, L, L, L, R, R, R, R] -> [[L, L, L, L], [R, R, R, R]]
+        for (c, channel) in output.iter_mut().enumerate() {
+            channel.extend_from_slice(&audio[c * len..(c + 1) * len]);
+        }
+        position += len;
+    }
+    Ok(output)
+}
+
+/// 入力の信号のチャンネル数を count に変換する。
+/// モノラルに変換する場合は全てのチャンネルを平均し、
+/// それ以外の場合は入力のチャンネルを順に繰り返して割り当てる (モノラルからステレオへの変換では両方に同じ信号が入る)。
+pub fn map_channels(input: &[Vec<f32>], count: usize) -> Vec<Vec<f32>> {
+    if input.is_empty() || input.len() == count {
+        return input.to_vec();
+    }
+    if count == 1 {
+        let len = input.iter().map(|c| c.len()).max().unwrap_or(0);
+        let mut mixed = vec![0.0; len];
+        for channel in input {
+            for (m, v) in mixed.iter_mut().zip(channel) {
+                *m += v / input.len() as f32;
+            }
+        }
+        return vec![mixed];
+    }
+    (0..count).map(|c| input[c % input.len()].clone()).collect()
+}
+
+/// MIDI イベントの最後の時刻に tail 秒を足した長さ (1 チャンネルあたりのサンプル数) を返す
+pub fn length(events: &[TimedEvent], sample_rate: u32, tail: f64) -> usize {
+    let end = events.iter().map(|e| e.time).fold(0.0, f64::max) + tail.max(0.0);
+    (end * sample_rate as f64).ceil() as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::js::JsRuntimeBuilder;
+    use crate::runtime::midi::{self, MidiEvent};
+
+    // Note On を受け取った時刻から、そのノート番号を出力し続けるスクリプト
+    const SCRIPT: &str = r#"
+        let note = 0;
+        const audio = (ctx) => {
+            const length = ctx.audio.length / ctx.ch;
+            const events = ps88.parseMidi(ctx.midi);
+            for (let i = 0; i < length; i++) {
+                for (const e of events) {
+                    if (e.time === i && e.type === 0x9) note = e.data1;
+                }
+                for (let c = 0; c < ctx.ch; c++) {
+                    ctx.audio[c * length + i] = note + c * 1000 + Math.random() * 0.5;
+                }
+            }
+        };
+        const gui = () => {};
+    "#;
+
+    fn render_script(block_size: usize, seed: u32) -> Vec<Vec<f32>> {
+        let mut runtime = JsRuntimeBuilder::new().random_seed(seed).build();
+        runtime.compile(SCRIPT, None).unwrap();
+        let events = [
+            TimedEvent {
+                time: 0.5,
+                event: MidiEvent::new(0, midi::NOTE_ON, 0, 60, 100),
+            },
+            TimedEvent {
+                time: 1.0,
+                event: MidiEvent::new(0, midi::NOTE_ON, 0, 72, 100),
+            },
+        ];
+        let options = RenderOptions {
+            sample_rate: 10,
+            block_size,
+            channels: 2,
+            frames: length(&events, 10, 0.5),
+        };
+        render(&mut runtime, &[], &events, &options).unwrap()
+    }
+
+    #[test]
+    fn render_events() {
+        let output = render_script(4, 1);
+        assert_eq!(output.len(), 2);
+        assert_eq!(output[0].len(), 15);
+
+        // イベントはブロックをまたいでも正しい時刻に届く
+        let notes: Vec<f32> = output[0].iter().map(|v| v.floor()).collect();
+        let mut expected = vec![0.0; 5];
+        expected.extend([60.0; 5]);
+        expected.extend([72.0; 5]);
+        assert_eq!(notes, expected);
+        assert!(output[1].iter().all(|v| *v >= 1000.0));
+
+        // 同じ設定なら出力は常に同じ
+        assert_eq!(output, render_script(4, 1));
+        assert_ne!(output, render_script(4, 2));
+    }
+
+    #[test]
+    fn render_input() {
+        // 入力を 2 倍にするエフェクト
+        let mut runtime = JsRuntimeBuilder::new().build();
+        runtime
+            .compile(
+                r#"
+                const audio = (ctx) => {
+                    for (let i = 0; i < ctx.audio.length; i++) ctx.audio[i] *= 2;
+                };
+                const gui = () => {};
+            "#,
+                None,
+            )
+            .unwrap();
+        let input = vec![vec![1.0, 2.0, 3.0, 4.0, 5.0], vec![-1.0, -2.0, -3.0]];
+        let options = RenderOptions {
+            sample_rate: 48000,
+            block_size: 2,
+            channels: 2,
+            frames: 6,
+        };
+        let output = render(&mut runtime, &input, &[], &options).unwrap();
+        assert_eq!(
+            output,
+            vec![
+                vec![2.0, 4.0, 6.0, 8.0, 10.0, 0.0],
+                vec![-2.0, -4.0, -6.0, 0.0, 0.0, 0.0],
+            ]
+        );
+    }
+
+    #[test]
+    fn channel_mapping() {
+        let mono = vec![vec![1.0, 2.0]];
+        let stereo = vec![vec![1.0, 2.0], vec![3.0, 0.0]];
+        assert_eq!(map_channels(&mono, 2), vec![vec![1.0, 2.0], vec![1.0, 2.0]]);
+        assert_eq!(map_channels(&stereo, 1), vec![vec![2.0, 1.0]]);
+        assert_eq!(map_channels(&stereo, 2), stereo);
+        assert_eq!(map_channels(&stereo, 3).len(), 3);
+        assert_eq!(map_channels(&stereo, 3)[2], stereo[0]);
+    }
+}
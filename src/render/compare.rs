@@ -0,0 +1,241 @@
+use crate::runtime::dsp;
+use serde::Deserialize;
+use thiserror::Error;
+
+// スペクトルの比較に使うフレームの長さとホップサイズ
+const FRAME_SIZE: usize = 2048;
+const HOP_SIZE: usize = FRAME_SIZE / 2;
+
+/// 出力と参照用の信号の差の許容値
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tolerance {
+    // サンプルごとの差の絶対値の最大値
+    pub peak: f32,
+
+    // 差の二乗平均平方根
+    pub rms: f32,
+
+    // 振幅スペクトルの差のエネルギーと参照用の信号のスペクトルのエネルギーの比 (dB)
+    pub spectral: f32,
+}
+
+impl Default for Tolerance {
+    fn default() -> Self {
+        Tolerance {
+            peak: 1e-4,
+            rms: 1e-5,
+            spectral: -60.0,
+        }
+    }
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum CompareError {
+    #[error("expected {expected} channels but got {actual}")]
+    Channels { actual: usize, expected: usize },
+    #[error("expected {expected} samples per channel but got {actual}")]
+    Length { actual: usize, expected: usize },
+}
+
+/// 出力と参照用の信号の差
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Difference {
+    pub peak: f32,
+
+    // 差が最大になったチャンネルとサンプルの位置
+    pub peak_channel: usize,
+    pub peak_frame: usize,
+
+    pub rms: f32,
+
+    // 差が無い場合は負の無限大
+    pub spectral: f32,
+}
+
+/// チャンネルごとの信号を比較する。チャンネル数と長さが異なる場合はエラーになる。
+pub fn compare(actual: &[Vec<f32>], expected: &[Vec<f32>]) -> Result<Difference, CompareError> {
+    if actual.len() != expected.len() {
+        return Err(CompareError::Channels {
+            actual: actual.len(),
+            expected: expected.len(),
+        });
+    }
+    for (a, e) in actual.iter().zip(expected) {
+        if a.len() != e.len() {
+            return Err(CompareError::Length {
+                actual: a.len(),
+                expected: e.len(),
+            });
+        }
+    }
+
+    let mut difference = Difference {
+        peak: 0.0,
+        peak_channel: 0,
+        peak_frame: 0,
+        rms: 0.0,
+        spectral: f32::NEG_INFINITY,
+    };
+    let mut square_sum = 0.0f64;
+    let mut count = 0usize;
+    for (c, (a, e)) in actual.iter().zip(expected).enumerate() {
+        for (i, (a, e)) in a.iter().zip(e).enumerate() {
+            let diff = (a - e).abs();
+            if diff > difference.peak || diff.is_nan() {
+                difference.peak = diff;
+                difference.peak_channel = c;
+                difference.peak_frame = i;
+            }
+            square_sum += (diff as f64).powi(2);
+        }
+        count += a.len();
+    }
+    if count > 0 {
+        difference.rms = (square_sum / count as f64).sqrt() as f32;
+    }
+
+    let (error, energy) = actual
+        .iter()
+        .zip(expected)
+        .map(|(a, e)| spectral_error(a, e))
+        .fold((0.0, 0.0), |(x, y), (a, b)| (x + a, y + b));
+    if error > 0.0 || error.is_nan() {
+        difference.spectral = (10.0 * (error / energy.max(1e-20)).log10()) as f32;
+    }
+    Ok(difference)
+}
+
+// 窓をかけたフレームごとの振幅スペクトルの差のエネルギーと、参照用の信号のスペクトルのエネルギーを返す
+fn spectral_error(actual: &[f32], expected: &[f32]) -> (f64, f64) {
+    let window: Vec<f32> = (0..FRAME_SIZE)
+        .map(|i| 0.5 - 0.5 * (std::f32::consts::TAU * i as f32 / FRAME_SIZE as f32).cos())
+        .collect();
+    let magnitude = |signal: &[f32], start: usize| {
+        let mut re = vec![0.0; FRAME_SIZE];
+        let mut im = vec![0.0; FRAME_SIZE];
+        for (i, x) in signal.iter().skip(start).take(FRAME_SIZE).enumerate() {
+            re[i] = x * window[i];
+        }
+        dsp::fft(&mut re, &mut im, false);
+        (0..=FRAME_SIZE / 2)
+            .map(|k| (re[k] as f64).hypot(im[k] as f64))
+            .collect::<Vec<f64>>()
+    };
+    let (mut error, mut energy) = (0.0, 0.0);
+    let mut start = 0;
+    loop {
+        let a = magnitude(actual, start);
+        let e = magnitude(expected, start);
+        for (a, e) in a.iter().zip(&e) {
+            error += (a - e).powi(2);
+            energy += e.powi(2);
+        }
+        start += HOP_SIZE;
+        if start + HOP_SIZE >= expected.len() {
+            break;
+        }
+    }
+    (error, energy)
+}
+
+impl Difference {
+    /// 許容値を超えた項目の説明を返す。空の場合は許容範囲内。
+    pub fn failures(&self, tolerance: &Tolerance, sample_rate: u32) -> Vec<String> {
+        let mut failures = Vec::new();
+        if !(self.peak <= tolerance.peak) {
+            failures.push(format!(
+                "peak difference {:.3e} exceeds {:.3e} (channel {} at {:.6}s, sample {})",
+                self.peak,
+                tolerance.peak,
+                self.peak_channel,
+                self.peak_frame as f64 / sample_rate.max(1) as f64,
+                self.peak_frame
+            ));
+        }
+        if !(self.rms <= tolerance.rms) {
+            failures.push(format!(
+                "rms difference {:.3e} exceeds {:.3e}",
+                self.rms, tolerance.rms
+            ));
+        }
+        if !(self.spectral <= tolerance.spectral) {
+            failures.push(format!(
+                "spectral difference {:.1} dB exceeds {:.1} dB",
+                self.spectral, tolerance.spectral
+            ));
+        }
+        failures
+    }
+}
+
+impl std::fmt::Display for Difference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "peak {:.3e}, rms {:.3e}, spectral {:.1} dB",
+            self.peak, self.rms, self.spectral
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(len: usize, freq: f32, gain: f32) -> Vec<f32> {
+        (0..len)
+            .map(|i| gain * (std::f32::consts::TAU * freq * i as f32 / 48000.0).sin())
+            .collect()
+    }
+
+    #[test]
+    fn identical() {
+        let signal = vec![sine(4800, 440.0, 0.5), sine(4800, 880.0, 0.5)];
+        let difference = compare(&signal, &signal).unwrap();
+        assert_eq!(difference.peak, 0.0);
+        assert_eq!(difference.rms, 0.0);
+        assert_eq!(difference.spectral, f32::NEG_INFINITY);
+        assert!(difference.failures(&Tolerance::default(), 48000).is_empty());
+    }
+
+    #[test]
+    fn differences() {
+        let expected = vec![sine(4800, 440.0, 0.5)];
+
+        // わずかなノイズは許容範囲内
+        let mut actual = expected.clone();
+        actual[0][100] += 1e-5;
+        let difference = compare(&actual, &expected).unwrap();
+        assert_eq!(difference.peak_frame, 100);
+        assert!(difference.failures(&Tolerance::default(), 48000).is_empty());
+
+        // 周波数が異なる場合は全ての項目が許容値を超える
+        let actual = vec![sine(4800, 445.0, 0.5)];
+        let difference = compare(&actual, &expected).unwrap();
+        let failures = difference.failures(&Tolerance::default(), 48000);
+        assert_eq!(failures.len(), 3, "{:?}", failures);
+        assert!(difference.spectral > -60.0);
+
+        // 音量の違いはスペクトルの差として現れる (-6 dB の差 = 振幅の差が参照の半分)
+        let actual = vec![sine(4800, 440.0, 0.25)];
+        let difference = compare(&actual, &expected).unwrap();
+        assert!((difference.spectral - -6.02).abs() < 0.1, "{}", difference);
+
+        // チャンネル数や長さが異なる場合は比較できない
+        assert_eq!(
+            compare(&[vec![0.0; 4800], vec![0.0; 4800]], &expected),
+            Err(CompareError::Channels {
+                actual: 2,
+                expected: 1
+            })
+        );
+        assert_eq!(
+            compare(&[vec![0.0; 10]], &expected),
+            Err(CompareError::Length {
+                actual: 10,
+                expected: 4800
+            })
+        );
+    }
+}
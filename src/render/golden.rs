@@ -0,0 +1,291 @@
+use crate::render::compare::{self, Difference, Tolerance};
+use crate::render::{self, smf};
+use crate::runtime::runtime::{self, ScriptRuntime};
+use crate::runtime::sample::{self, Sample};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+// テストの定義ファイルの拡張子
+pub const EXTENSION: &str = ".test.json";
+
+/// スクリプトの出力を参照用の WAV ファイルと比較するテスト。
+/// `*.test.json` に定義し、ファイルのパスは定義ファイルのディレクトリからの相対パスで書く。
+///
+/// e.g.
+///   {
+///     "script": "../patches/pad.js",
+///     "midi": "chords.mid",
+///     "reference": "pad.wav",
+///     "duration": 4,
+///     "tolerance": { "peak": 1e-3, "spectral": -50 }
+///   }
+#[derive(Debug, Clone, PartialEq)]
+pub struct GoldenTest {
+    // 定義ファイルのパス
+    pub path: PathBuf,
+
+    pub script: PathBuf,
+    pub midi: Option<PathBuf>,
+
+    // エフェクトへの入力
+    pub input: Option<PathBuf>,
+    pub reference: PathBuf,
+
+    // None の場合は入力のファイルのサンプリングレート (入力が無ければ 48000)
+    pub sample_rate: Option<u32>,
+    pub block_size: usize,
+    pub channels: usize,
+
+    // None の場合は入力の長さ、または最後の MIDI イベントの時刻に tail を足した長さ
+    pub duration: Option<f64>,
+    pub tail: f64,
+    pub seed: u32,
+    pub tolerance: Tolerance,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct Definition {
+    script: PathBuf,
+    midi: Option<PathBuf>,
+    input: Option<PathBuf>,
+    reference: PathBuf,
+    sample_rate: Option<u32>,
+    #[serde(default = "default_block_size")]
+    block_size: usize,
+    #[serde(default = "default_channels")]
+    channels: usize,
+    duration: Option<f64>,
+    #[serde(default)]
+    tail: f64,
+    #[serde(default)]
+    seed: u32,
+    #[serde(default)]
+    tolerance: Tolerance,
+}
+
+fn default_block_size() -> usize {
+    512
+}
+
+fn default_channels() -> usize {
+    2
+}
+
+#[derive(Debug, Error)]
+pub enum GoldenError {
+    #[error("failed to read {0}: {1}")]
+    Read(PathBuf, String),
+    #[error("invalid test definition: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// 参照用のファイルとの比較結果
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Passed(Difference),
+    Failed {
+        // チャンネル数や長さが異なる場合は None
+        difference: Option<Difference>,
+        failures: Vec<String>,
+    },
+    MissingReference,
+}
+
+impl GoldenTest {
+    pub fn load(path: &Path) -> Result<Self, GoldenError> {
+        let json = std::fs::read_to_string(path)
+            .map_err(|err| GoldenError::Read(path.to_path_buf(), err.to_string()))?;
+        let definition: Definition = serde_json::from_str(&json)?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+        Ok(GoldenTest {
+            path: path.to_path_buf(),
+            script: dir.join(definition.script),
+            midi: definition.midi.map(|midi| dir.join(midi)),
+            input: definition.input.map(|input| dir.join(input)),
+            reference: dir.join(definition.reference),
+            sample_rate: definition.sample_rate,
+            block_size: definition.block_size,
+            channels: definition.channels,
+            duration: definition.duration,
+            tail: definition.tail,
+            seed: definition.seed,
+            tolerance: definition.tolerance,
+        })
+    }
+
+    /// コンパイル済みのスクリプトに MIDI と入力を与えて出力を得る
+    pub fn render(&self, runtime: &mut dyn ScriptRuntime) -> runtime::Result<Sample> {
+        let events = match &self.midi {
+            Some(path) => {
+                let bytes = std::fs::read(path)
+                    .map_err(|err| GoldenError::Read(path.clone(), err.to_string()))?;
+                smf::parse(&bytes)
+                    .map_err(|err| GoldenError::Read(path.clone(), err.to_string()))?
+            }
+            None => Vec::new(),
+        };
+        let input = match &self.input {
+            Some(path) => Some(
+                sample::decode(path)
+                    .map_err(|err| GoldenError::Read(path.clone(), err.to_string()))?,
+            ),
+            None => None,
+        };
+        let sample_rate = self
+            .sample_rate
+            .or(input.as_ref().map(|input| input.sample_rate))
+            .unwrap_or(48000);
+        let audio = input
+            .map(|input| render::map_channels(&input.channels, self.channels))
+            .unwrap_or_default();
+        let frames = match self.duration {
+            Some(duration) => (duration.max(0.0) * sample_rate as f64).ceil() as usize,
+            None => {
+                let input_len = audio.iter().map(|c| c.len()).max().unwrap_or(0);
+                let tail = (self.tail.max(0.0) * sample_rate as f64).ceil() as usize;
+                (input_len + tail).max(render::length(&events, sample_rate, self.tail))
+            }
+        };
+        let options = render::RenderOptions {
+            sample_rate,
+            block_size: self.block_size,
+            channels: self.channels,
+            frames,
+        };
+        let channels = render::render(runtime, &audio, &events, &options)?;
+        Ok(Sample {
+            sample_rate,
+            channels,
+        })
+    }
+
+    /// 出力を参照用のファイルと比較する
+    pub fn check(&self, output: &Sample) -> Outcome {
+        if !self.reference.exists() {
+            return Outcome::MissingReference;
+        }
+        let reference = match sample::decode(&self.reference) {
+            Ok(reference) => reference,
+            Err(err) => {
+                return Outcome::Failed {
+                    difference: None,
+                    failures: vec![format!(
+                        "failed to read {}: {}",
+                        self.reference.display(),
+                        err
+                    )],
+                }
+            }
+        };
+        if reference.sample_rate != output.sample_rate {
+            return Outcome::Failed {
+                difference: None,
+                failures: vec![format!(
+                    "expected sample rate {} but got {}",
+                    reference.sample_rate, output.sample_rate
+                )],
+            };
+        }
+        match compare::compare(&output.channels, &reference.channels) {
+            Ok(difference) => {
+                let failures = difference.failures(&self.tolerance, output.sample_rate);
+                if failures.is_empty() {
+                    Outcome::Passed(difference)
+                } else {
+                    Outcome::Failed {
+                        difference: Some(difference),
+                        failures,
+                    }
+                }
+            }
+            Err(err) => Outcome::Failed {
+                difference: None,
+                failures: vec![err.to_string()],
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::wav;
+    use crate::runtime::js::JsRuntimeBuilder;
+
+    #[test]
+    fn golden() {
+        let dir = std::env::temp_dir().join("ps88_golden_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("gain.js"),
+            r#"
+                const audio = (ctx) => {
+                    for (let i = 0; i < ctx.audio.length; i++) ctx.audio[i] *= 0.5;
+                };
+                const gui = () => {};
+            "#,
+        )
+        .unwrap();
+        wav::write(
+            &dir.join("input.wav"),
+            44100,
+            &[vec![0.5, 1.0, -1.0]],
+            wav::WavFormat::Float32,
+        )
+        .unwrap();
+        let path = dir.join(format!("gain{}", EXTENSION));
+        std::fs::write(
+            &path,
+            r#"{ "script": "gain.js", "input": "input.wav", "reference": "gain.wav", "tail": 0.0001 }"#,
+        )
+        .unwrap();
+
+        let test = GoldenTest::load(&path).unwrap();
+        assert_eq!(test.script, dir.join("gain.js"));
+        assert_eq!(test.tolerance, Tolerance::default());
+        let render = |test: &GoldenTest| {
+            let mut runtime = JsRuntimeBuilder::new().build();
+            let code = std::fs::read_to_string(&test.script).unwrap();
+            runtime.compile(&code, Some(&test.script)).unwrap();
+            test.render(&mut runtime).unwrap()
+        };
+
+        // モノラルの入力は両方のチャンネルに入り、tail の分だけ無音が続く
+        let output = render(&test);
+        assert_eq!(output.sample_rate, 44100);
+        assert_eq!(
+            output.channels,
+            vec![vec![0.25, 0.5, -0.5, 0.0, 0.0, 0.0, 0.0, 0.0]; 2]
+        );
+        assert_eq!(test.check(&output), Outcome::MissingReference);
+
+        // 参照用のファイルと一致する
+        wav::write(
+            &test.reference,
+            output.sample_rate,
+            &output.channels,
+            wav::WavFormat::Float32,
+        )
+        .unwrap();
+        assert!(matches!(test.check(&output), Outcome::Passed(_)));
+
+        // スクリプトの変更は失敗として検出される
+        std::fs::write(
+            &test.script,
+            "const audio = (ctx) => {}; const gui = () => {};",
+        )
+        .unwrap();
+        let Outcome::Failed {
+            difference: Some(difference),
+            failures,
+        } = test.check(&render(&test))
+        else {
+            panic!("expected a failure");
+        };
+        assert_eq!(difference.peak, 0.5);
+        assert!(failures[0].contains("peak difference"));
+    }
+}
@@ -0,0 +1,311 @@
+use crate::runtime::midi::{self, MidiEvent};
+use thiserror::Error;
+
+// テンポが指定されていない場合のテンポ (4 分音符あたりのマイクロ秒, 120 BPM)
+const DEFAULT_TEMPO: u32 = 500_000;
+
+/// Standard MIDI File から読み込んだイベント
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimedEvent {
+    // ファイルの先頭からの時刻 (秒)
+    pub time: f64,
+
+    // timing は使わない (0 になる)
+    pub event: MidiEvent,
+}
+
+#[derive(Debug, Error)]
+pub enum SmfError {
+    #[error("not a standard MIDI file")]
+    InvalidHeader,
+    #[error("unexpected end of file")]
+    UnexpectedEof,
+    #[error("invalid event at byte {0}")]
+    InvalidEvent(usize),
+}
+
+/// Standard MIDI File (format 0 / 1) を読み込み、全てのトラックのイベントを時刻順に並べて返す。
+/// プラグインがホストから受け取るイベントと同じく、Note On / Note Off / Control Change のみを返す。
+pub fn parse(bytes: &[u8]) -> Result<Vec<TimedEvent>, SmfError> {
+    let mut reader = Reader { bytes, pos: 0 };
+    if reader.take(4)? != b"MThd" {
+        return Err(SmfError::InvalidHeader);
+    }
+    let header_len = reader.u32()? as usize;
+    if header_len < 6 {
+        return Err(SmfError::InvalidHeader);
+    }
+    let _format = reader.u16()?;
+    let track_count = reader.u16()?;
+    let division = reader.u16()?;
+    reader.take(header_len - 6)?;
+
+    // (tick, イベント) と (tick, テンポ) をトラックごとに集める
+    let mut events: Vec<(u64, MidiEvent)> = Vec::new();
+    let mut tempos: Vec<(u64, u32)> = Vec::new();
+    let mut tracks = 0;
+    while tracks < track_count && reader.pos < bytes.len() {
+        let id = reader.take(4)?;
+        let len = reader.u32()? as usize;
+        let start = reader.pos;
+        let chunk = reader.take(len)?;
+        if id == b"MTrk" {
+            parse_track(chunk, start, &mut events, &mut tempos)?;
+            tracks += 1;
+        }
+    }
+    // 同じ tick のイベントはトラック順、トラック内の順序を保つ
+    events.sort_by_key(|(tick, _)| *tick);
+    tempos.sort_by_key(|(tick, _)| *tick);
+
+    let to_seconds = TickConverter::new(division, tempos);
+    Ok(events
+        .into_iter()
+        .map(|(tick, event)| TimedEvent {
+            time: to_seconds.seconds(tick),
+            event,
+        })
+        .collect())
+}
+
+fn parse_track(
+    chunk: &[u8],
+    offset: usize,
+    events: &mut Vec<(u64, MidiEvent)>,
+    tempos: &mut Vec<(u64, u32)>,
+) -> Result<(), SmfError> {
+    let mut reader = Reader {
+        bytes: chunk,
+        pos: 0,
+    };
+    let mut tick = 0u64;
+    let mut running_status: Option<u8> = None;
+    while reader.pos < chunk.len() {
+        tick += reader.vlq()? as u64;
+        let position = offset + reader.pos;
+        let status = match reader.u8()? {
+            status if status >= 0x80 => status,
+            // ランニングステータス (ステータスバイトが省略されている)
+            _ => {
+                reader.pos -= 1;
+                running_status.ok_or(SmfError::InvalidEvent(position))?
+            }
+        };
+        match status {
+            0xff => {
+                let kind = reader.u8()?;
+                let len = reader.vlq()? as usize;
+                let data = reader.take(len)?;
+                match kind {
+                    // テンポ
+                    0x51 if len == 3 => {
+                        let tempo = u32::from_be_bytes([0, data[0], data[1], data[2]]);
+                        tempos.push((tick, tempo));
+                    }
+                    // トラックの終端
+                    0x2f => break,
+                    _ => {}
+                }
+                running_status = None;
+            }
+            0xf0 | 0xf7 => {
+                let len = reader.vlq()? as usize;
+                reader.take(len)?;
+                running_status = None;
+            }
+            0xf1..=0xfe => return Err(SmfError::InvalidEvent(position)),
+            _ => {
+                running_status = Some(status);
+                let kind = status >> 4;
+                let data1 = reader.u8()?;
+                // Program Change と Channel Pressure はデータバイトが 1 つ
+                let data2 = if kind == 0xc || kind == 0xd {
+                    0
+                } else {
+                    reader.u8()?
+                };
+                if data1 >= 0x80 || data2 >= 0x80 {
+                    return Err(SmfError::InvalidEvent(position));
+                }
+                if matches!(kind, midi::NOTE_OFF | midi::NOTE_ON | midi::CONTROL_CHANGE) {
+                    events.push((tick, MidiEvent::new(0, kind, status & 0x0f, data1, data2)));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// tick を秒に変換する
+struct TickConverter {
+    // 4 分音符あたりの tick 数 (SMPTE の場合は 1 秒あたりの tick 数)
+    ticks_per_quarter: Option<f64>,
+    ticks_per_second: f64,
+
+    // 各テンポが始まる (tick, 秒, テンポ)
+    segments: Vec<(u64, f64, u32)>,
+}
+
+impl TickConverter {
+    fn new(division: u16, tempos: Vec<(u64, u32)>) -> Self {
+        if division & 0x8000 != 0 {
+            // SMPTE: 上位バイトは負のフレームレート、下位バイトは 1 フレームあたりの tick 数
+            let fps = match -((division >> 8) as i8) {
+                29 => 29.97,
+                fps => fps as f64,
+            };
+            let ticks_per_frame = (division & 0xff) as f64;
+            return TickConverter {
+                ticks_per_quarter: None,
+                ticks_per_second: fps * ticks_per_frame,
+                segments: Vec::new(),
+            };
+        }
+        let ticks_per_quarter = division.max(1) as f64;
+        let mut segments = vec![(0, 0.0, DEFAULT_TEMPO)];
+        for (tick, tempo) in tempos {
+            let &(start, seconds, current) = segments.last().unwrap();
+            let seconds =
+                seconds + (tick - start) as f64 * current as f64 / 1e6 / ticks_per_quarter;
+            if tick == start {
+                segments.pop();
+            }
+            segments.push((tick, seconds, tempo));
+        }
+        TickConverter {
+            ticks_per_quarter: Some(ticks_per_quarter),
+            ticks_per_second: 0.0,
+            segments,
+        }
+    }
+
+    fn seconds(&self, tick: u64) -> f64 {
+        let Some(ticks_per_quarter) = self.ticks_per_quarter else {
+            return tick as f64 / self.ticks_per_second;
+        };
+        let index = self
+            .segments
+            .partition_point(|(start, _, _)| *start <= tick);
+        let (start, seconds, tempo) = self.segments[index.saturating_sub(1)];
+        seconds + (tick - start) as f64 * tempo as f64 / 1e6 / ticks_per_quarter
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], SmfError> {
+        let end = self.pos.checked_add(len).ok_or(SmfError::UnexpectedEof)?;
+        let bytes = self
+            .bytes
+            .get(self.pos..end)
+            .ok_or(SmfError::UnexpectedEof)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, SmfError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, SmfError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, SmfError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    // 可変長数値 (7 bit ずつ、最大 4 バイト)
+    fn vlq(&mut self) -> Result<u32, SmfError> {
+        let mut value = 0u32;
+        for _ in 0..4 {
+            let byte = self.u8()?;
+            value = (value << 7) | (byte & 0x7f) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(SmfError::InvalidEvent(self.pos))
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// 4 分音符 = 480 tick の format 1 の MIDI ファイルを作成する
+    pub fn smf(tracks: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = b"MThd".to_vec();
+        bytes.extend_from_slice(&6u32.to_be_bytes());
+        bytes.extend_from_slice(&1u16.to_be_bytes());
+        bytes.extend_from_slice(&(tracks.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&480u16.to_be_bytes());
+        for track in tracks {
+            bytes.extend_from_slice(b"MTrk");
+            bytes.extend_from_slice(&(track.len() as u32 + 4).to_be_bytes());
+            bytes.extend_from_slice(track);
+            bytes.extend_from_slice(&[0x00, 0xff, 0x2f, 0x00]);
+        }
+        bytes
+    }
+
+    #[test]
+    fn parse_tracks() {
+        // テンポトラック: 先頭で 120 BPM、1 拍目の後に 60 BPM
+        let tempo = vec![
+            0x00, 0xff, 0x51, 0x03, 0x07, 0xa1, 0x20, // 500000
+            0x83, 0x60, 0xff, 0x51, 0x03, 0x0f, 0x42, 0x40, // 480 tick 後に 1000000
+        ];
+        // ランニングステータス、SysEx、Program Change を含むトラック
+        let notes = vec![
+            0x00, 0x90, 0x3c, 0x64, // Note On
+            0x00, 0x40, 0x64, // ランニングステータスの Note On
+            0x00, 0xf0, 0x01, 0xf7, // SysEx
+            0x00, 0xc0, 0x05, // Program Change (読み飛ばす)
+            0x87, 0x40, 0x80, 0x3c, 0x00, // 960 tick 後に Note Off
+            0x00, 0xb1, 0x40, 0x7f, // Control Change
+        ];
+        let events = parse(&smf(&[tempo, notes])).unwrap();
+        assert_eq!(
+            events,
+            vec![
+                TimedEvent {
+                    time: 0.0,
+                    event: MidiEvent::new(0, midi::NOTE_ON, 0, 60, 100),
+                },
+                TimedEvent {
+                    time: 0.0,
+                    event: MidiEvent::new(0, midi::NOTE_ON, 0, 64, 100),
+                },
+                // 1 拍目は 0.5 秒、2 拍目は 1 秒
+                TimedEvent {
+                    time: 1.5,
+                    event: MidiEvent::new(0, midi::NOTE_OFF, 0, 60, 0),
+                },
+                TimedEvent {
+                    time: 1.5,
+                    event: MidiEvent::new(0, midi::CONTROL_CHANGE, 1, 64, 127),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_file() {
+        assert!(matches!(parse(b"RIFF"), Err(SmfError::InvalidHeader)));
+        let mut bytes = smf(&[vec![0x00, 0x90, 0x3c, 0x64]]);
+        bytes.truncate(bytes.len() - 6);
+        assert!(matches!(parse(&bytes), Err(SmfError::UnexpectedEof)));
+        // ランニングステータスの前にステータスバイトが無い
+        assert!(matches!(
+            parse(&smf(&[vec![0x00, 0x3c, 0x64]])),
+            Err(SmfError::InvalidEvent(_))
+        ));
+    }
+}
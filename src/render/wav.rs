@@ -0,0 +1,101 @@
+use std::io::Write;
+use std::path::Path;
+
+/// WAV ファイルのサンプルの形式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WavFormat {
+    Pcm16,
+    Float32,
+}
+
+/// チャンネルごとの信号を WAV ファイルとして書き出す
+pub fn write(
+    path: &Path,
+    sample_rate: u32,
+    channels: &[Vec<f32>],
+    format: WavFormat,
+) -> std::io::Result<()> {
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    file.write_all(&encode(sample_rate, channels, format))?;
+    file.flush()
+}
+
+/// チャンネルごとの信号を WAV ファイルのバイト列に変換する
+pub fn encode(sample_rate: u32, channels: &[Vec<f32>], format: WavFormat) -> Vec<u8> {
+    let frames = channels.iter().map(|c| c.len()).max().unwrap_or(0);
+    let count = channels.len() as u16;
+    let (tag, bytes_per_sample): (u16, u16) = match format {
+        WavFormat::Pcm16 => (1, 2),
+        WavFormat::Float32 => (3, 4),
+    };
+    let data_len = (frames * channels.len() * bytes_per_sample as usize) as u32;
+    // PCM 以外の形式では fact チャンクが必要
+    let fact_len = if format == WavFormat::Pcm16 { 0 } else { 12 };
+
+    let mut bytes = Vec::with_capacity(44 + fact_len as usize + data_len as usize);
+    bytes.extend_from_slice(b"RIFF");
+    bytes.extend_from_slice(&(36 + fact_len + data_len).to_le_bytes());
+    bytes.extend_from_slice(b"WAVEfmt ");
+    bytes.extend_from_slice(&16u32.to_le_bytes());
+    bytes.extend_from_slice(&tag.to_le_bytes());
+    bytes.extend_from_slice(&count.to_le_bytes());
+    bytes.extend_from_slice(&sample_rate.to_le_bytes());
+    bytes.extend_from_slice(&(sample_rate * (count * bytes_per_sample) as u32).to_le_bytes());
+    bytes.extend_from_slice(&(count * bytes_per_sample).to_le_bytes());
+    bytes.extend_from_slice(&(bytes_per_sample * 8).to_le_bytes());
+    if fact_len > 0 {
+        bytes.extend_from_slice(b"fact");
+        bytes.extend_from_slice(&4u32.to_le_bytes());
+        bytes.extend_from_slice(&(frames as u32).to_le_bytes());
+    }
+    bytes.extend_from_slice(b"data");
+    bytes.extend_from_slice(&data_len.to_le_bytes());
+    for i in 0..frames {
+        for channel in channels {
+            let value = channel.get(i).copied().unwrap_or(0.0);
+            match format {
+                WavFormat::Pcm16 => {
+                    let value = (value * 32768.0).clamp(-32768.0, 32767.0) as i16;
+                    bytes.extend_from_slice(&value.to_le_bytes());
+                }
+                WavFormat::Float32 => bytes.extend_from_slice(&value.to_le_bytes()),
+            }
+        }
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::sample;
+
+    #[test]
+    fn write_and_decode() {
+        let dir = std::env::temp_dir().join("ps88_wav_write_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let channels = vec![vec![0.0, 0.5, -0.25], vec![1.0 / 3.0, -1.0, 0.125]];
+
+        // float は値が変化しない
+        let path = dir.join("float.wav");
+        write(&path, 48000, &channels, WavFormat::Float32).unwrap();
+        let decoded = sample::decode(&path).unwrap();
+        assert_eq!(decoded.sample_rate, 48000);
+        assert_eq!(decoded.channels, channels);
+
+        // 16 bit は量子化される
+        let path = dir.join("pcm16.wav");
+        write(&path, 44100, &channels, WavFormat::Pcm16).unwrap();
+        let decoded = sample::decode(&path).unwrap();
+        assert_eq!(decoded.sample_rate, 44100);
+        for (decoded, expected) in decoded
+            .channels
+            .iter()
+            .flatten()
+            .zip(channels.iter().flatten())
+        {
+            assert!((decoded - expected).abs() <= 1.0 / 32768.0);
+        }
+    }
+}
@@ -1,3 +1,23 @@
+pub mod code_cache;
+pub mod dsp;
+pub mod gc;
+pub mod gui;
 pub mod js;
+pub mod js_dsp;
+pub mod js_gc;
+pub mod js_inspector;
+pub mod js_module;
+pub mod js_sample;
+pub mod js_state;
 pub mod js_sync;
+pub mod js_test;
+pub mod js_voice;
+pub mod js_worker;
+pub mod midi;
+pub mod profile;
 pub mod runtime;
+pub mod sample;
+pub mod state;
+pub mod stats;
+pub mod stdlib;
+pub mod voice;
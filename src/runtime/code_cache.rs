@@ -0,0 +1,474 @@
+use crate::runtime::js_module;
+use std::collections::{HashMap, VecDeque};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use v8;
+
+// ディスクに保存するキャッシュのファイル数と、メモリ上に持つキャッシュの数の上限。超えたら古いものから消す。
+// (ホットリロードのたびに新しいソースコードのキャッシュが作られるため)
+const MAX_FILES: usize = 256;
+
+// キャッシュのファイルの拡張子
+const EXTENSION: &str = "v8cache";
+
+/// V8 のコードキャッシュ。
+/// ソースコードのハッシュをキーにして、プロセス内の全てのランタイムで共有するメモリと、ディスクに保存する。
+/// 別のバージョンの V8 や異なるフラグで作られたキャッシュは V8 が拒否するので、その場合は作り直す。
+/// ランタイムからは isolate の slot に保存して使う。
+#[derive(Clone, Default)]
+pub struct CodeCache {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: Mutex<Entries>,
+    dir: Option<PathBuf>,
+
+    // キャッシュを使ってコンパイルできた回数と、使えなかった (無かった/拒否された) 回数
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+// メモリ上のキャッシュ。最後に使ってから長く経ったものから、MAX_FILES 個を超えた分を捨てる
+#[derive(Default)]
+struct Entries {
+    map: HashMap<u64, Arc<Vec<u8>>>,
+    // 使った順のキー (最後が最新)
+    order: VecDeque<u64>,
+}
+
+impl Entries {
+    fn get(&mut self, key: u64) -> Option<Arc<Vec<u8>>> {
+        let data = self.map.get(&key)?.clone();
+        self.touch(key);
+        Some(data)
+    }
+
+    fn insert(&mut self, key: u64, data: Arc<Vec<u8>>) {
+        self.map.insert(key, data);
+        self.touch(key);
+        while self.order.len() > MAX_FILES {
+            if let Some(oldest) = self.order.pop_front() {
+                self.map.remove(&oldest);
+            }
+        }
+    }
+
+    fn touch(&mut self, key: u64) {
+        if let Some(index) = self.order.iter().position(|k| *k == key) {
+            self.order.remove(index);
+        }
+        self.order.push_back(key);
+    }
+}
+
+/// コードキャッシュの利用状況
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CodeCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CodeCache {
+    /// メモリだけに保存するキャッシュ
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// dir 以下のファイルにも保存するキャッシュ
+    pub fn with_dir(dir: &Path) -> Self {
+        CodeCache {
+            inner: Arc::new(Inner {
+                dir: Some(dir.to_path_buf()),
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// プロセスで共有するキャッシュ。ユーザーごとのディレクトリ (cache_dir) に保存する。
+    /// 同じスクリプトを使う複数のインスタンスを開く場合、2 つ目以降はメモリ上のキャッシュを使う。
+    pub fn shared() -> Self {
+        static SHARED: OnceLock<CodeCache> = OnceLock::new();
+        SHARED
+            .get_or_init(|| match cache_dir() {
+                Some(dir) => CodeCache::with_dir(&dir),
+                None => CodeCache::new(),
+            })
+            .clone()
+    }
+
+    pub fn stats(&self) -> CodeCacheStats {
+        CodeCacheStats {
+            hits: self.inner.hits.load(Ordering::Relaxed),
+            misses: self.inner.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    fn get(&self, key: u64) -> Option<Arc<Vec<u8>>> {
+        if let Some(data) = self.inner.entries.lock().unwrap().get(key) {
+            return Some(data);
+        }
+        let data = Arc::new(std::fs::read(self.path(key)?).ok()?);
+        self.inner.entries.lock().unwrap().insert(key, data.clone());
+        Some(data)
+    }
+
+    fn put(&self, key: u64, data: Vec<u8>) {
+        if let Some(path) = self.path(key) {
+            // 書き込みに失敗してもキャッシュが使えないだけなので無視する
+            let _ = write_file(&path, &data);
+            if let Some(dir) = &self.inner.dir {
+                prune(dir);
+            }
+        }
+        self.inner
+            .entries
+            .lock()
+            .unwrap()
+            .insert(key, Arc::new(data));
+    }
+
+    fn record(&self, hit: bool) {
+        let counter = if hit {
+            &self.inner.hits
+        } else {
+            &self.inner.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn path(&self, key: u64) -> Option<PathBuf> {
+        let dir = self.inner.dir.as_ref()?;
+        Some(dir.join(format!("{:016x}.{}", key, EXTENSION)))
+    }
+}
+
+/// スクリプトをコンパイルする。isolate にコードキャッシュが設定されていればそれを使い、
+/// キャッシュが無いか拒否された場合はコンパイル結果からキャッシュを作る。
+/// 失敗した場合は例外が投げられた状態で None を返す。
+pub fn compile_script<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    code: &str,
+    name: &str,
+) -> Option<v8::Local<'s, v8::UnboundScript>> {
+    let cache = scope.get_slot::<CodeCache>().cloned();
+    let key = key(code, name, false);
+    let cached = cache.as_ref().and_then(|cache| cache.get(key));
+    let (mut source, options) = source(
+        scope,
+        code,
+        name,
+        false,
+        cached.as_deref().map(Vec::as_slice),
+    )?;
+    let script = v8::script_compiler::compile_unbound_script(
+        scope,
+        &mut source,
+        options,
+        v8::script_compiler::NoCacheReason::NoReason,
+    )?;
+    if let Some(cache) = cache {
+        update(&cache, key, &source, || script.create_code_cache());
+    }
+    Some(script)
+}
+
+/// ES Module としてコンパイルする。コードキャッシュの扱いは compile_script と同じ。
+/// 失敗した場合は例外が投げられた状態で None を返す。
+pub fn compile_module<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    code: &str,
+    name: &str,
+) -> Option<v8::Local<'s, v8::Module>> {
+    let cache = scope.get_slot::<CodeCache>().cloned();
+    let key = key(code, name, true);
+    let cached = cache.as_ref().and_then(|cache| cache.get(key));
+    let (mut source, options) = source(
+        scope,
+        code,
+        name,
+        true,
+        cached.as_deref().map(Vec::as_slice),
+    )?;
+    let module = v8::script_compiler::compile_module2(
+        scope,
+        &mut source,
+        options,
+        v8::script_compiler::NoCacheReason::NoReason,
+    )?;
+    if let Some(cache) = cache {
+        let unbound = module.get_unbound_module_script(scope);
+        update(&cache, key, &source, || unbound.create_code_cache());
+    }
+    Some(module)
+}
+
+/// コードキャッシュをディスクに保存するディレクトリ ($HOME/.ps88/code_cache)。
+/// V8 はキャッシュの内容を検証せずに使うため、他のユーザーが書き込める一時ディレクトリは使わない。
+pub fn cache_dir() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(|home| PathBuf::from(home).join(".ps88").join("code_cache"))
+}
+
+// ソースコード、名前、V8 のバージョンからキャッシュのキーを作る。
+// キーはファイル名として別のプロセスとも共有するため、Rust のバージョンで結果が変わりうる DefaultHasher ではなく FNV-1a を使う
+fn key(code: &str, name: &str, is_module: bool) -> u64 {
+    let mut hash = Fnv1a::new();
+    for part in [
+        v8::V8::get_version(),
+        env!("CARGO_PKG_VERSION"),
+        if is_module { "module" } else { "script" },
+        name,
+        code,
+    ] {
+        hash.write_part(part.as_bytes());
+    }
+    hash.finish()
+}
+
+fn source(
+    scope: &mut v8::HandleScope,
+    code: &str,
+    name: &str,
+    is_module: bool,
+    cached: Option<&[u8]>,
+) -> Option<(
+    v8::script_compiler::Source,
+    v8::script_compiler::CompileOptions,
+)> {
+    let code = v8::String::new(scope, code)?;
+    let origin = js_module::script_origin(scope, name, is_module)?;
+    Some(match cached {
+        Some(data) => (
+            v8::script_compiler::Source::new_with_cached_data(
+                code,
+                Some(&origin),
+                v8::script_compiler::CachedData::new(data),
+            ),
+            v8::script_compiler::CompileOptions::ConsumeCodeCache,
+        ),
+        None => (
+            v8::script_compiler::Source::new(code, Some(&origin)),
+            v8::script_compiler::CompileOptions::NoCompileOptions,
+        ),
+    })
+}
+
+// キャッシュを使えたかを記録し、使えなかった場合は新しく作って保存する
+fn update<F>(cache: &CodeCache, key: u64, source: &v8::script_compiler::Source, create: F)
+where
+    F: FnOnce() -> Option<v8::UniqueRef<v8::script_compiler::CachedData<'static>>>,
+{
+    let hit = source
+        .get_cached_data()
+        .is_some_and(|cached| !cached.rejected());
+    cache.record(hit);
+    if !hit {
+        if let Some(data) = create() {
+            cache.put(key, data.to_vec());
+        }
+    }
+}
+
+// 同時に起動した別のプロセスが書きかけのファイルを読まないように、一時ファイルに書いてから名前を変える。
+// ディレクトリとファイルは所有者だけが読み書きできるようにする
+fn write_file(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        create_dir(dir)?;
+    }
+    // 同じプロセスの別のワーカーが同じキーを同時に書き込むこともあるため、プロセス内の連番も付ける
+    static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+    let temp = path.with_extension(format!(
+        "{}.{}.{}",
+        EXTENSION,
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(&temp)?.write_all(data)?;
+    std::fs::rename(&temp, path)
+}
+
+fn create_dir(dir: &Path) -> std::io::Result<()> {
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(dir)?;
+    // 既にあったディレクトリも権限を絞る
+    #[cfg(unix)]
+    std::fs::set_permissions(dir, std::os::unix::fs::PermissionsExt::from_mode(0o700))?;
+    Ok(())
+}
+
+fn prune(dir: &Path) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    let mut files: Vec<_> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            entry
+                .path()
+                .extension()
+                .is_some_and(|extension| extension == EXTENSION)
+        })
+        .filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+        .collect();
+    if files.len() <= MAX_FILES {
+        return;
+    }
+    files.sort();
+    for (_, path) in &files[..files.len() - MAX_FILES] {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::js::JsRuntimeBuilder;
+    use crate::runtime::runtime::ScriptRuntime;
+
+    const SCRIPT: &str = r#"
+        const gain = (x) => x * 0.5;
+        const audio = (ctx) => {
+            for (let i = 0; i < ctx.audio.length; i++) ctx.audio[i] = gain(1);
+        };
+        const gui = () => {};
+    "#;
+
+    const MODULE: &str = r#"
+        export const audio = (ctx) => ctx.audio.fill(0.25);
+        export const gui = () => {};
+    "#;
+
+    #[test]
+    fn reuse_between_runtimes() {
+        let cache = CodeCache::new();
+        for (code, expected) in [(SCRIPT, 0.5), (MODULE, 0.25)] {
+            let before = cache.stats();
+            for _ in 0..2 {
+                let mut runtime = JsRuntimeBuilder::new().code_cache(cache.clone()).build();
+                runtime.compile(code, None).unwrap();
+                let mut audio = vec![0.0; 4];
+                runtime.audio(&mut audio, 1, 48000.0, &[]).unwrap();
+                assert_eq!(audio, vec![expected; 4]);
+            }
+            // 2 つ目のランタイムは 1 つ目が作ったキャッシュを使う
+            let after = cache.stats();
+            assert!(after.hits > before.hits, "{:?}", after);
+        }
+    }
+
+    #[test]
+    fn disk() {
+        let dir = std::env::temp_dir().join("ps88_code_cache_test");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let mut runtime = JsRuntimeBuilder::new()
+            .code_cache(CodeCache::with_dir(&dir))
+            .build();
+        runtime.compile(SCRIPT, None).unwrap();
+        assert!(std::fs::read_dir(&dir).unwrap().count() > 0);
+
+        // 別のプロセスを想定して、メモリ上のキャッシュを持たない CodeCache から読み込む
+        let cache = CodeCache::with_dir(&dir);
+        let mut runtime = JsRuntimeBuilder::new().code_cache(cache.clone()).build();
+        runtime.compile(SCRIPT, None).unwrap();
+        assert!(cache.stats().hits > 0);
+
+        // 壊れたキャッシュは V8 に拒否され、作り直される
+        for entry in std::fs::read_dir(&dir).unwrap() {
+            std::fs::write(entry.unwrap().path(), b"broken").unwrap();
+        }
+        let cache = CodeCache::with_dir(&dir);
+        let mut runtime = JsRuntimeBuilder::new().code_cache(cache.clone()).build();
+        runtime.compile(SCRIPT, None).unwrap();
+        assert_eq!(cache.stats().hits, 0);
+        let mut audio = vec![0.0; 4];
+        runtime.audio(&mut audio, 1, 48000.0, &[]).unwrap();
+        assert_eq!(audio, vec![0.5; 4]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stable_key() {
+        // キーはディスク上のファイル名になるため、内容が同じなら常に同じ値になる
+        assert_eq!(key(SCRIPT, "main.js", false), key(SCRIPT, "main.js", false));
+        assert_ne!(key(SCRIPT, "main.js", false), key(SCRIPT, "main.js", true));
+        assert_ne!(key(SCRIPT, "main.js", false), key(SCRIPT, "lib.js", false));
+        assert_ne!(key("ab", "c", false), key("b", "ac", false));
+
+        // FNV-1a の既知の値
+        let mut hash = Fnv1a::new();
+        hash.write(b"a");
+        assert_eq!(hash.finish(), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn bounded_memory() {
+        // メモリ上のキャッシュは MAX_FILES 個までで、最後に使ってから長く経ったものから捨てる
+        let cache = CodeCache::new();
+        for key in 0..MAX_FILES as u64 {
+            cache.put(key, vec![key as u8]);
+        }
+        assert!(cache.get(0).is_some());
+        cache.put(MAX_FILES as u64, vec![0]);
+        assert!(cache.get(0).is_some());
+        assert!(cache.get(1).is_none());
+        assert!(cache.get(MAX_FILES as u64).is_some());
+        let entries = cache.inner.entries.lock().unwrap();
+        assert_eq!(entries.map.len(), MAX_FILES);
+        assert_eq!(entries.order.len(), MAX_FILES);
+    }
+
+    #[test]
+    fn concurrent_writes() {
+        // 同じプロセスの複数のスレッドから同じキーを書き込んでも、一時ファイルが衝突しない
+        let dir = std::env::temp_dir().join("ps88_code_cache_concurrent_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join(format!("0.{}", EXTENSION));
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..20 {
+                        write_file(&path, &[i; 1024]).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let data = std::fs::read(&path).unwrap();
+        assert_eq!(data.len(), 1024);
+        assert!(data.iter().all(|b| *b == data[0]));
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join("ps88_code_cache_permissions_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join(format!("0.{}", EXTENSION));
+        write_file(&path, b"cache").unwrap();
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&dir), 0o700);
+        assert_eq!(mode(&path), 0o600);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
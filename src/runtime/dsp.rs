@@ -0,0 +1,264 @@
+// スクリプトから ps88.dsp として呼び出されるブロック単位の信号処理
+// 全ての関数はバッファを直接書き換える
+
+use std::f64::consts::TAU;
+
+/// dst[i] += src[i]
+pub fn add(dst: &mut [f32], src: &[f32]) {
+    dst.iter_mut().zip(src).for_each(|(d, s)| *d += *s);
+}
+
+/// dst[i] += value
+pub fn add_scalar(dst: &mut [f32], value: f32) {
+    dst.iter_mut().for_each(|d| *d += value);
+}
+
+/// dst[i] *= src[i]
+pub fn mul(dst: &mut [f32], src: &[f32]) {
+    dst.iter_mut().zip(src).for_each(|(d, s)| *d *= *s);
+}
+
+/// dst[i] *= value
+pub fn mul_scalar(dst: &mut [f32], value: f32) {
+    dst.iter_mut().for_each(|d| *d *= value);
+}
+
+/// dst[i] += src[i] * gain
+pub fn mix(dst: &mut [f32], src: &[f32], gain: f32) {
+    dst.iter_mut().zip(src).for_each(|(d, s)| *d += *s * gain);
+}
+
+/// 正弦波のオシレーターバンク
+/// freqs[k] (Hz), amps[k] の正弦波を足し合わせたもので out を上書きする。
+/// phases[k] は 0-1 の位相で、処理した分だけ進めて書き戻す。
+pub fn osc_bank(
+    out: &mut [f32],
+    freqs: &[f32],
+    phases: &mut [f32],
+    amps: &[f32],
+    sample_rate: f32,
+) {
+    out.fill(0.0);
+    for ((freq, phase), amp) in freqs.iter().zip(phases.iter_mut()).zip(amps) {
+        let step = *freq as f64 / sample_rate as f64;
+        let mut p = *phase as f64;
+        for o in out.iter_mut() {
+            *o += ((p * TAU).sin() * *amp as f64) as f32;
+            p = (p + step).rem_euclid(1.0);
+        }
+        *phase = p as f32;
+    }
+}
+
+/// 双二次フィルタ (Transposed Direct Form II)
+/// coeffs は a0 で正規化された [b0, b1, b2, a1, a2]、state は [z1, z2] でブロックをまたいで保持する。
+pub fn biquad(buffer: &mut [f32], coeffs: &[f32; 5], state: &mut [f32; 2]) {
+    let [b0, b1, b2, a1, a2] = coeffs.map(|c| c as f64);
+    let [mut z1, mut z2] = state.map(|z| z as f64);
+    for x in buffer.iter_mut() {
+        let input = *x as f64;
+        let y = b0 * input + z1;
+        z1 = b1 * input - a1 * y + z2;
+        z2 = b2 * input - a2 * y;
+        *x = y as f32;
+    }
+    *state = [z1 as f32, z2 as f32];
+}
+
+/// 基数 2 の FFT
+/// re と im の長さは同じ 2 のべき乗でなければならない。
+/// inverse の場合は 1/N でスケーリングした逆変換を行う。
+pub fn fft(re: &mut [f32], im: &mut [f32], inverse: bool) {
+    let n = re.len();
+    assert!(n == im.len() && n.is_power_of_two());
+
+    // ビット反転の並べ替え
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut len = 2;
+    while len <= n {
+        let half = len / 2;
+        for k in 0..half {
+            let angle = sign * TAU * k as f64 / len as f64;
+            let (w_im, w_re) = angle.sin_cos();
+            for start in (0..n).step_by(len) {
+                let (a, b) = (start + k, start + k + half);
+                let (b_re, b_im) = (re[b] as f64, im[b] as f64);
+                let t_re = b_re * w_re - b_im * w_im;
+                let t_im = b_re * w_im + b_im * w_re;
+                let (a_re, a_im) = (re[a] as f64, im[a] as f64);
+                re[a] = (a_re + t_re) as f32;
+                im[a] = (a_im + t_im) as f32;
+                re[b] = (a_re - t_re) as f32;
+                im[b] = (a_im - t_im) as f32;
+            }
+        }
+        len <<= 1;
+    }
+
+    if inverse {
+        let scale = 1.0 / n as f32;
+        mul_scalar(re, scale);
+        mul_scalar(im, scale);
+    }
+}
+
+/// FIR フィルタによる畳み込み
+/// history には直前のブロックの入力が新しい順に ir.len() - 1 個以上入っており、処理した分だけ更新する。
+pub fn convolve(buffer: &mut [f32], ir: &[f32], history: &mut [f32]) {
+    let Some((first, rest)) = ir.split_first() else {
+        buffer.fill(0.0);
+        return;
+    };
+    let history = &mut history[..rest.len()];
+    for x in buffer.iter_mut() {
+        let input = *x;
+        let mut y = *first as f64 * input as f64;
+        for (h, z) in rest.iter().zip(history.iter()) {
+            y += *h as f64 * *z as f64;
+        }
+        if !history.is_empty() {
+            history.copy_within(0..history.len() - 1, 1);
+            history[0] = input;
+        }
+        *x = y as f32;
+    }
+}
+
+/// src 全体を dst の長さに線形補間で伸縮する
+pub fn resample(dst: &mut [f32], src: &[f32]) {
+    match (dst.len(), src.len()) {
+        (0, _) => {}
+        (_, 0) => dst.fill(0.0),
+        (1, _) => dst[0] = src[0],
+        (d, s) => {
+            let ratio = (s - 1) as f64 / (d - 1) as f64;
+            for (i, o) in dst.iter_mut().enumerate() {
+                let pos = i as f64 * ratio;
+                let index = (pos as usize).min(s - 1);
+                let next = (index + 1).min(s - 1);
+                let frac = pos - index as f64;
+                *o = (src[index] as f64 * (1.0 - frac) + src[next] as f64 * frac) as f32;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(actual: &[f32], expected: &[f32], tolerance: f32) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!(
+                (a - e).abs() <= tolerance,
+                "expected {:?} but got {:?}",
+                expected,
+                actual
+            );
+        }
+    }
+
+    #[test]
+    fn arithmetic() {
+        let mut dst = [1.0, 2.0, 3.0];
+        add(&mut dst, &[1.0, 1.0, 1.0]);
+        assert_eq!(dst, [2.0, 3.0, 4.0]);
+        mul(&mut dst, &[2.0, 0.0, 0.5]);
+        assert_eq!(dst, [4.0, 0.0, 2.0]);
+        mix(&mut dst, &[1.0, 2.0, 3.0], 0.5);
+        assert_eq!(dst, [4.5, 1.0, 3.5]);
+        add_scalar(&mut dst, 1.0);
+        mul_scalar(&mut dst, 2.0);
+        assert_eq!(dst, [11.0, 4.0, 9.0]);
+    }
+
+    #[test]
+    fn oscillator_bank() {
+        let mut out = [0.0; 4];
+        let mut phases = [0.0, 0.25];
+        osc_bank(&mut out, &[12000.0, 0.0], &mut phases, &[1.0, 0.5], 48000.0);
+        assert_near(&out, &[0.5, 1.5, 0.5, -0.5], 1e-6);
+        // 位相は 1 周して元に戻る
+        assert_near(&phases, &[0.0, 0.25], 1e-6);
+    }
+
+    #[test]
+    fn biquad_filter() {
+        // 1 サンプルの遅延 (y[n] = x[n - 1]) は state を通してブロックをまたぐ
+        let coeffs = [0.0, 1.0, 0.0, 0.0, 0.0];
+        let mut state = [0.0; 2];
+        let mut block = [1.0, 2.0, 3.0];
+        biquad(&mut block, &coeffs, &mut state);
+        assert_eq!(block, [0.0, 1.0, 2.0]);
+        let mut block = [0.0, 0.0];
+        biquad(&mut block, &coeffs, &mut state);
+        assert_eq!(block, [3.0, 0.0]);
+    }
+
+    #[test]
+    fn fft_round_trip() {
+        // インパルスのスペクトルは平坦になる
+        let mut re = [1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0];
+        let mut im = [0.0; 8];
+        fft(&mut re, &mut im, false);
+        assert_near(&re, &[1.0; 8], 1e-6);
+        assert_near(&im, &[0.0; 8], 1e-6);
+
+        // 余弦波は対応するビンにだけ現れる
+        let signal: Vec<f32> = (0..16)
+            .map(|i| (TAU * 2.0 * i as f64 / 16.0).cos() as f32)
+            .collect();
+        let mut re = signal.clone();
+        let mut im = vec![0.0; 16];
+        fft(&mut re, &mut im, false);
+        let mut expected = vec![0.0; 16];
+        expected[2] = 8.0;
+        expected[14] = 8.0;
+        assert_near(&re, &expected, 1e-4);
+        assert_near(&im, &[0.0; 16], 1e-4);
+
+        // 逆変換で元に戻る
+        fft(&mut re, &mut im, true);
+        assert_near(&re, &signal, 1e-5);
+        assert_near(&im, &[0.0; 16], 1e-5);
+    }
+
+    #[test]
+    fn convolution() {
+        // ブロックに分けて処理しても一度に処理した結果と一致する
+        let ir = [1.0, 0.5, 0.25];
+        let mut history = [0.0; 2];
+        let mut first = [1.0, 0.0];
+        let mut second = [0.0, 2.0, 0.0];
+        convolve(&mut first, &ir, &mut history);
+        convolve(&mut second, &ir, &mut history);
+        assert_eq!(first, [1.0, 0.5]);
+        assert_eq!(second, [0.25, 2.0, 1.0]);
+        assert_eq!(history, [0.0, 2.0]);
+    }
+
+    #[test]
+    fn resampling() {
+        let mut dst = [0.0; 5];
+        resample(&mut dst, &[0.0, 1.0, 0.0]);
+        assert_near(&dst, &[0.0, 0.5, 1.0, 0.5, 0.0], 1e-6);
+        let mut dst = [0.0; 2];
+        resample(&mut dst, &[0.0, 1.0, 2.0, 3.0]);
+        assert_near(&dst, &[0.0, 3.0], 1e-6);
+    }
+}
@@ -0,0 +1,229 @@
+use std::cell::Cell;
+use std::ffi::c_void;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use v8;
+
+/// isolate で行われたガベージコレクションの統計
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct GcStats {
+    pub count: u64,
+
+    // GC で処理が止まっていた時間の合計と最大値
+    pub pause: Duration,
+    pub max_pause: Duration,
+
+    // GC で解放された JavaScript のヒープのバイト数
+    pub reclaimed_bytes: u64,
+}
+
+// gcOptions.threshold を省略した場合の値 (バイト)
+const DEFAULT_THRESHOLD: usize = 1024 * 1024;
+
+/// ブロックの合間に行う GC の方法。スクリプトの gcOptions.schedule で指定する。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum GcSchedule {
+    // V8 に任せる (audio の実行中に GC が行われることがある)
+    #[default]
+    Auto,
+
+    // memory pressure notification (Moderate) でメモリが逼迫していると V8 に伝え、インクリメンタルマーキングを始めさせる
+    // (アイドル時間の GC ではなく、V8 がいつ回収するかは V8 に任される)
+    MemoryPressure,
+
+    // low memory notification で、その場でヒープ全体を回収する
+    LowMemory,
+}
+
+/// スクリプトの gcOptions で指定する GC の設定
+///
+/// e.g.
+///   const gcOptions = { schedule: "memoryPressure", threshold: 1 << 20, strict: true };
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GcOptions {
+    pub schedule: GcSchedule,
+
+    // 前回ブロックの合間に GC を行ってから、ヒープの使用量がこのバイト数増えたら GC を行う
+    pub threshold: usize,
+
+    // audio の呼び出しで JavaScript のヒープに確保した場合に警告する
+    pub strict: bool,
+}
+
+impl Default for GcOptions {
+    fn default() -> Self {
+        GcOptions {
+            schedule: GcSchedule::Auto,
+            threshold: DEFAULT_THRESHOLD,
+            strict: false,
+        }
+    }
+}
+
+/// 若い世代の semi-space の大きさ (MB) を V8 のフラグで指定する。
+/// V8 のフラグは初期化後に変更できないため、V8 の初期化前に呼ぶ必要があり、プロセス内の全ての isolate に適用される。
+pub fn set_young_generation_size(megabytes: usize) {
+    v8::V8::set_flags_from_string(&format!(
+        "--min-semi-space-size={} --max-semi-space-size={}",
+        megabytes, megabytes
+    ));
+}
+
+/// ブロックの合間に、options に従って GC を行う。GC を行った場合は true を返す。
+/// baseline は前回 GC を行った直後のヒープの使用量。
+pub fn collect_between_blocks(
+    isolate: &mut v8::Isolate,
+    options: &GcOptions,
+    baseline: &mut usize,
+) -> bool {
+    if options.schedule == GcSchedule::Auto {
+        return false;
+    }
+    let used = heap_used(isolate);
+    if used < baseline.saturating_add(options.threshold) {
+        // GC で使用量が減った場合は、そこから数え直す
+        *baseline = (*baseline).min(used);
+        return false;
+    }
+    match options.schedule {
+        GcSchedule::MemoryPressure => {
+            isolate.memory_pressure_notification(v8::MemoryPressureLevel::Moderate)
+        }
+        GcSchedule::LowMemory => isolate.low_memory_notification(),
+        GcSchedule::Auto => {}
+    }
+    *baseline = heap_used(isolate);
+    true
+}
+
+// strict realtime モードの警告を出す間隔
+const WARNING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// strict realtime モードで audio の呼び出しがヒープに確保したことを警告する。
+/// ブロックごとに警告すると出力が溢れるため、警告の間隔を空けてその間のブロック数をまとめて報告する。
+#[derive(Debug, Default)]
+pub struct AllocationWarning {
+    last: Option<Instant>,
+    blocks: u64,
+    max_bytes: usize,
+}
+
+impl AllocationWarning {
+    /// 確保したブロックを記録し、警告を出す時はそのメッセージを返す
+    pub fn record(&mut self, bytes: usize) -> Option<String> {
+        self.record_at(bytes, Instant::now())
+    }
+
+    fn record_at(&mut self, bytes: usize, now: Instant) -> Option<String> {
+        self.blocks += 1;
+        self.max_bytes = self.max_bytes.max(bytes);
+        if self
+            .last
+            .is_some_and(|last| now.duration_since(last) < WARNING_INTERVAL)
+        {
+            return None;
+        }
+        let message = format!(
+            "strict realtime: audio() allocated on the JS heap in {} block(s) (up to {} bytes per block)",
+            self.blocks, self.max_bytes
+        );
+        self.last = Some(now);
+        self.blocks = 0;
+        self.max_bytes = 0;
+        Some(message)
+    }
+}
+
+/// GC の開始と終了のコールバックで統計を取る。
+/// コールバックにはポインタとして渡すため、isolate より長く生存する必要がある
+/// (JsRuntime では isolate より後に drop されるフィールドに保持する)。
+pub struct GcTracker {
+    // 実行中の GC の開始時刻と、開始時点のヒープの使用量
+    start: Cell<Option<(Instant, usize)>>,
+
+    // ps88.gcStats から参照できるように isolate の slot にも保存する
+    stats: Rc<Cell<GcStats>>,
+}
+
+impl GcTracker {
+    pub fn install(isolate: &mut v8::Isolate) -> Box<GcTracker> {
+        let tracker = Box::new(GcTracker {
+            start: Cell::new(None),
+            stats: Default::default(),
+        });
+        let data = &*tracker as *const GcTracker as *mut c_void;
+        isolate.add_gc_prologue_callback(prologue, data, v8::GCType::kGCTypeAll);
+        isolate.add_gc_epilogue_callback(epilogue, data, v8::GCType::kGCTypeAll);
+        tracker
+    }
+
+    pub fn stats(&self) -> GcStats {
+        self.stats.get()
+    }
+
+    pub fn shared_stats(&self) -> Rc<Cell<GcStats>> {
+        self.stats.clone()
+    }
+}
+
+/// JavaScript のヒープの使用量 (バイト)
+pub fn heap_used(isolate: &mut v8::Isolate) -> usize {
+    let mut statistics = v8::HeapStatistics::default();
+    isolate.get_heap_statistics(&mut statistics);
+    statistics.used_heap_size()
+}
+
+extern "C" fn prologue(
+    isolate: *mut v8::Isolate,
+    _type: v8::GCType,
+    _flags: v8::GCCallbackFlags,
+    data: *mut c_void,
+) {
+    let tracker = unsafe { &*(data as *const GcTracker) };
+    let used = heap_used(unsafe { &mut *isolate });
+    tracker.start.set(Some((Instant::now(), used)));
+}
+
+extern "C" fn epilogue(
+    isolate: *mut v8::Isolate,
+    _type: v8::GCType,
+    _flags: v8::GCCallbackFlags,
+    data: *mut c_void,
+) {
+    let tracker = unsafe { &*(data as *const GcTracker) };
+    let Some((start, used_before)) = tracker.start.take() else {
+        return;
+    };
+    let pause = start.elapsed();
+    let used_after = heap_used(unsafe { &mut *isolate });
+    let mut stats = tracker.stats.get();
+    stats.count += 1;
+    stats.pause += pause;
+    stats.max_pause = stats.max_pause.max(pause);
+    stats.reclaimed_bytes += used_before.saturating_sub(used_after) as u64;
+    tracker.stats.set(stats);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocation_warning() {
+        let mut warning = AllocationWarning::default();
+        let start = Instant::now();
+        assert_eq!(
+            warning.record_at(64, start).as_deref(),
+            Some("strict realtime: audio() allocated on the JS heap in 1 block(s) (up to 64 bytes per block)")
+        );
+
+        // 1 秒以内の確保はまとめて次の警告で報告する
+        let ms = Duration::from_millis;
+        assert_eq!(warning.record_at(128, start + ms(10)), None);
+        assert_eq!(warning.record_at(32, start + ms(500)), None);
+        assert_eq!(
+            warning.record_at(16, start + ms(1000)).as_deref(),
+            Some("strict realtime: audio() allocated on the JS heap in 3 block(s) (up to 128 bytes per block)")
+        );
+    }
+}
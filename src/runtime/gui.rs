@@ -0,0 +1,704 @@
+use lyon::math::point;
+use lyon::path::Path;
+use lyon::tessellation::{
+    BuffersBuilder, FillOptions, FillRule, FillTessellator, FillVertex, VertexBuffers,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+// 省略した場合の線の太さと文字の大きさ
+const DEFAULT_LINE_WIDTH: f32 = 1.0;
+const DEFAULT_TEXT_SIZE: f32 = 14.0;
+
+// 省略した場合の鍵盤の音域 (C3 - C5)
+const DEFAULT_KEYBOARD_LOW: u8 = 48;
+const DEFAULT_KEYBOARD_HIGH: u8 = 72;
+
+#[derive(Debug, Error)]
+pub enum GuiError {
+    #[error("invalid display list: {0}")]
+    Invalid(#[from] serde_json::Error),
+}
+
+/// スクリプトの gui(ctx) に渡す ctx
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct GuiContext {
+    // GUI の領域の大きさ (ピクセル)
+    pub width: f32,
+    pub height: f32,
+    pub input: Input,
+    // ホストのパラメータの値 (パラメータの id → 値)
+    pub params: BTreeMap<String, f32>,
+    // 前のフレームでユーザーが操作したウィジェットの新しい値 (ウィジェットの id → 値)。
+    // パラメータに結び付けたウィジェットの値は params に反映されるので含まれない
+    pub changes: BTreeMap<String, WidgetValue>,
+}
+
+/// ユーザーの操作で変わったウィジェットの値
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum WidgetValue {
+    // knob, slider
+    Number(f32),
+    // toggle
+    Bool(bool),
+    // dropdown で選ばれた選択肢のインデックス
+    Index(usize),
+    // xyPad
+    Point([f32; 2]),
+    // keyboard で押されているノート番号。離した時は null
+    Note(Option<u8>),
+}
+
+/// 前のフレームからの入力。座標は図形と同じく GUI の領域の左上が原点。
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Input {
+    // ポインタの位置。領域の外にある場合は null だが、ドラッグ中は領域の外でも位置が入る
+    pub pointer: Option<[f32; 2]>,
+    // 押されているボタンと、このフレームで押された/離されたボタン
+    pub buttons: Buttons,
+    pub pressed: Buttons,
+    pub released: Buttons,
+    // 前のフレームからのポインタの移動量
+    pub delta: [f32; 2],
+    // ホイールの回転量 (ピクセル)
+    pub wheel: [f32; 2],
+    // ポインタの下にあるヒット領域の id
+    pub hover: Option<String>,
+    // ボタンを押した時にポインタの下にあったヒット領域の id。ボタンを離すまで変わらない
+    pub active: Option<String>,
+    pub keys: Vec<KeyEvent>,
+    // 入力された文字列
+    pub text: String,
+    pub modifiers: Modifiers,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Buttons {
+    pub primary: bool,
+    pub secondary: bool,
+    pub middle: bool,
+}
+
+impl Buttons {
+    pub fn any(&self) -> bool {
+        self.primary || self.secondary || self.middle
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct KeyEvent {
+    // キーの名前 ("A", "Enter", "ArrowLeft" など)
+    pub key: String,
+    pub pressed: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+    // Mac では Cmd、それ以外では Ctrl
+    pub command: bool,
+}
+
+impl Input {
+    /// 前回の gui(ctx) が返したヒット領域から hover と active を決める。
+    /// active はフレームをまたいで保持する必要があるので、呼び出し側で持っておく。
+    pub fn hit_test(&mut self, shapes: &[Shape], active: &mut Option<String>) {
+        self.hover = self
+            .pointer
+            .and_then(|pointer| hit_region(shapes, pointer).map(str::to_string));
+        if self.pressed.any() && active.is_none() {
+            *active = self.hover.clone();
+        }
+        self.active = active.clone();
+        // ボタンを離したフレームまでは active を渡し、次のフレームから解除する
+        if !self.buttons.any() {
+            *active = None;
+        }
+    }
+}
+
+/// ポインタの下にあるヒット領域の id を返す。重なっている場合は後に書いた (上に描かれる) ものを優先する。
+pub fn hit_region(shapes: &[Shape], pointer: [f32; 2]) -> Option<&str> {
+    shapes.iter().rev().find_map(|shape| match shape {
+        Shape::Region {
+            id,
+            x,
+            y,
+            width,
+            height,
+        } if (*x..=x + width).contains(&pointer[0]) && (*y..=y + height).contains(&pointer[1]) => {
+            Some(id.as_str())
+        }
+        _ => None,
+    })
+}
+
+/// スクリプトの gui(ctx) が返す図形とウィジェット。座標は GUI の領域の左上を原点とするピクセル単位。
+/// ウィジェットは param にホストのパラメータの id ("param1" など) を指定すると、そのパラメータの値を表示・操作する。
+/// 指定しない場合は value の値を表示し、操作した結果は次のフレームの ctx.changes に入る。
+///
+/// e.g.
+///   const gui = (ctx) => [
+///     { type: "rect", x: 0, y: 0, width: ctx.width, height: 40, fill: "#202020", radius: 4 },
+///     { type: "line", points: [[0, 20], [ctx.width, 20]], color: "#ffffff80", lineWidth: 2 },
+///     { type: "polygon", points: [[10, 10], [30, 10], [20, 20], [30, 30], [10, 30]], fill: [255, 128, 0] },
+///     { type: "circle", x: 50, y: 20, radius: 8, stroke: "#fff" },
+///     { type: "text", x: 60, y: 12, text: "ps88", size: 16, align: "left" },
+///     { type: "region", id: "knob", x: 40, y: 10, width: 20, height: 20 },
+///     { type: "knob", id: "gain", x: 80, y: 0, width: 40, height: 40, param: "param1", label: "Gain" },
+///   ];
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Shape {
+    // closed が true の場合は最後の点と最初の点も結ぶ
+    Line {
+        points: Vec<[f32; 2]>,
+        #[serde(default = "white")]
+        color: Color,
+        #[serde(default = "line_width", rename = "lineWidth")]
+        line_width: f32,
+        #[serde(default)]
+        closed: bool,
+    },
+
+    // 凸でない多角形も塗りつぶせる (自己交差する場合は偶奇規則で塗る)
+    Polygon {
+        points: Vec<[f32; 2]>,
+        fill: Option<Color>,
+        stroke: Option<Color>,
+        #[serde(default = "line_width", rename = "lineWidth")]
+        line_width: f32,
+    },
+
+    Rect {
+        x: f32,
+        y: f32,
+        width: f32,
+        height: f32,
+        fill: Option<Color>,
+        stroke: Option<Color>,
+        #[serde(default = "line_width", rename = "lineWidth")]
+        line_width: f32,
+        // 角の丸みの半径
+        #[serde(default)]
+        radius: f32,
+    },
+
+    Circle {
+        x: f32,
+        y: f32,
+        radius: f32,
+        fill: Option<Color>,
+        stroke: Option<Color>,
+        #[serde(default = "line_width", rename = "lineWidth")]
+        line_width: f32,
+    },
+
+    // (x, y) は文字列の上端で、align によって左端、中央、右端のいずれかになる
+    Text {
+        x: f32,
+        y: f32,
+        text: String,
+        #[serde(default = "text_size")]
+        size: f32,
+        #[serde(default = "white")]
+        color: Color,
+        #[serde(default)]
+        align: Align,
+    },
+
+    // 描画されないヒット領域。ポインタが上にある時やドラッグ中に ctx.input.hover / ctx.input.active に id が入る
+    Region {
+        id: String,
+        x: f32,
+        y: f32,
+        width: f32,
+        height: f32,
+    },
+
+    // 上下にドラッグして min から max の値を変えるつまみ。ダブルクリックでパラメータの初期値に戻る
+    Knob {
+        id: String,
+        x: f32,
+        y: f32,
+        width: f32,
+        height: f32,
+        #[serde(default)]
+        value: f32,
+        #[serde(default)]
+        min: f32,
+        #[serde(default = "one")]
+        max: f32,
+        param: Option<String>,
+        label: Option<String>,
+    },
+
+    // 幅より高さが大きい場合は縦向きになる
+    Slider {
+        id: String,
+        x: f32,
+        y: f32,
+        width: f32,
+        height: f32,
+        #[serde(default)]
+        value: f32,
+        #[serde(default)]
+        min: f32,
+        #[serde(default = "one")]
+        max: f32,
+        param: Option<String>,
+        label: Option<String>,
+    },
+
+    // パラメータに結び付けた場合は 0.5 以上をオンとし、オン/オフで最大値/最小値にする
+    Toggle {
+        id: String,
+        x: f32,
+        y: f32,
+        width: f32,
+        height: f32,
+        #[serde(default)]
+        value: bool,
+        param: Option<String>,
+        label: Option<String>,
+    },
+
+    // パラメータに結び付けた場合は、パラメータの範囲を選択肢の数で等分する
+    Dropdown {
+        id: String,
+        x: f32,
+        y: f32,
+        width: f32,
+        height: f32,
+        options: Vec<String>,
+        #[serde(default)]
+        value: usize,
+        param: Option<String>,
+    },
+
+    // value は左下を [0, 0]、右上を [1, 1] とする位置
+    XyPad {
+        id: String,
+        x: f32,
+        y: f32,
+        width: f32,
+        height: f32,
+        #[serde(default)]
+        value: [f32; 2],
+        #[serde(rename = "paramX")]
+        param_x: Option<String>,
+        #[serde(rename = "paramY")]
+        param_y: Option<String>,
+    },
+
+    // data の値を min から max の範囲で折れ線として表示する
+    Waveform {
+        x: f32,
+        y: f32,
+        width: f32,
+        height: f32,
+        data: Vec<f32>,
+        #[serde(default = "minus_one")]
+        min: f32,
+        #[serde(default = "one")]
+        max: f32,
+        color: Option<Color>,
+    },
+
+    // data の値を min から max の範囲で棒グラフとして表示する (dB で渡す場合は min: -90, max: 0 など)
+    Spectrum {
+        x: f32,
+        y: f32,
+        width: f32,
+        height: f32,
+        data: Vec<f32>,
+        #[serde(default)]
+        min: f32,
+        #[serde(default = "one")]
+        max: f32,
+        color: Option<Color>,
+    },
+
+    // low から high までの鍵盤。notes のノートは押されているように表示する
+    Keyboard {
+        id: String,
+        x: f32,
+        y: f32,
+        width: f32,
+        height: f32,
+        #[serde(default = "keyboard_low")]
+        low: u8,
+        #[serde(default = "keyboard_high")]
+        high: u8,
+        #[serde(default)]
+        notes: Vec<u8>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Align {
+    #[default]
+    Left,
+    Center,
+    Right,
+}
+
+/// 色。スクリプトからは "#rgb", "#rrggbb", "#rrggbbaa" の文字列か、[r, g, b] または [r, g, b, a] (0-255) の配列で指定する。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "ColorValue")]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ColorValue {
+    Hex(String),
+    Rgba(Vec<f32>),
+}
+
+impl TryFrom<ColorValue> for Color {
+    type Error = String;
+
+    fn try_from(value: ColorValue) -> Result<Self, Self::Error> {
+        match value {
+            ColorValue::Hex(hex) => {
+                let digits = hex
+                    .strip_prefix('#')
+                    .filter(|digits| digits.chars().all(|c| c.is_ascii_hexdigit()))
+                    .ok_or_else(|| format!("invalid color: {}", hex))?;
+                // #rgb は各桁を 2 桁に伸ばす
+                let digits = match digits.len() {
+                    3 => digits.chars().flat_map(|c| [c, c]).collect(),
+                    6 | 8 => digits.to_string(),
+                    _ => return Err(format!("invalid color: {}", hex)),
+                };
+                let channel = |i: usize| {
+                    digits
+                        .get(i * 2..i * 2 + 2)
+                        .map_or(255, |digits| u8::from_str_radix(digits, 16).unwrap_or(255))
+                };
+                Ok(Color {
+                    r: channel(0),
+                    g: channel(1),
+                    b: channel(2),
+                    a: channel(3),
+                })
+            }
+            ColorValue::Rgba(channels) => {
+                if !(3..=4).contains(&channels.len()) {
+                    return Err(format!("invalid color: {:?}", channels));
+                }
+                let channel = |i: usize| {
+                    channels
+                        .get(i)
+                        .map_or(255, |c| c.round().clamp(0.0, 255.0) as u8)
+                };
+                Ok(Color {
+                    r: channel(0),
+                    g: channel(1),
+                    b: channel(2),
+                    a: channel(3),
+                })
+            }
+        }
+    }
+}
+
+fn white() -> Color {
+    Color {
+        r: 255,
+        g: 255,
+        b: 255,
+        a: 255,
+    }
+}
+
+fn line_width() -> f32 {
+    DEFAULT_LINE_WIDTH
+}
+
+fn text_size() -> f32 {
+    DEFAULT_TEXT_SIZE
+}
+
+fn one() -> f32 {
+    1.0
+}
+
+fn minus_one() -> f32 {
+    -1.0
+}
+
+fn keyboard_low() -> u8 {
+    DEFAULT_KEYBOARD_LOW
+}
+
+fn keyboard_high() -> u8 {
+    DEFAULT_KEYBOARD_HIGH
+}
+
+/// 鍵盤のノートが黒鍵かどうか
+pub fn is_black_key(note: u8) -> bool {
+    matches!(note % 12, 1 | 3 | 6 | 8 | 10)
+}
+
+/// gui(ctx) の戻り値を JSON にしたものから図形の一覧を読み取る
+pub fn parse(json: &str) -> Result<Vec<Shape>, GuiError> {
+    Ok(serde_json::from_str(json)?)
+}
+
+/// 多角形を三角形に分割する。egui は凸多角形しか塗りつぶせないため、凸でない多角形はこれでメッシュにして描く。
+/// 頂点の一覧と、3 つずつ組になって三角形を表す頂点のインデックスを返す。
+pub fn tessellate(points: &[[f32; 2]]) -> (Vec<[f32; 2]>, Vec<u32>) {
+    let mut buffers: VertexBuffers<[f32; 2], u32> = VertexBuffers::new();
+    let Some((first, rest)) = points.split_first() else {
+        return (buffers.vertices, buffers.indices);
+    };
+    let mut builder = Path::builder();
+    builder.begin(point(first[0], first[1]));
+    for p in rest {
+        builder.line_to(point(p[0], p[1]));
+    }
+    builder.end(true);
+    let path = builder.build();
+
+    let result = FillTessellator::new().tessellate_path(
+        &path,
+        &FillOptions::default().with_fill_rule(FillRule::EvenOdd),
+        &mut BuffersBuilder::new(&mut buffers, |vertex: FillVertex| {
+            vertex.position().to_array()
+        }),
+    );
+    if result.is_err() {
+        return (Vec::new(), Vec::new());
+    }
+    (buffers.vertices, buffers.indices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_shapes() {
+        let shapes = parse(
+            r##"[
+                { "type": "line", "points": [[0, 0], [10, 10]] },
+                { "type": "rect", "x": 1, "y": 2, "width": 3, "height": 4, "fill": "#f80", "radius": 2 },
+                { "type": "circle", "x": 5, "y": 5, "radius": 3, "stroke": [0, 128, 255, 128], "lineWidth": 2 },
+                { "type": "text", "x": 0, "y": 0, "text": "ps88", "align": "center" }
+            ]"##,
+        )
+        .unwrap();
+        assert_eq!(
+            shapes[0],
+            Shape::Line {
+                points: vec![[0.0, 0.0], [10.0, 10.0]],
+                color: white(),
+                line_width: 1.0,
+                closed: false,
+            }
+        );
+        let Shape::Rect { fill, radius, .. } = &shapes[1] else {
+            panic!("{:?}", shapes[1]);
+        };
+        assert_eq!(
+            *fill,
+            Some(Color {
+                r: 255,
+                g: 136,
+                b: 0,
+                a: 255
+            })
+        );
+        assert_eq!(*radius, 2.0);
+        let Shape::Circle {
+            stroke, line_width, ..
+        } = &shapes[2]
+        else {
+            panic!("{:?}", shapes[2]);
+        };
+        assert_eq!(stroke.unwrap().a, 128);
+        assert_eq!(*line_width, 2.0);
+        let Shape::Text { size, align, .. } = &shapes[3] else {
+            panic!("{:?}", shapes[3]);
+        };
+        assert_eq!((*size, *align), (14.0, Align::Center));
+
+        // 不正な図形や色はエラーになる
+        assert!(parse(r#"[{ "type": "star" }]"#).is_err());
+        assert!(parse(r#"[{ "type": "line", "points": [], "color": "red" }]"#).is_err());
+    }
+
+    #[test]
+    fn tessellate_concave_polygon() {
+        // L 字型 (面積 3) の凹多角形
+        let points = [
+            [0.0, 0.0],
+            [2.0, 0.0],
+            [2.0, 1.0],
+            [1.0, 1.0],
+            [1.0, 2.0],
+            [0.0, 2.0],
+        ];
+        let (vertices, indices) = tessellate(&points);
+        assert_eq!(indices.len() % 3, 0);
+        let area: f32 = indices
+            .chunks(3)
+            .map(|triangle| {
+                let [a, b, c] = [0, 1, 2].map(|i| vertices[triangle[i] as usize]);
+                ((b[0] - a[0]) * (c[1] - a[1]) - (c[0] - a[0]) * (b[1] - a[1])).abs() / 2.0
+            })
+            .sum();
+        assert!((area - 3.0).abs() < 1e-4, "{}", area);
+        assert_eq!(tessellate(&[]), (vec![], vec![]));
+
+        // 自己交差する星形は偶奇規則で塗るため、中央の五角形は塗られない
+        let star: Vec<[f32; 2]> = (0..5)
+            .map(|i| {
+                let angle = std::f32::consts::PI * (0.5 + 0.8 * i as f32);
+                [angle.cos(), angle.sin()]
+            })
+            .collect();
+        let (vertices, indices) = tessellate(&star);
+        assert!(!indices.is_empty());
+        let cross = |a: [f32; 2], b: [f32; 2]| a[0] * b[1] - a[1] * b[0];
+        let covers_center = indices.chunks(3).any(|triangle| {
+            let [a, b, c] = [0, 1, 2].map(|i| vertices[triangle[i] as usize]);
+            let signs = [cross(a, b), cross(b, c), cross(c, a)];
+            signs.iter().all(|s| *s > 1e-4) || signs.iter().all(|s| *s < -1e-4)
+        });
+        assert!(!covers_center);
+    }
+
+    #[test]
+    fn hit_test() {
+        let shapes = parse(
+            r#"[
+                { "type": "region", "id": "pad", "x": 0, "y": 0, "width": 100, "height": 100 },
+                { "type": "region", "id": "knob", "x": 10, "y": 10, "width": 20, "height": 20 }
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(hit_region(&shapes, [15.0, 15.0]), Some("knob"));
+        assert_eq!(hit_region(&shapes, [50.0, 50.0]), Some("pad"));
+        assert_eq!(hit_region(&shapes, [150.0, 50.0]), None);
+
+        let mut active = None;
+        let press = |pointer: [f32; 2], pressed: bool, down: bool| Input {
+            pointer: Some(pointer),
+            buttons: Buttons {
+                primary: down,
+                ..Default::default()
+            },
+            pressed: Buttons {
+                primary: pressed,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        // ノブの上でボタンを押すと、領域の外にドラッグしても active のまま
+        let mut input = press([15.0, 15.0], true, true);
+        input.hit_test(&shapes, &mut active);
+        assert_eq!(input.hover.as_deref(), Some("knob"));
+        assert_eq!(input.active.as_deref(), Some("knob"));
+        let mut input = press([150.0, 15.0], false, true);
+        input.hit_test(&shapes, &mut active);
+        assert_eq!(input.hover, None);
+        assert_eq!(input.active.as_deref(), Some("knob"));
+
+        // 離したフレームまでは active で、次のフレームで解除される
+        let mut input = press([150.0, 15.0], false, false);
+        input.hit_test(&shapes, &mut active);
+        assert_eq!(input.active.as_deref(), Some("knob"));
+        let mut input = press([50.0, 50.0], false, false);
+        input.hit_test(&shapes, &mut active);
+        assert_eq!((input.hover.as_deref(), input.active), (Some("pad"), None));
+    }
+
+    #[test]
+    fn parse_widgets() {
+        let shapes = parse(
+            r#"[
+                { "type": "knob", "id": "cutoff", "x": 0, "y": 0, "width": 40, "height": 40, "param": "param1" },
+                { "type": "xyPad", "id": "pad", "x": 0, "y": 0, "width": 100, "height": 100, "value": [0.5, 0.25], "paramY": "param2" },
+                { "type": "keyboard", "id": "keys", "x": 0, "y": 0, "width": 200, "height": 40 },
+                { "type": "waveform", "x": 0, "y": 0, "width": 100, "height": 40, "data": [0, 1, -1] }
+            ]"#,
+        )
+        .unwrap();
+        let Shape::Knob {
+            value,
+            min,
+            max,
+            param,
+            ..
+        } = &shapes[0]
+        else {
+            panic!("{:?}", shapes[0]);
+        };
+        assert_eq!((*value, *min, *max), (0.0, 0.0, 1.0));
+        assert_eq!(param.as_deref(), Some("param1"));
+        let Shape::XyPad {
+            value,
+            param_x,
+            param_y,
+            ..
+        } = &shapes[1]
+        else {
+            panic!("{:?}", shapes[1]);
+        };
+        assert_eq!(*value, [0.5, 0.25]);
+        assert_eq!(
+            (param_x.as_deref(), param_y.as_deref()),
+            (None, Some("param2"))
+        );
+        let Shape::Keyboard {
+            low, high, notes, ..
+        } = &shapes[2]
+        else {
+            panic!("{:?}", shapes[2]);
+        };
+        assert_eq!((*low, *high, notes.len()), (48, 72, 0));
+        let Shape::Waveform { min, max, .. } = &shapes[3] else {
+            panic!("{:?}", shapes[3]);
+        };
+        assert_eq!((*min, *max), (-1.0, 1.0));
+
+        // ドロップダウンには選択肢が必要
+        assert!(parse(
+            r#"[{ "type": "dropdown", "id": "mode", "x": 0, "y": 0, "width": 1, "height": 1 }]"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn serialize_context() {
+        let mut ctx = GuiContext::default();
+        ctx.params.insert("param1".to_string(), 0.5);
+        ctx.changes
+            .insert("cutoff".to_string(), WidgetValue::Number(0.25));
+        ctx.changes
+            .insert("pad".to_string(), WidgetValue::Point([0.5, 1.0]));
+        ctx.changes
+            .insert("keys".to_string(), WidgetValue::Note(None));
+        let json = serde_json::to_value(&ctx).unwrap();
+        assert_eq!(json["params"]["param1"], 0.5);
+        assert_eq!(
+            json["changes"],
+            serde_json::json!({ "cutoff": 0.25, "keys": null, "pad": [0.5, 1.0] })
+        );
+        assert_eq!(json["input"]["pointer"], serde_json::Value::Null);
+    }
+}
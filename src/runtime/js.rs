@@ -1,26 +1,90 @@
+use crate::runtime::code_cache::{self, CodeCache};
+use crate::runtime::gc::{self, AllocationWarning, GcOptions, GcStats, GcTracker};
+use crate::runtime::gui::{self, GuiContext, Shape};
+use crate::runtime::js_dsp;
+use crate::runtime::js_gc;
+use crate::runtime::js_inspector::{
+    self, InspectorEvent, InspectorServer, InspectorSession, LocalSession,
+};
+use crate::runtime::js_module::{self, ModuleLoader};
+use crate::runtime::js_sample::{self, SampleLoader};
+use crate::runtime::js_state;
+use crate::runtime::js_test;
+use crate::runtime::js_voice::JsVoices;
+use crate::runtime::profile::Profile;
 use crate::runtime::runtime;
-use std::cell::RefCell;
+use crate::runtime::sample::SampleCache;
+use crate::runtime::state::ScriptState;
+use crate::runtime::stdlib;
+use std::cell::{Cell, RefCell};
 use std::mem::size_of;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
-use std::sync::Once;
+use std::sync::{Arc, Mutex, Once};
 use thiserror::Error;
 use v8;
 
 pub struct JsRuntimeBuilder {
     on_log: Option<Rc<dyn Fn(String)>>,
+    state: Option<Arc<Mutex<ScriptState>>>,
+    random_seed: Option<u32>,
+    inspector: Option<InspectorServer>,
+    code_cache: Option<CodeCache>,
 }
 
 pub struct JsRuntime {
     isolate: v8::OwnedIsolate,
     on_log: Option<Rc<dyn Fn(String)>>,
+    dependencies: Vec<PathBuf>,
+
+    // コンパイル済みの標準ライブラリ
+    stdlib: Option<v8::Global<v8::UnboundScript>>,
+
+    // ps88.loadSample で読み込んだファイルのキャッシュ (再コンパイルしても保持する)
+    samples: SampleCache,
+
+    // Math.random のシード (None の場合は v8 のデフォルトの乱数を使う)
+    random_seed: Option<u32>,
+
+    // GC の統計 (コールバックから参照されるため isolate より後に drop する)
+    gc: Box<GcTracker>,
+
+    // ブロックの合間に GC を行った直後のヒープの使用量
+    gc_baseline: usize,
+
+    // Chrome DevTools の接続を受け付けるサーバー (再コンパイルしても接続先は変わらない)
+    inspector: Option<Rc<InspectorServer>>,
 }
 
+// ファイルパスを持たないスクリプトのエラーメッセージに表示する名前
+const MAIN_SCRIPT_NAME: &str = "main.js";
+
+// REPL で評価したコードのエラーメッセージに表示する名前
+const REPL_SCRIPT_NAME: &str = "repl";
+
+// REPL の結果の表示を打ち切る文字数
+const REPL_RESULT_LIMIT: usize = 2000;
+
+// CPU プロファイラのサンプリング間隔 (マイクロ秒)。1 ブロックが数ミリ秒なので、デフォルトの 1ms より細かくする
+const PROFILER_SAMPLING_INTERVAL_US: u32 = 100;
+
+// strict realtime モードで確保を調べないブロック数。
+// コンパイル直後は JIT が生成するバイトコードや最適化されたコードもヒープに確保されるため除く。
+const STRICT_WARMUP_BLOCKS: u64 = 100;
+
+// フィールドは宣言順に drop される。inspector は context を参照するため、context より先に drop する。
 struct JsRuntimeContext {
+    inspector: Rc<RefCell<InspectorClient>>,
     context: v8::Global<v8::Context>,
-    _inspector: Option<Rc<RefCell<InspectorClient>>>,
     audio: v8::Global<v8::ArrayBuffer>,
-    audio_func: v8::Global<v8::Function>,
+    audio_func: Option<v8::Global<v8::Function>>,
     gui_func: v8::Global<v8::Function>,
+    voices: Option<JsVoices>,
+
+    // スクリプトの gcOptions と、strict realtime モードの警告の状態
+    gc_options: GcOptions,
+    blocks: u64,
+    allocation_warning: AllocationWarning,
 }
 
 #[derive(Debug, Error)]
@@ -33,24 +97,92 @@ pub enum JsRuntimeError {
     NotCompiled,
     #[error("unexpected error: {0}")]
     UnexpectedError(String),
+    #[error("{0}")]
+    EvaluationError(String),
+    #[error("paused in the debugger")]
+    Paused,
+    #[error("profiler: {0}")]
+    ProfilerError(String),
+    #[error("failed to draw gui: `{0}`")]
+    DrawError(String),
+    #[error("the script did not respond within {0:?}")]
+    Timeout(std::time::Duration),
+    #[error(
+        "audio() was stopped because it exceeded its time limit; recompile the script to resume"
+    )]
+    Stalled,
+}
+
+// V8 の初期化と、初期化の前に設定された若い世代の大きさ (MB)
+static V8_INIT: Once = Once::new();
+static YOUNG_GENERATION_SIZE: Mutex<Option<usize>> = Mutex::new(None);
+
+/// V8 の若い世代 (semi-space) の大きさ (MB) を設定する。大きくするとスカベンジの回数が減る。
+/// V8 のフラグは初期化後に変更できないため、プロセスで最初のランタイムを作る前に呼ぶ必要があり、
+/// プロセス内の全てのランタイムに適用される。既に初期化されていて反映できない場合は、警告を出力して false を返す。
+pub fn set_young_generation_size(megabytes: usize) -> bool {
+    let mut size = YOUNG_GENERATION_SIZE.lock().unwrap();
+    if V8_INIT.is_completed() {
+        eprintln!(
+            "the young generation size ({} MB) was ignored because V8 has already been initialized",
+            megabytes
+        );
+        return false;
+    }
+    *size = Some(megabytes);
+    true
+}
+
+fn initialize_v8() {
+    V8_INIT.call_once(|| {
+        // 初期化中に set_young_generation_size が呼ばれても、反映されたかどうかが正しく分かるようにロックしたまま初期化する
+        let size = YOUNG_GENERATION_SIZE.lock().unwrap();
+        if let Some(size) = *size {
+            gc::set_young_generation_size(size);
+        }
+        let platform = v8::new_default_platform(0, false).make_shared();
+        v8::V8::initialize_platform(platform);
+        v8::V8::initialize();
+    });
 }
 
 impl JsRuntimeBuilder {
     pub fn new() -> Self {
-        JsRuntimeBuilder { on_log: None }
+        JsRuntimeBuilder {
+            on_log: None,
+            state: None,
+            random_seed: None,
+            inspector: None,
+            code_cache: None,
+        }
     }
 
     pub fn build(self) -> JsRuntime {
-        static PUPPY_INIT: Once = Once::new();
-        PUPPY_INIT.call_once(move || {
-            let platform = v8::new_default_platform(0, false).make_shared();
-            v8::V8::initialize_platform(platform);
-            v8::V8::initialize();
-        });
-        let isolate = v8::Isolate::new(Default::default());
+        initialize_v8();
+        let mut isolate = v8::Isolate::new(Default::default());
+        let gc = GcTracker::install(&mut isolate);
+        let gc_baseline = gc::heap_used(&mut isolate);
+
+        // ps88.gcStats で参照する GC の統計
+        isolate.set_slot(gc.shared_stats());
+
+        // ps88.state の保存先
+        isolate.set_slot(self.state.unwrap_or_default());
+
+        // スクリプトとモジュールのコンパイルに使うコードキャッシュ
+        if let Some(code_cache) = self.code_cache {
+            isolate.set_slot(code_cache);
+        }
         JsRuntime {
             isolate,
             on_log: self.on_log,
+            dependencies: Vec::new(),
+            stdlib: None,
+            samples: SampleCache::new(),
+            random_seed: self.random_seed,
+            gc,
+            gc_baseline,
+            inspector: self.inspector.map(Rc::new),
         }
     }
 
@@ -58,15 +190,119 @@ impl JsRuntimeBuilder {
         self.on_log = Some(on_log);
         self
     }
+
+    /// スクリプトが ps88.state で読み書きするデータの保存先
+    pub fn state(mut self, state: Arc<Mutex<ScriptState>>) -> Self {
+        self.state = Some(state);
+        self
+    }
+
+    /// Math.random が返す値の列を固定する。オフラインでのレンダリングで、毎回同じ結果を得るために使う。
+    pub fn random_seed(mut self, seed: u32) -> Self {
+        self.random_seed = Some(seed);
+        self
+    }
+
+    /// Chrome DevTools から接続してデバッグできるようにする。
+    /// DevTools からのメッセージは audio の呼び出しごとに処理され、ブレークポイントで停止している間は audio から戻らない。
+    pub fn inspector(mut self, server: InspectorServer) -> Self {
+        self.inspector = Some(server);
+        self
+    }
+
+    /// 標準ライブラリ、スクリプト、import したモジュールのコンパイルに V8 のコードキャッシュを使う。
+    /// 同じソースコードを再びコンパイルする時 (プロジェクトを開き直した時や、同じスクリプトを使う別のインスタンス) に速くなる。
+    pub fn code_cache(mut self, cache: CodeCache) -> Self {
+        self.code_cache = Some(cache);
+        self
+    }
+}
+
+impl JsRuntime {
+    /// isolate を作ってからのガベージコレクションの統計
+    pub fn gc_stats(&self) -> GcStats {
+        self.gc.stats()
+    }
+
+    /// JavaScript のヒープの使用量 (バイト)
+    pub fn heap_used(&mut self) -> usize {
+        gc::heap_used(&mut self.isolate)
+    }
+
+    /// V8 のスレッドセーフなハンドル。他のスレッドから実行中のスクリプトを止めるのに使う。
+    pub(crate) fn thread_safe_handle(&self) -> v8::IsolateHandle {
+        self.isolate.thread_safe_handle()
+    }
+
+    // コンパイルしたスクリプトの状態を破棄する。
+    // DevTools のセッションは古い context のものなので、理由を通知して切断してから、
+    // inspector、context (JsRuntimeContext の宣言順)、モジュールとサンプルの loader の順に drop する。
+    fn clear_context(&mut self, reason: &str) {
+        if let Some(runtime_context) = self.isolate.get_slot::<Rc<RefCell<JsRuntimeContext>>>() {
+            let runtime_context = runtime_context.borrow();
+            runtime_context.inspector.borrow().detach(reason);
+        }
+        self.isolate.remove_slot::<Rc<RefCell<JsRuntimeContext>>>();
+        self.isolate.remove_slot::<Rc<RefCell<ModuleLoader>>>();
+        self.isolate.remove_slot::<Rc<RefCell<SampleLoader>>>();
+    }
+
+    /// isolate をこのスレッドの current にする。
+    /// isolate は作成時に enter され、drop 時に exit されるため、1 つのスレッドで複数のランタイムを使う場合は
+    /// 使わない間は exit し、使う時と drop する前に enter する必要がある。
+    ///
+    /// # Safety
+    /// enter と exit は対にして呼び、drop する時は current になっている必要がある。
+    pub(crate) unsafe fn enter(&mut self) {
+        self.isolate.enter();
+    }
+
+    /// isolate をこのスレッドの current から外す。
+    ///
+    /// # Safety
+    /// enter されている状態で呼ぶ必要がある。
+    pub(crate) unsafe fn exit(&mut self) {
+        self.isolate.exit();
+    }
+
+    /// スクリプトの gcOptions.schedule に従って GC を行う。GC を行った場合は true を返す。
+    /// audio の実行中に GC で止まらないように、ブロックの合間 (次のブロックを待っている間) に呼ぶ。
+    pub fn collect_garbage(&mut self) -> bool {
+        let Some(runtime_context) = self.isolate.get_slot::<Rc<RefCell<JsRuntimeContext>>>() else {
+            return false;
+        };
+        let options = runtime_context.borrow().gc_options;
+        gc::collect_between_blocks(&mut self.isolate, &options, &mut self.gc_baseline)
+    }
+
+    /// スクリプトで ps88.test を使って登録されたテストを実行する
+    pub fn run_tests(&mut self) -> runtime::Result<Vec<js_test::TestResult>> {
+        let Some(runtime_context) = self.isolate.get_slot::<Rc<RefCell<JsRuntimeContext>>>() else {
+            return Err(JsRuntimeError::NotCompiled.into());
+        };
+        let context = runtime_context.borrow().context.clone();
+        let scope = &mut v8::HandleScope::with_context(&mut self.isolate, &context);
+        Ok(js_test::run(scope))
+    }
+}
+
+// isolate の slot に残ったものは isolate の破棄時に順不同で drop されるため、
+// inspector と context を先に決まった順序で破棄する。isolate はこのスレッドの current になっている必要がある。
+impl Drop for JsRuntime {
+    fn drop(&mut self) {
+        self.clear_context("The runtime was shut down");
+        self.stdlib = None;
+    }
 }
 
 impl runtime::ScriptRuntime for JsRuntime {
-    fn compile(&mut self, code: &str) -> runtime::Result<()> {
+    fn compile(&mut self, code: &str, path: Option<&Path>) -> runtime::Result<()> {
         // MEMO:
         //   新しい inspector を作った後に set_slot で古い inspector を drop すると
         //   古い inspector のデストラクタが新しい inspector に影響して console.log
         //   の出力を得られなくなってしまうため、先にここで古いインスタンスを drop しておく。
-        self.isolate.remove_slot::<Rc<RefCell<JsRuntimeContext>>>();
+        self.clear_context("The script was recompiled");
+        self.dependencies.clear();
 
         let context = {
             let handle_scope = &mut v8::HandleScope::new(&mut self.isolate);
@@ -74,14 +310,11 @@ impl runtime::ScriptRuntime for JsRuntime {
             v8::Global::new(handle_scope, context)
         };
 
-        let on_log = self.on_log.clone();
-        let inspector = if let Some(on_log) = on_log {
+        // console.log の出力だけでなく、デバッガーと CPU プロファイラにも inspector を使う
+        let inspector = {
             let scope = &mut v8::HandleScope::with_context(&mut self.isolate, &context);
             let context = v8::Local::new(scope, &context);
-            let inspector = InspectorClient::new(scope, context, on_log)?;
-            Some(inspector)
-        } else {
-            None
+            InspectorClient::new(scope, context, self.on_log.clone(), self.inspector.clone())?
         };
 
         let audio = {
@@ -91,104 +324,208 @@ impl runtime::ScriptRuntime for JsRuntime {
             audio
         };
 
-        let (audio_func, gui_func) = {
+        // 標準ライブラリとネイティブの関数 (ps88.dsp, ps88.gcStats, ps88.loadSample, ps88.state, ps88.test) を読み込む
+        {
+            let scope = &mut v8::HandleScope::with_context(&mut self.isolate, &context);
+            load_stdlib(scope, &mut self.stdlib)?;
+            js_dsp::install(scope)?;
+            js_gc::install(scope)?;
+            js_sample::install(scope)?;
+            js_state::install(scope)?;
+            js_test::install(scope)?;
+            if let Some(seed) = self.random_seed {
+                seed_random(scope, seed)?;
+            }
+        }
+
+        // import されたモジュールと読み込まれたサンプルを管理するための loader を用意
+        let loader = ModuleLoader::new(path);
+        self.isolate.set_slot(loader.clone());
+        self.isolate
+            .set_slot(SampleLoader::new(self.samples.clone(), path));
+
+        let (audio_func, gui_func, voices, gc_options) = {
             let scope = &mut v8::HandleScope::with_context(&mut self.isolate, &context);
-            let Some(code) = v8::String::new(scope, code) else {
+            let name = path
+                .map(|p| p.to_string_lossy().to_string())
+                .unwrap_or(MAIN_SCRIPT_NAME.into());
+            let namespace = run_main(scope, &loader, code, &name, path);
+            self.dependencies = loader.borrow().dependencies().to_vec();
+            let namespace = namespace?;
+
+            // audio と gui は必須だが、voice を定義した場合は audio を省略できる
+            let audio_func = get_function(scope, "audio", namespace)?;
+            let gui_func = get_function(scope, "gui", namespace)?;
+            let voice_func = get_function(scope, "voice", namespace)?;
+            if audio_func.is_none() && voice_func.is_none() {
                 return Err(
-                    JsRuntimeError::UnexpectedError("failed to allocate string".into()).into(),
+                    JsRuntimeError::CompileError("'audio' function is not defined".into()).into(),
                 );
-            };
-            let funcs = {
-                let mut try_catch = v8::TryCatch::new(scope);
-                let Some(script) = v8::Script::compile(&mut try_catch, code, None) else {
-                    return Err(JsRuntimeError::CompileError(report_exceptions(try_catch)).into());
-                };
-                if script.run(&mut try_catch).is_none() {
-                    return Err(JsRuntimeError::CompileError(report_exceptions(try_catch)).into());
-                };
-
-                const EXPECTED_FUNCTION_NAMES: [&str; 2] = ["audio", "gui"];
-                let expected_functions: runtime::Result<Vec<v8::Local<v8::Function>>> =
-                    EXPECTED_FUNCTION_NAMES
-                        .iter()
-                        .map(|name| {
-                            let mut try_catch = v8::TryCatch::new(&mut try_catch);
-                            let Some(code) = v8::String::new(&mut try_catch, name) else {
-                                return Err(JsRuntimeError::CompileError(format!(
-                                    "failed to allocate string: '{}'",
-                                    name
-                                ))
-                                .into());
-                            };
-                            let Some(script) = v8::Script::compile(&mut try_catch, code, None)
-                            else {
-                                return Err(JsRuntimeError::CompileError(report_exceptions(
-                                    try_catch,
-                                ))
-                                .into());
-                            };
-                            let Some(variable) = script.run(&mut try_catch) else {
-                                return Err(JsRuntimeError::CompileError(report_exceptions(
-                                    try_catch,
-                                ))
-                                .into());
-                            };
-                            if variable.is_undefined() {
-                                return Err(JsRuntimeError::CompileError(format!(
-                                    "'{}' function is not defined",
-                                    name,
-                                ))
-                                .into());
-                            }
-                            let Ok(func) = v8::Local::<v8::Function>::try_from(variable) else {
-                                return Err(JsRuntimeError::CompileError(format!(
-                                    "'{}' is not a function",
-                                    name,
-                                ))
-                                .into());
-                            };
-                            return Ok(func);
-                        })
-                        .collect();
-                expected_functions
-            }?;
-            let funcs: Vec<v8::Global<v8::Function>> =
-                funcs.iter().map(|f| v8::Global::new(scope, *f)).collect();
-            let Some([audio_func, gui_func]) = funcs.get(0..2) else {
+            }
+            let Some(gui_func) = gui_func else {
                 return Err(
-                    JsRuntimeError::UnexpectedError("failed to get functions".into()).into(),
+                    JsRuntimeError::CompileError("'gui' function is not defined".into()).into(),
                 );
             };
-            (audio_func.clone(), gui_func.clone())
+            let voices = match voice_func {
+                Some(voice_func) => {
+                    let options = get_variable(scope, "voiceOptions", namespace)?
+                        .and_then(|options| options.to_object(scope));
+                    Some(JsVoices::new(scope, voice_func, options)?)
+                }
+                None => None,
+            };
+            let gc_options = get_variable(scope, "gcOptions", namespace)?
+                .and_then(|options| options.to_object(scope));
+            (
+                audio_func.map(|f| v8::Global::new(scope, f)),
+                v8::Global::new(scope, gui_func),
+                voices,
+                js_gc::parse_options(scope, gc_options),
+            )
         };
 
         let runtime_context = Rc::new(RefCell::new(JsRuntimeContext {
             context,
-            _inspector: inspector,
+            inspector,
             audio,
             audio_func,
             gui_func,
+            voices,
+            gc_options,
+            blocks: 0,
+            allocation_warning: AllocationWarning::default(),
         }));
         self.isolate.set_slot(runtime_context);
 
         Ok(())
     }
 
+    fn dependencies(&mut self) -> Vec<PathBuf> {
+        let mut dependencies = self.dependencies.clone();
+        if let Some(samples) = self.isolate.get_slot::<Rc<RefCell<SampleLoader>>>() {
+            for path in samples.borrow().paths() {
+                if !dependencies.contains(path) {
+                    dependencies.push(path.clone());
+                }
+            }
+        }
+        dependencies
+    }
+
+    fn evaluate(&mut self, code: &str) -> runtime::Result<String> {
+        let Some(runtime_context) = self.isolate.get_slot::<Rc<RefCell<JsRuntimeContext>>>() else {
+            return Err(JsRuntimeError::NotCompiled.into());
+        };
+        let context = runtime_context.borrow().context.clone();
+        let scope = &mut v8::HandleScope::with_context(&mut self.isolate, &context);
+        let mut try_catch = v8::TryCatch::new(scope);
+        let Some(source) = v8::String::new(&mut try_catch, code) else {
+            return Err(JsRuntimeError::UnexpectedError("failed to allocate string".into()).into());
+        };
+        let Some(origin) = js_module::script_origin(&mut try_catch, REPL_SCRIPT_NAME, false) else {
+            return Err(
+                JsRuntimeError::UnexpectedError("failed to create script origin".into()).into(),
+            );
+        };
+
+        // 通常のスクリプトとして実行するため、メインスクリプトのトップレベルの let / const も参照できる
+        // (ES Module として読み込まれたスクリプトの変数はモジュールのスコープにあるため参照できない)
+        let Some(script) = v8::Script::compile(&mut try_catch, source, Some(&origin)) else {
+            return Err(JsRuntimeError::EvaluationError(report_exceptions(try_catch)).into());
+        };
+        let Some(result) = script.run(&mut try_catch) else {
+            return Err(JsRuntimeError::EvaluationError(report_exceptions(try_catch)).into());
+        };
+
+        // Promise の場合は完了を待って結果を表示する
+        try_catch.perform_microtask_checkpoint();
+        let result = match v8::Local::<v8::Promise>::try_from(result) {
+            Ok(promise) => {
+                while matches!(promise.state(), v8::PromiseState::Pending) {
+                    if !js_sample::wait(&mut try_catch) {
+                        break;
+                    }
+                    try_catch.perform_microtask_checkpoint();
+                }
+                match promise.state() {
+                    v8::PromiseState::Fulfilled => promise.result(&mut try_catch),
+                    v8::PromiseState::Rejected => {
+                        let exception = promise.result(&mut try_catch);
+                        return Err(JsRuntimeError::EvaluationError(report_exception_value(
+                            &mut try_catch,
+                            exception,
+                        ))
+                        .into());
+                    }
+                    v8::PromiseState::Pending => result,
+                }
+            }
+            Err(_) => result,
+        };
+        Ok(inspect(&mut try_catch, result))
+    }
+
+    fn wait_samples(&mut self) {
+        let Some(runtime_context) = self.isolate.get_slot::<Rc<RefCell<JsRuntimeContext>>>() else {
+            return;
+        };
+        let context = runtime_context.borrow().context.clone();
+        let scope = &mut v8::HandleScope::with_context(&mut self.isolate, &context);
+        while js_sample::wait(scope) {}
+        scope.perform_microtask_checkpoint();
+    }
+
+    fn start_profiling(&mut self) -> runtime::Result<()> {
+        let Some(runtime_context) = self.isolate.get_slot::<Rc<RefCell<JsRuntimeContext>>>() else {
+            return Err(JsRuntimeError::NotCompiled.into());
+        };
+        let runtime_context = runtime_context.clone();
+        let runtime_context = runtime_context.borrow();
+        let _scope =
+            &mut v8::HandleScope::with_context(&mut self.isolate, &runtime_context.context);
+        let result = runtime_context.inspector.borrow().start_profiling();
+        Ok(result?)
+    }
+
+    fn stop_profiling(&mut self) -> runtime::Result<Profile> {
+        let Some(runtime_context) = self.isolate.get_slot::<Rc<RefCell<JsRuntimeContext>>>() else {
+            return Err(JsRuntimeError::NotCompiled.into());
+        };
+        let runtime_context = runtime_context.clone();
+        let runtime_context = runtime_context.borrow();
+        let _scope =
+            &mut v8::HandleScope::with_context(&mut self.isolate, &runtime_context.context);
+        let result = runtime_context.inspector.borrow().stop_profiling();
+        Ok(result?)
+    }
+
     fn audio(
         &mut self,
         audio: &mut [f32],
         ch: usize,
         sampling_rate: f32,
         midi: &[u8],
-    ) -> runtime::Result<()> {
+    ) -> runtime::Result<Vec<runtime::OutputEvent>> {
         let Some(runtime_context) = self.isolate.get_slot::<Rc<RefCell<JsRuntimeContext>>>() else {
             return Err(JsRuntimeError::NotCompiled.into());
         };
         let context = runtime_context.clone();
         let audio_func = context.borrow_mut().audio_func.clone();
+        let mut output = Vec::new();
         {
             let context = &mut *context.borrow_mut();
             let scope = &mut v8::HandleScope::with_context(&mut self.isolate, &context.context);
+
+            // DevTools からのメッセージを処理する
+            context.inspector.borrow().poll();
+
+            let strict = context.gc_options.strict && context.blocks >= STRICT_WARMUP_BLOCKS;
+            context.blocks += 1;
+
+            // 読み込みが終わったサンプルの Promise を解決する
+            js_sample::poll(scope);
+
             if v8::Local::new(scope, &context.audio).byte_length() != audio.len() * size_of::<f32>()
             {
                 let array = v8::ArrayBuffer::new(scope, audio.len() * size_of::<f32>());
@@ -196,13 +533,7 @@ impl runtime::ScriptRuntime for JsRuntime {
             }
             let audio_arr = v8::Local::new(scope, &context.audio);
             let midi_arr = v8::ArrayBuffer::new(scope, midi.len() * size_of::<f32>());
-            let audio_backing_store = audio_arr.get_backing_store();
             let midi_backing_store = midi_arr.get_backing_store();
-            if let Some(pointer) = audio_backing_store.data() {
-                unsafe {
-                    std::ptr::copy(audio.as_ptr(), pointer.as_ptr() as *mut f32, audio.len());
-                }
-            }
             if let Some(pointer) = midi_backing_store.data() {
                 unsafe {
                     std::ptr::copy(midi.as_ptr(), pointer.as_ptr() as *mut u8, midi.len());
@@ -224,13 +555,32 @@ impl runtime::ScriptRuntime for JsRuntime {
             let ch_key = v8::String::new(scope, "ch").unwrap();
             let sampling_rate_key = v8::String::new(scope, "sampling_rate").unwrap();
             let midi_key = v8::String::new(scope, "midi").unwrap();
-            let ch = v8::Integer::new(scope, ch as i32);
-            let sampling_rate = v8::Number::new(scope, sampling_rate as f64);
+            let ch_value = v8::Integer::new(scope, ch as i32);
+            let sampling_rate_value = v8::Number::new(scope, sampling_rate as f64);
             ctx.set(scope, audio_key.into(), audio_array_t.into());
-            ctx.set(scope, ch_key.into(), ch.into());
-            ctx.set(scope, sampling_rate_key.into(), sampling_rate.into());
+            ctx.set(scope, ch_key.into(), ch_value.into());
+            ctx.set(scope, sampling_rate_key.into(), sampling_rate_value.into());
             ctx.set(scope, midi_key.into(), midi_array_t.into());
 
+            // voice が定義されている場合は、各ボイスの出力を足し合わせたものが audio の入力になる
+            if let Some(voices) = context.voices.as_mut() {
+                output = voices.process(scope, audio, ch, sampling_rate, midi, ctx)?;
+            }
+
+            let Some(audio_func) = audio_func else {
+                return Ok(output);
+            };
+            let audio_backing_store = audio_arr.get_backing_store();
+            if let Some(pointer) = audio_backing_store.data() {
+                unsafe {
+                    std::ptr::copy(audio.as_ptr(), pointer.as_ptr() as *mut f32, audio.len());
+                }
+            }
+
+            // strict realtime モードでは、audio の呼び出しの前後でヒープの使用量を比べる
+            // (途中で GC が行われた場合は、解放された分を足して確保した量を求める)
+            let before = strict.then(|| (gc::heap_used(scope), self.gc.stats().reclaimed_bytes));
+
             let audio_func = v8::Local::new(scope, audio_func);
             let this = v8::undefined(scope).into();
             let _result = {
@@ -245,7 +595,17 @@ impl runtime::ScriptRuntime for JsRuntime {
                 }
             };
 
-            let audio_backing_store = audio_arr.get_backing_store();
+            if let Some((used, reclaimed)) = before {
+                let allocated = gc::heap_used(scope) as i64 - used as i64
+                    + (self.gc.stats().reclaimed_bytes - reclaimed) as i64;
+                if allocated > 0 {
+                    let warning = context.allocation_warning.record(allocated as usize);
+                    if let (Some(warning), Some(on_log)) = (warning, &self.on_log) {
+                        on_log(warning);
+                    }
+                }
+            }
+
             if let Some(pointer) = audio_backing_store.data() {
                 unsafe {
                     std::ptr::copy(
@@ -257,27 +617,85 @@ impl runtime::ScriptRuntime for JsRuntime {
             }
         }
 
-        Ok(())
+        Ok(output)
+    }
+
+    fn gui(&mut self, ctx: &GuiContext) -> runtime::Result<Vec<Shape>> {
+        let Some(runtime_context) = self.isolate.get_slot::<Rc<RefCell<JsRuntimeContext>>>() else {
+            return Err(JsRuntimeError::NotCompiled.into());
+        };
+        let (context, gui_func) = {
+            let runtime_context = runtime_context.borrow();
+            (
+                runtime_context.context.clone(),
+                runtime_context.gui_func.clone(),
+            )
+        };
+        let scope = &mut v8::HandleScope::with_context(&mut self.isolate, &context);
+        let json = serde_json::to_string(ctx)?;
+        let mut try_catch = v8::TryCatch::new(scope);
+        // 実行が停止された直後などは、文字列の作成やパースにも失敗する
+        let Some(json) = v8::String::new(&mut try_catch, &json) else {
+            return Err(JsRuntimeError::DrawError("failed to allocate string".into()).into());
+        };
+        let Some(ctx) = v8::json::parse(&mut try_catch, json) else {
+            return Err(JsRuntimeError::DrawError(report_exceptions(try_catch)).into());
+        };
+
+        let gui_func = v8::Local::new(&mut try_catch, gui_func);
+        let this = v8::undefined(&mut try_catch).into();
+        let Some(result) = gui_func.call(&mut try_catch, this, &[ctx]) else {
+            return Err(JsRuntimeError::DrawError(report_exceptions(try_catch)).into());
+        };
+
+        // 何も返さない場合は何も描かない
+        if result.is_null_or_undefined() {
+            return Ok(Vec::new());
+        }
+        let Some(json) = v8::json::stringify(&mut try_catch, result) else {
+            return Err(JsRuntimeError::DrawError(report_exceptions(try_catch)).into());
+        };
+        let json = json.to_rust_string_lossy(&mut try_catch);
+        Ok(gui::parse(&json)?)
     }
 }
 
 struct InspectorClient {
     v8_inspector_client: v8::inspector::V8InspectorClientBase,
+
+    // 接続中の DevTools のセッションと、CPU プロファイラのセッション
+    // (v8_inspector を参照するため、v8_inspector より先に drop する)
+    session: RefCell<Option<Box<InspectorSession>>>,
+    profiler: RefCell<Option<LocalSession>>,
+
     v8_inspector: Rc<RefCell<v8::UniquePtr<v8::inspector::V8Inspector>>>,
-    on_log: Rc<dyn Fn(String)>,
+    on_log: Option<Rc<dyn Fn(String)>>,
+    server: Option<Rc<InspectorServer>>,
+
+    // DevTools から再開を指示されたら true になる
+    quit_pause: Cell<bool>,
+
+    // 停止中に切断された場合、セッションは停止から抜けた後に破棄する
+    disconnected: Cell<bool>,
 }
 
 impl InspectorClient {
     fn new(
         scope: &mut v8::HandleScope,
         context: v8::Local<v8::Context>,
-        on_log: Rc<dyn Fn(String)>,
+        on_log: Option<Rc<dyn Fn(String)>>,
+        server: Option<Rc<InspectorServer>>,
     ) -> runtime::Result<Rc<RefCell<Self>>> {
         let v8_inspector_client = v8::inspector::V8InspectorClientBase::new::<Self>();
         let self__ = Rc::new(RefCell::new(Self {
             v8_inspector_client,
+            session: RefCell::new(None),
+            profiler: RefCell::new(None),
             v8_inspector: Default::default(),
             on_log,
+            server,
+            quit_pause: Cell::new(false),
+            disconnected: Cell::new(false),
         }));
         {
             // MEMO: self__ が drop される前に client が無効な参照になると segfault するので注意
@@ -290,9 +708,12 @@ impl InspectorClient {
             let aux_data = r#"{"isDefault": true}"#;
             let aux_data_view = v8::inspector::StringView::from(aux_data.as_bytes());
             match self_.v8_inspector.borrow_mut().as_mut() {
-                Some(v8_inspector) => {
-                    v8_inspector.context_created(context, 1, context_name, aux_data_view)
-                }
+                Some(v8_inspector) => v8_inspector.context_created(
+                    context,
+                    js_inspector::CONTEXT_GROUP_ID,
+                    context_name,
+                    aux_data_view,
+                ),
                 None => {
                     return Err(JsRuntimeError::UnexpectedError(
                         "failed to create inspector".into(),
@@ -304,6 +725,85 @@ impl InspectorClient {
 
         Ok(self__)
     }
+
+    // 停止していない時に DevTools から届いたメッセージを処理する
+    fn poll(&self) {
+        let Some(server) = self.server.clone() else {
+            return;
+        };
+        if self.disconnected.take() {
+            self.session.borrow_mut().take();
+        }
+        while let Some(event) = server.try_recv() {
+            match event {
+                InspectorEvent::Connected(sender) => {
+                    let mut v8_inspector = self.v8_inspector.borrow_mut();
+                    if let Some(v8_inspector) = v8_inspector.as_mut() {
+                        *self.session.borrow_mut() =
+                            Some(InspectorSession::connect(v8_inspector, sender));
+                    }
+                }
+                InspectorEvent::Message(message) => self.dispatch(&message),
+                InspectorEvent::Disconnected => {
+                    self.session.borrow_mut().take();
+                }
+            }
+        }
+    }
+
+    fn dispatch(&self, message: &str) {
+        // MEMO:
+        //   dispatch の中でブレークポイントに当たると run_message_loop_on_pause から再帰的に dispatch されるため、
+        //   session を borrow したまま呼び出さない。セッションの破棄は poll でのみ行うため、ポインタは無効にならない。
+        let session = self
+            .session
+            .borrow_mut()
+            .as_deref_mut()
+            .map(|session| session as *mut InspectorSession);
+        if let Some(session) = session {
+            unsafe { (*session).dispatch(message) };
+        }
+    }
+
+    // 既に記録中の場合は、それまでの記録を捨てて記録し直す
+    fn start_profiling(&self) -> Result<(), JsRuntimeError> {
+        self.profiler.borrow_mut().take();
+        let mut v8_inspector = self.v8_inspector.borrow_mut();
+        let Some(v8_inspector) = v8_inspector.as_mut() else {
+            return Err(JsRuntimeError::ProfilerError(
+                "inspector is not available".into(),
+            ));
+        };
+        let mut session = LocalSession::connect(v8_inspector);
+        let interval = serde_json::json!({ "interval": PROFILER_SAMPLING_INTERVAL_US });
+        session
+            .call("Profiler.enable", serde_json::json!({}))
+            .and_then(|_| session.call("Profiler.setSamplingInterval", interval))
+            .and_then(|_| session.call("Profiler.start", serde_json::json!({})))
+            .map_err(JsRuntimeError::ProfilerError)?;
+        *self.profiler.borrow_mut() = Some(session);
+        Ok(())
+    }
+
+    fn stop_profiling(&self) -> Result<Profile, JsRuntimeError> {
+        // 再コンパイルするとセッションも破棄される
+        let Some(mut session) = self.profiler.borrow_mut().take() else {
+            return Err(JsRuntimeError::ProfilerError(
+                "not recording (the script may have been recompiled)".into(),
+            ));
+        };
+        let mut result = session
+            .call("Profiler.stop", serde_json::json!({}))
+            .map_err(JsRuntimeError::ProfilerError)?;
+        Profile::from_json(result["profile"].take().to_string())
+            .map_err(|err| JsRuntimeError::ProfilerError(err.to_string()))
+    }
+
+    fn detach(&self, reason: &str) {
+        if let Some(session) = self.session.borrow_mut().take() {
+            session.detach(reason);
+        }
+    }
 }
 
 impl v8::inspector::V8InspectorClientImpl for InspectorClient {
@@ -334,21 +834,318 @@ impl v8::inspector::V8InspectorClientImpl for InspectorClient {
         _stack_trace: &mut v8::inspector::V8StackTrace,
     ) {
         // ログメッセージの出力
-        (self.on_log)(message.to_string());
+        if let Some(on_log) = &self.on_log {
+            on_log(message.to_string());
+        }
+    }
+
+    // ブレークポイントで停止している間、DevTools からのメッセージを処理し続ける。
+    // audio を呼び出したスレッドはここで止まるため、js_sync は停止中に無音を出力する。
+    fn run_message_loop_on_pause(&mut self, _context_group_id: i32) {
+        let Some(server) = self.server.clone() else {
+            return;
+        };
+        server.set_paused(true);
+        self.quit_pause.set(false);
+        while !self.quit_pause.get() {
+            match server.recv() {
+                Some(InspectorEvent::Message(message)) => self.dispatch(&message),
+                // 停止中は同時に 1 つしか接続されないため、新しい接続は来ない
+                Some(InspectorEvent::Connected(_)) => {}
+                // 切断されたら再開する
+                Some(InspectorEvent::Disconnected) | None => {
+                    self.disconnected.set(true);
+                    break;
+                }
+            }
+        }
+        server.set_paused(false);
+    }
+
+    fn quit_message_loop_on_pause(&mut self) {
+        self.quit_pause.set(true);
+    }
+}
+
+// 標準ライブラリを実行してグローバル変数 ps88 を定義する。
+// コンパイルは isolate ごとに 1 度だけ行い、以降はコンパイル結果を新しい context で使い回す。
+fn load_stdlib(
+    scope: &mut v8::HandleScope,
+    cache: &mut Option<v8::Global<v8::UnboundScript>>,
+) -> runtime::Result<()> {
+    let mut try_catch = v8::TryCatch::new(scope);
+    let unbound = match cache.as_ref() {
+        Some(unbound) => v8::Local::new(&mut try_catch, unbound),
+        None => {
+            let Some(unbound) =
+                code_cache::compile_script(&mut try_catch, stdlib::STDLIB, stdlib::STDLIB_NAME)
+            else {
+                return Err(JsRuntimeError::UnexpectedError(report_exceptions(try_catch)).into());
+            };
+            *cache = Some(v8::Global::new(&mut try_catch, unbound));
+            unbound
+        }
+    };
+    let script = unbound.bind_to_current_context(&mut try_catch);
+    if script.run(&mut try_catch).is_none() {
+        return Err(JsRuntimeError::UnexpectedError(report_exceptions(try_catch)).into());
+    }
+    Ok(())
+}
+
+// REPL に表示するために値を文字列に変換する
+// 文字列や配列、オブジェクトは JSON で表示し、それ以外は String(value) と同じ表示にする
+fn inspect(scope: &mut v8::HandleScope, value: v8::Local<v8::Value>) -> String {
+    let scope = &mut v8::TryCatch::new(scope);
+    let json = value.is_string()
+        || (value.is_object()
+            && !value.is_function()
+            && !value.is_array_buffer_view()
+            && !value.is_map()
+            && !value.is_set());
+    let text = if json {
+        // 循環参照などで JSON に変換できない場合は String(value) にする
+        v8::json::stringify(scope, value).map(|json| json.to_rust_string_lossy(scope))
+    } else {
+        None
+    };
+    let text = text.or_else(|| {
+        let text = value.to_string(scope)?.to_rust_string_lossy(scope);
+        // e.g. Float32Array [0,0.5,1]
+        if value.is_array_buffer_view() {
+            let name = value.to_object(scope)?.get_constructor_name();
+            return Some(format!("{} [{}]", name.to_rust_string_lossy(scope), text));
+        }
+        Some(text)
+    });
+    // Symbol などは String(value) で例外になる
+    let text = text.unwrap_or_else(|| value.type_repr().to_string());
+    match text.char_indices().nth(REPL_RESULT_LIMIT) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text,
+    }
+}
+
+// Math.random をシード付きの疑似乱数 (mulberry32) に置き換える
+fn seed_random(scope: &mut v8::HandleScope, seed: u32) -> runtime::Result<()> {
+    let code = format!(
+        r#"
+        (() => {{
+            let state = {};
+            Math.random = () => {{
+                state = (state + 0x6d2b79f5) >>> 0;
+                let t = state;
+                t = Math.imul(t ^ (t >>> 15), t | 1);
+                t ^= t + Math.imul(t ^ (t >>> 7), t | 61);
+                return ((t ^ (t >>> 14)) >>> 0) / 4294967296;
+            }};
+        }})();
+        "#,
+        seed
+    );
+    let mut try_catch = v8::TryCatch::new(scope);
+    let Some(code) = v8::String::new(&mut try_catch, &code) else {
+        return Err(JsRuntimeError::UnexpectedError("failed to allocate string".into()).into());
+    };
+    let Some(script) = v8::Script::compile(&mut try_catch, code, None) else {
+        return Err(JsRuntimeError::UnexpectedError(report_exceptions(try_catch)).into());
+    };
+    if script.run(&mut try_catch).is_none() {
+        return Err(JsRuntimeError::UnexpectedError(report_exceptions(try_catch)).into());
+    }
+    Ok(())
+}
+
+// メインスクリプトを実行する。
+// import/export を含むなどの理由で通常のスクリプトとしてコンパイルできない場合は ES Module として実行し、
+// モジュールの名前空間オブジェクトを返す。
+fn run_main<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    loader: &Rc<RefCell<ModuleLoader>>,
+    code: &str,
+    name: &str,
+    path: Option<&Path>,
+) -> runtime::Result<Option<v8::Local<'s, v8::Object>>> {
+    // 通常のスクリプトとして実行
+    {
+        let mut try_catch = v8::TryCatch::new(scope);
+        if let Some(unbound) = code_cache::compile_script(&mut try_catch, code, name) {
+            let script = unbound.bind_to_current_context(&mut try_catch);
+            if script.run(&mut try_catch).is_none() {
+                return Err(JsRuntimeError::CompileError(report_exceptions(try_catch)).into());
+            }
+            return Ok(None);
+        }
+    }
+
+    // ES Module として実行
+    let mut try_catch = v8::TryCatch::new(scope);
+    let Some(module) = code_cache::compile_module(&mut try_catch, code, name) else {
+        return Err(JsRuntimeError::CompileError(report_exceptions(try_catch)).into());
+    };
+    loader.borrow_mut().register(&mut try_catch, module, path);
+    if module
+        .instantiate_module(&mut try_catch, js_module::resolve_module)
+        .is_none()
+    {
+        return Err(JsRuntimeError::CompileError(report_exceptions(try_catch)).into());
+    }
+    let Some(result) = module.evaluate(&mut try_catch) else {
+        return Err(JsRuntimeError::CompileError(report_exceptions(try_catch)).into());
+    };
+
+    // top-level await を含むモジュールは Promise を返すので、完了を待つ
+    // ps88.loadSample を待っている場合は、読み込みが終わるたびに処理を進める
+    try_catch.perform_microtask_checkpoint();
+    if let Ok(promise) = v8::Local::<v8::Promise>::try_from(result) {
+        while matches!(promise.state(), v8::PromiseState::Pending) {
+            if !js_sample::wait(&mut try_catch) {
+                break;
+            }
+        }
+        match promise.state() {
+            v8::PromiseState::Fulfilled => {}
+            v8::PromiseState::Rejected => {
+                let exception = promise.result(&mut try_catch);
+                return Err(JsRuntimeError::CompileError(report_exception_value(
+                    &mut try_catch,
+                    exception,
+                ))
+                .into());
+            }
+            v8::PromiseState::Pending => {
+                return Err(JsRuntimeError::CompileError(
+                    "top-level await did not complete".into(),
+                )
+                .into());
+            }
+        }
+    }
+
+    let Some(namespace) = module.get_module_namespace().to_object(&mut try_catch) else {
+        return Err(
+            JsRuntimeError::UnexpectedError("failed to get module namespace".into()).into(),
+        );
+    };
+    Ok(Some(namespace))
+}
+
+// スクリプトで定義された変数を取得する。定義されていない場合は None を返す。
+// ES Module の場合は export されたものを優先し、無ければグローバルから探す。
+fn get_variable<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    name: &str,
+    namespace: Option<v8::Local<'s, v8::Object>>,
+) -> runtime::Result<Option<v8::Local<'s, v8::Value>>> {
+    let mut try_catch = v8::TryCatch::new(scope);
+    let Some(key) = v8::String::new(&mut try_catch, name) else {
+        return Err(
+            JsRuntimeError::CompileError(format!("failed to allocate string: '{}'", name)).into(),
+        );
+    };
+    let exported = namespace
+        .and_then(|namespace| namespace.get(&mut try_catch, key.into()))
+        .filter(|variable| !variable.is_undefined());
+    if exported.is_some() {
+        return Ok(exported);
     }
+
+    // 定義されていない変数を参照すると ReferenceError になるので typeof で確認する
+    let code = format!("typeof {0} === 'undefined' ? undefined : {0}", name);
+    let Some(code) = v8::String::new(&mut try_catch, &code) else {
+        return Err(
+            JsRuntimeError::CompileError(format!("failed to allocate string: '{}'", name)).into(),
+        );
+    };
+    let Some(script) = v8::Script::compile(&mut try_catch, code, None) else {
+        return Err(JsRuntimeError::CompileError(report_exceptions(try_catch)).into());
+    };
+    let Some(variable) = script.run(&mut try_catch) else {
+        return Err(JsRuntimeError::CompileError(report_exceptions(try_catch)).into());
+    };
+    if variable.is_undefined() {
+        return Ok(None);
+    }
+    Ok(Some(variable))
+}
+
+// スクリプトで定義された関数を取得する。定義されていない場合は None を返す。
+fn get_function<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    name: &str,
+    namespace: Option<v8::Local<'s, v8::Object>>,
+) -> runtime::Result<Option<v8::Local<'s, v8::Function>>> {
+    let Some(variable) = get_variable(scope, name, namespace)? else {
+        return Ok(None);
+    };
+    let Ok(func) = v8::Local::<v8::Function>::try_from(variable) else {
+        return Err(JsRuntimeError::CompileError(format!("'{}' is not a function", name)).into());
+    };
+    Ok(Some(func))
+}
+
+// 標準ライブラリが定義したグローバル変数 ps88 を取得する
+pub(crate) fn get_ps88<'s>(scope: &mut v8::HandleScope<'s>) -> Option<v8::Local<'s, v8::Object>> {
+    let global = scope.get_current_context().global(scope);
+    let key = v8::String::new(scope, "ps88")?;
+    global.get(scope, key.into())?.to_object(scope)
+}
+
+// Rust で実装した関数を object に登録する
+pub(crate) fn set_function(
+    scope: &mut v8::HandleScope,
+    object: v8::Local<v8::Object>,
+    name: &str,
+    callback: impl v8::MapFnTo<v8::FunctionCallback>,
+) -> Option<()> {
+    let key = v8::String::new(scope, name)?;
+    let function = v8::FunctionTemplate::new(scope, callback).get_function(scope)?;
+    function.set_name(key);
+    object.set(scope, key.into(), function.into())?;
+    Some(())
 }
 
 // TryCatch からエラー情報を文字列に変換する
-fn report_exceptions(mut try_catch: v8::TryCatch<v8::HandleScope>) -> String {
-    let mut description = Vec::<String>::new();
+pub(crate) fn report_exceptions(mut try_catch: v8::TryCatch<v8::HandleScope>) -> String {
+    // 制限時間を超えてワーカーに止められた場合
+    if try_catch.has_terminated() {
+        return "execution terminated".into();
+    }
     let Some(exception) = try_catch.exception() else {
         return "no error".into();
     };
-    let Some(exception_string) = exception.to_string(&mut try_catch) else {
+    let message = try_catch.message();
+    let stack_trace = try_catch.stack_trace();
+    describe_exception(&mut try_catch, exception, message, stack_trace)
+}
+
+// Promise の reject などで TryCatch を経由せずに得た例外を文字列に変換する
+pub(crate) fn report_exception_value(
+    scope: &mut v8::HandleScope,
+    exception: v8::Local<v8::Value>,
+) -> String {
+    let message = v8::Exception::create_message(scope, exception);
+    let stack_trace = exception.to_object(scope).and_then(|exception| {
+        let key = v8::String::new(scope, "stack")?;
+        exception
+            .get(scope, key.into())
+            .filter(|stack| !stack.is_undefined())
+    });
+    describe_exception(scope, exception, Some(message), stack_trace)
+}
+
+fn describe_exception(
+    scope: &mut v8::HandleScope,
+    exception: v8::Local<v8::Value>,
+    message: Option<v8::Local<v8::Message>>,
+    stack_trace: Option<v8::Local<v8::Value>>,
+) -> String {
+    let mut description = Vec::<String>::new();
+    let Some(exception_string) = exception.to_string(scope) else {
         return "unexpected error".into();
     };
-    let exception_string = exception_string.to_rust_string_lossy(&mut try_catch);
-    let Some(message) = try_catch.message() else {
+    let exception_string = exception_string.to_rust_string_lossy(scope);
+    let Some(message) = message else {
         return exception_string;
     };
 
@@ -356,12 +1153,12 @@ fn report_exceptions(mut try_catch: v8::TryCatch<v8::HandleScope>) -> String {
     // e.g.
     //   main.js:5: SyntaxError: Unexpected token '=='
     let filename = message
-        .get_script_resource_name(&mut try_catch)
-        .and_then(|s| s.to_string(&mut try_catch))
-        .map(|s| s.to_rust_string_lossy(&mut try_catch))
+        .get_script_resource_name(scope)
+        .and_then(|s| s.to_string(scope))
+        .map(|s| s.to_rust_string_lossy(scope))
         .unwrap_or("(unknown)".into());
     let line_number = message
-        .get_line_number(&mut try_catch)
+        .get_line_number(scope)
         .map(|n| n.to_string())
         .unwrap_or("(unknown)".into());
     description.push(format!(
@@ -373,30 +1170,29 @@ fn report_exceptions(mut try_catch: v8::TryCatch<v8::HandleScope>) -> String {
     // e.g.
     //   let a == 1;
     //         ^^
-    if let Some(source_line) = message.get_source_line(&mut try_catch) {
-        let source_line = source_line.to_rust_string_lossy(&mut try_catch);
+    if let Some(source_line) = message.get_source_line(scope) {
+        let source_line = source_line.to_rust_string_lossy(scope);
         let start_column = message.get_start_column();
         let end_column = message.get_end_column();
         description.push(format!(
             "\n{}\n{}{}\n",
             source_line,
             " ".repeat(start_column),
-            "^".repeat(end_column - start_column)
+            "^".repeat(end_column.saturating_sub(start_column))
         ));
     }
 
     // スタックトレースを出力
     // e.g.
     //   Error: aaa
-    //       at f3 (<anonymous>:4:26)
-    //       at f2 (<anonymous>:3:20)
-    //       at f1 (<anonymous>:2:20)
-    //       at main (<anonymous>:1:22)
-    //       at <anonymous>:5:1
-    if let Some(stack_trace) = try_catch
-        .stack_trace()
-        .and_then(|s| s.to_string(&mut try_catch))
-        .map(|s| s.to_rust_string_lossy(&mut try_catch))
+    //       at f3 (main.js:4:26)
+    //       at f2 (main.js:3:20)
+    //       at f1 (main.js:2:20)
+    //       at main (main.js:1:22)
+    //       at main.js:5:1
+    if let Some(stack_trace) = stack_trace
+        .and_then(|s| s.to_string(scope))
+        .map(|s| s.to_rust_string_lossy(scope))
     {
         description.push(format!("{}", stack_trace));
     }
@@ -407,6 +1203,7 @@ fn report_exceptions(mut try_catch: v8::TryCatch<v8::HandleScope>) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::runtime::midi::{self, MidiEvent};
     use crate::runtime::runtime;
 
     #[test]
@@ -443,6 +1240,7 @@ mod tests {
                 "#
                     .replace("${i}", &i.to_string())
                     .as_str(),
+                    None,
                 )
                 .unwrap();
 
@@ -483,15 +1281,15 @@ mod tests {
             Box::new(JsRuntimeBuilder::new().build());
 
         // 不正な構文
-        let result = runtime.compile("let a == 1;");
+        let result = runtime.compile("let a == 1;", None);
         assert!(result.is_err());
 
         // 関数を返す前に例外
-        let result = runtime.compile("new Error('aaa');");
+        let result = runtime.compile("new Error('aaa');", None);
         assert!(result.is_err());
 
         // 返される値が関数でない
-        let result = runtime.compile("undefined;");
+        let result = runtime.compile("undefined;", None);
         assert!(result.is_err());
     }
 
@@ -509,10 +1307,386 @@ mod tests {
                 };
                 const gui = () => {};
             "#,
+            None,
         );
         assert!(result.is_ok());
         let mut audio: Vec<f32> = (0..100).map(|x| x as f32).collect();
         let result = runtime.audio(&mut audio, 2, 48000.0, &[]);
         assert!(result.is_err());
     }
+
+    #[test]
+    fn voice() {
+        let mut runtime: Box<dyn runtime::ScriptRuntime> =
+            Box::new(JsRuntimeBuilder::new().build());
+        runtime
+            .compile(
+                r#"
+                "use strict";
+                const voiceOptions = { maxVoices: 2 };
+                const voice = (v, out, ctx) => {
+                    out.fill(1);
+                    if (v.released) {
+                        v.free();
+                    }
+                };
+                const audio = (ctx) => {
+                    for (let i = 0; i < ctx.audio.length; i++) {
+                        ctx.audio[i] *= 0.5;
+                    }
+                };
+                const gui = () => {};
+            "#,
+                None,
+            )
+            .unwrap();
+        let note_on = |timing, note| MidiEvent::new(timing, midi::NOTE_ON, 0, note, 100).encode();
+        let note_off = |timing, note| MidiEvent::new(timing, midi::NOTE_OFF, 0, note, 0).encode();
+
+        // Note On の時刻から発音される
+        let mut audio = vec![0.0; 16];
+        let events = runtime
+            .audio(&mut audio, 1, 48000.0, &note_on(4, 60))
+            .unwrap();
+        assert_eq!(&audio[0..4], &[0.0; 4]);
+        assert_eq!(&audio[4..16], &[0.5; 12]);
+        assert!(events.is_empty());
+
+        // 最大ボイス数を超えると最も古いボイスが止まる
+        let midi = [note_on(0, 62), note_on(8, 64)].concat();
+        let events = runtime.audio(&mut audio, 1, 48000.0, &midi).unwrap();
+        assert_eq!(&audio[0..16], &[1.0; 16]);
+        assert_eq!(
+            events,
+            vec![runtime::OutputEvent::VoiceTerminated {
+                timing: 8,
+                channel: 0,
+                note: 60
+            }]
+        );
+
+        // スクリプトが v.free() を呼ぶとボイスが終了する
+        let events = runtime
+            .audio(&mut audio, 1, 48000.0, &note_off(4, 62))
+            .unwrap();
+        assert_eq!(&audio[0..16], &[1.0; 16]);
+        assert_eq!(
+            events,
+            vec![runtime::OutputEvent::VoiceTerminated {
+                timing: 15,
+                channel: 0,
+                note: 62
+            }]
+        );
+        let events = runtime.audio(&mut audio, 1, 48000.0, &[]).unwrap();
+        assert_eq!(&audio[0..16], &[0.5; 16]);
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn module() {
+        // import されるファイルを用意
+        let dir = std::env::temp_dir().join("ps88_js_module_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+        std::fs::write(
+            dir.join("lib/gain.js"),
+            "export const gain = (x) => x * 2.0;",
+        )
+        .unwrap();
+        std::fs::write(dir.join("lib/broken.js"), "export const = 1;").unwrap();
+        let main = dir.join("main.js");
+
+        let mut runtime: Box<dyn runtime::ScriptRuntime> =
+            Box::new(JsRuntimeBuilder::new().build());
+
+        // スクリプトのディレクトリからの相対パスで import できる
+        runtime
+            .compile(
+                r#"
+                import { gain } from "./lib/gain.js";
+                export const audio = (ctx) => {
+                    for (let i = 0; i < ctx.audio.length; i++) {
+                        ctx.audio[i] = gain(ctx.audio[i]);
+                    }
+                };
+                export const gui = () => {};
+            "#,
+                Some(&main),
+            )
+            .unwrap();
+        let mut audio: Vec<f32> = (0..100).map(|x| x as f32).collect();
+        runtime.audio(&mut audio, 2, 48000.0, &[]).unwrap();
+        assert_eq!(
+            audio,
+            (0..100).map(|x| (x * 2) as f32).collect::<Vec<f32>>()
+        );
+        assert_eq!(
+            runtime.dependencies(),
+            vec![std::fs::canonicalize(dir.join("lib/gain.js")).unwrap()]
+        );
+
+        // import 先のエラーはそのファイル名で報告される
+        let result = runtime.compile(
+            r#"
+                import "./lib/broken.js";
+                export const audio = () => {};
+                export const gui = () => {};
+            "#,
+            Some(&main),
+        );
+        assert!(result.unwrap_err().to_string().contains("broken.js"));
+
+        // 存在しないファイルも依存ファイルとして記録される
+        let result = runtime.compile(
+            r#"
+                import "./lib/missing.js";
+                export const audio = () => {};
+                export const gui = () => {};
+            "#,
+            Some(&main),
+        );
+        assert!(result.is_err());
+        assert_eq!(runtime.dependencies(), vec![dir.join("lib/missing.js")]);
+
+        // ファイルパスが無い場合は相対パスの import はできない
+        let result = runtime.compile(
+            r#"
+                import { gain } from "./lib/gain.js";
+                export const audio = () => {};
+                export const gui = () => {};
+            "#,
+            None,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn evaluate() {
+        let mut runtime: Box<dyn runtime::ScriptRuntime> =
+            Box::new(JsRuntimeBuilder::new().build());
+        assert!(runtime.evaluate("1 + 1").is_err());
+        runtime
+            .compile(
+                r#"
+                let gain = 1;
+                const keys = new Map([[60, 1]]);
+                const audio = (ctx) => {
+                    ctx.audio.fill(gain);
+                };
+                const gui = () => {};
+            "#,
+                None,
+            )
+            .unwrap();
+
+        // トップレベルの変数を読み書きでき、audio の出力に反映される
+        assert_eq!(runtime.evaluate("keys.size").unwrap(), "1");
+        assert_eq!(runtime.evaluate("gain = 0.5").unwrap(), "0.5");
+        let mut audio = vec![0.0; 2];
+        runtime.audio(&mut audio, 1, 48000.0, &[]).unwrap();
+        assert_eq!(audio, vec![0.5, 0.5]);
+
+        // 評価結果の表示
+        assert_eq!(runtime.evaluate("'a'").unwrap(), "\"a\"");
+        assert_eq!(
+            runtime.evaluate("({ a: [1, 2] })").unwrap(),
+            r#"{"a":[1,2]}"#
+        );
+        assert_eq!(
+            runtime.evaluate("new Float32Array([0, 0.5])").unwrap(),
+            "Float32Array [0,0.5]"
+        );
+        assert_eq!(runtime.evaluate("undefined").unwrap(), "undefined");
+        assert!(runtime.evaluate("Symbol('s')").is_ok());
+        assert_eq!(runtime.evaluate("Promise.resolve(3)").unwrap(), "3");
+
+        // REPL で宣言した変数は次の評価でも使える
+        runtime.evaluate("let x = 2").unwrap();
+        assert_eq!(runtime.evaluate("x * 2").unwrap(), "4");
+
+        // エラー
+        let error = runtime.evaluate("missing").unwrap_err().to_string();
+        assert!(error.contains("ReferenceError"), "{}", error);
+        assert!(runtime.evaluate("Promise.reject(new Error('no'))").is_err());
+        assert!(runtime.evaluate("1 +").is_err());
+    }
+
+    #[test]
+    fn profile() {
+        let mut runtime: Box<dyn runtime::ScriptRuntime> =
+            Box::new(JsRuntimeBuilder::new().build());
+        runtime
+            .compile(
+                r#"
+                const heavy = (audio) => {
+                    for (let n = 0; n < 200; n++) {
+                        for (let i = 0; i < audio.length; i++) {
+                            audio[i] = Math.sin(audio[i] + i * n);
+                        }
+                    }
+                };
+                const audio = (ctx) => heavy(ctx.audio);
+                const gui = () => {};
+            "#,
+                None,
+            )
+            .unwrap();
+        assert!(runtime.stop_profiling().is_err());
+
+        runtime.start_profiling().unwrap();
+        let mut audio = vec![0.0; 512];
+        for _ in 0..100 {
+            runtime.audio(&mut audio, 2, 48000.0, &[]).unwrap();
+        }
+        let profile = runtime.stop_profiling().unwrap();
+
+        // 処理時間のほとんどは heavy で使われる
+        let functions = profile.top_functions();
+        let heavy = functions.iter().find(|f| f.name == "heavy").unwrap();
+        assert!(heavy.total_ratio > 0.5, "{:?}", functions);
+        assert_eq!(heavy.line, 2);
+
+        // 記録は 1 度しか取り出せない
+        assert!(runtime.stop_profiling().is_err());
+    }
+
+    #[test]
+    fn gc() {
+        use runtime::ScriptRuntime;
+
+        let logs = Rc::new(RefCell::<Vec<String>>::new(vec![]));
+        let logs_clone = logs.clone();
+        let mut runtime = JsRuntimeBuilder::new()
+            .on_log(Rc::new(move |log| logs_clone.borrow_mut().push(log)))
+            .build();
+        runtime
+            .compile(
+                r#"
+                const gcOptions = { schedule: "lowMemory", threshold: 0, strict: true };
+                let notes = [];
+                const audio = (ctx) => {
+                    notes = Array.from(ctx.audio, (x) => ({ value: x }));
+                };
+                const gui = () => {};
+            "#,
+                None,
+            )
+            .unwrap();
+
+        // strict realtime モードでは audio でヒープに確保すると警告する (1 秒に 1 回まで)
+        let mut audio = vec![0.0; 256];
+        for _ in 0..STRICT_WARMUP_BLOCKS + 10 {
+            runtime.audio(&mut audio, 2, 48000.0, &[]).unwrap();
+        }
+        let warnings = logs
+            .borrow()
+            .iter()
+            .filter(|log| log.starts_with("strict realtime:"))
+            .count();
+        assert_eq!(warnings, 1);
+
+        // ブロックの合間の GC は ps88.gcStats に反映される
+        let count = runtime.gc_stats().count;
+        assert!(runtime.collect_garbage());
+        assert!(runtime.gc_stats().count > count);
+        let stats = runtime.evaluate("ps88.gcStats()").unwrap();
+        for key in [
+            "count",
+            "pauseMs",
+            "maxPauseMs",
+            "reclaimedBytes",
+            "heapUsedBytes",
+        ] {
+            assert!(stats.contains(key), "{}", stats);
+        }
+        assert_eq!(
+            runtime
+                .evaluate("ps88.gcStats().count > 0 && ps88.gcStats().heapUsedBytes > 0")
+                .unwrap(),
+            "true"
+        );
+    }
+
+    #[test]
+    fn young_generation_size_after_initialization() {
+        // V8 の初期化後は反映できないので、設定したように見せずに false を返す
+        let _runtime = JsRuntimeBuilder::new().build();
+        assert!(!set_young_generation_size(32));
+        assert_eq!(*YOUNG_GENERATION_SIZE.lock().unwrap(), None);
+    }
+
+    #[test]
+    fn gui() {
+        use runtime::ScriptRuntime;
+
+        let mut runtime = JsRuntimeBuilder::new().build();
+        runtime
+            .compile(
+                r##"
+                let shapes = (ctx) => [
+                    { type: "rect", x: 0, y: 0, width: ctx.width, height: ctx.height, fill: "#000" },
+                    { type: "text", x: ctx.width / 2, y: 0, text: ctx.input.hover ?? "", align: "center" },
+                ];
+                const audio = () => {};
+                const gui = (ctx) => shapes(ctx);
+            "##,
+                None,
+            )
+            .unwrap();
+
+        // ctx には領域の大きさと入力が入る
+        let mut ctx = GuiContext {
+            width: 200.0,
+            height: 100.0,
+            ..Default::default()
+        };
+        ctx.input.hover = Some("knob".to_string());
+        let shapes = runtime.gui(&ctx).unwrap();
+        assert_eq!(shapes.len(), 2);
+        let Shape::Rect { width, height, .. } = &shapes[0] else {
+            panic!("{:?}", shapes[0]);
+        };
+        assert_eq!((*width, *height), (200.0, 100.0));
+        let Shape::Text { x, text, .. } = &shapes[1] else {
+            panic!("{:?}", shapes[1]);
+        };
+        assert_eq!((*x, text.as_str()), (100.0, "knob"));
+
+        // 入力のフィールドはキャメルケースで、押されていないボタンや無い値も含まれる
+        runtime
+            .evaluate("shapes = (ctx) => (globalThis.input = ctx.input, [])")
+            .unwrap();
+        runtime.gui(&ctx).unwrap();
+        assert_eq!(
+            runtime
+                .evaluate("input.pointer === null && input.buttons.primary === false && input.keys.length === 0 && 'modifiers' in input")
+                .unwrap(),
+            "true"
+        );
+
+        // 何も返さない場合は何も描かない
+        runtime.evaluate("shapes = () => {}").unwrap();
+        assert_eq!(runtime.gui(&ctx).unwrap(), vec![]);
+
+        // 不正な図形や例外はエラーになる
+        runtime
+            .evaluate(r#"shapes = () => [{ type: "star" }]"#)
+            .unwrap();
+        assert!(runtime.gui(&ctx).is_err());
+        runtime
+            .evaluate(r#"shapes = () => { throw new Error("gui"); }"#)
+            .unwrap();
+        assert!(runtime.gui(&ctx).is_err());
+
+        // 実行が停止されている間もパニックせずにエラーを返し、再開すれば描ける
+        runtime.evaluate("shapes = () => []").unwrap();
+        runtime.isolate.thread_safe_handle().terminate_execution();
+        assert!(runtime.gui(&ctx).is_err());
+        runtime
+            .isolate
+            .thread_safe_handle()
+            .cancel_terminate_execution();
+        assert_eq!(runtime.gui(&ctx).unwrap(), vec![]);
+    }
 }
@@ -0,0 +1,484 @@
+use crate::runtime::dsp;
+use crate::runtime::js::{get_ps88, set_function, JsRuntimeError};
+use crate::runtime::runtime;
+use v8;
+
+/// Rust で実装した信号処理関数を ps88.dsp としてスクリプトに公開する。
+/// 全ての関数は引数の Float32Array を直接書き換え、最初の引数をそのまま返す。
+///
+/// e.g.
+///   ps88.dsp.add(dst, src)                              // dst[i] += src[i] (src は数値でもよい)
+///   ps88.dsp.mul(dst, src)                              // dst[i] *= src[i] (src は数値でもよい)
+///   ps88.dsp.mix(dst, src, gain)                        // dst[i] += src[i] * gain
+///   ps88.dsp.oscBank(out, freqs, phases, amps, sampleRate) // 正弦波の和で out を上書きし、phases を進める
+///   ps88.dsp.biquad(buffer, coeffs, state)                 // coeffs は [b0, b1, b2, a1, a2]、state は長さ 2
+///   ps88.dsp.fft(re, im) / ps88.dsp.ifft(re, im)           // 長さは 2 のべき乗
+///   ps88.dsp.convolve(buffer, ir, history)                 // history は長さ ir.length - 1 以上
+///   ps88.dsp.resample(dst, src)                            // src 全体を dst の長さに線形補間する
+pub fn install(scope: &mut v8::HandleScope) -> runtime::Result<()> {
+    let error = || JsRuntimeError::UnexpectedError("failed to install ps88.dsp".into());
+    let ps88 = get_ps88(scope).ok_or_else(error)?;
+    let dsp = v8::Object::new(scope);
+    set_function(scope, dsp, "add", add).ok_or_else(error)?;
+    set_function(scope, dsp, "mul", mul).ok_or_else(error)?;
+    set_function(scope, dsp, "mix", mix).ok_or_else(error)?;
+    set_function(scope, dsp, "oscBank", osc_bank).ok_or_else(error)?;
+    set_function(scope, dsp, "biquad", biquad).ok_or_else(error)?;
+    set_function(scope, dsp, "fft", fft).ok_or_else(error)?;
+    set_function(scope, dsp, "ifft", ifft).ok_or_else(error)?;
+    set_function(scope, dsp, "convolve", convolve).ok_or_else(error)?;
+    set_function(scope, dsp, "resample", resample).ok_or_else(error)?;
+    let key = v8::String::new(scope, "dsp").ok_or_else(error)?;
+    ps88.set(scope, key.into(), dsp.into());
+    Ok(())
+}
+
+// Float32Array が指すメモリ領域
+#[derive(Clone, Copy)]
+struct View {
+    ptr: *mut f32,
+    len: usize,
+}
+
+impl View {
+    fn overlaps(&self, other: &View) -> bool {
+        let (a, b) = (self.ptr as usize, other.ptr as usize);
+        let (a_end, b_end) = (a + self.len * 4, b + other.len * 4);
+        self.len > 0 && other.len > 0 && a < b_end && b < a_end
+    }
+
+    // コールバックの実行中は JS が動かないため、引数の ArrayBuffer が解放/移動されることはない
+    unsafe fn as_slice<'a>(&self) -> &'a [f32] {
+        std::slice::from_raw_parts(self.ptr, self.len)
+    }
+
+    unsafe fn as_mut_slice<'a>(&self) -> &'a mut [f32] {
+        std::slice::from_raw_parts_mut(self.ptr, self.len)
+    }
+}
+
+fn throw_type_error(scope: &mut v8::HandleScope, message: &str) {
+    let Some(message) = v8::String::new(scope, message) else {
+        return;
+    };
+    let exception = v8::Exception::type_error(scope, message);
+    scope.throw_exception(exception);
+}
+
+fn throw_range_error(scope: &mut v8::HandleScope, message: &str) {
+    let Some(message) = v8::String::new(scope, message) else {
+        return;
+    };
+    let exception = v8::Exception::range_error(scope, message);
+    scope.throw_exception(exception);
+}
+
+// index 番目の引数を Float32Array として取得する。失敗した場合は例外を投げて None を返す。
+fn array_arg(
+    scope: &mut v8::HandleScope,
+    args: &v8::FunctionCallbackArguments,
+    function: &str,
+    index: i32,
+    name: &str,
+) -> Option<View> {
+    let view = v8::Local::<v8::Float32Array>::try_from(args.get(index))
+        .ok()
+        .and_then(|array| {
+            let buffer = array.buffer(scope)?;
+            let len = array.byte_length() / std::mem::size_of::<f32>();
+            let ptr = match buffer.get_backing_store().data() {
+                Some(data) => unsafe { (data.as_ptr() as *mut u8).add(array.byte_offset()) },
+                None if len == 0 => std::ptr::NonNull::<f32>::dangling().as_ptr() as *mut u8,
+                None => return None,
+            };
+            Some(View {
+                ptr: ptr as *mut f32,
+                len,
+            })
+        });
+    if view.is_none() {
+        throw_type_error(
+            scope,
+            &format!("ps88.dsp.{}: {} must be a Float32Array", function, name),
+        );
+    }
+    view
+}
+
+// index 番目の引数を数値として取得する。省略された場合は default を返す。
+fn number_arg(
+    scope: &mut v8::HandleScope,
+    args: &v8::FunctionCallbackArguments,
+    function: &str,
+    index: i32,
+    name: &str,
+    default: Option<f64>,
+) -> Option<f64> {
+    let value = args.get(index);
+    if value.is_undefined() && default.is_some() {
+        return default;
+    }
+    if !value.is_number() {
+        throw_type_error(
+            scope,
+            &format!("ps88.dsp.{}: {} must be a number", function, name),
+        );
+        return None;
+    }
+    value.number_value(scope)
+}
+
+// 書き込む配列が他の配列とメモリを共有していないことを確認する
+fn check_disjoint(
+    scope: &mut v8::HandleScope,
+    function: &str,
+    writes: &[View],
+    reads: &[View],
+) -> bool {
+    for (i, write) in writes.iter().enumerate() {
+        let others = writes.iter().skip(i + 1).chain(reads);
+        if others.into_iter().any(|other| write.overlaps(other)) {
+            throw_range_error(
+                scope,
+                &format!("ps88.dsp.{}: arrays must not share memory", function),
+            );
+            return false;
+        }
+    }
+    true
+}
+
+fn check_length(scope: &mut v8::HandleScope, function: &str, ok: bool, message: &str) -> bool {
+    if !ok {
+        throw_range_error(scope, &format!("ps88.dsp.{}: {}", function, message));
+    }
+    ok
+}
+
+// dst と src をとる関数
+// dst と src が同じ配列を指している場合は src をコピーしてから処理する
+fn binary(
+    scope: &mut v8::HandleScope,
+    args: &v8::FunctionCallbackArguments,
+    function: &str,
+    op: impl Fn(&mut [f32], &[f32]),
+) -> Option<()> {
+    let dst = array_arg(scope, args, function, 0, "dst")?;
+    let src = array_arg(scope, args, function, 1, "src")?;
+    if !check_length(
+        scope,
+        function,
+        dst.len == src.len,
+        "dst and src must have the same length",
+    ) {
+        return None;
+    }
+    if dst.overlaps(&src) {
+        let src = unsafe { src.as_slice() }.to_vec();
+        op(unsafe { dst.as_mut_slice() }, &src);
+    } else {
+        op(unsafe { dst.as_mut_slice() }, unsafe { src.as_slice() });
+    }
+    Some(())
+}
+
+fn add(scope: &mut v8::HandleScope, args: v8::FunctionCallbackArguments, mut rv: v8::ReturnValue) {
+    if args.get(1).is_number() {
+        let Some(dst) = array_arg(scope, &args, "add", 0, "dst") else {
+            return;
+        };
+        let value = args.get(1).number_value(scope).unwrap_or(0.0);
+        dsp::add_scalar(unsafe { dst.as_mut_slice() }, value as f32);
+    } else if binary(scope, &args, "add", dsp::add).is_none() {
+        return;
+    }
+    rv.set(args.get(0));
+}
+
+fn mul(scope: &mut v8::HandleScope, args: v8::FunctionCallbackArguments, mut rv: v8::ReturnValue) {
+    if args.get(1).is_number() {
+        let Some(dst) = array_arg(scope, &args, "mul", 0, "dst") else {
+            return;
+        };
+        let value = args.get(1).number_value(scope).unwrap_or(0.0);
+        dsp::mul_scalar(unsafe { dst.as_mut_slice() }, value as f32);
+    } else if binary(scope, &args, "mul", dsp::mul).is_none() {
+        return;
+    }
+    rv.set(args.get(0));
+}
+
+fn mix(scope: &mut v8::HandleScope, args: v8::FunctionCallbackArguments, mut rv: v8::ReturnValue) {
+    let Some(gain) = number_arg(scope, &args, "mix", 2, "gain", Some(1.0)) else {
+        return;
+    };
+    if binary(scope, &args, "mix", |dst, src| {
+        dsp::mix(dst, src, gain as f32)
+    })
+    .is_none()
+    {
+        return;
+    }
+    rv.set(args.get(0));
+}
+
+fn osc_bank(
+    scope: &mut v8::HandleScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let f = "oscBank";
+    let Some(out) = array_arg(scope, &args, f, 0, "out") else {
+        return;
+    };
+    let Some(freqs) = array_arg(scope, &args, f, 1, "freqs") else {
+        return;
+    };
+    let Some(phases) = array_arg(scope, &args, f, 2, "phases") else {
+        return;
+    };
+    let Some(amps) = array_arg(scope, &args, f, 3, "amps") else {
+        return;
+    };
+    let Some(sample_rate) = number_arg(scope, &args, f, 4, "sampleRate", None) else {
+        return;
+    };
+    if !check_length(
+        scope,
+        f,
+        freqs.len == phases.len && freqs.len == amps.len,
+        "freqs, phases and amps must have the same length",
+    ) || !check_length(scope, f, sample_rate > 0.0, "sampleRate must be positive")
+        || !check_disjoint(scope, f, &[out, phases], &[freqs, amps])
+    {
+        return;
+    }
+    unsafe {
+        dsp::osc_bank(
+            out.as_mut_slice(),
+            freqs.as_slice(),
+            phases.as_mut_slice(),
+            amps.as_slice(),
+            sample_rate as f32,
+        );
+    }
+    rv.set(args.get(0));
+}
+
+fn biquad(
+    scope: &mut v8::HandleScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let f = "biquad";
+    let Some(buffer) = array_arg(scope, &args, f, 0, "buffer") else {
+        return;
+    };
+    let Some(coeffs) = array_arg(scope, &args, f, 1, "coeffs") else {
+        return;
+    };
+    let Some(state) = array_arg(scope, &args, f, 2, "state") else {
+        return;
+    };
+    if !check_length(scope, f, coeffs.len >= 5, "coeffs must have 5 elements")
+        || !check_length(scope, f, state.len >= 2, "state must have 2 elements")
+        || !check_disjoint(scope, f, &[buffer, state], &[coeffs])
+    {
+        return;
+    }
+    let (coeffs, state) = unsafe { (coeffs.as_slice(), state.as_mut_slice()) };
+    let (Ok(coeffs), Ok(state)) = (
+        <&[f32; 5]>::try_from(&coeffs[..5]),
+        <&mut [f32; 2]>::try_from(&mut state[..2]),
+    ) else {
+        return;
+    };
+    dsp::biquad(unsafe { buffer.as_mut_slice() }, coeffs, state);
+    rv.set(args.get(0));
+}
+
+fn fft_impl(
+    scope: &mut v8::HandleScope,
+    args: &v8::FunctionCallbackArguments,
+    f: &str,
+    inverse: bool,
+) -> Option<()> {
+    let re = array_arg(scope, args, f, 0, "re")?;
+    let im = array_arg(scope, args, f, 1, "im")?;
+    if !check_length(
+        scope,
+        f,
+        re.len == im.len && re.len.is_power_of_two(),
+        "re and im must have the same power-of-two length",
+    ) || !check_disjoint(scope, f, &[re, im], &[])
+    {
+        return None;
+    }
+    unsafe { dsp::fft(re.as_mut_slice(), im.as_mut_slice(), inverse) };
+    Some(())
+}
+
+fn fft(scope: &mut v8::HandleScope, args: v8::FunctionCallbackArguments, mut rv: v8::ReturnValue) {
+    if fft_impl(scope, &args, "fft", false).is_some() {
+        rv.set(args.get(0));
+    }
+}
+
+fn ifft(scope: &mut v8::HandleScope, args: v8::FunctionCallbackArguments, mut rv: v8::ReturnValue) {
+    if fft_impl(scope, &args, "ifft", true).is_some() {
+        rv.set(args.get(0));
+    }
+}
+
+fn convolve(
+    scope: &mut v8::HandleScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let f = "convolve";
+    let Some(buffer) = array_arg(scope, &args, f, 0, "buffer") else {
+        return;
+    };
+    let Some(ir) = array_arg(scope, &args, f, 1, "ir") else {
+        return;
+    };
+    let Some(history) = array_arg(scope, &args, f, 2, "history") else {
+        return;
+    };
+    if !check_length(
+        scope,
+        f,
+        history.len + 1 >= ir.len,
+        "history must have at least ir.length - 1 elements",
+    ) || !check_disjoint(scope, f, &[buffer, history], &[ir])
+    {
+        return;
+    }
+    unsafe { dsp::convolve(buffer.as_mut_slice(), ir.as_slice(), history.as_mut_slice()) };
+    rv.set(args.get(0));
+}
+
+fn resample(
+    scope: &mut v8::HandleScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let f = "resample";
+    let Some(dst) = array_arg(scope, &args, f, 0, "dst") else {
+        return;
+    };
+    let Some(src) = array_arg(scope, &args, f, 1, "src") else {
+        return;
+    };
+    if !check_disjoint(scope, f, &[dst], &[src]) {
+        return;
+    }
+    unsafe { dsp::resample(dst.as_mut_slice(), src.as_slice()) };
+    rv.set(args.get(0));
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::runtime::js;
+    use crate::runtime::runtime::ScriptRuntime;
+
+    // 関数本体を実行し、返された配列を取得する
+    fn run(body: &str, len: usize) -> Vec<f32> {
+        let mut runtime = js::JsRuntimeBuilder::new().build();
+        runtime
+            .compile(
+                &r#"
+                "use strict";
+                const audio = (ctx) => {
+                    const result = (() => { ${body} })();
+                    for (let i = 0; i < ctx.audio.length; i++) {
+                        ctx.audio[i] = result[i] ?? 0;
+                    }
+                };
+                const gui = () => {};
+            "#
+                .replace("${body}", body),
+                None,
+            )
+            .unwrap();
+        let mut audio = vec![0.0; len];
+        runtime.audio(&mut audio, 1, 48000.0, &[]).unwrap();
+        audio
+    }
+
+    #[test]
+    fn arithmetic() {
+        let result = run(
+            r#"
+                const a = new Float32Array([1, 2, 3]);
+                ps88.dsp.add(a, new Float32Array([1, 1, 1]));
+                ps88.dsp.mul(a, 2);
+                ps88.dsp.mix(a, a, 0.5);
+                return ps88.dsp.add(a, -1);
+            "#,
+            3,
+        );
+        assert_eq!(result, vec![5.0, 8.0, 11.0]);
+    }
+
+    #[test]
+    fn fft() {
+        let result = run(
+            r#"
+                const re = new Float32Array([1, 2, 3, 4]);
+                const im = new Float32Array(4);
+                ps88.dsp.fft(re, im);
+                const dc = re[0];
+                ps88.dsp.ifft(re, im);
+                return [dc, ...re];
+            "#,
+            5,
+        );
+        assert_eq!(result[0], 10.0);
+        for (actual, expected) in result[1..].iter().zip([1.0, 2.0, 3.0, 4.0]) {
+            assert!((actual - expected).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn subarray() {
+        // subarray で作った配列は元の配列の該当範囲を書き換える
+        let result = run(
+            r#"
+                const a = new Float32Array(4);
+                const history = new Float32Array(1);
+                ps88.dsp.add(a.subarray(1, 3), 1);
+                ps88.dsp.convolve(a.subarray(1), new Float32Array([1, 1]), history);
+                return a;
+            "#,
+            4,
+        );
+        assert_eq!(result, vec![0.0, 1.0, 2.0, 1.0]);
+    }
+
+    #[test]
+    fn invalid_arguments() {
+        let result = run(
+            r#"
+                const errors = [];
+                const check = (f) => {
+                    try {
+                        f();
+                        errors.push("");
+                    } catch (e) {
+                        errors.push(e.constructor.name);
+                    }
+                };
+                check(() => ps88.dsp.add([1, 2], new Float32Array(2)));
+                check(() => ps88.dsp.add(new Float32Array(2), new Float32Array(3)));
+                check(() => ps88.dsp.fft(new Float32Array(3), new Float32Array(3)));
+                const shared = new Float32Array(8);
+                check(() => ps88.dsp.fft(shared.subarray(0, 4), shared.subarray(2, 6)));
+                check(() => ps88.dsp.oscBank(new Float32Array(4), new Float32Array(1), new Float32Array(1), new Float32Array(1)));
+                return errors.map((e) => ["", "TypeError", "RangeError"].indexOf(e));
+            "#,
+            5,
+        );
+        assert_eq!(result, vec![1.0, 2.0, 2.0, 2.0, 1.0]);
+    }
+}
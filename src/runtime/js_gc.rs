@@ -0,0 +1,86 @@
+use crate::runtime::gc::{self, GcOptions, GcSchedule, GcStats};
+use crate::runtime::js::{get_ps88, set_function, JsRuntimeError};
+use crate::runtime::runtime;
+use std::cell::Cell;
+use std::rc::Rc;
+use v8;
+
+/// ps88.gcStats をスクリプトに公開する。
+/// 統計は GcTracker が isolate の slot に保存したものを参照する。
+///
+/// e.g.
+///   const { count, pauseMs, maxPauseMs, reclaimedBytes, heapUsedBytes } = ps88.gcStats();
+pub fn install(scope: &mut v8::HandleScope) -> runtime::Result<()> {
+    let error = || JsRuntimeError::UnexpectedError("failed to install ps88.gcStats".into());
+    let ps88 = get_ps88(scope).ok_or_else(error)?;
+    set_function(scope, ps88, "gcStats", gc_stats).ok_or_else(error)?;
+    Ok(())
+}
+
+/// スクリプトが定義した gcOptions を読み取る。
+/// e.g. { schedule: "auto" | "memoryPressure" | "lowMemory", threshold: 1048576, strict: true }
+pub fn parse_options(
+    scope: &mut v8::HandleScope,
+    options: Option<v8::Local<v8::Object>>,
+) -> GcOptions {
+    let mut config = GcOptions::default();
+    let Some(options) = options else {
+        return config;
+    };
+    if let Some(schedule) = get(scope, options, "schedule").filter(|v| v.is_string()) {
+        config.schedule = match schedule.to_rust_string_lossy(scope).as_str() {
+            "memoryPressure" => GcSchedule::MemoryPressure,
+            "lowMemory" => GcSchedule::LowMemory,
+            _ => GcSchedule::Auto,
+        };
+    }
+    if let Some(threshold) = get(scope, options, "threshold")
+        .filter(|v| v.is_number())
+        .and_then(|v| v.integer_value(scope))
+    {
+        config.threshold = threshold.max(0) as usize;
+    }
+    if let Some(strict) = get(scope, options, "strict").filter(|v| v.is_boolean()) {
+        config.strict = strict.is_true();
+    }
+    config
+}
+
+fn gc_stats(
+    scope: &mut v8::HandleScope,
+    _args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let stats = scope
+        .get_slot::<Rc<Cell<GcStats>>>()
+        .map(|stats| stats.get())
+        .unwrap_or_default();
+    let heap_used = gc::heap_used(scope);
+    let ms = |duration: std::time::Duration| duration.as_secs_f64() * 1000.0;
+
+    let result = v8::Object::new(scope);
+    let values = [
+        ("count", stats.count as f64),
+        ("pauseMs", ms(stats.pause)),
+        ("maxPauseMs", ms(stats.max_pause)),
+        ("reclaimedBytes", stats.reclaimed_bytes as f64),
+        ("heapUsedBytes", heap_used as f64),
+    ];
+    for (key, value) in values {
+        let Some(key) = v8::String::new(scope, key) else {
+            return;
+        };
+        let value = v8::Number::new(scope, value);
+        result.set(scope, key.into(), value.into());
+    }
+    rv.set(result.into());
+}
+
+fn get<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    object: v8::Local<v8::Object>,
+    key: &str,
+) -> Option<v8::Local<'s, v8::Value>> {
+    let key = v8::String::new(scope, key)?;
+    object.get(scope, key.into())
+}
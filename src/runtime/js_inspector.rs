@@ -0,0 +1,650 @@
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tungstenite::{Message, WebSocket};
+use v8;
+
+/// inspector に登録する context のグループ
+pub const CONTEXT_GROUP_ID: i32 = 1;
+
+// 省略された場合に待ち受けるアドレス (Node.js の --inspect と同じ)
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:9229";
+
+// プラグインで inspector を有効にする環境変数
+const ENV_VAR: &str = "PS88_INSPECT";
+
+// HTTP のリクエストヘッダーの最大サイズと、受信を待つ時間
+const MAX_REQUEST_HEAD: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// WebSocket の受信を待つ間隔。この間隔で V8 からのメッセージを送信する
+const SESSION_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// DevTools との接続で起きたこと
+pub enum InspectorEvent {
+    // 引数は V8 からのメッセージを DevTools に送るための Sender
+    Connected(Sender<String>),
+    Message(String),
+    Disconnected,
+}
+
+/// Chrome DevTools Protocol を話す WebSocket のサーバー。
+/// chrome://inspect から見つけられるように、HTTP の /json/list と /json/version にも応答する。
+///
+/// 同時に接続できる DevTools は 1 つだけで、受信したメッセージは events で受け取れる。
+/// メッセージの処理は isolate のスレッドで JsRuntime が行う。
+pub struct InspectorServer {
+    addr: SocketAddr,
+    id: String,
+    events: Receiver<InspectorEvent>,
+
+    // スクリプトがブレークポイントで停止している間 true になる
+    paused: Arc<AtomicBool>,
+
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl InspectorServer {
+    /// addr で待ち受けを開始する。ポート番号に 0 を指定した場合は空いているポートを使う。
+    pub fn start(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let id = target_id();
+        let (sender, events) = channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let (id, stop) = (id.clone(), stop.clone());
+            let active = Arc::new(AtomicBool::new(false));
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stop.load(Ordering::Acquire) {
+                        break;
+                    }
+                    let Ok(stream) = stream else {
+                        continue;
+                    };
+                    let (id, sender, active) = (id.clone(), sender.clone(), active.clone());
+                    std::thread::spawn(move || {
+                        if let Err(err) = serve(stream, addr, &id, sender, active) {
+                            eprintln!("inspector: {}", err);
+                        }
+                    });
+                }
+            })
+        };
+        Ok(InspectorServer {
+            addr,
+            id,
+            events,
+            paused: Arc::new(AtomicBool::new(false)),
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// DevTools の WebSocket の接続先
+    pub fn url(&self) -> String {
+        format!("ws://{}/{}", self.addr, self.id)
+    }
+
+    /// スクリプトがブレークポイントで停止しているかどうか。別のスレッドから参照できる。
+    pub fn paused(&self) -> Arc<AtomicBool> {
+        self.paused.clone()
+    }
+
+    pub(crate) fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Release);
+    }
+
+    pub(crate) fn try_recv(&self) -> Option<InspectorEvent> {
+        self.events.try_recv().ok()
+    }
+
+    /// 停止中に使う。サーバーが終了している場合は None を返す。
+    pub(crate) fn recv(&self) -> Option<InspectorEvent> {
+        self.events.recv().ok()
+    }
+}
+
+impl Drop for InspectorServer {
+    fn drop(&mut self) {
+        // accept で待っているスレッドを自分から接続して起こす
+        self.stop.store(true, Ordering::Release);
+        let _ = TcpStream::connect(self.addr);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// 接続された DevTools と V8 の間のセッション。V8 からのメッセージを DevTools に送る channel を兼ねる。
+pub struct InspectorSession {
+    // V8InspectorSession は channel を参照するため、channel より先に drop する
+    session: Option<v8::UniqueRef<v8::inspector::V8InspectorSession>>,
+    channel: v8::inspector::ChannelBase,
+    sender: Sender<String>,
+}
+
+impl InspectorSession {
+    pub fn connect(
+        inspector: &mut v8::inspector::V8Inspector,
+        sender: Sender<String>,
+    ) -> Box<Self> {
+        // MEMO: channel のアドレスが V8 に渡されるため、Box に入れてから接続する
+        let mut self_ = Box::new(InspectorSession {
+            session: None,
+            channel: v8::inspector::ChannelBase::new::<Self>(),
+            sender,
+        });
+        let session = inspector.connect(
+            CONTEXT_GROUP_ID,
+            &mut *self_,
+            v8::inspector::StringView::empty(),
+            v8::inspector::V8InspectorClientTrustLevel::FullyTrusted,
+        );
+        self_.session = Some(session);
+        self_
+    }
+
+    /// DevTools から受け取ったメッセージを V8 に渡す。
+    /// 処理中にブレークポイントで停止した場合、再開されるまで戻らない。
+    pub fn dispatch(&mut self, message: &str) {
+        if let Some(session) = self.session.as_mut() {
+            session.dispatch_protocol_message(v8::inspector::StringView::from(message.as_bytes()));
+        }
+    }
+
+    /// DevTools に接続を切る理由を通知する。DevTools には再接続のボタンが表示される。
+    pub fn detach(&self, reason: &str) {
+        let message = serde_json::json!({
+            "method": "Inspector.detached",
+            "params": { "reason": reason },
+        });
+        let _ = self.sender.send(message.to_string());
+    }
+}
+
+impl v8::inspector::ChannelImpl for InspectorSession {
+    fn base(&self) -> &v8::inspector::ChannelBase {
+        &self.channel
+    }
+
+    fn base_mut(&mut self) -> &mut v8::inspector::ChannelBase {
+        &mut self.channel
+    }
+
+    unsafe fn base_ptr(this: *const Self) -> *const v8::inspector::ChannelBase
+    where
+        Self: Sized,
+    {
+        // SAFETY: this pointer is valid for the whole lifetime of session
+        unsafe { std::ptr::addr_of!((*this).channel) }
+    }
+
+    fn send_response(
+        &mut self,
+        _call_id: i32,
+        message: v8::UniquePtr<v8::inspector::StringBuffer>,
+    ) {
+        if let Some(message) = message.as_ref() {
+            let _ = self.sender.send(message.string().to_string());
+        }
+    }
+
+    fn send_notification(&mut self, message: v8::UniquePtr<v8::inspector::StringBuffer>) {
+        if let Some(message) = message.as_ref() {
+            let _ = self.sender.send(message.string().to_string());
+        }
+    }
+
+    fn flush_protocol_notifications(&mut self) {}
+}
+
+/// DevTools を介さずに、Rust から Chrome DevTools Protocol のメソッドを呼び出すためのセッション。
+/// CPU プロファイラなど、V8 が inspector 経由でのみ提供している機能を使うために使う。
+pub struct LocalSession {
+    session: Box<InspectorSession>,
+    receiver: Receiver<String>,
+    next_id: u64,
+}
+
+impl LocalSession {
+    pub fn connect(inspector: &mut v8::inspector::V8Inspector) -> Self {
+        let (sender, receiver) = channel();
+        LocalSession {
+            session: InspectorSession::connect(inspector, sender),
+            receiver,
+            next_id: 1,
+        }
+    }
+
+    /// メソッドを呼び出して結果を返す。応答は dispatch の中で同期的に送られる。
+    pub fn call(
+        &mut self,
+        method: &str,
+        params: serde_json::Value,
+    ) -> Result<serde_json::Value, String> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let message = serde_json::json!({ "id": id, "method": method, "params": params });
+        self.session.dispatch(&message.to_string());
+        for message in self.receiver.try_iter() {
+            let Ok(mut message) = serde_json::from_str::<serde_json::Value>(&message) else {
+                continue;
+            };
+            if message["id"] != id {
+                continue;
+            }
+            if let Some(error) = message.get("error") {
+                return Err(format!("{}: {}", method, error["message"]));
+            }
+            return Ok(message["result"].take());
+        }
+        Err(format!("{}: no response", method))
+    }
+}
+
+/// 環境変数 PS88_INSPECT (e.g. "9229", "127.0.0.1:9229") が設定されていればサーバーを起動する。
+/// DAW に読み込まれたプラグインをデバッグするために使う。
+pub fn start_from_env() -> Option<InspectorServer> {
+    let address = std::env::var(ENV_VAR).ok()?;
+    start(&address)
+}
+
+/// サーバーを起動し、接続先を標準エラー出力に表示する
+pub fn start(address: &str) -> Option<InspectorServer> {
+    match InspectorServer::start(parse_address(address)) {
+        Ok(server) => {
+            eprintln!("Debugger listening on {}", server.url());
+            eprintln!("Open chrome://inspect in Chrome to attach DevTools");
+            Some(server)
+        }
+        Err(err) => {
+            eprintln!("failed to start the inspector on {}: {}", address, err);
+            None
+        }
+    }
+}
+
+// "9229" のようにポート番号だけが指定された場合は localhost で待ち受ける
+fn parse_address(address: &str) -> String {
+    match address.parse::<u16>() {
+        Ok(port) => format!("127.0.0.1:{}", port),
+        Err(_) => address.to_string(),
+    }
+}
+
+// DevTools がターゲットを区別するための ID。
+// WebSocket の接続先の URL になるため、推測できないように OS の乱数から作る
+fn target_id() -> String {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes).expect("failed to get random bytes");
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("ps88-{}", hex)
+}
+
+// Host ヘッダーがこのサーバーを指しているか。
+// DNS リバインディングで Web ページから接続されないように、localhost か待ち受けているアドレスだけを受け付ける
+fn allowed_host(host: &str, addr: SocketAddr) -> bool {
+    let name = match host.strip_prefix('[') {
+        Some(rest) => match rest.split_once(']') {
+            Some((ip, _)) => format!("[{}]", ip),
+            None => return false,
+        },
+        None => host.split(':').next().unwrap_or_default().to_string(),
+    };
+    let bound = match addr.ip() {
+        std::net::IpAddr::V4(ip) => ip.to_string(),
+        std::net::IpAddr::V6(ip) => format!("[{}]", ip),
+    };
+    name.eq_ignore_ascii_case("localhost")
+        || name == "127.0.0.1"
+        || name == "[::1]"
+        || name == bound
+}
+
+// WebSocket の接続元。ブラウザのページからの接続は Origin が付くため、DevTools (devtools://) 以外は拒否する
+fn allowed_origin(origin: Option<&str>) -> bool {
+    match origin {
+        Some(origin) => origin.starts_with("devtools://"),
+        None => true,
+    }
+}
+
+// 1 つの TCP の接続を処理する。WebSocket へのアップグレードでなければ HTTP で応答して閉じる。
+fn serve(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    id: &str,
+    events: Sender<InspectorEvent>,
+    active: Arc<AtomicBool>,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let (head, head_len) = peek_request_head(&stream)?;
+    let mut lines = head.lines();
+    let path = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .unwrap_or("/")
+        .to_string();
+    let mut host = addr.to_string();
+    let mut origin = None;
+    let mut upgrade = false;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        match name.trim().to_ascii_lowercase().as_str() {
+            "host" => host = value.trim().to_string(),
+            "origin" => origin = Some(value.trim().to_string()),
+            "upgrade" => upgrade = value.trim().eq_ignore_ascii_case("websocket"),
+            _ => {}
+        }
+    }
+    if !allowed_host(&host, addr) || (upgrade && !allowed_origin(origin.as_deref())) {
+        stream.read_exact(&mut vec![0; head_len])?;
+        return respond(&mut stream, "403 Forbidden", "");
+    }
+
+    if !upgrade {
+        // WebSocket にしない場合はヘッダーを読み捨ててから応答する
+        stream.read_exact(&mut vec![0; head_len])?;
+        let body = match path.trim_end_matches('/') {
+            "/json" | "/json/list" => serde_json::json!([{
+                "description": "ps88",
+                "devtoolsFrontendUrl": format!(
+                    "devtools://devtools/bundled/js_app.html?experiments=true&v8only=true&ws={}/{}",
+                    host, id
+                ),
+                "id": id,
+                "title": format!("ps88 [{}]", std::process::id()),
+                "type": "node",
+                "url": "ps88://script",
+                "webSocketDebuggerUrl": format!("ws://{}/{}", host, id),
+            }]),
+            "/json/version" => serde_json::json!({
+                "Browser": format!("ps88/{}", env!("CARGO_PKG_VERSION")),
+                "Protocol-Version": "1.3",
+                "V8-Version": v8::V8::get_version(),
+            }),
+            _ => return respond(&mut stream, "404 Not Found", ""),
+        };
+        return respond(&mut stream, "200 OK", &body.to_string());
+    }
+
+    if path.trim_start_matches('/') != id {
+        stream.read_exact(&mut vec![0; head_len])?;
+        return respond(&mut stream, "404 Not Found", "");
+    }
+    if active.swap(true, Ordering::AcqRel) {
+        stream.read_exact(&mut vec![0; head_len])?;
+        return respond(&mut stream, "409 Conflict", "another debugger is attached");
+    }
+    let result = tungstenite::accept(stream)
+        .map_err(|err| io::Error::other(err.to_string()))
+        .and_then(|websocket| relay(websocket, &events));
+    // Disconnected を送ってから次の接続を受け付ける
+    let _ = events.send(InspectorEvent::Disconnected);
+    active.store(false, Ordering::Release);
+    result
+}
+
+// WebSocket と isolate のスレッドの間でメッセージを中継する
+fn relay(mut websocket: WebSocket<TcpStream>, events: &Sender<InspectorEvent>) -> io::Result<()> {
+    websocket
+        .get_mut()
+        .set_read_timeout(Some(SESSION_POLL_INTERVAL))?;
+    let (sender, outgoing) = channel();
+    if events.send(InspectorEvent::Connected(sender)).is_err() {
+        return Ok(());
+    }
+    loop {
+        // セッションが破棄された場合 (スクリプトの再コンパイルなど) は接続を閉じる
+        loop {
+            match outgoing.try_recv() {
+                Ok(message) => websocket
+                    .send(Message::Text(message))
+                    .map_err(|err| io::Error::other(err.to_string()))?,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    let _ = websocket.close(None);
+                    let _ = websocket.flush();
+                    return Ok(());
+                }
+            }
+        }
+        match websocket.read() {
+            Ok(Message::Text(message)) => {
+                if events.send(InspectorEvent::Message(message)).is_err() {
+                    return Ok(());
+                }
+            }
+            Ok(Message::Close(_)) => return Ok(()),
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(err))
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) => {}
+            Err(tungstenite::Error::ConnectionClosed) => return Ok(()),
+            Err(err) => return Err(io::Error::other(err.to_string())),
+        }
+    }
+}
+
+// リクエストヘッダーを読み進めずに取得する (WebSocket の場合は tungstenite が改めて読む)
+fn peek_request_head(stream: &TcpStream) -> io::Result<(String, usize)> {
+    let mut buffer = vec![0; MAX_REQUEST_HEAD];
+    let start = Instant::now();
+    loop {
+        let len = stream.peek(&mut buffer)?;
+        if len == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if let Some(end) = buffer[..len].windows(4).position(|w| w == b"\r\n\r\n") {
+            let head_len = end + 4;
+            return Ok((
+                String::from_utf8_lossy(&buffer[..head_len]).to_string(),
+                head_len,
+            ));
+        }
+        if len == buffer.len() || start.elapsed() > REQUEST_TIMEOUT {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid request header",
+            ));
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+fn respond(stream: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json; charset=UTF-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::runtime::js::JsRuntimeBuilder;
+    use crate::runtime::runtime::ScriptRuntime;
+    use std::io::BufRead;
+
+    /// DevTools の代わりにサーバーに接続する
+    pub fn connect(server: &InspectorServer) -> WebSocket<TcpStream> {
+        let stream = TcpStream::connect(server.addr()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let (websocket, _) = tungstenite::client(server.url(), stream).unwrap();
+        websocket
+    }
+
+    pub fn call(websocket: &mut WebSocket<TcpStream>, id: u64, method: &str) {
+        let message = serde_json::json!({ "id": id, "method": method });
+        websocket.send(Message::Text(message.to_string())).unwrap();
+    }
+
+    /// 条件を満たすメッセージを受信するまで待つ
+    pub fn wait_for(
+        websocket: &mut WebSocket<TcpStream>,
+        predicate: impl Fn(&serde_json::Value) -> bool,
+    ) -> serde_json::Value {
+        loop {
+            let Message::Text(message) = websocket.read().unwrap() else {
+                continue;
+            };
+            let message: serde_json::Value = serde_json::from_str(&message).unwrap();
+            if predicate(&message) {
+                return message;
+            }
+        }
+    }
+
+    fn get(server: &InspectorServer, path: &str) -> (String, String) {
+        request(server, path, &format!("Host: {}\r\n", server.addr()))
+    }
+
+    fn request(server: &InspectorServer, path: &str, headers: &str) -> (String, String) {
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\n{}\r\n", path, headers).unwrap();
+        let mut reader = io::BufReader::new(stream);
+        let mut status = String::new();
+        reader.read_line(&mut status).unwrap();
+        let mut response = String::new();
+        reader.read_to_string(&mut response).unwrap();
+        let body = response.split_once("\r\n\r\n").unwrap().1.to_string();
+        (status.trim().to_string(), body)
+    }
+
+    #[test]
+    fn list_targets() {
+        let server = InspectorServer::start("127.0.0.1:0").unwrap();
+        let (status, body) = get(&server, "/json/list");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let targets: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(targets[0]["webSocketDebuggerUrl"], server.url());
+
+        let (status, body) = get(&server, "/json/version");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert!(body.contains("Protocol-Version"));
+
+        let (status, _) = get(&server, "/unknown");
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+    }
+
+    #[test]
+    fn reject_other_hosts() {
+        let server = InspectorServer::start("127.0.0.1:0").unwrap();
+        let port = server.addr().port();
+        for host in ["localhost", "LocalHost", "127.0.0.1", "[::1]"] {
+            let (status, _) = request(
+                &server,
+                "/json/list",
+                &format!("Host: {}:{}\r\n", host, port),
+            );
+            assert_eq!(status, "HTTP/1.1 200 OK", "{}", host);
+        }
+
+        // DNS リバインディングで別の名前から接続された場合は拒否する
+        for host in [
+            "attacker.example",
+            "attacker.example:9229",
+            "127.0.0.1.example",
+            "[::1",
+        ] {
+            let (status, _) = request(&server, "/json/list", &format!("Host: {}\r\n", host));
+            assert_eq!(status, "HTTP/1.1 403 Forbidden", "{}", host);
+        }
+
+        // ブラウザのページからの WebSocket の接続は拒否する
+        let path = server.url().rsplit('/').next().unwrap().to_string();
+        let upgrade = "Upgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n";
+        let (status, _) = request(
+            &server,
+            &format!("/{}", path),
+            &format!(
+                "Host: localhost:{}\r\nOrigin: https://attacker.example\r\n{}",
+                port, upgrade
+            ),
+        );
+        assert_eq!(status, "HTTP/1.1 403 Forbidden");
+
+        // ID は推測できない値になる
+        let other = InspectorServer::start("127.0.0.1:0").unwrap();
+        assert_ne!(
+            server.url().rsplit('/').next(),
+            other.url().rsplit('/').next()
+        );
+        assert!(path.len() >= 32, "{}", path);
+    }
+
+    #[test]
+    fn pause_at_breakpoint() {
+        let server = InspectorServer::start("127.0.0.1:0").unwrap();
+        let paused = server.paused();
+        let server_paused = server.paused();
+        let mut websocket = connect(&server);
+        let mut runtime = JsRuntimeBuilder::new().inspector(server).build();
+        runtime
+            .compile(
+                r#"
+                    let count = 0;
+                    const audio = (ctx) => {
+                        count++;
+                        debugger;
+                        ctx.audio.fill(count);
+                    };
+                    const gui = () => {};
+                "#,
+                None,
+            )
+            .unwrap();
+
+        // DevTools 側: デバッガーを有効にし、停止したら再開する
+        let devtools = std::thread::spawn(move || {
+            call(&mut websocket, 1, "Runtime.enable");
+            call(&mut websocket, 2, "Debugger.enable");
+            wait_for(&mut websocket, |m| m["id"] == 2);
+            wait_for(&mut websocket, |m| m["method"] == "Debugger.paused");
+            // 通知は停止のループに入る直前に送られるため、フラグが立つまで少し待つ
+            let start = Instant::now();
+            while !paused.load(Ordering::Acquire) {
+                assert!(start.elapsed() < Duration::from_secs(10));
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            call(&mut websocket, 3, "Debugger.resume");
+            wait_for(&mut websocket, |m| m["method"] == "Debugger.resumed");
+        });
+
+        // デバッガーが有効になるまでは debugger 文では止まらない
+        let mut audio = vec![0.0; 4];
+        let start = Instant::now();
+        while !devtools.is_finished() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            runtime.audio(&mut audio, 1, 48000.0, &[]).unwrap();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        devtools.join().unwrap();
+        assert!(!server_paused.load(Ordering::Acquire));
+
+        // 再開後も処理を続けられる
+        runtime.audio(&mut audio, 1, 48000.0, &[]).unwrap();
+        assert!(audio[0] > 1.0);
+    }
+}
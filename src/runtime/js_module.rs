@@ -0,0 +1,161 @@
+use crate::runtime::code_cache;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use v8;
+
+/// ES Module の読み込み状態を管理する。
+/// v8 の resolve callback にはユーザーデータを渡せないため、isolate の slot に保存して使う。
+pub struct ModuleLoader {
+    // import された各モジュール (同じファイルは 1 度だけコンパイルする)
+    modules: HashMap<PathBuf, v8::Global<v8::Module>>,
+
+    // モジュールの identity hash からファイルパスへの対応
+    // ファイルパスを持たないメインスクリプトは None になる
+    paths: HashMap<i32, Option<PathBuf>>,
+
+    // ファイルパスを持たないメインスクリプトが import を解決する時の基準ディレクトリ
+    base_dir: Option<PathBuf>,
+
+    // 読み込んだ (読み込もうとした) ファイルの一覧
+    dependencies: Vec<PathBuf>,
+}
+
+impl ModuleLoader {
+    pub fn new(main_path: Option<&Path>) -> Rc<RefCell<Self>> {
+        Rc::new(RefCell::new(ModuleLoader {
+            modules: HashMap::new(),
+            paths: HashMap::new(),
+            base_dir: main_path.and_then(|p| p.parent()).map(|p| p.to_path_buf()),
+            dependencies: Vec::new(),
+        }))
+    }
+
+    pub fn dependencies(&self) -> &[PathBuf] {
+        &self.dependencies
+    }
+
+    /// モジュールとそのファイルパスを対応付ける
+    pub fn register(
+        &mut self,
+        scope: &mut v8::HandleScope,
+        module: v8::Local<v8::Module>,
+        path: Option<&Path>,
+    ) {
+        let hash = i32::from(module.get_identity_hash());
+        self.paths.insert(hash, path.map(|p| p.to_path_buf()));
+        if let Some(path) = path {
+            self.modules
+                .insert(path.to_path_buf(), v8::Global::new(scope, module));
+        }
+    }
+
+    /// import 文の specifier を、import 元のモジュールからの相対パスとして解決する
+    fn resolve(&self, specifier: &str, referrer: v8::Local<v8::Module>) -> Result<PathBuf, String> {
+        let is_relative = specifier.starts_with("./") || specifier.starts_with("../");
+        let is_absolute = Path::new(specifier).is_absolute();
+        if !is_relative && !is_absolute {
+            return Err(format!(
+                "cannot resolve module '{}': only relative or absolute paths can be imported",
+                specifier
+            ));
+        }
+        let path = if is_absolute {
+            PathBuf::from(specifier)
+        } else {
+            let referrer = self
+                .paths
+                .get(&i32::from(referrer.get_identity_hash()))
+                .cloned()
+                .flatten();
+            let base_dir = match referrer {
+                Some(referrer) => referrer.parent().map(|p| p.to_path_buf()),
+                None => self.base_dir.clone(),
+            };
+            let Some(base_dir) = base_dir else {
+                return Err(format!(
+                    "cannot resolve module '{}': the script has not been opened from a file",
+                    specifier
+                ));
+            };
+            base_dir.join(specifier)
+        };
+        // 同じファイルを別のパスで import しても 1 つのモジュールとして扱えるように正規化する
+        Ok(std::fs::canonicalize(&path).unwrap_or(path))
+    }
+}
+
+/// ScriptOrigin を作成する。
+/// ここで指定した名前がエラーメッセージのファイル名になる。
+pub fn script_origin<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    name: &str,
+    is_module: bool,
+) -> Option<v8::ScriptOrigin<'s>> {
+    let name = v8::String::new(scope, name)?;
+    let source_map_url = v8::String::empty(scope);
+    Some(v8::ScriptOrigin::new(
+        scope,
+        name.into(),
+        0,
+        0,
+        false,
+        0,
+        source_map_url.into(),
+        false,
+        false,
+        is_module,
+    ))
+}
+
+/// import 文を解決する v8 の callback
+pub fn resolve_module<'s>(
+    context: v8::Local<'s, v8::Context>,
+    specifier: v8::Local<'s, v8::String>,
+    _import_assertions: v8::Local<'s, v8::FixedArray>,
+    referrer: v8::Local<'s, v8::Module>,
+) -> Option<v8::Local<'s, v8::Module>> {
+    // SAFETY: CallbackScope は callback に渡された Context から作成できる
+    let scope = &mut unsafe { v8::CallbackScope::new(context) };
+    let specifier = specifier.to_rust_string_lossy(scope);
+    let Some(loader) = scope.get_slot::<Rc<RefCell<ModuleLoader>>>().cloned() else {
+        throw_error(scope, "module loader is not initialized");
+        return None;
+    };
+
+    let path = match loader.borrow().resolve(&specifier, referrer) {
+        Ok(path) => path,
+        Err(message) => {
+            throw_error(scope, &message);
+            return None;
+        }
+    };
+    if let Some(module) = loader.borrow().modules.get(&path) {
+        return Some(v8::Local::new(scope, module));
+    }
+
+    // 読み込みに失敗したファイルも、作成/修正された時に再コンパイルできるように記録しておく
+    loader.borrow_mut().dependencies.push(path.clone());
+    let code = match std::fs::read_to_string(&path) {
+        Ok(code) => code,
+        Err(err) => {
+            throw_error(
+                scope,
+                &format!("cannot load module '{}': {}", path.display(), err),
+            );
+            return None;
+        }
+    };
+    let module = code_cache::compile_module(scope, &code, &path.to_string_lossy())?;
+    loader.borrow_mut().register(scope, module, Some(&path));
+    Some(module)
+}
+
+fn throw_error(scope: &mut v8::HandleScope, message: &str) {
+    let Some(message) = v8::String::new(scope, message) else {
+        return;
+    };
+    let exception = v8::Exception::error(scope, message);
+    scope.throw_exception(exception);
+}
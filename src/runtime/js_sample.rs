@@ -0,0 +1,366 @@
+use crate::runtime::js::{get_ps88, set_function, JsRuntimeError};
+use crate::runtime::runtime;
+use crate::runtime::sample::{Sample, SampleCache};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use v8;
+
+type LoadResult = (PathBuf, Result<Arc<Sample>, String>);
+
+/// ps88.loadSample(path) で要求されたオーディオファイルをバックグラウンドのスレッドで読み込む。
+/// v8 の関数コールバックにはユーザーデータを渡せないため、isolate の slot に保存して使う。
+///
+/// e.g.
+///   const kick = await ps88.loadSample("kick.wav");
+///   kick.sampleRate   // 元のサンプリングレート
+///   kick.length       // 1 チャンネルあたりのサンプル数
+///   kick.channels[0]  // 1 チャンネル目の Float32Array
+pub struct SampleLoader {
+    cache: SampleCache,
+    base_dir: Option<PathBuf>,
+
+    // 読み込み中のファイルと、その完了を待っている Promise
+    // 同じファイルを読み込み中に再度要求された場合は、読み込みを追加せずに Promise だけ加える
+    pending: HashMap<PathBuf, Vec<v8::Global<v8::PromiseResolver>>>,
+
+    // 読み込むファイルを送るキュー。最初の要求で読み込み用のスレッドを 1 つ起動する
+    // (SampleLoader が破棄されるとキューが閉じ、スレッドは読み込み中のファイルを終えて終了する)
+    jobs: Option<Sender<PathBuf>>,
+    sender: Sender<LoadResult>,
+    receiver: Receiver<LoadResult>,
+
+    // 読み込みを要求されたファイル (ファイルの監視に使う)
+    paths: Vec<PathBuf>,
+}
+
+impl SampleLoader {
+    /// main_path はメインスクリプトのファイルパスで、相対パスはそのディレクトリを基準に解決する
+    pub fn new(cache: SampleCache, main_path: Option<&Path>) -> Rc<RefCell<Self>> {
+        let (sender, receiver) = channel();
+        Rc::new(RefCell::new(SampleLoader {
+            cache,
+            base_dir: main_path.and_then(|p| p.parent()).map(|p| p.to_path_buf()),
+            pending: HashMap::new(),
+            jobs: None,
+            sender,
+            receiver,
+            paths: Vec::new(),
+        }))
+    }
+
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    fn resolve(&self, name: &str) -> Result<PathBuf, String> {
+        let path = Path::new(name);
+        if path.is_absolute() {
+            return Ok(path.to_path_buf());
+        }
+        let Some(base_dir) = self.base_dir.as_ref() else {
+            return Err(format!(
+                "cannot load sample '{}': the script has not been opened from a file",
+                name
+            ));
+        };
+        let path = base_dir.join(path);
+        Ok(std::fs::canonicalize(&path).unwrap_or(path))
+    }
+
+    // 読み込みを開始する
+    fn request(
+        &mut self,
+        name: &str,
+        resolver: v8::Global<v8::PromiseResolver>,
+    ) -> Result<(), String> {
+        let path = self.resolve(name)?;
+        if !self.paths.contains(&path) {
+            self.paths.push(path.clone());
+        }
+        if let Some(resolvers) = self.pending.get_mut(&path) {
+            resolvers.push(resolver);
+            return Ok(());
+        }
+        if self.jobs.is_none() {
+            self.jobs = Some(self.spawn()?);
+        }
+        if let Some(jobs) = &self.jobs {
+            jobs.send(path.clone()).map_err(|e| e.to_string())?;
+        }
+        self.pending.insert(path, vec![resolver]);
+        Ok(())
+    }
+
+    // 要求された順にファイルを読み込むスレッドを起動する
+    fn spawn(&self) -> Result<Sender<PathBuf>, String> {
+        let (jobs, queue) = channel::<PathBuf>();
+        let cache = self.cache.clone();
+        let sender = self.sender.clone();
+        std::thread::Builder::new()
+            .name("ps88-sample-loader".into())
+            .spawn(move || {
+                for path in queue {
+                    let result = cache
+                        .load(&path)
+                        .map_err(|e| format!("cannot load sample '{}': {}", path.display(), e));
+                    // スクリプトが再コンパイルされた場合は受信側が無くなっているが、無視してよい
+                    let _ = sender.send((path, result));
+                }
+            })
+            .map_err(|e| e.to_string())?;
+        Ok(jobs)
+    }
+}
+
+/// ps88.loadSample を登録する
+pub fn install(scope: &mut v8::HandleScope) -> runtime::Result<()> {
+    let error = || JsRuntimeError::UnexpectedError("failed to install ps88.loadSample".into());
+    let ps88 = get_ps88(scope).ok_or_else(error)?;
+    set_function(scope, ps88, "loadSample", load_sample).ok_or_else(error)?;
+    Ok(())
+}
+
+/// 読み込みが終わったファイルの Promise を解決する。読み込み中のファイルは待たない。
+pub fn poll(scope: &mut v8::HandleScope) {
+    while let Some((resolvers, result)) = receive(scope, false) {
+        settle(scope, resolvers, result);
+    }
+    scope.perform_microtask_checkpoint();
+}
+
+/// 読み込み中のファイルが 1 つ以上完了するまで待ち、その Promise を解決する。
+/// 読み込み中のファイルが無い場合は false を返す。
+pub fn wait(scope: &mut v8::HandleScope) -> bool {
+    let Some((resolvers, result)) = receive(scope, true) else {
+        return false;
+    };
+    settle(scope, resolvers, result);
+    poll(scope);
+    true
+}
+
+fn receive(
+    scope: &mut v8::HandleScope,
+    block: bool,
+) -> Option<(
+    Vec<v8::Global<v8::PromiseResolver>>,
+    Result<Arc<Sample>, String>,
+)> {
+    let loader = scope.get_slot::<Rc<RefCell<SampleLoader>>>()?.clone();
+    let mut loader = loader.borrow_mut();
+    loop {
+        if !loader.has_pending() {
+            return None;
+        }
+        let (path, result) = if block {
+            loader.receiver.recv().ok()?
+        } else {
+            loader.receiver.try_recv().ok()?
+        };
+        if let Some(resolvers) = loader.pending.remove(&path) {
+            return Some((resolvers, result));
+        }
+    }
+}
+
+fn settle(
+    scope: &mut v8::HandleScope,
+    resolvers: Vec<v8::Global<v8::PromiseResolver>>,
+    result: Result<Arc<Sample>, String>,
+) {
+    for resolver in resolvers {
+        let resolver = v8::Local::new(scope, resolver);
+        match result.as_ref().map(|sample| to_object(scope, sample)) {
+            Ok(Some(object)) => {
+                resolver.resolve(scope, object.into());
+            }
+            Ok(None) => {}
+            Err(message) => {
+                let Some(message) = v8::String::new(scope, message) else {
+                    return;
+                };
+                let exception = v8::Exception::error(scope, message);
+                resolver.reject(scope, exception);
+            }
+        }
+    }
+}
+
+// { sampleRate, length, channels: [Float32Array, ...] } に変換する
+fn to_object<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    sample: &Sample,
+) -> Option<v8::Local<'s, v8::Object>> {
+    let length = sample.channels.first().map(|c| c.len()).unwrap_or(0);
+    let channels: Vec<v8::Local<v8::Value>> = sample
+        .channels
+        .iter()
+        .map(|channel| {
+            let buffer = v8::ArrayBuffer::new(scope, channel.len() * size_of::<f32>());
+            if let Some(pointer) = buffer.get_backing_store().data() {
+                unsafe {
+                    std::ptr::copy(
+                        channel.as_ptr(),
+                        pointer.as_ptr() as *mut f32,
+                        channel.len(),
+                    );
+                }
+            }
+            v8::Float32Array::new(scope, buffer, 0, channel.len()).map(|array| array.into())
+        })
+        .collect::<Option<_>>()?;
+    let channels = v8::Array::new_with_elements(scope, &channels);
+
+    let object = v8::Object::new(scope);
+    let sample_rate_key = v8::String::new(scope, "sampleRate")?;
+    let length_key = v8::String::new(scope, "length")?;
+    let channels_key = v8::String::new(scope, "channels")?;
+    let sample_rate = v8::Number::new(scope, sample.sample_rate as f64);
+    let length = v8::Number::new(scope, length as f64);
+    object.set(scope, sample_rate_key.into(), sample_rate.into())?;
+    object.set(scope, length_key.into(), length.into())?;
+    object.set(scope, channels_key.into(), channels.into())?;
+    Some(object)
+}
+
+fn load_sample(
+    scope: &mut v8::HandleScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let Some(resolver) = v8::PromiseResolver::new(scope) else {
+        return;
+    };
+    rv.set(resolver.get_promise(scope).into());
+
+    let result = if !args.get(0).is_string() {
+        Err("ps88.loadSample: path must be a string".to_string())
+    } else {
+        let name = args.get(0).to_rust_string_lossy(scope);
+        let resolver = v8::Global::new(scope, resolver);
+        match scope.get_slot::<Rc<RefCell<SampleLoader>>>().cloned() {
+            Some(loader) => loader.borrow_mut().request(&name, resolver),
+            None => Err("sample loader is not initialized".to_string()),
+        }
+    };
+    if let Err(message) = result {
+        let Some(message) = v8::String::new(scope, &message) else {
+            return;
+        };
+        let exception = v8::Exception::error(scope, message);
+        resolver.reject(scope, exception);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::runtime::js;
+    use crate::runtime::runtime::ScriptRuntime;
+    use crate::runtime::sample::tests::write_wav;
+
+    #[test]
+    fn load_sample() {
+        let dir = std::env::temp_dir().join("ps88_js_sample_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("samples")).unwrap();
+        write_wav(
+            &dir.join("samples/kick.wav"),
+            44100,
+            &[vec![0.5, 0.25], vec![-0.5, -0.25]],
+        );
+        let main = dir.join("main.js");
+
+        // top-level await で読み込みの完了を待てる
+        let mut runtime = js::JsRuntimeBuilder::new().build();
+        runtime
+            .compile(
+                r#"
+                const kick = await ps88.loadSample("./samples/kick.wav");
+                export const audio = (ctx) => {
+                    ctx.audio.set([kick.sampleRate, kick.length, kick.channels.length]);
+                    ctx.audio.set(kick.channels[1], 3);
+                };
+                export const gui = () => {};
+            "#,
+                Some(&main),
+            )
+            .unwrap();
+        let mut audio = vec![0.0; 5];
+        runtime.audio(&mut audio, 1, 48000.0, &[]).unwrap();
+        assert_eq!(audio, vec![44100.0, 2.0, 2.0, -0.5, -0.25]);
+
+        // 読み込み中の同じファイルを再度要求しても、それぞれの Promise が解決される
+        runtime
+            .compile(
+                r#"
+                const [a, b] = await Promise.all([
+                    ps88.loadSample("./samples/kick.wav"),
+                    ps88.loadSample("./samples/../samples/kick.wav"),
+                ]);
+                export const audio = (ctx) => {
+                    ctx.audio.set([a.channels[0][0], b.channels[0][0], a === b ? 1 : 0]);
+                };
+                export const gui = () => {};
+            "#,
+                Some(&main),
+            )
+            .unwrap();
+        let mut audio = vec![0.0; 3];
+        runtime.audio(&mut audio, 1, 48000.0, &[]).unwrap();
+        assert_eq!(audio, vec![0.5, 0.5, 0.0]);
+
+        // 読み込んだファイルは監視対象になる
+        assert_eq!(
+            runtime.dependencies(),
+            vec![std::fs::canonicalize(dir.join("samples/kick.wav")).unwrap()]
+        );
+
+        // 読み込みに失敗すると Promise が reject される
+        let result = runtime.compile(
+            r#"
+                await ps88.loadSample("./samples/missing.wav");
+                export const audio = () => {};
+                export const gui = () => {};
+            "#,
+            Some(&main),
+        );
+        assert!(result.unwrap_err().to_string().contains("missing.wav"));
+        assert_eq!(
+            runtime.dependencies(),
+            vec![dir.join("samples/missing.wav")]
+        );
+
+        // 通常のスクリプトでは audio の呼び出し時に Promise が解決される
+        runtime
+            .compile(
+                r#"
+                let kick = null;
+                ps88.loadSample("./samples/kick.wav").then((sample) => (kick = sample));
+                const audio = (ctx) => {
+                    ctx.audio[0] = kick ? kick.channels[0][0] : 0;
+                };
+                const gui = () => {};
+            "#,
+                Some(&main),
+            )
+            .unwrap();
+        let mut audio = vec![0.0; 1];
+        for _ in 0..100 {
+            runtime.audio(&mut audio, 1, 48000.0, &[]).unwrap();
+            if audio[0] != 0.0 {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(audio, vec![0.5]);
+    }
+}
@@ -0,0 +1,245 @@
+use crate::runtime::js::{get_ps88, set_function, JsRuntimeError};
+use crate::runtime::runtime;
+use crate::runtime::state::{ScriptState, StateValue};
+use std::sync::{Arc, Mutex};
+use v8;
+
+/// ps88.state をスクリプトに公開する。
+/// 値はプラグインの状態としてホストのプロジェクトに保存され、プロジェクトを開き直しても復元される。
+/// 保存先の ScriptState は isolate の slot に保存して使う。
+///
+/// e.g.
+///   ps88.state.set("pattern", [1, 0, 1, 0]);              // JSON に変換できる値
+///   ps88.state.set("table", new Float32Array(2048));      // ArrayBuffer や TypedArray はバイト列として保存
+///   ps88.state.get("pattern");                            // 保存されていない場合は undefined
+///   new Float32Array(ps88.state.get("table"));            // バイト列は ArrayBuffer として返される
+///   ps88.state.delete("pattern");
+///   ps88.state.keys();
+pub fn install(scope: &mut v8::HandleScope) -> runtime::Result<()> {
+    let error = || JsRuntimeError::UnexpectedError("failed to install ps88.state".into());
+    let ps88 = get_ps88(scope).ok_or_else(error)?;
+    let state = v8::Object::new(scope);
+    set_function(scope, state, "get", get).ok_or_else(error)?;
+    set_function(scope, state, "set", set).ok_or_else(error)?;
+    set_function(scope, state, "delete", delete).ok_or_else(error)?;
+    set_function(scope, state, "keys", keys).ok_or_else(error)?;
+    let key = v8::String::new(scope, "state").ok_or_else(error)?;
+    ps88.set(scope, key.into(), state.into());
+    Ok(())
+}
+
+fn throw_type_error(scope: &mut v8::HandleScope, message: &str) {
+    let Some(message) = v8::String::new(scope, message) else {
+        return;
+    };
+    let exception = v8::Exception::type_error(scope, message);
+    scope.throw_exception(exception);
+}
+
+fn state(scope: &mut v8::HandleScope) -> Option<Arc<Mutex<ScriptState>>> {
+    let state = scope.get_slot::<Arc<Mutex<ScriptState>>>().cloned();
+    if state.is_none() {
+        throw_type_error(scope, "ps88.state is not initialized");
+    }
+    state
+}
+
+fn key_arg(
+    scope: &mut v8::HandleScope,
+    args: &v8::FunctionCallbackArguments,
+    function: &str,
+) -> Option<String> {
+    if !args.get(0).is_string() {
+        throw_type_error(
+            scope,
+            &format!("ps88.state.{}: key must be a string", function),
+        );
+        return None;
+    }
+    Some(args.get(0).to_rust_string_lossy(scope))
+}
+
+fn get(scope: &mut v8::HandleScope, args: v8::FunctionCallbackArguments, mut rv: v8::ReturnValue) {
+    let Some(key) = key_arg(scope, &args, "get") else {
+        return;
+    };
+    let Some(state) = state(scope) else {
+        return;
+    };
+    let Some(value) = state.lock().unwrap().get(&key).cloned() else {
+        return;
+    };
+    match value {
+        StateValue::Json(json) => {
+            let Some(json) = v8::String::new(scope, &json) else {
+                return;
+            };
+            if let Some(value) = v8::json::parse(scope, json) {
+                rv.set(value);
+            }
+        }
+        StateValue::Binary(bytes) => {
+            let buffer = v8::ArrayBuffer::new(scope, bytes.len());
+            if let Some(pointer) = buffer.get_backing_store().data() {
+                unsafe {
+                    std::ptr::copy(bytes.as_ptr(), pointer.as_ptr() as *mut u8, bytes.len());
+                }
+            }
+            rv.set(buffer.into());
+        }
+    }
+}
+
+fn set(scope: &mut v8::HandleScope, args: v8::FunctionCallbackArguments, _rv: v8::ReturnValue) {
+    let Some(key) = key_arg(scope, &args, "set") else {
+        return;
+    };
+    let value = args.get(1);
+    let value = if value.is_undefined() {
+        None
+    } else if let Ok(view) = v8::Local::<v8::ArrayBufferView>::try_from(value) {
+        let mut bytes = vec![0; view.byte_length()];
+        view.copy_contents(&mut bytes);
+        Some(StateValue::Binary(bytes))
+    } else if let Ok(buffer) = v8::Local::<v8::ArrayBuffer>::try_from(value) {
+        let mut bytes = vec![0; buffer.byte_length()];
+        if let Some(pointer) = buffer.get_backing_store().data() {
+            unsafe {
+                std::ptr::copy(
+                    pointer.as_ptr() as *const u8,
+                    bytes.as_mut_ptr(),
+                    bytes.len(),
+                );
+            }
+        }
+        Some(StateValue::Binary(bytes))
+    } else if value.is_function() || value.is_symbol() {
+        throw_type_error(scope, "ps88.state.set: value must be JSON-serializable");
+        return;
+    } else {
+        // 循環参照などで変換できない場合は JSON.stringify が例外を投げる
+        let Some(json) = v8::json::stringify(scope, value) else {
+            return;
+        };
+        Some(StateValue::Json(json.to_rust_string_lossy(scope)))
+    };
+    let Some(state) = state(scope) else {
+        return;
+    };
+    let mut state = state.lock().unwrap();
+    match value {
+        Some(value) => state.set(&key, value),
+        None => {
+            state.remove(&key);
+        }
+    }
+}
+
+fn delete(
+    scope: &mut v8::HandleScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let Some(key) = key_arg(scope, &args, "delete") else {
+        return;
+    };
+    let Some(state) = state(scope) else {
+        return;
+    };
+    let removed = state.lock().unwrap().remove(&key).is_some();
+    rv.set(v8::Boolean::new(scope, removed).into());
+}
+
+fn keys(
+    scope: &mut v8::HandleScope,
+    _args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let Some(state) = state(scope) else {
+        return;
+    };
+    let keys: Vec<String> = state.lock().unwrap().keys().cloned().collect();
+    let keys: Option<Vec<v8::Local<v8::Value>>> = keys
+        .iter()
+        .map(|key| v8::String::new(scope, key).map(|key| key.into()))
+        .collect();
+    let Some(keys) = keys else {
+        return;
+    };
+    rv.set(v8::Array::new_with_elements(scope, &keys).into());
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::runtime::js;
+    use crate::runtime::runtime::ScriptRuntime;
+    use crate::runtime::state::{ScriptState, StateValue};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn state() {
+        let state = Arc::new(Mutex::new(ScriptState::new()));
+
+        // スクリプトから保存した値は ScriptState に書き込まれる
+        let mut runtime = js::JsRuntimeBuilder::new().state(state.clone()).build();
+        runtime
+            .compile(
+                r#"
+                ps88.state.set("pattern", { steps: [1, 0, 1, 0] });
+                ps88.state.set("table", new Float32Array([1]));
+                ps88.state.set("removed", 1);
+                ps88.state.delete("removed");
+                const audio = (ctx) => {};
+                const gui = () => {};
+            "#,
+                None,
+            )
+            .unwrap();
+        {
+            let state = state.lock().unwrap();
+            assert_eq!(
+                state.get("pattern"),
+                Some(&StateValue::Json(r#"{"steps":[1,0,1,0]}"#.into()))
+            );
+            assert_eq!(
+                state.get("table"),
+                Some(&StateValue::Binary(1.0f32.to_ne_bytes().to_vec()))
+            );
+            assert_eq!(state.get("removed"), None);
+        }
+
+        // 別のランタイムでも、audio の呼び出し前に保存された値を読める
+        let mut runtime = js::JsRuntimeBuilder::new().state(state.clone()).build();
+        runtime
+            .compile(
+                r#"
+                const pattern = ps88.state.get("pattern");
+                const table = new Float32Array(ps88.state.get("table"));
+                const keys = ps88.state.keys();
+                const audio = (ctx) => {
+                    ctx.audio.set([pattern.steps[2], table[0], keys.length, ps88.state.get("missing") ?? -1]);
+                };
+                const gui = () => {};
+            "#,
+                None,
+            )
+            .unwrap();
+        let mut audio = vec![0.0; 4];
+        runtime.audio(&mut audio, 1, 48000.0, &[]).unwrap();
+        assert_eq!(audio, vec![1.0, 1.0, 2.0, -1.0]);
+
+        // JSON に変換できない値はエラーになる
+        let result = runtime.compile(
+            r#"
+                ps88.state.set("f", () => {});
+                const audio = (ctx) => {};
+                const gui = () => {};
+            "#,
+            None,
+        );
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("JSON-serializable"));
+    }
+}
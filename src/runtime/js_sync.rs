@@ -1,59 +1,172 @@
+use crate::runtime::code_cache::CodeCache;
+use crate::runtime::gui::{GuiContext, Shape};
 use crate::runtime::js;
+use crate::runtime::js_inspector::InspectorServer;
+use crate::runtime::js_worker::{Instance, InstanceSender, WorkerPool};
+use crate::runtime::midi::{self, MidiEvent};
+use crate::runtime::profile::Profile;
 use crate::runtime::runtime;
 use crate::runtime::runtime::ScriptRuntime;
+use crate::runtime::state::ScriptState;
+use crate::runtime::stats::{BlockTiming, ProcessStats};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::{Duration, Instant};
+
+// デバッガーで停止していないかを確認する間隔
+const PAUSE_CHECK_INTERVAL: Duration = Duration::from_millis(1);
+
+// audio の制限時間 (ブロックの長さの倍数)。
+// 超えるとワーカーがスクリプトを止め (同じワーカーの他のインスタンスを止めないため)、audio は待つのをやめて無音を出力する
+const AUDIO_TIME_LIMIT_BLOCKS: f64 = 8.0;
+
+// 短いブロックで、GC などの一時的な遅れでスクリプトを止めてしまわないように、audio の制限時間はこれより短くしない
+const MIN_AUDIO_TIME_LIMIT: Duration = Duration::from_millis(100);
+
+// gui(ctx) の制限時間
+const GUI_TIME_LIMIT: Duration = Duration::from_secs(1);
+
+// コンパイルや REPL の評価など、その他のメッセージの制限時間
+const TIME_LIMIT: Duration = Duration::from_secs(10);
 
 pub struct JsRuntimeBuilder {
     on_log: Option<std::sync::Arc<dyn Fn(String) + Send + Sync>>,
+    state: Option<std::sync::Arc<std::sync::Mutex<ScriptState>>>,
+    inspector: Option<InspectorServer>,
+    stats: Option<std::sync::Arc<ProcessStats>>,
+    code_cache: Option<CodeCache>,
+    pool: Option<std::sync::Arc<WorkerPool>>,
 }
 
 pub struct JsRuntime {
-    message: std::sync::mpsc::Sender<Message>,
+    // スクリプトを実行するワーカー上のランタイム
+    instance: Instance,
+
+    // スクリプトがブレークポイントで停止している間 true になる
+    paused: std::sync::Arc<AtomicBool>,
+
+    // ブロックごとの処理時間の記録先
+    stats: Option<std::sync::Arc<ProcessStats>>,
+
+    // 停止中に受け取った、鳴っている音を止める MIDI イベント。
+    // 音が鳴り続けないように、再開した後の最初のブロックの先頭で渡す
+    held_midi: Vec<MidiEvent>,
 }
 
-enum Message {
-    Compile(String, std::sync::mpsc::Sender<runtime::Result<()>>),
+/// JsRuntime と同じランタイムの gui(ctx) の呼び出しや、依存するファイルの取得をするハンドル。
+/// オーディオのスレッドと共有する JsRuntime のロックを取らずに、エディタのスレッドから使う。
+#[derive(Clone)]
+pub struct GuiHandle {
+    sender: InstanceSender,
+    paused: std::sync::Arc<AtomicBool>,
+}
+
+pub(crate) enum Message {
+    Compile(
+        String,
+        Option<std::path::PathBuf>,
+        std::sync::mpsc::Sender<runtime::Result<()>>,
+    ),
+    Dependencies(std::sync::mpsc::Sender<Vec<std::path::PathBuf>>),
+    Evaluate(String, std::sync::mpsc::Sender<runtime::Result<String>>),
+    StartProfiling(std::sync::mpsc::Sender<runtime::Result<()>>),
+    StopProfiling(std::sync::mpsc::Sender<runtime::Result<Profile>>),
+    Gui(
+        GuiContext,
+        std::sync::mpsc::Sender<runtime::Result<Vec<Shape>>>,
+    ),
     Audio(
         Vec<f32>,
         usize,
         f32,
         Vec<u8>,
-        std::sync::mpsc::Sender<(runtime::Result<()>, Vec<f32>)>,
+        // 送信した時刻 (処理が始まるまでの待ち時間の計測に使う)
+        Instant,
+        std::sync::mpsc::Sender<(
+            runtime::Result<Vec<runtime::OutputEvent>>,
+            Vec<f32>,
+            BlockTiming,
+        )>,
     ),
 }
 
+impl Message {
+    /// ワーカーがこのメッセージの処理に使える時間。超えた場合はスクリプトの実行を止める。
+    pub(crate) fn time_limit(&self) -> Duration {
+        match self {
+            Message::Audio(audio, ch, sampling_rate, ..) => {
+                audio_time_limit(audio.len() / (*ch).max(1), *sampling_rate)
+            }
+            Message::Gui(..) => GUI_TIME_LIMIT,
+            _ => TIME_LIMIT,
+        }
+    }
+}
+
+// frames 個のサンプルのブロックを処理する audio の制限時間
+fn audio_time_limit(frames: usize, sampling_rate: f32) -> Duration {
+    if sampling_rate <= 0.0 {
+        return TIME_LIMIT;
+    }
+    let block = Duration::from_secs_f64(frames as f64 / sampling_rate as f64);
+    block
+        .mul_f64(AUDIO_TIME_LIMIT_BLOCKS)
+        .clamp(MIN_AUDIO_TIME_LIMIT, TIME_LIMIT)
+}
+
 impl JsRuntimeBuilder {
     pub fn new() -> Self {
-        JsRuntimeBuilder { on_log: None }
+        JsRuntimeBuilder {
+            on_log: None,
+            state: None,
+            inspector: None,
+            stats: None,
+            code_cache: None,
+            pool: None,
+        }
     }
 
     pub fn build(self) -> JsRuntime {
-        let (message_tx, message_rx) = std::sync::mpsc::channel();
-        std::thread::spawn(move || {
-            let builder = js::JsRuntimeBuilder::new();
-            let builder = if let Some(on_log) = self.on_log {
-                builder.on_log(std::rc::Rc::new(move |log| {
-                    on_log(log);
-                }))
-            } else {
-                builder
-            };
-            let mut runtime = builder.build();
-            for event in message_rx {
-                match event {
-                    Message::Compile(code, output_tx) => {
-                        let result = runtime.compile(&code);
-                        let _ = output_tx.send(result);
-                    }
-                    Message::Audio(mut audio, ch, sampling_rate, midi, output_tx) => {
-                        // TODO: unsafe を使えば audio は参照渡しで読み書きできるかもしれない
-                        let result = runtime.audio(&mut audio, ch, sampling_rate, &midi);
-                        let _ = output_tx.send((result, audio));
-                    }
-                }
-            }
-        });
+        let paused = match &self.inspector {
+            Some(server) => server.paused(),
+            None => Default::default(),
+        };
+        let dedicated = self.inspector.is_some();
+        let pool = self.pool.unwrap_or_else(WorkerPool::shared);
+        let instance = pool.spawn(
+            move || {
+                let builder = js::JsRuntimeBuilder::new();
+                let builder = if let Some(on_log) = self.on_log {
+                    builder.on_log(std::rc::Rc::new(move |log| {
+                        on_log(log);
+                    }))
+                } else {
+                    builder
+                };
+                let builder = if let Some(state) = self.state {
+                    builder.state(state)
+                } else {
+                    builder
+                };
+                let builder = if let Some(server) = self.inspector {
+                    builder.inspector(server)
+                } else {
+                    builder
+                };
+                let builder = if let Some(cache) = self.code_cache {
+                    builder.code_cache(cache)
+                } else {
+                    builder
+                };
+                builder.build()
+            },
+            dedicated,
+        );
         JsRuntime {
-            message: message_tx,
+            instance,
+            paused,
+            stats: self.stats,
+            held_midi: Vec::new(),
         }
     }
 
@@ -61,18 +174,209 @@ impl JsRuntimeBuilder {
         self.on_log = Some(on_log);
         self
     }
+
+    /// スクリプトが ps88.state で読み書きするデータの保存先
+    pub fn state(mut self, state: std::sync::Arc<std::sync::Mutex<ScriptState>>) -> Self {
+        self.state = Some(state);
+        self
+    }
+
+    /// Chrome DevTools から接続してデバッグできるようにする。
+    /// ブレークポイントで停止している間、audio は停止したスレッドを待たずに無音を返す。
+    pub fn inspector(mut self, server: InspectorServer) -> Self {
+        self.inspector = Some(server);
+        self
+    }
+
+    /// ブロックごとのスクリプトの実行時間、待ち時間、GC の時間を記録する
+    pub fn stats(mut self, stats: std::sync::Arc<ProcessStats>) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    /// スクリプトのコンパイルに V8 のコードキャッシュを使う
+    pub fn code_cache(mut self, cache: CodeCache) -> Self {
+        self.code_cache = Some(cache);
+        self
+    }
+
+    /// スクリプトを実行するワーカーのプール。指定しない場合はプロセスで共有するプールを使う。
+    /// inspector を使う場合は、デバッガーで停止しても他のランタイムを止めないように専用のワーカーが割り当てられる。
+    pub fn pool(mut self, pool: std::sync::Arc<WorkerPool>) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+}
+
+impl JsRuntime {
+    fn receive<T>(&self, rx: std::sync::mpsc::Receiver<T>) -> Result<T, js::JsRuntimeError> {
+        receive(rx, &self.paused, None)
+    }
+
+    // 停止中に受け取った MIDI のうち、音を止めるイベントを再開するまで取っておく。
+    // 同じ音やペダルのイベントは 1 つあれば足りるので、停止が長くてもたまり続けない
+    fn hold_releases(&mut self, midi: &[u8]) {
+        for event in midi::decode(midi).filter(MidiEvent::is_release) {
+            let event = MidiEvent { timing: 0, ..event };
+            if !self
+                .held_midi
+                .iter()
+                .any(|e| e.status == event.status && e.data1 == event.data1)
+            {
+                self.held_midi.push(event);
+            }
+        }
+    }
+
+    // 取っておいたイベントを、このブロックの MIDI の前に加える
+    fn with_held_midi(&mut self, midi: &[u8]) -> Vec<u8> {
+        if self.held_midi.is_empty() {
+            return midi.to_vec();
+        }
+        let mut all: Vec<u8> = self.held_midi.drain(..).flat_map(|e| e.encode()).collect();
+        all.extend_from_slice(midi);
+        all
+    }
+
+    /// ロックを取らずに gui(ctx) を呼び出すためのハンドルを作る
+    pub fn gui_handle(&self) -> GuiHandle {
+        GuiHandle {
+            sender: self.instance.sender(),
+            paused: self.paused.clone(),
+        }
+    }
+}
+
+impl GuiHandle {
+    /// スクリプトの gui(ctx) を呼び出す。JsRuntime が drop された後はエラーを返す。
+    pub fn gui(&self, ctx: &GuiContext) -> runtime::Result<Vec<Shape>> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        self.sender.send(Message::Gui(ctx.clone(), tx))?;
+        receive(rx, &self.paused, None)?
+    }
+
+    /// スクリプトが import したファイルと読み込んだサンプルの一覧を返す。
+    /// スクリプトが停止している場合などで取得できない時はエラーを返す。
+    pub fn dependencies(&self) -> runtime::Result<Vec<std::path::PathBuf>> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        self.sender.send(Message::Dependencies(tx))?;
+        receive(rx, &self.paused, None)
+    }
+}
+
+// 結果を待つ。スクリプトが停止している場合は待たずに Paused を返し、deadline を過ぎたら Timeout を返す。
+// (停止中に送ったメッセージは、再開した後に処理される)
+fn receive<T>(
+    rx: std::sync::mpsc::Receiver<T>,
+    paused: &AtomicBool,
+    deadline: Option<(Instant, Duration)>,
+) -> Result<T, js::JsRuntimeError> {
+    loop {
+        match rx.recv_timeout(PAUSE_CHECK_INTERVAL) {
+            Ok(result) => return Ok(result),
+            Err(RecvTimeoutError::Timeout) if paused.load(Ordering::Acquire) => {
+                return Err(js::JsRuntimeError::Paused)
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                if let Some((deadline, limit)) = deadline {
+                    if Instant::now() >= deadline {
+                        return Err(js::JsRuntimeError::Timeout(limit));
+                    }
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(js::JsRuntimeError::UnexpectedError(
+                    "failed to receive".into(),
+                ))
+            }
+        }
+    }
+}
+
+/// ワーカーのスレッドでメッセージを処理する。オーディオのブロックを処理した場合は true を返す。
+pub(crate) fn handle(runtime: &mut js::JsRuntime, message: Message) -> bool {
+    match message {
+        Message::Compile(code, path, output_tx) => {
+            let result = runtime.compile(&code, path.as_deref());
+            let _ = output_tx.send(result);
+        }
+        Message::Dependencies(output_tx) => {
+            let _ = output_tx.send(runtime.dependencies());
+        }
+        // REPL のコードはオーディオのブロックの処理の合間に評価される
+        Message::Evaluate(code, output_tx) => {
+            let _ = output_tx.send(runtime.evaluate(&code));
+        }
+        Message::StartProfiling(output_tx) => {
+            let _ = output_tx.send(runtime.start_profiling());
+        }
+        Message::StopProfiling(output_tx) => {
+            let _ = output_tx.send(runtime.stop_profiling());
+        }
+        Message::Gui(ctx, output_tx) => {
+            let _ = output_tx.send(runtime.gui(&ctx));
+        }
+        Message::Audio(mut audio, ch, sampling_rate, midi, sent, output_tx) => {
+            let start = Instant::now();
+            let gc = runtime.gc_stats();
+            // TODO: unsafe を使えば audio は参照渡しで読み書きできるかもしれない
+            let result = runtime.audio(&mut audio, ch, sampling_rate, &midi);
+            let script = start.elapsed();
+            let gc_after = runtime.gc_stats();
+            let timing = BlockTiming {
+                script,
+                wait: start.saturating_duration_since(sent),
+                gc: gc_after.pause.saturating_sub(gc.pause),
+                gc_count: gc_after.count - gc.count,
+                heap_used: runtime.heap_used(),
+            };
+            let _ = output_tx.send((result, audio, timing));
+            return true;
+        }
+    }
+    false
 }
 
 impl runtime::ScriptRuntime for JsRuntime {
-    fn compile(&mut self, code: &str) -> runtime::Result<()> {
+    fn compile(&mut self, code: &str, path: Option<&std::path::Path>) -> runtime::Result<()> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        self.instance.send(Message::Compile(
+            code.to_string(),
+            path.map(|p| p.to_path_buf()),
+            tx,
+        ))?;
+        self.receive(rx)?
+    }
+
+    fn dependencies(&mut self) -> Vec<std::path::PathBuf> {
         let (tx, rx) = std::sync::mpsc::channel();
-        self.message
-            .send(Message::Compile(code.to_string(), tx))
-            .map_err(|_| js::JsRuntimeError::UnexpectedError("failed to send".into()))?;
-        match rx.recv() {
-            Ok(result) => result,
-            _ => Err(js::JsRuntimeError::UnexpectedError("failed to receive".into()).into()),
+        if self.instance.send(Message::Dependencies(tx)).is_err() {
+            return Vec::new();
         }
+        self.receive(rx).unwrap_or_default()
+    }
+
+    fn evaluate(&mut self, code: &str) -> runtime::Result<String> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        self.instance
+            .send(Message::Evaluate(code.to_string(), tx))?;
+        self.receive(rx)?
+    }
+
+    fn start_profiling(&mut self) -> runtime::Result<()> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        self.instance.send(Message::StartProfiling(tx))?;
+        self.receive(rx)?
+    }
+
+    fn stop_profiling(&mut self) -> runtime::Result<Profile> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        self.instance.send(Message::StopProfiling(tx))?;
+        self.receive(rx)?
+    }
+
+    fn gui(&mut self, ctx: &GuiContext) -> runtime::Result<Vec<Shape>> {
+        self.gui_handle().gui(ctx)
     }
 
     fn audio(
@@ -81,26 +385,49 @@ impl runtime::ScriptRuntime for JsRuntime {
         ch: usize,
         sampling_rate: f32,
         midi: &[u8],
-    ) -> runtime::Result<()> {
+    ) -> runtime::Result<Vec<runtime::OutputEvent>> {
+        // 停止中は処理を頼まずに無音を出力する
+        if self.paused.load(Ordering::Acquire) {
+            self.hold_releases(midi);
+            audio.fill(0.0);
+            return Ok(Vec::new());
+        }
+        let midi = self.with_held_midi(midi);
         let (tx, rx) = std::sync::mpsc::channel();
-        self.message
-            .send(Message::Audio(
-                audio.to_vec(),
-                ch,
-                sampling_rate,
-                midi.to_vec(),
-                tx,
-            ))
-            .map_err(|_| js::JsRuntimeError::UnexpectedError("failed to send".into()))?;
-        match rx.recv() {
-            Ok((result, out_audio)) => {
+        let sent = Instant::now();
+        let limit = audio_time_limit(audio.len() / ch.max(1), sampling_rate);
+        self.instance.send(Message::Audio(
+            audio.to_vec(),
+            ch,
+            sampling_rate,
+            midi,
+            sent,
+            tx,
+        ))?;
+        // ワーカーが他のインスタンスのスクリプトで止まっていても、ホストのオーディオのスレッドを待たせ続けない
+        match receive(rx, &self.paused, Some((sent + limit, limit))) {
+            Ok((result, out_audio, timing)) => {
                 audio
                     .iter_mut()
                     .zip(out_audio.iter())
                     .for_each(|(o, v)| *o = *v);
+                if let Some(stats) = self.stats.as_ref().filter(|_| sampling_rate > 0.0) {
+                    let frames = audio.len() / ch.max(1);
+                    let budget = Duration::from_secs_f64(frames as f64 / sampling_rate as f64);
+                    stats.record(timing, budget);
+                }
                 result
             }
-            _ => Err(js::JsRuntimeError::UnexpectedError("failed to receive".into()).into()),
+            // このブロックの処理中に停止した場合
+            Err(js::JsRuntimeError::Paused) => {
+                audio.fill(0.0);
+                Ok(Vec::new())
+            }
+            Err(err @ js::JsRuntimeError::Timeout(_)) => {
+                audio.fill(0.0);
+                Err(err.into())
+            }
+            Err(err) => Err(err.into()),
         }
     }
 }
@@ -150,6 +477,7 @@ mod tests {
                 "#
                         .replace("${i}", &i.to_string())
                         .as_str(),
+                        None,
                     )
                     .unwrap();
 
@@ -189,4 +517,130 @@ mod tests {
         assert_eq!(logs[10], "init: 2, count: 1");
         assert_eq!(logs[11], "init: 2, count: 2");
     }
+
+    #[test]
+    fn stats() {
+        let stats = std::sync::Arc::new(ProcessStats::new());
+        let mut runtime = JsRuntimeBuilder::new().stats(stats.clone()).build();
+        runtime
+            .compile(
+                r#"
+                    const audio = (ctx) => {
+                        for (let i = 0; i < ctx.audio.length; i++) ctx.audio[i] = Math.sin(i);
+                    };
+                    const gui = () => {};
+                "#,
+                None,
+            )
+            .unwrap();
+        let mut audio = vec![0.0; 256];
+        for _ in 0..10 {
+            runtime.audio(&mut audio, 2, 48000.0, &[]).unwrap();
+        }
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.blocks, 10);
+        assert!(snapshot.script > Duration::ZERO);
+        assert!(snapshot.average_load > 0.0);
+    }
+
+    #[test]
+    fn gui_handle() {
+        use crate::runtime::gui::GuiContext;
+
+        let mut runtime = JsRuntimeBuilder::new().build();
+        runtime
+            .compile(
+                r#"
+                    const audio = (ctx) => {};
+                    const gui = (ctx) => [{ type: "rect", x: 0, y: 0, width: ctx.width, height: 1 }];
+                "#,
+                None,
+            )
+            .unwrap();
+        let handle = runtime.gui_handle();
+        let ctx = GuiContext {
+            width: 200.0,
+            ..Default::default()
+        };
+
+        // オーディオのスレッドが JsRuntime をロックしていても gui(ctx) を呼び出せる
+        let runtime = std::sync::Arc::new(std::sync::Mutex::new(runtime));
+        let locked = runtime.lock().unwrap();
+        let shapes = handle.gui(&ctx).unwrap();
+        assert!(matches!(shapes[..], [Shape::Rect { width, .. }] if width == 200.0));
+        assert_eq!(
+            handle.dependencies().unwrap(),
+            Vec::<std::path::PathBuf>::new()
+        );
+        drop(locked);
+
+        // JsRuntime が破棄された後はエラーになる
+        drop(runtime);
+        assert!(handle.gui(&ctx).is_err());
+        assert!(handle.dependencies().is_err());
+    }
+
+    #[test]
+    fn silence_while_paused() {
+        use crate::runtime::js_inspector::tests::{call, connect, wait_for};
+        use std::time::Instant;
+
+        let server = InspectorServer::start("127.0.0.1:0").unwrap();
+        let paused = server.paused();
+        let mut websocket = connect(&server);
+        let mut runtime = JsRuntimeBuilder::new().inspector(server).build();
+        runtime
+            .compile(
+                r#"
+                    const audio = (ctx) => {
+                        debugger;
+                        if (ctx.midi.length > 0) {
+                            globalThis.midi = Array.from(ctx.midi).join(",");
+                        }
+                        ctx.audio.fill(1);
+                    };
+                    const gui = () => {};
+                "#,
+                None,
+            )
+            .unwrap();
+
+        // デバッガーが有効になると debugger 文で止まる
+        call(&mut websocket, 1, "Debugger.enable");
+        let mut audio = vec![0.5; 4];
+        let start = Instant::now();
+        while !paused.load(Ordering::Acquire) {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            runtime.audio(&mut audio, 1, 48000.0, &[]).unwrap();
+        }
+        wait_for(&mut websocket, |m| m["method"] == "Debugger.paused");
+
+        // 停止中は無音を出力する
+        let note_on = MidiEvent::new(1, midi::NOTE_ON, 0, 60, 100).encode();
+        let note_off = MidiEvent::new(2, midi::NOTE_OFF, 0, 60, 0).encode();
+        for midi in [&note_on[..], &note_off[..], &note_off[..]] {
+            audio.fill(0.5);
+            runtime.audio(&mut audio, 1, 48000.0, midi).unwrap();
+            assert_eq!(audio, vec![0.0; 4]);
+        }
+        assert!(runtime.evaluate("1 + 1").is_err());
+
+        // 再開してデバッガーを無効にすると、スクリプトの出力に戻る
+        call(&mut websocket, 2, "Debugger.resume");
+        call(&mut websocket, 3, "Debugger.disable");
+        loop {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            runtime.audio(&mut audio, 1, 48000.0, &[]).unwrap();
+            if audio == vec![1.0; 4] {
+                break;
+            }
+        }
+        assert_eq!(runtime.evaluate("1 + 1").unwrap(), "2");
+
+        // 停止中に受け取ったノートオフだけが、再開後のブロックの先頭で 1 度渡される
+        assert_eq!(
+            runtime.evaluate("midi === '0,0,0,0,128,60,0'").unwrap(),
+            "true"
+        );
+    }
 }
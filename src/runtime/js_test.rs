@@ -0,0 +1,163 @@
+use crate::runtime::js::{
+    get_ps88, report_exception_value, report_exceptions, set_function, JsRuntimeError,
+};
+use crate::runtime::js_sample;
+use crate::runtime::runtime;
+use std::cell::RefCell;
+use std::rc::Rc;
+use v8;
+
+/// ps88.test(name, fn) で登録されたテスト。
+/// v8 の関数コールバックにはユーザーデータを渡せないため、isolate の slot に保存して使う。
+///
+/// テストはプラグインとしての実行中には実行されず、ps88 test コマンドなどから run で実行する。
+/// 関数が例外を投げるか、返した Promise が reject された場合は失敗になる。
+///
+/// e.g.
+///   ps88.test("silent without notes", () => {
+///     const ctx = ps88.testContext({ length: 128 });
+///     audio(ctx);
+///     ps88.assertClose(ctx.audio, new Float32Array(256));
+///   });
+pub type TestRegistry = Rc<RefCell<Vec<(String, v8::Global<v8::Function>)>>>;
+
+/// テストの結果
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestResult {
+    pub name: String,
+
+    // 失敗した場合はその理由
+    pub error: Option<String>,
+}
+
+/// ps88.test を登録する
+pub fn install(scope: &mut v8::HandleScope) -> runtime::Result<()> {
+    let error = || JsRuntimeError::UnexpectedError("failed to install ps88.test".into());
+    scope.set_slot(TestRegistry::default());
+    let ps88 = get_ps88(scope).ok_or_else(error)?;
+    set_function(scope, ps88, "test", test).ok_or_else(error)?;
+    Ok(())
+}
+
+fn test(scope: &mut v8::HandleScope, args: v8::FunctionCallbackArguments, _rv: v8::ReturnValue) {
+    let Ok(function) = v8::Local::<v8::Function>::try_from(args.get(1)) else {
+        let message =
+            v8::String::new(scope, "ps88.test: the second argument must be a function").unwrap();
+        let exception = v8::Exception::type_error(scope, message);
+        scope.throw_exception(exception);
+        return;
+    };
+    let name = args.get(0).to_rust_string_lossy(scope);
+    let function = v8::Global::new(scope, function);
+    if let Some(registry) = scope.get_slot::<TestRegistry>() {
+        registry.borrow_mut().push((name, function));
+    }
+}
+
+/// 登録されたテストを順に実行する
+pub fn run(scope: &mut v8::HandleScope) -> Vec<TestResult> {
+    let Some(registry) = scope.get_slot::<TestRegistry>().cloned() else {
+        return Vec::new();
+    };
+    let tests = registry.borrow().clone();
+    tests
+        .into_iter()
+        .map(|(name, function)| TestResult {
+            name,
+            error: run_test(scope, function).err(),
+        })
+        .collect()
+}
+
+fn run_test(scope: &mut v8::HandleScope, function: v8::Global<v8::Function>) -> Result<(), String> {
+    let function = v8::Local::new(scope, &function);
+    let mut try_catch = v8::TryCatch::new(scope);
+    let this = v8::undefined(&mut try_catch).into();
+    let Some(result) = function.call(&mut try_catch, this, &[]) else {
+        return Err(report_exceptions(try_catch));
+    };
+
+    // async 関数の場合は完了を待つ
+    // ps88.loadSample を待っている場合は、読み込みが終わるたびに処理を進める
+    try_catch.perform_microtask_checkpoint();
+    let Ok(promise) = v8::Local::<v8::Promise>::try_from(result) else {
+        return Ok(());
+    };
+    while matches!(promise.state(), v8::PromiseState::Pending) {
+        if !js_sample::wait(&mut try_catch) {
+            break;
+        }
+        try_catch.perform_microtask_checkpoint();
+    }
+    match promise.state() {
+        v8::PromiseState::Fulfilled => Ok(()),
+        v8::PromiseState::Rejected => {
+            let exception = promise.result(&mut try_catch);
+            Err(report_exception_value(&mut try_catch, exception))
+        }
+        v8::PromiseState::Pending => Err("the test never finished".into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::runtime::js;
+    use crate::runtime::runtime::ScriptRuntime;
+
+    #[test]
+    fn run_tests() {
+        let mut runtime = js::JsRuntimeBuilder::new().build();
+        runtime
+            .compile(
+                r#"
+                const audio = (ctx) => {
+                    for (const e of ps88.parseMidi(ctx.midi)) {
+                        ctx.audio[e.time] = e.data1;
+                    }
+                };
+                const gui = () => {};
+                ps88.test("passes", () => {
+                    const ctx = ps88.testContext({
+                        channels: 1,
+                        length: 4,
+                        midi: [{ time: 2, type: 0x9, channel: 0, data1: 60, data2: 100 }],
+                    });
+                    audio(ctx);
+                    ps88.assertClose(ctx.audio, [0, 0, 60, 0]);
+                });
+                ps88.test("fails", () => {
+                    ps88.assert(1 + 1 === 3, "math is broken");
+                });
+                ps88.test("async", async () => {
+                    await Promise.resolve();
+                    ps88.assertClose(0.1 + 0.2, 0.3);
+                });
+                ps88.test("rejects", async () => {
+                    await Promise.resolve();
+                    ps88.assertClose([1, 2], [1, 2.5], 0.1);
+                });
+            "#,
+                None,
+            )
+            .unwrap();
+
+        // テストはコンパイル時には実行されない
+        let results = runtime.run_tests().unwrap();
+        let names: Vec<&str> = results.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["passes", "fails", "async", "rejects"]);
+        assert_eq!(results[0].error, None);
+        assert!(results[1]
+            .error
+            .as_ref()
+            .unwrap()
+            .contains("AssertionError: math is broken"));
+        assert_eq!(results[2].error, None);
+        assert!(results[3].error.as_ref().unwrap().contains("index 1"));
+
+        // 再コンパイルすると登録されたテストは消える
+        runtime
+            .compile("const audio = () => {}; const gui = () => {};", None)
+            .unwrap();
+        assert!(runtime.run_tests().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,270 @@
+use crate::runtime::js::{get_ps88, report_exceptions, JsRuntimeError};
+use crate::runtime::midi;
+use crate::runtime::runtime;
+use crate::runtime::voice::{StealMode, VoiceConfig, VoiceEvent, VoiceManager};
+use std::collections::HashMap;
+use std::mem::size_of;
+use v8;
+
+/// ボイスマネージャーとスクリプトの voice(v, out, ctx) 関数をつなぐ。
+///
+/// v はボイスごとの状態を持つ ps88.Voice のインスタンスで、ボイスが終了するまで同じオブジェクトが渡される。
+/// out は ctx.audio と同じ並びのバッファで、MIDI イベントの時刻で区切られた区間の長さになっている。
+/// スクリプトが v.free() を呼ぶとボイスは終了する。
+pub struct JsVoices {
+    manager: VoiceManager,
+    voice_func: v8::Global<v8::Function>,
+    voice_class: v8::Global<v8::Function>,
+    objects: HashMap<u64, v8::Global<v8::Object>>,
+    buffer: v8::Global<v8::ArrayBuffer>,
+}
+
+impl JsVoices {
+    /// options はスクリプトが定義した voiceOptions
+    /// e.g. { maxVoices: 16, steal: "oldest" | "quietest", releaseTimeout: 10 }
+    pub fn new(
+        scope: &mut v8::HandleScope,
+        voice_func: v8::Local<v8::Function>,
+        options: Option<v8::Local<v8::Object>>,
+    ) -> runtime::Result<Self> {
+        let config = parse_config(scope, options);
+        let voice_class = get_ps88(scope)
+            .and_then(|ps88| get(scope, ps88, "Voice"))
+            .and_then(|class| v8::Local::<v8::Function>::try_from(class).ok());
+        let Some(voice_class) = voice_class else {
+            return Err(JsRuntimeError::UnexpectedError("ps88.Voice is not defined".into()).into());
+        };
+        let buffer = v8::ArrayBuffer::new(scope, 0);
+        Ok(JsVoices {
+            manager: VoiceManager::new(config),
+            voice_func: v8::Global::new(scope, voice_func),
+            voice_class: v8::Global::new(scope, voice_class),
+            objects: HashMap::new(),
+            buffer: v8::Global::new(scope, buffer),
+        })
+    }
+
+    /// MIDI イベントに従ってボイスを発音し、全てのボイスの出力を audio に書き込む
+    pub fn process(
+        &mut self,
+        scope: &mut v8::HandleScope,
+        audio: &mut [f32],
+        ch: usize,
+        sampling_rate: f32,
+        midi: &[u8],
+        ctx: v8::Local<v8::Object>,
+    ) -> runtime::Result<Vec<runtime::OutputEvent>> {
+        let frames = if ch == 0 { 0 } else { audio.len() / ch };
+        let events: Vec<midi::MidiEvent> = midi::decode(midi).collect();
+        let timing = |e: &midi::MidiEvent| (e.timing as usize).min(frames);
+        let mut output = Vec::new();
+        audio.fill(0.0);
+
+        if v8::Local::new(scope, &self.buffer).byte_length() < audio.len() * size_of::<f32>() {
+            let buffer = v8::ArrayBuffer::new(scope, audio.len() * size_of::<f32>());
+            self.buffer = v8::Global::new(scope, buffer);
+        }
+
+        // MIDI イベントの時刻で区切って処理する
+        let mut cursor = 0;
+        let mut next = 0;
+        loop {
+            while next < events.len() && timing(&events[next]) <= cursor {
+                self.manager.handle(&events[next]);
+                next += 1;
+            }
+            self.sync(scope, &mut output)?;
+            let end = events.get(next).map(timing).unwrap_or(frames);
+            if end > cursor {
+                self.render(scope, audio, ch, frames, cursor..end, ctx)?;
+                self.manager
+                    .advance((end - cursor) as u32, (end - 1) as u32, sampling_rate);
+                self.sync(scope, &mut output)?;
+            }
+            cursor = end;
+            if cursor >= frames && next >= events.len() {
+                break;
+            }
+        }
+        Ok(output)
+    }
+
+    // ボイスの開始/終了に合わせて ps88.Voice のインスタンスを作成/破棄する
+    fn sync(
+        &mut self,
+        scope: &mut v8::HandleScope,
+        output: &mut Vec<runtime::OutputEvent>,
+    ) -> runtime::Result<()> {
+        for event in self.manager.take_events() {
+            match event {
+                VoiceEvent::Started { id } => {
+                    let Some(voice) = self.manager.voices().iter().find(|v| v.id == id) else {
+                        continue;
+                    };
+                    let args = [
+                        v8::Number::new(scope, id as f64).into(),
+                        v8::Integer::new(scope, voice.note as i32).into(),
+                        v8::Integer::new(scope, voice.channel as i32).into(),
+                        v8::Number::new(scope, voice.velocity as f64).into(),
+                    ];
+                    let class = v8::Local::new(scope, &self.voice_class);
+                    let mut try_catch = v8::TryCatch::new(scope);
+                    let Some(object) = class.new_instance(&mut try_catch, &args) else {
+                        return Err(
+                            JsRuntimeError::ProcessError(report_exceptions(try_catch)).into()
+                        );
+                    };
+                    self.objects
+                        .insert(id, v8::Global::new(&mut try_catch, object));
+                }
+                VoiceEvent::Terminated {
+                    timing,
+                    id,
+                    channel,
+                    note,
+                } => {
+                    self.objects.remove(&id);
+                    output.push(runtime::OutputEvent::VoiceTerminated {
+                        timing,
+                        channel,
+                        note,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // range の区間について、各ボイスの voice(v, out, ctx) を呼び出して audio に加算する
+    fn render(
+        &mut self,
+        scope: &mut v8::HandleScope,
+        audio: &mut [f32],
+        ch: usize,
+        frames: usize,
+        range: std::ops::Range<usize>,
+        ctx: v8::Local<v8::Object>,
+    ) -> runtime::Result<()> {
+        let length = range.len();
+        let buffer = v8::Local::new(scope, &self.buffer);
+        let Some(out) = v8::Float32Array::new(scope, buffer, 0, length * ch) else {
+            return Err(
+                JsRuntimeError::UnexpectedError("failed to create voice buffer".into()).into(),
+            );
+        };
+        let backing_store = buffer.get_backing_store();
+        let Some(pointer) = backing_store.data() else {
+            return Ok(());
+        };
+        let pointer = pointer.as_ptr() as *mut f32;
+        let voice_func = v8::Local::new(scope, &self.voice_func);
+
+        let voices = self.manager.voices().to_vec();
+        for voice in voices {
+            let Some(object) = self.objects.get(&voice.id) else {
+                continue;
+            };
+            let object = v8::Local::new(scope, object);
+            let time = v8::Number::new(scope, voice.time as f64);
+            let released = v8::Boolean::new(scope, voice.released);
+            let release_time = v8::Number::new(scope, voice.release_time as f64);
+            let sustained = v8::Boolean::new(scope, voice.sustained);
+            set(scope, object, "time", time.into());
+            set(scope, object, "released", released.into());
+            set(scope, object, "releaseTime", release_time.into());
+            set(scope, object, "sustained", sustained.into());
+
+            unsafe {
+                std::ptr::write_bytes(pointer, 0, length * ch);
+            }
+            {
+                let mut try_catch = v8::TryCatch::new(scope);
+                let this = v8::undefined(&mut try_catch).into();
+                if voice_func
+                    .call(
+                        &mut try_catch,
+                        this,
+                        &[object.into(), out.into(), ctx.into()],
+                    )
+                    .is_none()
+                {
+                    return Err(JsRuntimeError::ProcessError(report_exceptions(try_catch)).into());
+                }
+            }
+
+            // [L, L, ..., R, R, ...] の並びのまま audio の該当区間に加算する
+            let rendered =
+                unsafe { std::slice::from_raw_parts(pointer as *const f32, length * ch) };
+            for c in 0..ch {
+                let src = &rendered[c * length..(c + 1) * length];
+                let dst = &mut audio[c * frames + range.start..c * frames + range.end];
+                dst.iter_mut().zip(src).for_each(|(d, s)| *d += *s);
+            }
+
+            let done = get(scope, object, "done")
+                .map(|done| done.boolean_value(scope))
+                .unwrap_or(false);
+            if done {
+                // 区間の最後のサンプルまで出力してから終了する
+                self.manager.terminate(voice.id, (range.end - 1) as u32);
+                continue;
+            }
+            let level = get(scope, object, "level")
+                .filter(|level| level.is_number())
+                .and_then(|level| level.number_value(scope));
+            if let (Some(level), Some(voice)) = (level, self.manager.voice_mut(voice.id)) {
+                voice.level = level as f32;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_config(
+    scope: &mut v8::HandleScope,
+    options: Option<v8::Local<v8::Object>>,
+) -> VoiceConfig {
+    let mut config = VoiceConfig::default();
+    let Some(options) = options else {
+        return config;
+    };
+    if let Some(max_voices) = get(scope, options, "maxVoices")
+        .filter(|v| v.is_number())
+        .and_then(|v| v.integer_value(scope))
+    {
+        config.max_voices = max_voices.max(0) as usize;
+    }
+    if let Some(steal) = get(scope, options, "steal").filter(|v| v.is_string()) {
+        config.steal = match steal.to_rust_string_lossy(scope).as_str() {
+            "quietest" => StealMode::Quietest,
+            _ => StealMode::Oldest,
+        };
+    }
+    if let Some(release_timeout) = get(scope, options, "releaseTimeout")
+        .filter(|v| v.is_number())
+        .and_then(|v| v.number_value(scope))
+    {
+        config.release_timeout = release_timeout as f32;
+    }
+    config
+}
+
+fn get<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    object: v8::Local<v8::Object>,
+    key: &str,
+) -> Option<v8::Local<'s, v8::Value>> {
+    let key = v8::String::new(scope, key)?;
+    object.get(scope, key.into())
+}
+
+fn set(
+    scope: &mut v8::HandleScope,
+    object: v8::Local<v8::Object>,
+    key: &str,
+    value: v8::Local<v8::Value>,
+) {
+    if let Some(key) = v8::String::new(scope, key) {
+        object.set(scope, key.into(), value);
+    }
+}
@@ -0,0 +1,609 @@
+use crate::runtime::js;
+use crate::runtime::js_sync::{self, Message};
+use crate::runtime::stats::BlockTiming;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex, OnceLock, Weak};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+// ランタイムの破棄とワーカーの終了を待つ時間。
+// 過ぎた場合は実行中のスクリプトを止めてもう一度待ち、それでも終わらなければ (デバッガーで停止している場合など) スレッドを切り離す。
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// スクリプトを実行するスレッドのプール。
+/// プラグインのインスタンスごとにスレッドを作ると、大きなプロジェクトでは使われないスレッドが大量にできてしまうため、
+/// CPU のコア数までのワーカーに isolate を割り当てて共有する。isolate は作られたワーカーから移動しない。
+/// インスタンスが無くなったワーカーは、そのワーカーの isolate を全て破棄してから終了する。
+pub struct WorkerPool {
+    // 共有するワーカーの数の上限
+    size: usize,
+    workers: Mutex<Vec<Option<Worker>>>,
+    next_id: AtomicU64,
+}
+
+struct Worker {
+    sender: Sender<Task>,
+    thread: JoinHandle<()>,
+    instances: usize,
+
+    // スレッドが終了すると切断される (JoinHandle の join にはタイムアウトが無いため、これで終了を待つ)
+    exited: Receiver<()>,
+
+    // デバッガーで停止すると同じワーカーの他の isolate も止まってしまうため、
+    // inspector を使うランタイムには専用のワーカーを割り当てる
+    dedicated: bool,
+}
+
+type Create = Box<dyn FnOnce() -> js::JsRuntime + Send>;
+
+enum Task {
+    // 作成したランタイムの isolate のハンドルを OnceLock に保存する
+    Create(u64, Create, Arc<OnceLock<v8::IsolateHandle>>),
+    Message(u64, Message),
+    // 破棄が終わったら応答を返す
+    Destroy(u64, Sender<()>),
+}
+
+// ワーカー上のランタイム
+struct Entry {
+    runtime: js::JsRuntime,
+
+    // audio が制限時間を超えて止められた。再コンパイルするまで audio を実行せずにエラーを返す
+    // (終わらないスクリプトがブロックごとにワーカーを止め、同じワーカーの他のインスタンスを遅らせないようにする)
+    stalled: bool,
+}
+
+// ワーカーが処理中のメッセージの制限時間を監視し、超えたらその isolate の実行を止める
+#[derive(Default)]
+struct Watchdog {
+    state: Mutex<WatchdogState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct WatchdogState {
+    // 処理中のメッセージの期限と isolate、期限を過ぎて止めたかどうか
+    job: Option<(Instant, v8::IsolateHandle, bool)>,
+    exited: bool,
+}
+
+/// ワーカーに割り当てられたランタイム。drop するとワーカー上の isolate も破棄される。
+pub struct Instance {
+    id: u64,
+    worker: usize,
+    sender: Arc<Sender<Task>>,
+    pool: Arc<WorkerPool>,
+    isolate: Arc<OnceLock<v8::IsolateHandle>>,
+}
+
+/// Instance と同じランタイムにメッセージを送るためのハンドル。
+/// ワーカーの終了を妨げないよう Instance の Sender を弱参照で持ち、Instance が drop された後は送信に失敗する。
+#[derive(Clone)]
+pub(crate) struct InstanceSender {
+    id: u64,
+    sender: Weak<Sender<Task>>,
+}
+
+impl WorkerPool {
+    pub fn new(size: usize) -> Arc<Self> {
+        Arc::new(WorkerPool {
+            size: size.max(1),
+            workers: Mutex::new(Vec::new()),
+            next_id: AtomicU64::new(0),
+        })
+    }
+
+    /// プロセスで共有するプール (ワーカーの数は CPU のコア数まで)
+    pub fn shared() -> Arc<Self> {
+        static SHARED: OnceLock<Arc<WorkerPool>> = OnceLock::new();
+        SHARED
+            .get_or_init(|| {
+                let size = std::thread::available_parallelism().map_or(1, |n| n.get());
+                WorkerPool::new(size)
+            })
+            .clone()
+    }
+
+    /// create でランタイムを作り、ワーカーに割り当てる。
+    /// 共有するワーカーが上限に達するまでは新しいワーカーを作り、それ以降は割り当てが最も少ないワーカーを使う。
+    pub fn spawn<F>(self: &Arc<Self>, create: F, dedicated: bool) -> Instance
+    where
+        F: FnOnce() -> js::JsRuntime + Send + 'static,
+    {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut workers = self.workers.lock().unwrap();
+        let shared = workers
+            .iter()
+            .flatten()
+            .filter(|worker| !worker.dedicated)
+            .count();
+        let least_loaded = workers
+            .iter()
+            .enumerate()
+            .filter_map(|(index, worker)| Some((index, worker.as_ref()?)))
+            .filter(|(_, worker)| !worker.dedicated)
+            .min_by_key(|(_, worker)| worker.instances)
+            .map(|(index, _)| index);
+        let index = match least_loaded {
+            Some(index) if !dedicated && shared >= self.size => index,
+            _ => {
+                let (sender, receiver) = std::sync::mpsc::channel();
+                let (exited_tx, exited) = std::sync::mpsc::channel::<()>();
+                let thread = std::thread::Builder::new()
+                    .name("ps88-js-worker".into())
+                    .spawn(move || {
+                        // デバッガーで停止している間に止めないよう、inspector を使う専用のワーカーは監視しない
+                        let watchdog = (!dedicated).then(|| Arc::new(Watchdog::default()));
+                        let watcher = watchdog.clone().map(|watchdog| {
+                            std::thread::Builder::new()
+                                .name("ps88-js-watchdog".into())
+                                .spawn(move || watchdog.watch())
+                                .expect("failed to spawn a watchdog thread")
+                        });
+                        run(receiver, watchdog.as_deref());
+                        if let (Some(watchdog), Some(watcher)) = (watchdog, watcher) {
+                            watchdog.exit();
+                            let _ = watcher.join();
+                        }
+                        drop(exited_tx);
+                    })
+                    .expect("failed to spawn a worker thread");
+                let worker = Worker {
+                    sender,
+                    thread,
+                    instances: 0,
+                    exited,
+                    dedicated,
+                };
+                match workers.iter().position(Option::is_none) {
+                    Some(index) => {
+                        workers[index] = Some(worker);
+                        index
+                    }
+                    None => {
+                        workers.push(Some(worker));
+                        workers.len() - 1
+                    }
+                }
+            }
+        };
+        let worker = workers[index].as_mut().unwrap();
+        worker.instances += 1;
+        let sender = worker.sender.clone();
+        let isolate = Arc::new(OnceLock::new());
+        let _ = sender.send(Task::Create(id, Box::new(create), isolate.clone()));
+        Instance {
+            id,
+            worker: index,
+            sender: Arc::new(sender),
+            pool: self.clone(),
+            isolate,
+        }
+    }
+
+    /// 動いているワーカーのスレッドの数
+    pub fn workers(&self) -> usize {
+        self.workers.lock().unwrap().iter().flatten().count()
+    }
+
+    /// 割り当てられているランタイムの数
+    pub fn instances(&self) -> usize {
+        self.workers
+            .lock()
+            .unwrap()
+            .iter()
+            .flatten()
+            .map(|worker| worker.instances)
+            .sum()
+    }
+
+    fn release(&self, index: usize, id: u64, isolate: &OnceLock<v8::IsolateHandle>) {
+        let (sender, finished) = {
+            let mut workers = self.workers.lock().unwrap();
+            let Some(worker) = workers[index].as_mut() else {
+                return;
+            };
+            worker.instances -= 1;
+            let finished = if worker.instances == 0 {
+                workers[index].take()
+            } else {
+                None
+            };
+            let sender = match &finished {
+                Some(worker) => worker.sender.clone(),
+                None => workers[index].as_ref().unwrap().sender.clone(),
+            };
+            (sender, finished)
+        };
+
+        // isolate の破棄が終わるまで待つ。スクリプトが終わらない場合は実行を止める。
+        let (tx, rx) = std::sync::mpsc::channel();
+        let destroyed = sender.send(Task::Destroy(id, tx)).is_err()
+            || wait(&rx, || {
+                if let Some(isolate) = isolate.get() {
+                    isolate.terminate_execution();
+                }
+            });
+
+        // 最後のインスタンスだった場合はワーカーを終了させる
+        if let Some(worker) = finished {
+            drop(sender);
+            drop(worker.sender);
+            if destroyed && wait(&worker.exited, || {}) {
+                let _ = worker.thread.join();
+            } else {
+                eprintln!(
+                    "js worker: the thread did not exit within {:?}, detaching it",
+                    SHUTDOWN_TIMEOUT * 2
+                );
+            }
+        }
+    }
+}
+
+impl Instance {
+    pub(crate) fn send(&self, message: Message) -> Result<(), js::JsRuntimeError> {
+        send(&self.sender, self.id, message)
+    }
+
+    pub(crate) fn sender(&self) -> InstanceSender {
+        InstanceSender {
+            id: self.id,
+            sender: Arc::downgrade(&self.sender),
+        }
+    }
+}
+
+impl InstanceSender {
+    pub(crate) fn send(&self, message: Message) -> Result<(), js::JsRuntimeError> {
+        match self.sender.upgrade() {
+            Some(sender) => send(&sender, self.id, message),
+            None => Err(js::JsRuntimeError::UnexpectedError("failed to send".into())),
+        }
+    }
+}
+
+impl Drop for Instance {
+    fn drop(&mut self) {
+        self.pool.release(self.worker, self.id, &self.isolate);
+    }
+}
+
+// ワーカー上の id のランタイムにメッセージを送る
+fn send(sender: &Sender<Task>, id: u64, message: Message) -> Result<(), js::JsRuntimeError> {
+    sender
+        .send(Task::Message(id, message))
+        .map_err(|_| js::JsRuntimeError::UnexpectedError("failed to send".into()))
+}
+
+// 応答か切断を待つ。1 回目のタイムアウトで on_timeout を呼んでからもう一度待ち、それでも届かなければ false を返す。
+fn wait<T>(receiver: &Receiver<T>, on_timeout: impl FnOnce()) -> bool {
+    match receiver.recv_timeout(SHUTDOWN_TIMEOUT) {
+        Ok(_) | Err(RecvTimeoutError::Disconnected) => return true,
+        Err(RecvTimeoutError::Timeout) => on_timeout(),
+    }
+    !matches!(
+        receiver.recv_timeout(SHUTDOWN_TIMEOUT),
+        Err(RecvTimeoutError::Timeout)
+    )
+}
+
+impl Watchdog {
+    // limit 後までに finish が呼ばれなければ isolate の実行を止める
+    fn start(&self, limit: Duration, isolate: v8::IsolateHandle) {
+        self.state.lock().unwrap().job = Some((Instant::now() + limit, isolate, false));
+        self.changed.notify_one();
+    }
+
+    // 監視を終える。期限を過ぎて実行を止めた場合は true を返す
+    fn finish(&self) -> bool {
+        let job = self.state.lock().unwrap().job.take();
+        job.is_some_and(|(_, _, terminated)| terminated)
+    }
+
+    fn exit(&self) {
+        self.state.lock().unwrap().exited = true;
+        self.changed.notify_one();
+    }
+
+    // 監視するスレッドの処理
+    fn watch(&self) {
+        let mut state = self.state.lock().unwrap();
+        while !state.exited {
+            let now = Instant::now();
+            let timeout = match &mut state.job {
+                Some((deadline, isolate, terminated)) if !*terminated => {
+                    if now >= *deadline {
+                        // ロックを持ったまま止めるので、finish の後に止めることはない
+                        isolate.terminate_execution();
+                        *terminated = true;
+                        None
+                    } else {
+                        Some(*deadline - now)
+                    }
+                }
+                _ => None,
+            };
+            state = match timeout {
+                Some(timeout) => self.changed.wait_timeout(state, timeout).unwrap().0,
+                None => self.changed.wait(state).unwrap(),
+            };
+        }
+    }
+}
+
+// ワーカーのスレッドの処理
+fn run(receiver: Receiver<Task>, watchdog: Option<&Watchdog>) {
+    let mut runtimes: HashMap<u64, Entry> = HashMap::new();
+
+    // audio の後に届いていたタスク
+    let mut pending = None;
+    loop {
+        let task = match pending.take() {
+            Some(task) => task,
+            None => match receiver.recv() {
+                Ok(task) => task,
+                Err(_) => break,
+            },
+        };
+        match task {
+            Task::Create(id, create, isolate) => {
+                let mut runtime = create();
+                let _ = isolate.set(runtime.thread_safe_handle());
+                // SAFETY: isolate は作成時に enter されているので、他の isolate を使う前に exit する
+                unsafe { runtime.exit() };
+                runtimes.insert(
+                    id,
+                    Entry {
+                        runtime,
+                        stalled: false,
+                    },
+                );
+            }
+            Task::Message(id, message) => {
+                // 破棄されたランタイムへのメッセージは、応答の Sender を drop して失敗させる
+                let Some(Entry { runtime, stalled }) = runtimes.get_mut(&id) else {
+                    continue;
+                };
+                let message = match message {
+                    Message::Audio(mut audio, .., output_tx) if *stalled => {
+                        audio.fill(0.0);
+                        let error = js::JsRuntimeError::Stalled.into();
+                        let _ = output_tx.send((Err(error), audio, BlockTiming::default()));
+                        continue;
+                    }
+                    message => message,
+                };
+                let compile = matches!(message, Message::Compile(..));
+                if let Some(watchdog) = watchdog {
+                    watchdog.start(message.time_limit(), runtime.thread_safe_handle());
+                }
+                // SAFETY: enter と exit は対にして呼ぶ
+                unsafe { runtime.enter() };
+                let audio = js_sync::handle(runtime, message);
+                let terminated = watchdog.is_some_and(|watchdog| watchdog.finish());
+                if terminated {
+                    // 次のメッセージを実行できるように、止めた状態を解除する
+                    runtime.thread_safe_handle().cancel_terminate_execution();
+                }
+                if compile {
+                    *stalled = false;
+                }
+                if audio && terminated {
+                    *stalled = true;
+                }
+
+                // 次のブロックが届くまでの間に、gcOptions に従って GC を行う
+                if audio && !terminated {
+                    match receiver.try_recv() {
+                        Ok(task) => pending = Some(task),
+                        Err(TryRecvError::Empty) => {
+                            runtime.collect_garbage();
+                        }
+                        Err(TryRecvError::Disconnected) => {}
+                    }
+                }
+                unsafe { runtime.exit() };
+            }
+            Task::Destroy(id, done) => {
+                if let Some(entry) = runtimes.remove(&id) {
+                    destroy(entry.runtime);
+                }
+                let _ = done.send(());
+            }
+        }
+    }
+    for (_, entry) in runtimes.drain() {
+        destroy(entry.runtime);
+    }
+}
+
+fn destroy(mut runtime: js::JsRuntime) {
+    // SAFETY: isolate は current の状態で drop する必要がある (drop 時に exit される)
+    unsafe { runtime.enter() };
+    drop(runtime);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::js_sync::JsRuntimeBuilder;
+    use crate::runtime::runtime::ScriptRuntime;
+    use crate::runtime::state::ScriptState;
+
+    const SCRIPT: &str = r#"
+        let gain = 1;
+        const audio = (ctx) => ctx.audio.fill(gain);
+        const gui = () => {};
+    "#;
+
+    #[test]
+    fn share_workers() {
+        let pool = WorkerPool::new(2);
+        let mut runtimes: Vec<_> = (0..5)
+            .map(|_| JsRuntimeBuilder::new().pool(pool.clone()).build())
+            .collect();
+        assert_eq!(pool.workers(), 2);
+        assert_eq!(pool.instances(), 5);
+
+        // 同じワーカー上の isolate はそれぞれ別の状態を持つ
+        for (i, runtime) in runtimes.iter_mut().enumerate() {
+            runtime.compile(SCRIPT, None).unwrap();
+            runtime.evaluate(&format!("gain = {}", i)).unwrap();
+        }
+        for (i, runtime) in runtimes.iter_mut().enumerate() {
+            let mut audio = vec![0.0; 4];
+            runtime.audio(&mut audio, 1, 48000.0, &[]).unwrap();
+            assert_eq!(audio, vec![i as f32; 4]);
+        }
+
+        // 全て drop するとワーカーも終了する
+        runtimes.truncate(1);
+        assert_eq!(pool.instances(), 1);
+        assert_eq!(pool.workers(), 1);
+        drop(runtimes);
+        assert_eq!(pool.instances(), 0);
+        assert_eq!(pool.workers(), 0);
+
+        // 終了した後も新しいランタイムを作れる
+        let mut runtime = JsRuntimeBuilder::new().pool(pool.clone()).build();
+        runtime.compile(SCRIPT, None).unwrap();
+        assert_eq!(pool.workers(), 1);
+    }
+
+    #[test]
+    fn destroy_isolates() {
+        // isolate の slot が持つ ScriptState の参照が無くなったことで、isolate が破棄されたことを確認する
+        let pool = WorkerPool::new(1);
+        let states: Vec<_> = (0..3)
+            .map(|_| Arc::new(Mutex::new(ScriptState::default())))
+            .collect();
+        let mut runtimes: Vec<_> = states
+            .iter()
+            .map(|state| {
+                JsRuntimeBuilder::new()
+                    .pool(pool.clone())
+                    .state(state.clone())
+                    .build()
+            })
+            .collect();
+        for runtime in runtimes.iter_mut() {
+            runtime.compile(SCRIPT, None).unwrap();
+        }
+        assert!(states.iter().all(|state| Arc::strong_count(state) == 2));
+
+        // 作成と異なる順で破棄しても、同じワーカーの他の isolate は動き続ける
+        let mut last = runtimes.pop().unwrap();
+        runtimes.remove(0);
+        assert_eq!(Arc::strong_count(&states[0]), 1);
+        let mut audio = vec![0.0; 4];
+        last.audio(&mut audio, 1, 48000.0, &[]).unwrap();
+        assert_eq!(audio, vec![1.0; 4]);
+        runtimes.clear();
+        assert_eq!(Arc::strong_count(&states[1]), 1);
+        assert_eq!(pool.workers(), 1);
+
+        drop(last);
+        assert!(states.iter().all(|state| Arc::strong_count(state) == 1));
+        assert_eq!(pool.workers(), 0);
+    }
+
+    #[test]
+    fn stress() {
+        // 作成と破棄を繰り返しても、スレッドと isolate が残らず、クラッシュしない
+        let pool = WorkerPool::new(4);
+        let state = Arc::new(Mutex::new(ScriptState::default()));
+        let mut alive = Vec::new();
+        for i in 0..300 {
+            let mut runtime = JsRuntimeBuilder::new()
+                .pool(pool.clone())
+                .state(state.clone())
+                .on_log(Arc::new(|_| {}))
+                .build();
+
+            // 一部はコンパイルや実行の前に破棄する
+            if i % 3 != 0 {
+                runtime.compile(SCRIPT, None).unwrap();
+                let mut audio = vec![0.0; 64];
+                runtime.audio(&mut audio, 2, 48000.0, &[]).unwrap();
+            }
+            if i % 7 == 0 {
+                alive.push(runtime);
+            }
+            if alive.len() > 8 {
+                alive.remove(i % alive.len());
+            }
+        }
+        assert!(pool.workers() <= 4);
+        drop(alive);
+        assert_eq!(pool.workers(), 0);
+        assert_eq!(Arc::strong_count(&state), 1);
+    }
+
+    #[test]
+    fn stop_stalled_script() {
+        // 終わらない audio を実行するインスタンスがあっても、同じワーカーの他のインスタンスは音を出し続ける
+        let pool = WorkerPool::new(1);
+        let mut stuck = JsRuntimeBuilder::new().pool(pool.clone()).build();
+        let mut other = JsRuntimeBuilder::new().pool(pool.clone()).build();
+        assert_eq!(pool.workers(), 1);
+        stuck
+            .compile(
+                "const audio = (ctx) => { for (;;) {} }; const gui = () => {};",
+                None,
+            )
+            .unwrap();
+        other.compile(SCRIPT, None).unwrap();
+
+        // 制限時間を過ぎると、待つのをやめて無音を返す
+        let start = Instant::now();
+        let mut audio = vec![0.5; 4];
+        assert!(stuck.audio(&mut audio, 1, 48000.0, &[]).is_err());
+        assert_eq!(audio, vec![0.0; 4]);
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // ワーカーが止めた後は、他のインスタンスの audio が処理される
+        for _ in 0..3 {
+            let mut audio = vec![0.0; 4];
+            other.audio(&mut audio, 1, 48000.0, &[]).unwrap();
+            assert_eq!(audio, vec![1.0; 4]);
+
+            // 止められたインスタンスは再コンパイルするまで実行されず、すぐにエラーを返す
+            let start = Instant::now();
+            let mut audio = vec![0.5; 4];
+            let err = stuck.audio(&mut audio, 1, 48000.0, &[]).unwrap_err();
+            assert!(err.to_string().contains("time limit"), "{}", err);
+            assert_eq!(audio, vec![0.0; 4]);
+            assert!(start.elapsed() < Duration::from_millis(100));
+        }
+
+        // 再コンパイルすると audio を実行できる
+        stuck.compile(SCRIPT, None).unwrap();
+        let mut audio = vec![0.0; 4];
+        stuck.audio(&mut audio, 1, 48000.0, &[]).unwrap();
+        assert_eq!(audio, vec![1.0; 4]);
+    }
+
+    #[test]
+    fn terminate_on_shutdown() {
+        // 終わらないスクリプトを実行している isolate も、破棄する時に実行を止めて終了させる
+        let pool = WorkerPool::new(1);
+        let instance = pool.spawn(|| js::JsRuntimeBuilder::new().build(), false);
+        let (tx, _rx) = std::sync::mpsc::channel();
+        instance
+            .send(Message::Compile(SCRIPT.to_string(), None, tx))
+            .unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        instance
+            .send(Message::Evaluate("for (;;) {}".to_string(), tx))
+            .unwrap();
+
+        let start = std::time::Instant::now();
+        drop(instance);
+        assert!(start.elapsed() < SHUTDOWN_TIMEOUT * 2);
+        assert_eq!(pool.workers(), 0);
+        assert!(rx.recv().unwrap().is_err());
+    }
+}
@@ -0,0 +1,74 @@
+// スクリプトに渡す MIDI イベント 1 つあたりのバイト数
+// 0-3 byte: イベントが発生した時刻 (ビッグエンディアン)
+//   4 byte: ステータスバイト
+//   5 byte: データバイト 1
+//   6 byte: データバイト 2
+pub const EVENT_SIZE: usize = 7;
+
+// ステータスバイトの上位 4 bit
+pub const NOTE_OFF: u8 = 0x8;
+pub const NOTE_ON: u8 = 0x9;
+pub const CONTROL_CHANGE: u8 = 0xb;
+
+// コントロールチェンジの番号
+pub const CC_SUSTAIN: u8 = 64;
+pub const CC_ALL_NOTES_OFF: u8 = 123;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MidiEvent {
+    pub timing: u32,
+    pub status: u8,
+    pub data1: u8,
+    pub data2: u8,
+}
+
+impl MidiEvent {
+    pub fn new(timing: u32, kind: u8, channel: u8, data1: u8, data2: u8) -> Self {
+        MidiEvent {
+            timing,
+            status: (kind << 4) | (channel & 0x0f),
+            data1,
+            data2,
+        }
+    }
+
+    // イベントの種類 (NOTE_ON など)
+    pub fn kind(&self) -> u8 {
+        self.status >> 4
+    }
+
+    pub fn channel(&self) -> u8 {
+        self.status & 0x0f
+    }
+
+    // 鳴っている音を止めるイベント (ノートオフ、ベロシティ 0 のノートオン、サステインペダルを離す、オールノートオフ)
+    pub fn is_release(&self) -> bool {
+        match self.kind() {
+            NOTE_OFF => true,
+            NOTE_ON => self.data2 == 0,
+            CONTROL_CHANGE => {
+                (self.data1 == CC_SUSTAIN && self.data2 < 64) || self.data1 == CC_ALL_NOTES_OFF
+            }
+            _ => false,
+        }
+    }
+
+    pub fn encode(&self) -> [u8; EVENT_SIZE] {
+        let mut e = [0u8; EVENT_SIZE];
+        e[0..4].copy_from_slice(&self.timing.to_be_bytes());
+        e[4] = self.status;
+        e[5] = self.data1;
+        e[6] = self.data2;
+        e
+    }
+}
+
+/// スクリプトに渡す形式のバイト列をイベントに変換する
+pub fn decode(midi: &[u8]) -> impl Iterator<Item = MidiEvent> + '_ {
+    midi.chunks_exact(EVENT_SIZE).map(|e| MidiEvent {
+        timing: u32::from_be_bytes([e[0], e[1], e[2], e[3]]),
+        status: e[4],
+        data1: e[5],
+        data2: e[6],
+    })
+}
@@ -0,0 +1,246 @@
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::time::Duration;
+use thiserror::Error;
+
+/// 書き出すファイルの拡張子。Chrome DevTools の Performance パネルや speedscope で開ける。
+pub const EXTENSION: &str = "cpuprofile";
+
+// 関数の実行時間の割合を計算する時に除くノード。
+// (idle) と (program) には audio の呼び出しの合間の待ち時間が含まれる。
+const EXCLUDED_NODES: [&str; 3] = ["(root)", "(idle)", "(program)"];
+
+#[derive(Debug, Error)]
+pub enum ProfileError {
+    #[error("invalid profile: {0}")]
+    Invalid(#[from] serde_json::Error),
+    #[error("failed to write {0}: {1}")]
+    Write(std::path::PathBuf, std::io::Error),
+}
+
+/// V8 の CPU プロファイラで記録したプロファイル (Chrome DevTools Protocol の Profiler.Profile)
+#[derive(Debug, Clone)]
+pub struct Profile {
+    // 書き出す時は V8 が出力した JSON をそのまま使う
+    json: String,
+    parsed: CpuProfile,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CpuProfile {
+    nodes: Vec<ProfileNode>,
+
+    // サンプルしたノードの ID と、前のサンプルからの時間 (マイクロ秒)
+    #[serde(default)]
+    samples: Vec<u32>,
+    #[serde(default)]
+    time_deltas: Vec<i64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ProfileNode {
+    id: u32,
+    call_frame: CallFrame,
+    #[serde(default)]
+    children: Vec<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CallFrame {
+    function_name: String,
+    url: String,
+    // 0 始まり
+    line_number: i32,
+    column_number: i32,
+}
+
+/// 関数ごとの実行時間。割合は (idle) などを除いた、スクリプトの実行に使われた時間に対するもの。
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionTime {
+    pub name: String,
+    pub url: String,
+    // 1 始まり (不明な場合は 0)
+    pub line: u32,
+    pub self_time: Duration,
+    pub total_time: Duration,
+    pub self_ratio: f64,
+    pub total_ratio: f64,
+}
+
+impl FunctionTime {
+    /// "main.js:12" のような表示用の位置
+    pub fn location(&self) -> String {
+        let file = self.url.rsplit('/').next().unwrap_or_default();
+        match (file, self.line) {
+            ("", _) => String::new(),
+            (file, 0) => file.to_string(),
+            (file, line) => format!("{}:{}", file, line),
+        }
+    }
+}
+
+impl Profile {
+    pub fn from_json(json: String) -> Result<Self, ProfileError> {
+        let parsed = serde_json::from_str(&json)?;
+        Ok(Profile { json, parsed })
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), ProfileError> {
+        std::fs::write(path, &self.json).map_err(|err| ProfileError::Write(path.to_path_buf(), err))
+    }
+
+    /// スクリプトの実行に使われた時間の合計
+    pub fn busy_time(&self) -> Duration {
+        let nodes = self.node_map();
+        self.sample_times()
+            .filter(|(id, _)| {
+                nodes.get(id).is_some_and(|node| {
+                    !EXCLUDED_NODES.contains(&node.call_frame.function_name.as_str())
+                })
+            })
+            .map(|(_, time)| time)
+            .sum()
+    }
+
+    /// 自身の実行時間 (呼び出した関数の時間を含まない) が長い順に関数を並べる
+    pub fn top_functions(&self) -> Vec<FunctionTime> {
+        let nodes = self.node_map();
+        let mut parents = HashMap::new();
+        for node in &self.parsed.nodes {
+            for child in &node.children {
+                parents.insert(*child, node.id);
+            }
+        }
+
+        let mut self_times: HashMap<&CallFrame, Duration> = HashMap::new();
+        let mut total_times: HashMap<&CallFrame, Duration> = HashMap::new();
+        let mut busy = Duration::ZERO;
+        for (id, time) in self.sample_times() {
+            let Some(node) = nodes.get(&id) else {
+                continue;
+            };
+            if EXCLUDED_NODES.contains(&node.call_frame.function_name.as_str()) {
+                continue;
+            }
+            busy += time;
+            *self_times.entry(&node.call_frame).or_default() += time;
+
+            // 再帰呼び出しで同じ関数が複数回現れても 1 回だけ数える
+            let mut seen = HashSet::new();
+            let mut current = Some(id);
+            while let Some(id) = current {
+                let Some(node) = nodes.get(&id) else {
+                    break;
+                };
+                if node.call_frame.function_name != "(root)" && seen.insert(&node.call_frame) {
+                    *total_times.entry(&node.call_frame).or_default() += time;
+                }
+                current = parents.get(&id).copied();
+            }
+        }
+
+        let ratio = |time: Duration| {
+            if busy.is_zero() {
+                0.0
+            } else {
+                time.as_secs_f64() / busy.as_secs_f64()
+            }
+        };
+        let mut functions: Vec<FunctionTime> = total_times
+            .into_iter()
+            .map(|(frame, total_time)| {
+                let self_time = self_times.get(frame).copied().unwrap_or_default();
+                FunctionTime {
+                    name: match frame.function_name.as_str() {
+                        "" => "(anonymous)".to_string(),
+                        name => name.to_string(),
+                    },
+                    url: frame.url.clone(),
+                    line: (frame.line_number + 1).max(0) as u32,
+                    self_time,
+                    total_time,
+                    self_ratio: ratio(self_time),
+                    total_ratio: ratio(total_time),
+                }
+            })
+            .collect();
+        functions.sort_by(|a, b| {
+            b.self_time
+                .cmp(&a.self_time)
+                .then(b.total_time.cmp(&a.total_time))
+                .then(a.name.cmp(&b.name))
+        });
+        functions
+    }
+
+    fn node_map(&self) -> HashMap<u32, &ProfileNode> {
+        self.parsed
+            .nodes
+            .iter()
+            .map(|node| (node.id, node))
+            .collect()
+    }
+
+    // 各サンプルのノードと、そのサンプルが表す時間 (次のサンプルまでの時間)
+    fn sample_times(&self) -> impl Iterator<Item = (u32, Duration)> + '_ {
+        let deltas = &self.parsed.time_deltas;
+        self.parsed.samples.iter().enumerate().map(move |(i, id)| {
+            let delta = deltas.get(i + 1).copied().unwrap_or_default().max(0);
+            (*id, Duration::from_micros(delta as u64))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (root) -> audio -> osc, audio -> filter -> filter (再帰)
+    const PROFILE: &str = r#"{
+        "nodes": [
+            { "id": 1, "callFrame": { "functionName": "(root)", "scriptId": "0", "url": "", "lineNumber": -1, "columnNumber": -1 }, "children": [2, 6] },
+            { "id": 2, "callFrame": { "functionName": "audio", "scriptId": "1", "url": "file:///synth/main.js", "lineNumber": 9, "columnNumber": 14 }, "children": [3, 4] },
+            { "id": 3, "callFrame": { "functionName": "osc", "scriptId": "1", "url": "file:///synth/main.js", "lineNumber": 2, "columnNumber": 12 } },
+            { "id": 4, "callFrame": { "functionName": "filter", "scriptId": "1", "url": "file:///synth/main.js", "lineNumber": 5, "columnNumber": 15 }, "children": [5] },
+            { "id": 5, "callFrame": { "functionName": "filter", "scriptId": "1", "url": "file:///synth/main.js", "lineNumber": 5, "columnNumber": 15 } },
+            { "id": 6, "callFrame": { "functionName": "(program)", "scriptId": "0", "url": "", "lineNumber": -1, "columnNumber": -1 } }
+        ],
+        "startTime": 0,
+        "endTime": 1000,
+        "samples": [6, 3, 3, 2, 5, 4, 6],
+        "timeDeltas": [0, 100, 100, 100, 100, 100, 100]
+    }"#;
+
+    #[test]
+    fn top_functions() {
+        let profile = Profile::from_json(PROFILE.to_string()).unwrap();
+        let us = Duration::from_micros;
+        // (program) を除いた 5 サンプル
+        assert_eq!(profile.busy_time(), us(500));
+
+        let functions = profile.top_functions();
+        let names: Vec<&str> = functions.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["filter", "osc", "audio"]);
+
+        let filter = &functions[0];
+        assert_eq!(filter.self_time, us(200));
+        // 再帰呼び出しは 2 重に数えない
+        assert_eq!(filter.total_time, us(200));
+        assert_eq!(filter.location(), "main.js:6");
+        assert!((filter.self_ratio - 0.4).abs() < 1e-9);
+
+        let audio = &functions[2];
+        assert_eq!(audio.self_time, us(100));
+        assert_eq!(audio.total_time, us(500));
+        assert!((audio.total_ratio - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn invalid_profile() {
+        assert!(Profile::from_json("{}".to_string()).is_err());
+    }
+}
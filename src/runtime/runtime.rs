@@ -1,13 +1,53 @@
+use crate::runtime::gui::{GuiContext, Shape};
+use crate::runtime::profile::Profile;
+
 pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync + 'static>>;
 
+/// スクリプトの実行結果としてホストに送るイベント
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutputEvent {
+    /// ボイスマネージャーが管理するボイスが終了した
+    VoiceTerminated { timing: u32, channel: u8, note: u8 },
+}
+
 pub trait ScriptRuntime {
     //fn init(&mut self, param: ());
-    fn compile(&mut self, code: &str) -> Result<()>;
+
+    /// スクリプトをコンパイルする。
+    /// path はスクリプトのファイルパスで、相対パスの import はこのファイルのディレクトリを基準に解決される。
+    fn compile(&mut self, code: &str, path: Option<&std::path::Path>) -> Result<()>;
+
+    /// 直前の compile で読み込んだ (読み込もうとした) ファイルの一覧を取得する。
+    /// コンパイルエラーの場合も、エラーになるまでに読み込んだファイルが含まれる。
+    fn dependencies(&mut self) -> Vec<std::path::PathBuf>;
+
+    /// コンパイル済みのスクリプトのコンテキストでコードを評価し、結果を文字列にして返す (REPL 用)。
+    /// 再コンパイルせずに、スクリプトのグローバル変数を読み書きできる。
+    fn evaluate(&mut self, code: &str) -> Result<String>;
+
+    /// ps88.loadSample など、非同期に読み込み中のファイルが全て読み込まれるまで待つ。
+    /// オフラインでの処理で、読み込みが終わる時刻によって出力が変わらないようにするために使う。
+    fn wait_samples(&mut self) {}
+
+    /// CPU プロファイラでスクリプトの実行時間の記録を開始する。
+    fn start_profiling(&mut self) -> Result<()> {
+        Err("profiling is not supported".into())
+    }
+
+    /// 記録を終了して、start_profiling からのプロファイルを返す。
+    fn stop_profiling(&mut self) -> Result<Profile> {
+        Err("profiling is not supported".into())
+    }
+
     fn audio(
         &mut self,
         audio: &mut [f32],
         ch: usize,
         sampling_rate: f32,
         midi: &[u8],
-    ) -> Result<()>;
+    ) -> Result<Vec<OutputEvent>>;
+
+    /// スクリプトの gui(ctx) を呼び出し、描画する図形の一覧を返す。
+    /// ctx は GUI の領域の大きさと前のフレームからの入力で、JSON と同じ形でスクリプトに渡される。
+    fn gui(&mut self, ctx: &GuiContext) -> Result<Vec<Shape>>;
 }
@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+use thiserror::Error;
+
+/// デコード済みのオーディオファイル
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub sample_rate: u32,
+
+    // チャンネルごとの信号
+    pub channels: Vec<Vec<f32>>,
+}
+
+#[derive(Debug, Error)]
+pub enum SampleError {
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    #[error("failed to decode: {0}")]
+    Decode(#[from] SymphoniaError),
+    #[error("no audio track")]
+    NoTrack,
+}
+
+/// WAV / AIFF / FLAC ファイルをデコードする
+pub fn decode(path: &Path) -> Result<Sample, SampleError> {
+    let file = std::fs::File::open(path)?;
+    let stream = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(extension);
+    }
+    let probed = symphonia::default::get_probe().format(
+        &hint,
+        stream,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )?;
+    let mut format = probed.format;
+    let track = format.default_track().ok_or(SampleError::NoTrack)?;
+    let track_id = track.id;
+    let mut sample_rate = track.codec_params.sample_rate.unwrap_or(0);
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+    let mut channels: Vec<Vec<f32>> = Vec::new();
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            // ファイルの終端
+            Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                break
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // 壊れたパケットは読み飛ばす
+            Err(SymphoniaError::DecodeError(_)) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let spec = *decoded.spec();
+        sample_rate = spec.rate;
+        let count = spec.channels.count();
+        let mut buffer = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+        buffer.copy_planar_ref(decoded);
+        let frames = buffer.samples().len() / count.max(1);
+        channels.resize(count, Vec::new());
+        for (c, channel) in channels.iter_mut().enumerate() {
+            channel.extend_from_slice(&buffer.samples()[c * frames..(c + 1) * frames]);
+        }
+    }
+    Ok(Sample {
+        sample_rate,
+        channels,
+    })
+}
+
+/// デコード済みのサンプルのキャッシュ。
+/// スクリプトを再コンパイルしてもファイルが変更されていなければ再度デコードしない。
+/// 複数のスレッドから同時に利用できる。
+#[derive(Clone, Default)]
+pub struct SampleCache {
+    // ファイルごとのエントリ。同じファイルのデコードを同時に行わないよう、エントリごとにロックする
+    entries: Arc<Mutex<HashMap<PathBuf, Arc<Mutex<Option<CacheEntry>>>>>>,
+}
+
+struct CacheEntry {
+    // ファイルの変更を検知するための更新日時とサイズ
+    modified: Option<SystemTime>,
+    len: u64,
+    sample: Arc<Sample>,
+}
+
+impl SampleCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// ファイルを読み込む。キャッシュが有効な場合はそれを返す。
+    pub fn load(&self, path: &Path) -> Result<Arc<Sample>, SampleError> {
+        let metadata = std::fs::metadata(path)?;
+        let modified = metadata.modified().ok();
+        let len = metadata.len();
+        let slot = self
+            .entries
+            .lock()
+            .unwrap()
+            .entry(path.to_path_buf())
+            .or_default()
+            .clone();
+        // 同じファイルを別のスレッドが読み込み中の場合は、それが終わるのを待って結果を使う。
+        // 別のファイルのデコードは待たないよう、全体のロックは外しておく
+        let mut entry = slot.lock().unwrap();
+        if let Some(entry) = entry.as_ref() {
+            if entry.modified.is_some() && entry.modified == modified && entry.len == len {
+                return Ok(entry.sample.clone());
+            }
+        }
+
+        let sample = Arc::new(decode(path)?);
+        *entry = Some(CacheEntry {
+            modified,
+            len,
+            sample: sample.clone(),
+        });
+        Ok(sample)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// 16 bit PCM の WAV ファイルを作成する
+    pub fn write_wav(path: &Path, sample_rate: u32, channels: &[Vec<f32>]) {
+        crate::render::wav::write(
+            path,
+            sample_rate,
+            channels,
+            crate::render::wav::WavFormat::Pcm16,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn decode_wav() {
+        let dir = std::env::temp_dir().join("ps88_sample_decode_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("stereo.wav");
+        write_wav(&path, 44100, &[vec![0.0, 0.5, -0.5], vec![0.25, 0.0, -1.0]]);
+
+        let sample = decode(&path).unwrap();
+        assert_eq!(sample.sample_rate, 44100);
+        assert_eq!(
+            sample.channels,
+            vec![vec![0.0, 0.5, -0.5], vec![0.25, 0.0, -1.0]]
+        );
+
+        // 存在しないファイルや音声ではないファイルはエラーになる
+        assert!(matches!(
+            decode(&dir.join("missing.wav")),
+            Err(SampleError::Io(_))
+        ));
+        std::fs::write(dir.join("text.wav"), "hello").unwrap();
+        assert!(decode(&dir.join("text.wav")).is_err());
+    }
+
+    #[test]
+    fn cache() {
+        let dir = std::env::temp_dir().join("ps88_sample_cache_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("mono.wav");
+        write_wav(&path, 48000, &[vec![0.5; 4]]);
+
+        // ファイルが変更されていなければ同じデータを返す
+        let cache = SampleCache::new();
+        let first = cache.load(&path).unwrap();
+        let second = cache.clone().load(&path).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+
+        // ファイルが変更されたら読み直す
+        write_wav(&path, 48000, &[vec![0.5; 8]]);
+        let third = cache.load(&path).unwrap();
+        assert!(!Arc::ptr_eq(&first, &third));
+        assert_eq!(third.channels[0].len(), 8);
+    }
+
+    #[test]
+    fn concurrent_load() {
+        let dir = std::env::temp_dir().join("ps88_sample_cache_concurrent_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("mono.wav");
+        write_wav(&path, 48000, &[vec![0.5; 48000]]);
+
+        // 同じファイルを同時に読み込んでも、デコードは 1 回だけで同じデータを返す
+        let cache = SampleCache::new();
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let (cache, path) = (cache.clone(), path.clone());
+                std::thread::spawn(move || cache.load(&path).unwrap())
+            })
+            .collect();
+        let samples: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        assert!(samples.iter().all(|s| Arc::ptr_eq(s, &samples[0])));
+    }
+}
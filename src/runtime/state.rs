@@ -0,0 +1,74 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// スクリプトが ps88.state で保存するデータ。
+/// プラグインの状態の一部としてホストのプロジェクトに保存される。
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ScriptState {
+    values: BTreeMap<String, StateValue>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StateValue {
+    // JSON.stringify された値
+    Json(String),
+
+    // ArrayBuffer や TypedArray のバイト列
+    Binary(Vec<u8>),
+}
+
+impl ScriptState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&StateValue> {
+        self.values.get(key)
+    }
+
+    pub fn set(&mut self, key: &str, value: StateValue) {
+        self.values.insert(key.to_string(), value);
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<StateValue> {
+        self.values.remove(key)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.values.keys()
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize() {
+        let mut state = ScriptState::new();
+        state.set("pattern", StateValue::Json("[1,0,1,0]".into()));
+        state.set("table", StateValue::Binary(vec![0, 128, 255]));
+
+        // プラグインの状態として保存され、読み込み後に同じ値に戻る
+        let json = serde_json::to_string(&state).unwrap();
+        let restored: ScriptState = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, state);
+        assert_eq!(
+            restored.keys().collect::<Vec<_>>(),
+            vec!["pattern", "table"]
+        );
+
+        assert_eq!(
+            state.remove("pattern"),
+            Some(StateValue::Json("[1,0,1,0]".into()))
+        );
+        assert_eq!(state.get("pattern"), None);
+        state.clear();
+        assert_eq!(state, ScriptState::new());
+    }
+}
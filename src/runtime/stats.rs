@@ -0,0 +1,210 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// 平均の時定数 (秒)
+const AVERAGE_TIME: f64 = 1.0;
+
+// ピークの表示が半分に下がるまでの時間 (秒)
+const PEAK_HALF_LIFE: f64 = 2.0;
+
+/// 処理時間がブロックの長さのこの割合を超えたら xrun の危険があるとみなす
+pub const XRUN_RISK_LOAD: f64 = 0.8;
+
+/// 1 ブロックの処理にかかった時間と GC の状況
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BlockTiming {
+    // スクリプトの実行時間
+    pub script: Duration,
+
+    // スクリプトを実行するスレッドが処理を始めるまでの待ち時間
+    pub wait: Duration,
+
+    // script のうち GC に使われた時間と、その間に行われた GC の回数
+    pub gc: Duration,
+    pub gc_count: u64,
+
+    // 処理後の JavaScript のヒープの使用量 (バイト)
+    pub heap_used: usize,
+}
+
+/// ブロックごとの処理時間の統計。
+/// オーディオのスレッドで書き込み、GUI などのスレッドで読み取れるように、ロックを使わずに atomic で保持する。
+/// 書き込むスレッドは 1 つだけであることを前提にしている。
+#[derive(Debug, Default)]
+pub struct ProcessStats {
+    blocks: AtomicU64,
+    xrun_risks: AtomicU64,
+    overruns: AtomicU64,
+    gc_count: AtomicU64,
+    heap_used: AtomicU64,
+
+    // 以下は f64 のビット列
+    // 負荷 (処理時間 / ブロックの長さ) の平均とピーク
+    average_load: AtomicU64,
+    peak_load: AtomicU64,
+    // 各時間の平均 (秒)
+    script: AtomicU64,
+    wait: AtomicU64,
+    gc: AtomicU64,
+}
+
+/// ある時点での統計
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct StatsSnapshot {
+    pub blocks: u64,
+    pub average_load: f64,
+    pub peak_load: f64,
+    pub script: Duration,
+    pub wait: Duration,
+    pub gc: Duration,
+
+    // audio の実行中に行われた GC の回数と、最後のブロックの後のヒープの使用量
+    pub gc_count: u64,
+    pub heap_used: usize,
+
+    // 処理時間がブロックの長さの 80% を超えたブロックの数 (overruns を含む)
+    pub xrun_risks: u64,
+    // 処理時間がブロックの長さを超えたブロックの数
+    pub overruns: u64,
+}
+
+impl ProcessStats {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// budget はブロックの長さ (フレーム数 / サンプリングレート)
+    pub fn record(&self, timing: BlockTiming, budget: Duration) {
+        if budget.is_zero() {
+            return;
+        }
+        let budget = budget.as_secs_f64();
+        let load = (timing.script + timing.wait).as_secs_f64() / budget;
+        let blocks = self.blocks.load(Ordering::Relaxed);
+
+        // 指数移動平均 (最初のブロックはそのままの値を使う)
+        let alpha = if blocks == 0 {
+            1.0
+        } else {
+            (budget / AVERAGE_TIME).min(1.0)
+        };
+        let average = |value: &AtomicU64, x: f64| {
+            let current = f64::from_bits(value.load(Ordering::Relaxed));
+            value.store(
+                (current + (x - current) * alpha).to_bits(),
+                Ordering::Relaxed,
+            );
+        };
+        average(&self.average_load, load);
+        average(&self.script, timing.script.as_secs_f64());
+        average(&self.wait, timing.wait.as_secs_f64());
+        average(&self.gc, timing.gc.as_secs_f64());
+
+        // ピークは時間と共に下げていく
+        let decay = 0.5f64.powf(budget / PEAK_HALF_LIFE);
+        let peak = f64::from_bits(self.peak_load.load(Ordering::Relaxed)) * decay;
+        self.peak_load
+            .store(peak.max(load).to_bits(), Ordering::Relaxed);
+
+        if load > XRUN_RISK_LOAD {
+            self.xrun_risks.fetch_add(1, Ordering::Relaxed);
+        }
+        if load > 1.0 {
+            self.overruns.fetch_add(1, Ordering::Relaxed);
+        }
+        self.gc_count.fetch_add(timing.gc_count, Ordering::Relaxed);
+        self.heap_used
+            .store(timing.heap_used as u64, Ordering::Relaxed);
+        self.blocks.store(blocks + 1, Ordering::Release);
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let seconds = |value: &AtomicU64| {
+            Duration::from_secs_f64(f64::from_bits(value.load(Ordering::Relaxed)).max(0.0))
+        };
+        StatsSnapshot {
+            blocks: self.blocks.load(Ordering::Acquire),
+            average_load: f64::from_bits(self.average_load.load(Ordering::Relaxed)),
+            peak_load: f64::from_bits(self.peak_load.load(Ordering::Relaxed)),
+            script: seconds(&self.script),
+            wait: seconds(&self.wait),
+            gc: seconds(&self.gc),
+            gc_count: self.gc_count.load(Ordering::Relaxed),
+            heap_used: self.heap_used.load(Ordering::Relaxed) as usize,
+            xrun_risks: self.xrun_risks.load(Ordering::Relaxed),
+            overruns: self.overruns.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl std::fmt::Display for StatsSnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        write!(
+            f,
+            "CPU {:.1}% (peak {:.1}%)  script {:.2} ms  wait {:.2} ms  GC {:.2} ms ({} in audio)  heap {:.1} MB  xrun risk {} (overrun {})",
+            self.average_load * 100.0,
+            self.peak_load * 100.0,
+            ms(self.script),
+            ms(self.wait),
+            ms(self.gc),
+            self.gc_count,
+            self.heap_used as f64 / (1024.0 * 1024.0),
+            self.xrun_risks,
+            self.overruns
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record() {
+        let stats = ProcessStats::new();
+        assert_eq!(stats.snapshot(), StatsSnapshot::default());
+
+        // 10ms のブロックを 2ms で処理し続ける
+        let budget = Duration::from_millis(10);
+        let timing = BlockTiming {
+            script: Duration::from_micros(1500),
+            wait: Duration::from_micros(500),
+            gc: Duration::from_micros(100),
+            gc_count: 0,
+            heap_used: 2 * 1024 * 1024,
+        };
+        for _ in 0..1000 {
+            stats.record(timing, budget);
+        }
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.blocks, 1000);
+        assert!((snapshot.average_load - 0.2).abs() < 1e-9);
+        assert!((snapshot.peak_load - 0.2).abs() < 1e-9);
+        assert!((snapshot.script.as_secs_f64() - 0.0015).abs() < 1e-9);
+        assert!((snapshot.gc.as_secs_f64() - 0.0001).abs() < 1e-9);
+        assert_eq!(snapshot.xrun_risks, 0);
+
+        // 1 ブロックだけ時間がかかるとピークに残り、平均は少しだけ上がる
+        let slow = BlockTiming {
+            script: Duration::from_millis(12),
+            gc_count: 1,
+            ..timing
+        };
+        stats.record(slow, budget);
+        let snapshot = stats.snapshot();
+        assert!((snapshot.peak_load - 1.2).abs() < 1e-9);
+        assert!(snapshot.average_load > 0.2 && snapshot.average_load < 0.3);
+        assert_eq!((snapshot.xrun_risks, snapshot.overruns), (1, 1));
+        assert_eq!(snapshot.gc_count, 1);
+        assert_eq!(snapshot.heap_used, 2 * 1024 * 1024);
+
+        // ピークは時間と共に下がる
+        for _ in 0..200 {
+            stats.record(timing, budget);
+        }
+        let snapshot = stats.snapshot();
+        assert!((snapshot.peak_load - 0.6).abs() < 1e-6);
+        assert!(snapshot.to_string().starts_with("CPU 20."));
+    }
+}
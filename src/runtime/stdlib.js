@@ -0,0 +1,580 @@
+"use strict";
+
+/**
+ * PS88 標準ライブラリ
+ *
+ * 全てのスクリプトの実行前に読み込まれ、グローバル変数 ps88 から利用できる。
+ *
+ * e.g. 鍵盤を押すとその音の正弦波が鳴る
+ *   const synth = new ps88.Synth({ shape: "sine" });
+ *   const audio = (ctx) => synth.process(ctx);
+ */
+globalThis.ps88 = (() => {
+  const TAU = 2 * Math.PI;
+
+  const clamp = (x, min, max) => Math.min(Math.max(x, min), max);
+
+  // MIDI ノート番号を周波数 (Hz) に変換する
+  const mtof = (note, tuning = 440) => tuning * Math.pow(2, (note - 69) / 12);
+
+  // 周波数 (Hz) を MIDI ノート番号に変換する
+  const ftom = (freq, tuning = 440) => 69 + 12 * Math.log2(freq / tuning);
+
+  // デシベルを振幅に変換する
+  const dbToGain = (db) => Math.pow(10, db / 20);
+
+  // 振幅をデシベルに変換する
+  const gainToDb = (gain) => 20 * Math.log10(Math.abs(gain));
+
+  /**
+   * ctx.midi を解析してイベントの配列にする
+   *
+   * @param {Uint8Array} midi - ctx.midi
+   * @returns {{ time: number, type: number, channel: number, data1: number, data2: number }[]}
+   *    type は上位 4 bit のイベントの種類 (0x9: Note On, 0x8: Note Off, ...)
+   */
+  const parseMidi = (midi) => {
+    const events = [];
+    for (let i = 0; i + 7 <= midi.length; i += 7) {
+      events.push({
+        time: ((midi[i] << 24) | (midi[i + 1] << 16) | (midi[i + 2] << 8) | midi[i + 3]) >>> 0,
+        type: midi[i + 4] >> 4,
+        channel: midi[i + 4] & 0x0f,
+        data1: midi[i + 5],
+        data2: midi[i + 6],
+      });
+    }
+    return events;
+  };
+
+  /**
+   * 鍵盤の状態を管理する
+   *
+   * e.g.
+   *   const keys = new ps88.NoteState();
+   *   const audio = (ctx) => {
+   *     for (const e of keys.update(ctx.midi)) { ... }
+   *     if (keys.isDown(60)) { ... }
+   *   };
+   */
+  class NoteState {
+    constructor() {
+      // note -> { velocity, channel, time }
+      this.held = new Map();
+    }
+
+    /**
+     * MIDI 入力で状態を更新し、ノートイベントの配列を返す
+     *
+     * @returns {{ type: "on" | "off", time: number, note: number, velocity: number, channel: number }[]}
+     */
+    update(midi) {
+      const events = [];
+      for (const e of parseMidi(midi)) {
+        // ベロシティ 0 の Note On は Note Off として扱う
+        if (e.type === 0x9 && e.data2 > 0) {
+          this.held.set(e.data1, { velocity: e.data2 / 127, channel: e.channel, time: e.time });
+          events.push({ type: "on", time: e.time, note: e.data1, velocity: e.data2 / 127, channel: e.channel });
+        } else if (e.type === 0x8 || e.type === 0x9) {
+          this.held.delete(e.data1);
+          events.push({ type: "off", time: e.time, note: e.data1, velocity: e.data2 / 127, channel: e.channel });
+        }
+      }
+      return events;
+    }
+
+    isDown(note) {
+      return this.held.has(note);
+    }
+
+    velocity(note) {
+      const key = this.held.get(note);
+      return key === undefined ? 0 : key.velocity;
+    }
+
+    // 押されているノート番号の配列
+    notes() {
+      return [...this.held.keys()];
+    }
+  }
+
+  // PolyBLEP の補正値
+  // t は 0-1 の位相、dt は 1 サンプルあたりの位相の増分
+  const polyBlep = (t, dt) => {
+    if (t < dt) {
+      t /= dt;
+      return t + t - t * t - 1;
+    }
+    if (t > 1 - dt) {
+      t = (t - 1) / dt;
+      return t * t + t + t + 1;
+    }
+    return 0;
+  };
+
+  /**
+   * 帯域制限されたオシレーター (PolyBLEP)
+   *
+   * @param {"sine" | "sawtooth" | "square" | "triangle"} shape
+   */
+  class Oscillator {
+    constructor(shape = "sine", frequency = 440, phase = 0) {
+      this.shape = shape;
+      this.frequency = frequency;
+      this.phase = phase;
+      this.pulseWidth = 0.5;
+      // 三角波の積分値 (位相に対応する三角波の値から始める)
+      this.triangle = phase < 0.5 ? 4 * phase - 1 : 3 - 4 * phase;
+    }
+
+    // 1 サンプル生成して位相を進める
+    next(sampleRate) {
+      const dt = clamp(this.frequency / sampleRate, 0, 0.5);
+      const t = this.phase;
+      let value;
+      switch (this.shape) {
+        case "sawtooth":
+          value = 2 * t - 1 - polyBlep(t, dt);
+          break;
+        case "square":
+        case "triangle": {
+          const pw = this.pulseWidth;
+          value = t < pw ? 1 : -1;
+          value += polyBlep(t, dt);
+          value -= polyBlep((t + 1 - pw) % 1, dt);
+          if (this.shape === "triangle") {
+            // 矩形波を積分して三角波にする
+            // 誤差が蓄積しないように、少しずつ 0 に戻るようにしている
+            this.triangle = dt * 4 * value + (1 - 0.05 * dt) * this.triangle;
+            value = this.triangle;
+          }
+          break;
+        }
+        default:
+          value = Math.sin(TAU * t);
+          break;
+      }
+      this.phase = t + dt;
+      if (this.phase >= 1) {
+        this.phase -= 1;
+      }
+      return value;
+    }
+
+    // out[offset] から length サンプルに波形を加算する
+    process(out, sampleRate, offset = 0, length = out.length - offset, gain = 1) {
+      for (let i = offset; i < offset + length; i++) {
+        out[i] += this.next(sampleRate) * gain;
+      }
+    }
+  }
+
+  /**
+   * ADSR エンベロープ
+   * attack, decay, release の単位は秒、sustain は 0-1 の振幅
+   */
+  class Adsr {
+    constructor({ attack = 0.01, decay = 0.1, sustain = 0.8, release = 0.2 } = {}) {
+      this.attack = attack;
+      this.decay = decay;
+      this.sustain = sustain;
+      this.release = release;
+      this.stage = "idle";
+      this.value = 0;
+      this.releaseFrom = 0;
+    }
+
+    // gate を on にすると attack から、off にすると release から始まる
+    gate(on) {
+      if (on) {
+        this.stage = "attack";
+      } else if (this.stage !== "idle") {
+        this.stage = "release";
+        this.releaseFrom = this.value;
+      }
+    }
+
+    isIdle() {
+      return this.stage === "idle";
+    }
+
+    next(sampleRate) {
+      switch (this.stage) {
+        case "attack":
+          this.value += 1 / Math.max(this.attack * sampleRate, 1);
+          if (this.value >= 1) {
+            this.value = 1;
+            this.stage = "decay";
+          }
+          break;
+        case "decay":
+          this.value -= (1 - this.sustain) / Math.max(this.decay * sampleRate, 1);
+          if (this.value <= this.sustain) {
+            this.value = this.sustain;
+            this.stage = "sustain";
+          }
+          break;
+        case "sustain":
+          this.value = this.sustain;
+          break;
+        case "release":
+          this.value -= this.releaseFrom / Math.max(this.release * sampleRate, 1);
+          if (this.value <= 0) {
+            this.value = 0;
+            this.stage = "idle";
+          }
+          break;
+        default:
+          this.value = 0;
+          break;
+      }
+      return this.value;
+    }
+  }
+
+  /**
+   * Biquad フィルター (RBJ Audio EQ Cookbook)
+   *
+   * @param {"lowpass" | "highpass" | "bandpass" | "notch" | "peak" | "lowshelf" | "highshelf" | "allpass"} type
+   */
+  class Biquad {
+    constructor(type = "lowpass", frequency = 1000, q = Math.SQRT1_2, gainDb = 0) {
+      this.type = type;
+      this.frequency = frequency;
+      this.q = q;
+      this.gainDb = gainDb;
+      this.sampleRate = 0;
+      this.b0 = 1;
+      this.b1 = 0;
+      this.b2 = 0;
+      this.a1 = 0;
+      this.a2 = 0;
+      this.reset();
+    }
+
+    reset() {
+      this.x1 = 0;
+      this.x2 = 0;
+      this.y1 = 0;
+      this.y2 = 0;
+    }
+
+    // パラメータを変更したら呼び出す
+    update(sampleRate) {
+      this.sampleRate = sampleRate;
+      const w0 = (TAU * clamp(this.frequency, 1, sampleRate * 0.49)) / sampleRate;
+      const cos = Math.cos(w0);
+      const alpha = Math.sin(w0) / (2 * this.q);
+      const a = Math.pow(10, this.gainDb / 40);
+      let b0, b1, b2, a0, a1, a2;
+      switch (this.type) {
+        case "highpass":
+          [b0, b1, b2] = [(1 + cos) / 2, -(1 + cos), (1 + cos) / 2];
+          [a0, a1, a2] = [1 + alpha, -2 * cos, 1 - alpha];
+          break;
+        case "bandpass":
+          [b0, b1, b2] = [alpha, 0, -alpha];
+          [a0, a1, a2] = [1 + alpha, -2 * cos, 1 - alpha];
+          break;
+        case "notch":
+          [b0, b1, b2] = [1, -2 * cos, 1];
+          [a0, a1, a2] = [1 + alpha, -2 * cos, 1 - alpha];
+          break;
+        case "peak":
+          [b0, b1, b2] = [1 + alpha * a, -2 * cos, 1 - alpha * a];
+          [a0, a1, a2] = [1 + alpha / a, -2 * cos, 1 - alpha / a];
+          break;
+        case "lowshelf": {
+          const s = 2 * Math.sqrt(a) * alpha;
+          [b0, b1, b2] = [a * (a + 1 - (a - 1) * cos + s), 2 * a * (a - 1 - (a + 1) * cos), a * (a + 1 - (a - 1) * cos - s)];
+          [a0, a1, a2] = [a + 1 + (a - 1) * cos + s, -2 * (a - 1 + (a + 1) * cos), a + 1 + (a - 1) * cos - s];
+          break;
+        }
+        case "highshelf": {
+          const s = 2 * Math.sqrt(a) * alpha;
+          [b0, b1, b2] = [a * (a + 1 + (a - 1) * cos + s), -2 * a * (a - 1 + (a + 1) * cos), a * (a + 1 + (a - 1) * cos - s)];
+          [a0, a1, a2] = [a + 1 - (a - 1) * cos + s, 2 * (a - 1 - (a + 1) * cos), a + 1 - (a - 1) * cos - s];
+          break;
+        }
+        case "allpass":
+          [b0, b1, b2] = [1 - alpha, -2 * cos, 1 + alpha];
+          [a0, a1, a2] = [1 + alpha, -2 * cos, 1 - alpha];
+          break;
+        default:
+          [b0, b1, b2] = [(1 - cos) / 2, 1 - cos, (1 - cos) / 2];
+          [a0, a1, a2] = [1 + alpha, -2 * cos, 1 - alpha];
+          break;
+      }
+      this.b0 = b0 / a0;
+      this.b1 = b1 / a0;
+      this.b2 = b2 / a0;
+      this.a1 = a1 / a0;
+      this.a2 = a2 / a0;
+    }
+
+    next(x) {
+      const y = this.b0 * x + this.b1 * this.x1 + this.b2 * this.x2 - this.a1 * this.y1 - this.a2 * this.y2;
+      this.x2 = this.x1;
+      this.x1 = x;
+      this.y2 = this.y1;
+      this.y1 = y;
+      return y;
+    }
+
+    // buf[offset] から length サンプルにフィルターをかける
+    process(buf, offset = 0, length = buf.length - offset) {
+      for (let i = offset; i < offset + length; i++) {
+        buf[i] = this.next(buf[i]);
+      }
+    }
+  }
+
+  /**
+   * ディレイライン
+   * 遅延時間はサンプル単位で、小数の場合は線形補間する
+   */
+  class DelayLine {
+    constructor(maxDelay) {
+      this.buffer = new Float32Array(Math.max(Math.ceil(maxDelay) + 1, 1));
+      this.index = 0;
+    }
+
+    write(x) {
+      this.index = (this.index + 1) % this.buffer.length;
+      this.buffer[this.index] = x;
+    }
+
+    // delay サンプル前に書き込んだ値を読み出す (0 で最後に書き込んだ値)
+    read(delay) {
+      const length = this.buffer.length;
+      const d = clamp(delay, 0, length - 1);
+      const i = Math.floor(d);
+      const frac = d - i;
+      const a = this.buffer[(this.index - i + length) % length];
+      const b = this.buffer[(this.index - i - 1 + length) % length];
+      return a + (b - a) * frac;
+    }
+
+    clear() {
+      this.buffer.fill(0);
+    }
+  }
+
+  /**
+   * ボイスマネージャーが voice(v, out, ctx) に渡すボイスの状態
+   *
+   * スクリプトが voice 関数を定義すると、ボイスの割り当てや停止はホストが行う。
+   * 発音数などは voiceOptions で設定できる。
+   *   const voiceOptions = { maxVoices: 16, steal: "oldest" | "quietest", releaseTimeout: 10 };
+   */
+  class Voice {
+    constructor(id, note, channel, velocity) {
+      this.id = id;
+      this.note = note;
+      this.channel = channel;
+      // 0-1
+      this.velocity = velocity;
+      this.frequency = mtof(note);
+      // 発音開始からのサンプル数
+      this.time = 0;
+      // 鍵盤が離された (サスティンペダルで保持中の場合は false)
+      this.released = false;
+      // リリース開始からのサンプル数
+      this.releaseTime = 0;
+      // 鍵盤は離されたがサスティンペダルで保持されている
+      this.sustained = false;
+      // 現在の音量 ("quietest" で止めるボイスを選ぶのに使われる)
+      this.level = velocity;
+      this.done = false;
+      // スクリプトが自由に使える領域
+      this.state = {};
+    }
+
+    // ボイスを終了する (リリースが終わった時に呼ぶ)
+    free() {
+      this.done = true;
+    }
+  }
+
+  /**
+   * 鍵盤を押すと鳴るだけのシンプルなシンセサイザー
+   * ノート 1 つにつき 1 つのオシレーターと ADSR を使う
+   */
+  class Synth {
+    constructor({ shape = "sine", gain = 0.5, attack, decay, sustain, release } = {}) {
+      this.shape = shape;
+      this.gain = gain;
+      this.envelope = { attack, decay, sustain, release };
+      this.keys = new NoteState();
+      // note -> { osc, env, velocity }
+      this.voices = new Map();
+    }
+
+    process(ctx) {
+      const frames = ctx.audio.length / ctx.ch;
+      const events = this.keys.update(ctx.midi);
+      let e = 0;
+      for (let i = 0; i < frames; i++) {
+        for (; e < events.length && events[e].time <= i; e++) {
+          const { type, note, velocity } = events[e];
+          if (type === "on") {
+            const voice = this.voices.get(note) ?? {
+              osc: new Oscillator(this.shape, mtof(note)),
+              env: new Adsr(this.envelope),
+            };
+            voice.velocity = velocity;
+            voice.env.gate(true);
+            this.voices.set(note, voice);
+          } else {
+            this.voices.get(note)?.env.gate(false);
+          }
+        }
+        let value = 0;
+        for (const [note, voice] of this.voices) {
+          const env = voice.env.next(ctx.sampling_rate);
+          if (voice.env.isIdle()) {
+            this.voices.delete(note);
+            continue;
+          }
+          value += voice.osc.next(ctx.sampling_rate) * env * voice.velocity;
+        }
+        for (let c = 0; c < ctx.ch; c++) {
+          ctx.audio[c * frames + i] = value * this.gain;
+        }
+      }
+    }
+  }
+
+  /**
+   * gui(ctx) で表示するウィジェットの値を保持する
+   * パラメータに結び付けないウィジェットは、操作した結果が次のフレームの ctx.changes に入るので、それを values に反映する。
+   * values は audio からも読める。
+   *
+   * e.g.
+   *   const ui = new ps88.Widgets({ cutoff: 0.5, mode: 0 });
+   *   const gui = (ctx) => {
+   *     ui.update(ctx);
+   *     return [
+   *       ui.knob("cutoff", { x: 0, y: 0, width: 48, height: 60, label: "Cutoff" }),
+   *       ui.dropdown("mode", { x: 60, y: 0, width: 100, height: 20, options: ["LP", "HP"] }),
+   *       ui.slider("gain", { x: 0, y: 70, width: 160, height: 20, param: "param1" }),
+   *     ];
+   *   };
+   *   const audio = (ctx) => { const cutoff = ui.values.cutoff; ... };
+   */
+  class Widgets {
+    constructor(values = {}) {
+      this.values = { ...values };
+    }
+
+    // ctx.changes を values に反映する。gui(ctx) の最初に呼ぶ
+    update(ctx) {
+      Object.assign(this.values, ctx.changes);
+    }
+
+    knob(id, props) {
+      return this.widget("knob", id, props);
+    }
+
+    slider(id, props) {
+      return this.widget("slider", id, props);
+    }
+
+    toggle(id, props) {
+      return this.widget("toggle", id, props);
+    }
+
+    dropdown(id, props) {
+      return this.widget("dropdown", id, props);
+    }
+
+    xyPad(id, props) {
+      return this.widget("xyPad", id, props);
+    }
+
+    // 鍵盤で押されているノート番号 (離されている場合は null) が values[id] に入る
+    keyboard(id, props) {
+      return { type: "keyboard", id, ...props };
+    }
+
+    // values に値が無い場合は props.value を初期値にする (どちらも無ければ value は JSON に含まれず、既定値になる)
+    widget(type, id, props) {
+      return { type, id, ...props, value: this.values[id] ?? props?.value };
+    }
+  }
+
+  /**
+   * テスト用の関数
+   * ps88.test(name, fn) で登録したテストの中で使う (ps88 test コマンドで実行される)
+   */
+  class AssertionError extends Error {
+    name = "AssertionError";
+  }
+
+  const assert = (condition, message = "assertion failed") => {
+    if (!condition) throw new AssertionError(message);
+  };
+
+  // 数値または配列の各要素が tolerance 以内で一致することを確認する
+  const assertClose = (actual, expected, tolerance = 1e-6, message = "") => {
+    const prefix = message ? `${message}: ` : "";
+    if (typeof expected === "number") {
+      if (!(Math.abs(actual - expected) <= tolerance)) {
+        throw new AssertionError(`${prefix}expected ${expected} but got ${actual}`);
+      }
+      return;
+    }
+    if (actual.length !== expected.length) {
+      throw new AssertionError(`${prefix}expected length ${expected.length} but got ${actual.length}`);
+    }
+    for (let i = 0; i < expected.length; i++) {
+      if (!(Math.abs(actual[i] - expected[i]) <= tolerance)) {
+        throw new AssertionError(`${prefix}expected ${expected[i]} but got ${actual[i]} at index ${i}`);
+      }
+    }
+  };
+
+  /**
+   * audio 関数に渡す ctx を作る
+   *
+   * @param {{ channels?: number, length?: number, sampleRate?: number, input?: number[][],
+   *           midi?: { time: number, type: number, channel: number, data1: number, data2: number }[] }} options
+   *    length は 1 チャンネルあたりのサンプル数、midi は parseMidi と同じ形式のイベント
+   */
+  const testContext = ({ channels = 2, length = 128, sampleRate = 48000, input = [], midi = [] } = {}) => {
+    const audio = new Float32Array(channels * length);
+    input.slice(0, channels).forEach((channel, c) => audio.set(channel.slice(0, length), c * length));
+    const bytes = new Uint8Array(midi.length * 7);
+    midi.forEach((e, i) => {
+      const time = e.time >>> 0;
+      bytes.set(
+        [time >>> 24, (time >>> 16) & 0xff, (time >>> 8) & 0xff, time & 0xff, (e.type << 4) | (e.channel ?? 0), e.data1, e.data2 ?? 0],
+        i * 7,
+      );
+    });
+    return { audio, ch: channels, sampling_rate: sampleRate, midi: bytes };
+  };
+
+  return {
+    TAU,
+    clamp,
+    mtof,
+    ftom,
+    dbToGain,
+    gainToDb,
+    parseMidi,
+    polyBlep,
+    NoteState,
+    Voice,
+    Oscillator,
+    Adsr,
+    Biquad,
+    DelayLine,
+    Synth,
+    Widgets,
+    AssertionError,
+    assert,
+    assertClose,
+    testContext,
+  };
+})();
@@ -0,0 +1,278 @@
+// 全てのスクリプトの実行前に読み込まれる標準ライブラリ
+pub const STDLIB: &'static str = std::include_str!("stdlib.js");
+
+// エラーメッセージなどに表示される標準ライブラリのファイル名
+pub const STDLIB_NAME: &'static str = "ps88:stdlib.js";
+
+#[cfg(test)]
+mod tests {
+    use crate::runtime::js;
+    use crate::runtime::runtime::ScriptRuntime;
+
+    // ctx を受け取って配列を返す関数本体を実行し、返された配列を取得する
+    fn run(body: &str, len: usize, midi: &[u8]) -> Vec<f32> {
+        let mut runtime = js::JsRuntimeBuilder::new().build();
+        runtime
+            .compile(
+                &r#"
+                "use strict";
+                const audio = (ctx) => {
+                    const result = ((ctx) => { ${body} })(ctx);
+                    for (let i = 0; i < ctx.audio.length; i++) {
+                        ctx.audio[i] = result[i] ?? 0;
+                    }
+                };
+                const gui = () => {};
+            "#
+                .replace("${body}", body),
+                None,
+            )
+            .unwrap();
+        let mut audio = vec![0.0; len];
+        runtime.audio(&mut audio, 1, 48000.0, midi).unwrap();
+        audio
+    }
+
+    fn midi_event(time: u32, status: u8, note: u8, velocity: u8) -> Vec<u8> {
+        let mut e = time.to_be_bytes().to_vec();
+        e.extend_from_slice(&[status, note, velocity]);
+        e
+    }
+
+    fn assert_near(actual: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "expected {} but got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn conversion() {
+        let result = run(
+            r#"
+                return [
+                    ps88.mtof(69),
+                    ps88.mtof(81),
+                    ps88.ftom(220),
+                    ps88.dbToGain(-20),
+                    ps88.gainToDb(0.5),
+                    ps88.clamp(2, -1, 1),
+                ];
+            "#,
+            6,
+            &[],
+        );
+        assert_near(result[0], 440.0, 1e-3);
+        assert_near(result[1], 880.0, 1e-3);
+        assert_near(result[2], 57.0, 1e-3);
+        assert_near(result[3], 0.1, 1e-6);
+        assert_near(result[4], -6.0206, 1e-3);
+        assert_near(result[5], 1.0, 0.0);
+    }
+
+    #[test]
+    fn note_state() {
+        let mut midi = midi_event(0, 0x90, 60, 127);
+        midi.extend(midi_event(1, 0x91, 64, 64));
+        midi.extend(midi_event(2, 0x80, 60, 0));
+        // ベロシティ 0 の Note On は Note Off として扱われる
+        midi.extend(midi_event(3, 0x90, 67, 127));
+        midi.extend(midi_event(4, 0x90, 67, 0));
+        let result = run(
+            r#"
+                const keys = new ps88.NoteState();
+                const events = keys.update(ctx.midi);
+                return [
+                    events.length,
+                    keys.isDown(60) ? 1 : 0,
+                    keys.isDown(64) ? 1 : 0,
+                    keys.isDown(67) ? 1 : 0,
+                    keys.velocity(64),
+                    events[1].channel,
+                    events[4].type === "off" ? 1 : 0,
+                    events[4].time,
+                ];
+            "#,
+            8,
+            &midi,
+        );
+        assert_eq!(&result[0..4], &[5.0, 0.0, 1.0, 0.0]);
+        assert_near(result[4], 64.0 / 127.0, 1e-6);
+        assert_eq!(&result[5..8], &[1.0, 1.0, 4.0]);
+    }
+
+    #[test]
+    fn oscillator() {
+        // 正弦波
+        let result = run(
+            r#"
+                const osc = new ps88.Oscillator("sine", 12000);
+                const out = new Float32Array(4);
+                osc.process(out, 48000);
+                return out;
+            "#,
+            4,
+            &[],
+        );
+        assert_near(result[0], 0.0, 1e-6);
+        assert_near(result[1], 1.0, 1e-6);
+        assert_near(result[2], 0.0, 1e-6);
+        assert_near(result[3], -1.0, 1e-6);
+
+        // 帯域制限された波形は大きくはみ出さず、直流成分もほぼ無い
+        for shape in ["sawtooth", "square", "triangle"] {
+            let result = run(
+                &r#"
+                    const osc = new ps88.Oscillator("${shape}", 1000);
+                    const out = new Float32Array(48000);
+                    osc.process(out, 48000);
+                    return out;
+                "#
+                .replace("${shape}", shape),
+                48000,
+                &[],
+            );
+            let peak = result.iter().fold(0.0f32, |a, b| a.max(b.abs()));
+            let mean = result.iter().sum::<f32>() / result.len() as f32;
+            assert!(peak > 0.5 && peak < 1.2, "{}: peak {}", shape, peak);
+            assert_near(mean, 0.0, 0.05);
+        }
+    }
+
+    #[test]
+    fn adsr() {
+        let result = run(
+            r#"
+                const env = new ps88.Adsr({ attack: 0.001, decay: 0.001, sustain: 0.5, release: 0.001 });
+                const out = [];
+                env.gate(true);
+                for (let i = 0; i < 200; i++) out.push(env.next(48000));
+                env.gate(false);
+                for (let i = 0; i < 100; i++) out.push(env.next(48000));
+                out.push(env.isIdle() ? 1 : 0);
+                return out;
+            "#,
+            301,
+            &[],
+        );
+        // attack で 48 サンプルかけて 1 に到達する
+        assert_near(result[47], 1.0, 1e-6);
+        // decay の後は sustain を維持する
+        assert_near(result[199], 0.5, 1e-6);
+        // release で 0 になり idle に戻る
+        assert_near(result[299], 0.0, 1e-6);
+        assert_eq!(result[300], 1.0);
+    }
+
+    #[test]
+    fn biquad() {
+        let result = run(
+            r#"
+                const filter = new ps88.Biquad("lowpass", 1000);
+                filter.update(48000);
+                const nyquist = new Float32Array(4800).map((_, i) => (i % 2 === 0 ? 1 : -1));
+                filter.process(nyquist);
+                filter.reset();
+                const dc = new Float32Array(4800).fill(1);
+                filter.process(dc);
+                return [nyquist[nyquist.length - 1], dc[dc.length - 1]];
+            "#,
+            2,
+            &[],
+        );
+        // ナイキスト周波数は減衰し、直流はそのまま通る
+        assert_near(result[0], 0.0, 1e-3);
+        assert_near(result[1], 1.0, 1e-3);
+    }
+
+    #[test]
+    fn delay_line() {
+        let result = run(
+            r#"
+                const delay = new ps88.DelayLine(8);
+                for (let i = 1; i <= 10; i++) delay.write(i);
+                return [delay.read(0), delay.read(3), delay.read(1.5), delay.read(100)];
+            "#,
+            4,
+            &[],
+        );
+        assert_eq!(result[0], 10.0);
+        assert_eq!(result[1], 7.0);
+        assert_eq!(result[2], 8.5);
+        // 最大遅延時間より長い遅延は最大遅延時間に丸められる
+        assert_eq!(result[3], 2.0);
+    }
+
+    #[test]
+    fn synth() {
+        // 鍵盤を押している間だけ音が鳴る
+        let mut runtime = js::JsRuntimeBuilder::new().build();
+        runtime
+            .compile(
+                r#"
+                const synth = new ps88.Synth({ release: 0.001 });
+                const audio = (ctx) => synth.process(ctx);
+                const gui = () => {};
+            "#,
+                None,
+            )
+            .unwrap();
+        let mut audio = vec![0.0; 1024];
+        runtime
+            .audio(&mut audio, 2, 48000.0, &midi_event(0, 0x90, 69, 127))
+            .unwrap();
+        assert!(audio[0..512].iter().any(|x| x.abs() > 0.1));
+        assert_eq!(&audio[0..512], &audio[512..1024]);
+
+        runtime
+            .audio(&mut audio, 2, 48000.0, &midi_event(0, 0x80, 69, 0))
+            .unwrap();
+        runtime.audio(&mut audio, 2, 48000.0, &[]).unwrap();
+        assert!(audio.iter().all(|x| *x == 0.0));
+    }
+
+    #[test]
+    fn widgets() {
+        use crate::runtime::gui::{GuiContext, Shape, WidgetValue};
+
+        // 操作した値が次のフレームの ctx.changes で渡され、values に反映される
+        let mut runtime = js::JsRuntimeBuilder::new().build();
+        runtime
+            .compile(
+                r#"
+                const ui = new ps88.Widgets({ cutoff: 0.5 });
+                const audio = (ctx) => ctx.audio.fill(ui.values.cutoff);
+                const gui = (ctx) => {
+                    ui.update(ctx);
+                    return [
+                        ui.knob("cutoff", { x: 0, y: 0, width: 40, height: 40 }),
+                        ui.toggle("bypass", { x: 50, y: 0, width: 40, height: 20, value: true }),
+                        ui.slider("gain", { x: 0, y: 50, width: 90, height: 20, param: "param1" }),
+                    ];
+                };
+            "#,
+                None,
+            )
+            .unwrap();
+        let mut ctx = GuiContext::default();
+        let shapes = runtime.gui(&ctx).unwrap();
+        assert!(matches!(shapes[0], Shape::Knob { value, .. } if value == 0.5));
+        assert!(matches!(shapes[1], Shape::Toggle { value: true, .. }));
+        assert!(
+            matches!(&shapes[2], Shape::Slider { param: Some(param), .. } if param == "param1")
+        );
+
+        ctx.changes
+            .insert("cutoff".to_string(), WidgetValue::Number(0.25));
+        ctx.changes
+            .insert("bypass".to_string(), WidgetValue::Bool(false));
+        let shapes = runtime.gui(&ctx).unwrap();
+        assert!(matches!(shapes[0], Shape::Knob { value, .. } if value == 0.25));
+        assert!(matches!(shapes[1], Shape::Toggle { value: false, .. }));
+        let mut audio = vec![0.0; 4];
+        runtime.audio(&mut audio, 1, 48000.0, &[]).unwrap();
+        assert_eq!(audio, vec![0.25; 4]);
+    }
+}
@@ -0,0 +1,386 @@
+use crate::runtime::midi::{self, MidiEvent};
+
+/// 最大ボイス数を超えた時に、どのボイスを止めるか
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StealMode {
+    // 最も古いボイス
+    Oldest,
+    // 最も音量の小さいボイス
+    Quietest,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VoiceConfig {
+    pub max_voices: usize,
+    pub steal: StealMode,
+
+    // リリース開始からこの秒数が経ったボイスは、スクリプトが終了しなくても強制的に終了する
+    pub release_timeout: f32,
+}
+
+impl Default for VoiceConfig {
+    fn default() -> Self {
+        VoiceConfig {
+            max_voices: 16,
+            steal: StealMode::Oldest,
+            release_timeout: 10.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Voice {
+    pub id: u64,
+    pub channel: u8,
+    pub note: u8,
+
+    // 0-1
+    pub velocity: f32,
+
+    // 発音開始からのサンプル数
+    pub time: u64,
+
+    // Note Off を受け取った (サスティンペダルで保持中の場合は false のまま)
+    pub released: bool,
+
+    // リリース開始からのサンプル数
+    pub release_time: u64,
+
+    // Note Off を受け取ったが、サスティンペダルで保持している
+    pub sustained: bool,
+
+    // スクリプトが報告した現在の音量 (Quietest で止めるボイスを選ぶのに使う)
+    pub level: f32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VoiceEvent {
+    Started {
+        id: u64,
+    },
+    Terminated {
+        timing: u32,
+        id: u64,
+        channel: u8,
+        note: u8,
+    },
+}
+
+/// MIDI イベントからボイスの割り当て、停止、サスティンペダルの処理を行う
+pub struct VoiceManager {
+    config: VoiceConfig,
+    voices: Vec<Voice>,
+    next_id: u64,
+    sustain: [bool; 16],
+    events: Vec<VoiceEvent>,
+}
+
+impl VoiceManager {
+    pub fn new(config: VoiceConfig) -> Self {
+        VoiceManager {
+            config,
+            voices: Vec::new(),
+            next_id: 0,
+            sustain: [false; 16],
+            events: Vec::new(),
+        }
+    }
+
+    pub fn voices(&self) -> &[Voice] {
+        &self.voices
+    }
+
+    pub fn voice_mut(&mut self, id: u64) -> Option<&mut Voice> {
+        self.voices.iter_mut().find(|v| v.id == id)
+    }
+
+    /// 前回呼び出してから発生したボイスの開始/終了イベントを取り出す
+    pub fn take_events(&mut self) -> Vec<VoiceEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    pub fn handle(&mut self, event: &MidiEvent) {
+        let channel = event.channel();
+        match event.kind() {
+            // ベロシティ 0 の Note On は Note Off として扱う
+            midi::NOTE_ON if event.data2 > 0 => self.note_on(
+                event.timing,
+                channel,
+                event.data1,
+                event.data2 as f32 / 127.0,
+            ),
+            midi::NOTE_ON | midi::NOTE_OFF => self.note_off(channel, event.data1),
+            midi::CONTROL_CHANGE if event.data1 == midi::CC_SUSTAIN => {
+                self.sustain(channel, event.data2 >= 64)
+            }
+            midi::CONTROL_CHANGE if event.data1 == midi::CC_ALL_NOTES_OFF => {
+                self.sustain(channel, false);
+                for voice in self.voices.iter_mut().filter(|v| v.channel == channel) {
+                    release(voice);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn note_on(&mut self, timing: u32, channel: u8, note: u8, velocity: f32) {
+        if self.config.max_voices == 0 {
+            return;
+        }
+        while self.voices.len() >= self.config.max_voices {
+            let Some(victim) = self.choose_victim() else {
+                break;
+            };
+            self.terminate(victim, timing);
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.voices.push(Voice {
+            id,
+            channel,
+            note,
+            velocity,
+            time: 0,
+            released: false,
+            release_time: 0,
+            sustained: false,
+            level: velocity,
+        });
+        self.events.push(VoiceEvent::Started { id });
+    }
+
+    fn note_off(&mut self, channel: u8, note: u8) {
+        let sustain = self.sustain[channel as usize];
+        for voice in self
+            .voices
+            .iter_mut()
+            .filter(|v| v.channel == channel && v.note == note && !v.released)
+        {
+            if sustain {
+                voice.sustained = true;
+            } else {
+                release(voice);
+            }
+        }
+    }
+
+    fn sustain(&mut self, channel: u8, on: bool) {
+        self.sustain[channel as usize] = on;
+        if on {
+            return;
+        }
+        for voice in self
+            .voices
+            .iter_mut()
+            .filter(|v| v.channel == channel && v.sustained)
+        {
+            release(voice);
+        }
+    }
+
+    // 止めるボイスを選ぶ
+    // リリース中のボイスがあればそれを優先する
+    fn choose_victim(&self) -> Option<u64> {
+        let key = |v: &Voice| match self.config.steal {
+            StealMode::Oldest => -(v.time as f64),
+            StealMode::Quietest => v.level as f64,
+        };
+        self.voices
+            .iter()
+            .min_by(|a, b| b.released.cmp(&a.released).then(key(a).total_cmp(&key(b))))
+            .map(|v| v.id)
+    }
+
+    /// ボイスを終了する
+    pub fn terminate(&mut self, id: u64, timing: u32) {
+        let Some(index) = self.voices.iter().position(|v| v.id == id) else {
+            return;
+        };
+        let voice = self.voices.remove(index);
+        self.events.push(VoiceEvent::Terminated {
+            timing,
+            id,
+            channel: voice.channel,
+            note: voice.note,
+        });
+    }
+
+    /// 全てのボイスの時間を frames サンプル進める。
+    /// リリース開始から release_timeout 秒経ったボイスは timing の時刻で終了する。
+    pub fn advance(&mut self, frames: u32, timing: u32, sampling_rate: f32) {
+        let timeout = (self.config.release_timeout * sampling_rate) as u64;
+        let mut expired = Vec::new();
+        for voice in self.voices.iter_mut() {
+            voice.time += frames as u64;
+            if voice.released {
+                voice.release_time += frames as u64;
+                if voice.release_time >= timeout {
+                    expired.push(voice.id);
+                }
+            }
+        }
+        for id in expired {
+            self.terminate(id, timing);
+        }
+    }
+}
+
+fn release(voice: &mut Voice) {
+    voice.released = true;
+    voice.sustained = false;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note_on(timing: u32, note: u8) -> MidiEvent {
+        MidiEvent::new(timing, midi::NOTE_ON, 0, note, 100)
+    }
+
+    fn note_off(timing: u32, note: u8) -> MidiEvent {
+        MidiEvent::new(timing, midi::NOTE_OFF, 0, note, 0)
+    }
+
+    fn sustain(timing: u32, on: bool) -> MidiEvent {
+        MidiEvent::new(
+            timing,
+            midi::CONTROL_CHANGE,
+            0,
+            midi::CC_SUSTAIN,
+            if on { 127 } else { 0 },
+        )
+    }
+
+    fn notes(manager: &VoiceManager) -> Vec<u8> {
+        manager.voices().iter().map(|v| v.note).collect()
+    }
+
+    #[test]
+    fn allocation() {
+        let mut manager = VoiceManager::new(VoiceConfig::default());
+        manager.handle(&note_on(0, 60));
+        manager.handle(&note_on(0, 64));
+        assert_eq!(notes(&manager), vec![60, 64]);
+        assert_eq!(
+            manager.take_events(),
+            vec![VoiceEvent::Started { id: 0 }, VoiceEvent::Started { id: 1 }]
+        );
+
+        // Note Off でリリースに入るが、終了はスクリプトかタイムアウトが決める
+        manager.handle(&note_off(10, 60));
+        assert!(manager.voices()[0].released);
+        assert!(!manager.voices()[1].released);
+        manager.terminate(0, 20);
+        assert_eq!(notes(&manager), vec![64]);
+        assert_eq!(
+            manager.take_events(),
+            vec![VoiceEvent::Terminated {
+                timing: 20,
+                id: 0,
+                channel: 0,
+                note: 60
+            }]
+        );
+    }
+
+    #[test]
+    fn release_timeout() {
+        let mut manager = VoiceManager::new(VoiceConfig {
+            release_timeout: 1.0,
+            ..Default::default()
+        });
+        manager.handle(&note_on(0, 60));
+        manager.handle(&note_off(0, 60));
+        manager.take_events();
+
+        manager.advance(47999, 47999, 48000.0);
+        assert_eq!(manager.voices().len(), 1);
+        manager.advance(1, 48000, 48000.0);
+        assert_eq!(manager.voices().len(), 0);
+        assert_eq!(
+            manager.take_events(),
+            vec![VoiceEvent::Terminated {
+                timing: 48000,
+                id: 0,
+                channel: 0,
+                note: 60
+            }]
+        );
+    }
+
+    #[test]
+    fn steal_oldest() {
+        let mut manager = VoiceManager::new(VoiceConfig {
+            max_voices: 2,
+            ..Default::default()
+        });
+        manager.handle(&note_on(0, 60));
+        manager.advance(10, 10, 48000.0);
+        manager.handle(&note_on(10, 62));
+        manager.advance(10, 20, 48000.0);
+        manager.take_events();
+
+        // 最も古いボイスが止まる
+        manager.handle(&note_on(20, 64));
+        assert_eq!(notes(&manager), vec![62, 64]);
+        assert_eq!(
+            manager.take_events(),
+            vec![
+                VoiceEvent::Terminated {
+                    timing: 20,
+                    id: 0,
+                    channel: 0,
+                    note: 60
+                },
+                VoiceEvent::Started { id: 2 }
+            ]
+        );
+
+        // リリース中のボイスは新しくても優先して止まる
+        manager.handle(&note_off(30, 64));
+        manager.handle(&note_on(30, 65));
+        assert_eq!(notes(&manager), vec![62, 65]);
+    }
+
+    #[test]
+    fn steal_quietest() {
+        let mut manager = VoiceManager::new(VoiceConfig {
+            max_voices: 2,
+            steal: StealMode::Quietest,
+            ..Default::default()
+        });
+        manager.handle(&note_on(0, 60));
+        manager.handle(&note_on(0, 62));
+        manager.voice_mut(0).unwrap().level = 0.8;
+        manager.voice_mut(1).unwrap().level = 0.1;
+        manager.handle(&note_on(0, 64));
+        assert_eq!(notes(&manager), vec![60, 64]);
+    }
+
+    #[test]
+    fn sustain_pedal() {
+        let mut manager = VoiceManager::new(VoiceConfig::default());
+        manager.handle(&sustain(0, true));
+        manager.handle(&note_on(0, 60));
+        manager.handle(&note_off(10, 60));
+
+        // ペダルを踏んでいる間はリリースしない
+        assert!(!manager.voices()[0].released);
+        assert!(manager.voices()[0].sustained);
+
+        // ペダルを離すとリリースする
+        manager.handle(&sustain(20, false));
+        assert!(manager.voices()[0].released);
+        assert!(!manager.voices()[0].sustained);
+
+        // ペダルを離す前に再度押された鍵盤はリリースしない
+        manager.handle(&sustain(30, true));
+        manager.handle(&note_on(30, 62));
+        manager.handle(&note_off(40, 62));
+        manager.handle(&note_on(50, 62));
+        manager.handle(&sustain(60, false));
+        let released: Vec<bool> = manager.voices().iter().map(|v| v.released).collect();
+        assert_eq!(released, vec![true, true, false]);
+    }
+}